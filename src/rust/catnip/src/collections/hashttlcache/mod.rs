@@ -273,6 +273,22 @@ where
     // todo: how do i implement `std::iter::IntoIterator` for this type?
     // todo: how do i get `&cache` to alias to `cache.iter()`?
     pub fn iter(&self) -> impl Iterator<Item = (&'_ K, &'_ V)> {
+        self.iter_records()
+            .map(|(key, record)| (key, &record.value))
+    }
+
+    /// Like `iter`, but also yields when each entry expires, if ever.
+    pub fn iter_with_expiry(&self) -> impl Iterator<Item = (&'_ K, &'_ V, Option<Instant>)> {
+        self.iter_records()
+            .map(|(key, record)| (key, &record.value, record.expiry.as_ref().map(|e| e.0)))
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.graveyard.clear();
+    }
+
+    fn iter_records(&self) -> impl Iterator<Item = (&'_ K, &'_ Record<V>)> {
         let clock = self.clock;
         self.map.iter().flat_map(move |(key, record)| {
             if let Some(e) = record.expiry.clone() {
@@ -281,12 +297,7 @@ where
                 }
             }
 
-            Some((key, &record.value))
+            Some((key, record))
         })
     }
-
-    pub fn clear(&mut self) {
-        self.map.clear();
-        self.graveyard.clear();
-    }
 }
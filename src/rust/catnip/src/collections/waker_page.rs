@@ -1,7 +1,14 @@
-use crate::sync::{
-    SharedWaker,
-    WakerU64,
+use crate::{
+    fail::Fail,
+    sync::{
+        SharedWaker,
+        ThreadOwner,
+        WakerU64,
+    },
 };
+use gen_iter::gen_iter;
+#[cfg(test)]
+use hashbrown::HashMap;
 use std::{
     alloc::{
         AllocRef,
@@ -14,67 +21,336 @@ use std::{
         self,
         NonNull,
     },
+    slice,
     task::{
         RawWaker,
         RawWakerVTable,
     },
 };
+#[cfg(test)]
+use std::{
+    lazy::SyncLazy,
+    sync::Mutex,
+};
 
+/// Slots per page for the scheduler and pollers. Each flag word is a `u64`, so a page can track
+/// at most 64 slots; `SizedWakerPage` allows fewer for callers that only need a handful.
 pub const WAKER_PAGE_SIZE: usize = 64;
 
+pub type WakerPage = SizedWakerPage<WAKER_PAGE_SIZE>;
+pub type WakerPageRef = SizedWakerPageRef<WAKER_PAGE_SIZE>;
+
+/// Iterates over the slot indices set in a bitmask like the one `take_notified` returns, lowest
+/// first.
+// Adapted from https://lemire.me/blog/2018/02/21/iterating-over-set-bits-quickly/
+pub fn iter_set_bits(mut bitset: u64) -> impl Iterator<Item = usize> {
+    gen_iter!({
+        while bitset != 0 {
+            // `bitset & -bitset` returns a bitset with only the lowest significant bit set
+            let t = bitset & bitset.wrapping_neg();
+            yield bitset.trailing_zeros() as usize;
+            bitset ^= t;
+        }
+    })
+}
+
+// Live `WakerRef`s per page address, raw or not, for `check_invariants` to hold the refcount
+// against. Only kept in tests: it's a global lock on every waker clone and drop, and it lives
+// outside the page so as not to grow it.
+#[cfg(test)]
+static LIVE_WAKERS: SyncLazy<Mutex<HashMap<usize, u64>>> =
+    SyncLazy::new(|| Mutex::new(HashMap::new()));
+
+#[cfg(test)]
+fn live_wakers(page: usize) -> u64 {
+    LIVE_WAKERS.lock().unwrap().get(&page).copied().unwrap_or(0)
+}
+
+#[cfg(test)]
+fn add_live_waker(page: usize) {
+    *LIVE_WAKERS.lock().unwrap().entry(page).or_insert(0) += 1;
+}
+
+#[cfg(test)]
+fn remove_live_waker(page: usize) {
+    let mut live = LIVE_WAKERS.lock().unwrap();
+    let count = live.get_mut(&page).expect("Dropped an uncounted waker");
+    *count -= 1;
+    // Drop the entry with the last waker, so a page allocated at the same address later on
+    // starts from zero.
+    if *count == 0 {
+        live.remove(&page);
+    }
+}
+
+/// Where waker pages get their memory, for runtimes that would rather hand out pages from a
+/// preallocated pool than go to the global allocator. Pages are freed with the allocator they
+/// came from, possibly on another thread, hence `Sync`.
+pub trait PageAllocator: Sync {
+    /// Returns memory for `layout`, or `None` if there's none left.
+    fn alloc(&self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// # Safety
+    ///
+    /// `ptr` must have come from `alloc` on this allocator with the same `layout`.
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+/// The order a page reports notified slots in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NotifyOrder {
+    /// `take_notified` hands them all over at once as a bitmask, so they're serviced lowest slot
+    /// first.
+    Bits,
+    /// `pop_notified` hands them over one at a time in the order they were notified, for
+    /// latency-sensitive executors.
+    Fifo,
+}
+
+/// Allocates pages from `Global`, which is what `SizedWakerPage::new` uses.
+pub struct GlobalPageAllocator;
+
+impl PageAllocator for GlobalPageAllocator {
+    fn alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        Global.alloc(layout).ok().map(|ptr| ptr.cast())
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        Global.dealloc(ptr, layout)
+    }
+}
+
+// A raw waker's data is the page's address, plus the slot index in the low six bits (pages are
+// 64-byte aligned) and the slot's generation in the top 16 bits, which are always zero in
+// user-space addresses on the 64-bit targets we run on.
+const GENERATION_SHIFT: u32 = 48;
+const ADDR_MASK: usize = (1 << GENERATION_SHIFT) - 1;
+
+// The refcount's top bit is set for pages created with `NotifyOrder::Fifo`, which have a ring of
+// notified slots after their generations. Keeping the flag there lets `Drop` work out the page's
+// layout without growing the header past a cache line.
+const FIFO_FLAG: u64 = 1 << 63;
+const COUNT_MASK: u64 = !FIFO_FLAG;
+
+// Like `std::sync::Arc`'s limit: we can't actually overflow the count by cloning, but an absurd
+// refcount means references are being leaked and we'd rather not keep going. Half the count's
+// range keeps it well clear of `FIFO_FLAG`.
+const MAX_REFCOUNT: u64 = COUNT_MASK / 2;
+
+// Layout of a FIFO page's ring: the number of pops and pushes so far, a mask of the slots waiting
+// in it, then one byte per position holding a slot index plus one, or zero once it's been popped.
+const RING_HEAD: usize = 0;
+const RING_TAIL: usize = 1;
+const RING_QUEUED: usize = 2;
+const RING_ENTRIES: usize = 3;
+
+// A `WakerRef` for slot `ix` points `ix` bytes past the start of its page, and finds the page
+// again by rounding down to the page's alignment. That only works while every slot index is less
+// than the alignment, which is why we're aligned to 64 (the most slots a page can have) whatever
+// `N` is.
+//
+// This is only the page's header. The slot generations are allocated right after it, sized for
+// `N` slots, so a small page takes two cache lines rather than three, and the notification ring
+// follows them on a FIFO page.
 #[repr(align(64))]
-pub struct WakerPage {
+pub struct SizedWakerPage<const N: usize> {
     refcount: WakerU64,
     notified: WakerU64,
     completed: WakerU64,
     dropped: WakerU64,
     waker: SharedWaker,
-    _unused: [u8; 24],
+    owner: ThreadOwner,
+    alloc: &'static dyn PageAllocator,
 }
 
-impl WakerPage {
-    pub fn new(waker: SharedWaker) -> WakerPageRef {
-        let layout = Layout::new::<WakerPage>();
+impl<const N: usize> SizedWakerPage<N> {
+    // Slot generations are 16 bits, packed four to a word.
+    const GENERATION_WORDS: usize = (N + 3) / 4;
+    const RING_WORDS: usize = RING_ENTRIES + (N + 7) / 8;
+    // Evaluated when a page size is first used, so an `N` the flag words can't hold fails the
+    // build instead of the allocation.
+    const VALID_SIZE: () = assert!(N > 0 && N <= 64, "Waker pages hold between 1 and 64 slots");
+
+    // The memory a page takes, header, generations and ring together.
+    fn layout(fifo: bool) -> Layout {
+        let words = Self::GENERATION_WORDS + if fifo { Self::RING_WORDS } else { 0 };
+        let size = mem::size_of::<Self>() + words * mem::size_of::<WakerU64>();
+        Layout::from_size_align(size, mem::align_of::<Self>())
+            .unwrap()
+            .pad_to_align()
+    }
+
+    pub fn new(waker: SharedWaker) -> Result<SizedWakerPageRef<N>, Fail> {
+        Self::new_in(waker, &GlobalPageAllocator)
+    }
+
+    /// Like `new`, but takes the page's memory from `alloc`, which also frees it once the last
+    /// reference to the page is dropped.
+    pub fn new_in(
+        waker: SharedWaker,
+        alloc: &'static dyn PageAllocator,
+    ) -> Result<SizedWakerPageRef<N>, Fail> {
+        Self::new_ordered(waker, alloc, NotifyOrder::Bits)
+    }
+
+    /// Like `new_in`, but with a choice of the order notified slots are reported in. A FIFO page
+    /// takes its ring from `alloc` along with the rest of the page.
+    pub fn new_ordered(
+        waker: SharedWaker,
+        alloc: &'static dyn PageAllocator,
+        order: NotifyOrder,
+    ) -> Result<SizedWakerPageRef<N>, Fail> {
+        let () = Self::VALID_SIZE;
+        let fifo = order == NotifyOrder::Fifo;
+        let layout = Self::layout(fifo);
         assert_eq!(layout.align(), 64);
-        let mut ptr: NonNull<WakerPage> = Global.alloc(layout).expect("Allocation failed").cast();
+        let mut ptr: NonNull<Self> = match alloc.alloc(layout) {
+            Some(ptr) => ptr.cast(),
+            None => return Err(Fail::OutOfMemory {}),
+        };
         unsafe {
             let page = ptr.as_mut();
-            ptr::write(&mut page.refcount as *mut _, WakerU64::new(1));
+            let flag = if fifo { FIFO_FLAG } else { 0 };
+            ptr::write(&mut page.refcount as *mut _, WakerU64::new(flag | 1));
             ptr::write(&mut page.notified as *mut _, WakerU64::new(0));
             ptr::write(&mut page.completed as *mut _, WakerU64::new(0));
             ptr::write(&mut page.dropped as *mut _, WakerU64::new(0));
             ptr::write(&mut page.waker as *mut _, waker);
+            ptr::write(&mut page.owner as *mut _, ThreadOwner::new());
+            ptr::write(&mut page.alloc as *mut _, alloc);
+            let words = ptr.as_ptr().add(1) as *mut WakerU64;
+            let len = (layout.size() - mem::size_of::<Self>()) / mem::size_of::<WakerU64>();
+            for i in 0..len {
+                ptr::write(words.add(i), WakerU64::new(0));
+            }
         }
-        WakerPageRef(ptr)
+        assert_eq!(
+            ptr.as_ptr() as usize & !ADDR_MASK,
+            0,
+            "Waker page address doesn't leave room for generation tags"
+        );
+        Ok(SizedWakerPageRef(ptr))
     }
 
     pub fn notify(&self, ix: usize) {
-        debug_assert!(ix < 64);
-        self.notified.fetch_or(1 << ix);
+        debug_assert!(ix < N);
+        self.set_notified(1 << ix);
         self.waker.wake();
     }
 
+    /// Notifies every slot in `mask` with a single atomic update and one wake.
+    pub fn notify_mask(&self, mask: u64) {
+        debug_assert!(N == 64 || mask >> N == 0);
+        if mask == 0 {
+            return;
+        }
+        self.set_notified(mask);
+        self.waker.wake();
+    }
+
+    fn set_notified(&self, mask: u64) {
+        let newly_notified = mask & !self.notified.fetch_or(mask);
+        if let Some(ring) = self.ring() {
+            for ix in iter_set_bits(newly_notified) {
+                Self::push(ring, ix);
+            }
+        }
+    }
+
+    fn ring(&self) -> Option<&[WakerU64]> {
+        if self.refcount.load() & FIFO_FLAG == 0 {
+            return None;
+        }
+        unsafe {
+            let first = self.trailer().add(Self::GENERATION_WORDS);
+            Some(slice::from_raw_parts(first, Self::RING_WORDS))
+        }
+    }
+
+    // Appends `ix` to the ring unless it's already waiting there, which keeps the ring to one
+    // position per slot. Each push claims its own position before filling it in, so pushes can
+    // come from any thread; a pop that finds a claimed position still empty waits for the wake
+    // that follows the push.
+    fn push(ring: &[WakerU64], ix: usize) {
+        let bit = 1 << ix;
+        if ring[RING_QUEUED].fetch_or(bit) & bit != 0 {
+            return;
+        }
+        let pos = ring[RING_TAIL].fetch_add(1) as usize % N;
+        ring[RING_ENTRIES + pos / 8].fetch_or((ix as u64 + 1) << (8 * (pos % 8)));
+    }
+
+    pub fn is_notified(&self, ix: usize) -> bool {
+        debug_assert!(ix < N);
+        self.notified.load() & (1 << ix) != 0
+    }
+
     pub fn take_notified(&self) -> u64 {
         // Unset all ready bits, since spurious notifications for completed futures would lead
         // us to poll them after completion.
         let mut notified = self.notified.swap(0);
         notified &= !self.completed.load();
         notified &= !self.dropped.load();
+        debug_assert_eq!(
+            notified & self.completed.load(),
+            0,
+            "Handed out a completed slot"
+        );
         notified
     }
 
+    /// Takes the slot that was notified longest ago, on a `NotifyOrder::Fifo` page. A slot
+    /// notified again before it's taken only comes out once, and completed or dropped slots are
+    /// skipped like in `take_notified`. Like `take_notified`, only for the page's owner.
+    pub fn pop_notified(&self) -> Option<usize> {
+        let ring = self
+            .ring()
+            .expect("pop_notified on a page without NotifyOrder::Fifo");
+        loop {
+            let head = ring[RING_HEAD].load();
+            if head == ring[RING_TAIL].load() {
+                return None;
+            }
+            let pos = head as usize % N;
+            let (word, shift) = (&ring[RING_ENTRIES + pos / 8], 8 * (pos % 8));
+            let entry = (word.load() >> shift) & 0xff;
+            if entry == 0 {
+                return None;
+            }
+            word.fetch_and(!(0xff << shift));
+            ring[RING_HEAD].swap(head + 1);
+
+            let ix = entry as usize - 1;
+            let bit = 1 << ix;
+            ring[RING_QUEUED].fetch_and(!bit);
+            // The bit is already gone if `take_notified` or `clear` got to the slot first.
+            if self.notified.fetch_and(!bit) & bit == 0 {
+                continue;
+            }
+            if (self.completed.load() | self.dropped.load()) & bit != 0 {
+                continue;
+            }
+            return Some(ix);
+        }
+    }
+
     pub fn has_completed(&self, ix: usize) -> bool {
-        debug_assert!(ix < 64);
+        debug_assert!(ix < N);
         self.completed.load() & (1 << ix) != 0
     }
 
+    /// Number of slots that have completed but haven't been taken or dropped yet.
+    pub fn completed_count(&self) -> usize {
+        (self.completed.load() & !self.dropped.load()).count_ones() as usize
+    }
+
     pub fn mark_completed(&self, ix: usize) {
-        debug_assert!(ix < 64);
+        debug_assert!(ix < N);
         self.completed.fetch_or(1 << ix);
     }
 
     pub fn mark_dropped(&self, ix: usize) {
-        debug_assert!(ix < 64);
+        debug_assert!(ix < N);
         self.dropped.fetch_or(1 << ix);
         self.waker.wake();
     }
@@ -83,101 +359,214 @@ impl WakerPage {
         self.dropped.swap(0)
     }
 
+    /// Number of slots marked dropped since the last `take_dropped`.
+    pub fn pending_dropped_count(&self) -> u32 {
+        self.dropped.load().count_ones()
+    }
+
+    pub(crate) fn dropped_bits(&self) -> u64 {
+        self.dropped.load()
+    }
+
     pub fn was_dropped(&self, ix: usize) -> bool {
-        debug_assert!(ix < 64);
+        debug_assert!(ix < N);
         self.dropped.load() & (1 << ix) != 0
     }
 
+    // The words allocated after the header.
+    fn trailer(&self) -> *const WakerU64 {
+        unsafe { (self as *const Self).add(1) as *const WakerU64 }
+    }
+
+    // Bumped each time a slot is reused, so wakers left over from its previous occupant can be
+    // told apart and ignored.
+    fn generations(&self) -> &[WakerU64] {
+        unsafe { slice::from_raw_parts(self.trailer(), Self::GENERATION_WORDS) }
+    }
+
+    pub fn generation(&self, ix: usize) -> u16 {
+        debug_assert!(ix < N);
+        (self.generations()[ix / 4].load() >> (16 * (ix % 4))) as u16
+    }
+
+    /// Prepares slot `ix` for a new occupant: it starts out notified, and wakers handed out for
+    /// the previous occupant no longer reach it.
     pub fn initialize(&self, ix: usize) {
-        debug_assert!(ix < 64);
-        self.notified.fetch_or(1 << ix);
+        debug_assert!(ix < N);
+        // Wakers only ever read the generations, and slots are only (re)initialized from the
+        // page's owning thread, so this doesn't need to be a single atomic update.
+        let word = &self.generations()[ix / 4];
+        let shift = 16 * (ix % 4);
+        let old = word.load();
+        let generation = ((old >> shift) as u16).wrapping_add(1);
+        word.swap((old & !(0xffff << shift)) | (generation as u64) << shift);
+
+        self.set_notified(1 << ix);
         self.completed.fetch_and(!(1 << ix));
         self.dropped.fetch_and(!(1 << ix));
     }
 
     pub fn clear(&self, ix: usize) {
-        debug_assert!(ix < 64);
+        debug_assert!(ix < N);
         let mask = !(1 << ix);
         self.notified.fetch_and(mask);
         self.completed.fetch_and(mask);
         self.dropped.fetch_and(mask);
     }
+
+    /// Panics if the page's bookkeeping is inconsistent: it's misaligned, the refcount is out of
+    /// range (or, in tests, doesn't cover the caller's reference plus every live waker), or a
+    /// flag is set for a slot past `N`. `take_notified` separately checks that it never hands out
+    /// a completed slot. Only meant for tests and debug builds of executors, where it catches the
+    /// unsafe code going wrong close to the cause.
+    #[cfg(debug_assertions)]
+    pub fn check_invariants(&self) {
+        let addr = self as *const Self as usize;
+        assert_eq!(addr % 64, 0, "Waker page isn't 64-byte aligned");
+        assert_eq!(
+            addr & !ADDR_MASK,
+            0,
+            "Waker page has no room for generation tags"
+        );
+
+        let refcount = self.refcount.load() & COUNT_MASK;
+        assert!(refcount >= 1 && refcount <= MAX_REFCOUNT);
+        #[cfg(test)]
+        {
+            let wakers = live_wakers(addr);
+            assert!(
+                refcount > wakers,
+                "Refcount {} doesn't cover {} wakers and a page reference",
+                refcount,
+                wakers
+            );
+        }
+
+        if N < 64 {
+            let flags = self.notified.load() | self.completed.load() | self.dropped.load();
+            assert_eq!(flags >> N, 0, "Flags set for slots past the page's size");
+        }
+
+        if let Some(ring) = self.ring() {
+            let waiting = ring[RING_TAIL].load() - ring[RING_HEAD].load();
+            assert!(
+                waiting <= N as u64,
+                "Notification ring holds more than one entry per slot"
+            );
+        }
+    }
 }
 
-pub struct WakerPageRef(NonNull<WakerPage>);
+pub struct SizedWakerPageRef<const N: usize>(NonNull<SizedWakerPage<N>>);
+
+impl<const N: usize> SizedWakerPageRef<N> {
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.0 == other.0
+    }
 
-impl WakerPageRef {
     pub fn raw_waker(&self, ix: usize) -> RawWaker {
         self.waker(ix).into_raw_waker()
     }
 
-    fn waker(&self, ix: usize) -> WakerRef {
-        debug_assert!(ix < 64);
+    fn waker(&self, ix: usize) -> WakerRef<N> {
+        debug_assert!(ix < N);
 
         // Bump the refcount for our new reference.
         let self_ = self.clone();
         mem::forget(self_);
+        let addr = self.0.as_ptr() as usize;
+        #[cfg(test)]
+        add_live_waker(addr);
 
-        unsafe {
-            let base_ptr: *mut u8 = self.0.as_ptr().cast();
-            let ptr = NonNull::new_unchecked(base_ptr.offset(ix as isize));
-            WakerRef(ptr)
-        }
+        let generation = self.generation(ix) as usize;
+        WakerRef(addr | ix | generation << GENERATION_SHIFT)
     }
 }
 
-impl Clone for WakerPageRef {
+// The refcount follows `std::sync::Arc`. Increments only need to be atomic, since the caller
+// already holds a reference that keeps the page alive. The decrement that reaches zero must
+// happen after every other reference's last use of the page, which `Arc` gets from a `Release`
+// decrement paired with an `Acquire` fence. `WakerU64` is `SeqCst` throughout, which is strictly
+// stronger than both, so we don't need a separate fence.
+impl<const N: usize> Clone for SizedWakerPageRef<N> {
     fn clone(&self) -> Self {
-        let new_refcount = unsafe {
-            // TODO: We could use `Relaxed` here, see `std::sync::Arc` for documentation.
-            self.0.as_ref().refcount.fetch_add(1)
-        };
-        debug_assert!(new_refcount < std::isize::MAX as u64);
+        let old_refcount = unsafe { self.0.as_ref().refcount.fetch_add(1) } & COUNT_MASK;
+        debug_assert!(old_refcount >= 1, "Cloned a freed SizedWakerPageRef");
+        // Like `Arc`, abort rather than panic: unwinding would run destructors that still
+        // reference the page.
+        if old_refcount > MAX_REFCOUNT {
+            std::process::abort();
+        }
         Self(self.0)
     }
 }
 
-impl Drop for WakerPageRef {
+impl<const N: usize> Drop for SizedWakerPageRef<N> {
     fn drop(&mut self) {
         unsafe {
-            if self.0.as_ref().refcount.fetch_sub(1) != 1 {
+            let old_refcount = self.0.as_ref().refcount.fetch_sub(1);
+            debug_assert!(
+                old_refcount & COUNT_MASK >= 1,
+                "Dropped a SizedWakerPageRef twice"
+            );
+            if old_refcount & COUNT_MASK != 1 {
                 return;
             }
+            let alloc = self.0.as_ref().alloc;
+            let layout = SizedWakerPage::<N>::layout(old_refcount & FIFO_FLAG != 0);
             ptr::drop_in_place(self.0.as_mut());
-            Global.dealloc(self.0.cast(), Layout::for_value(self.0.as_ref()));
+            alloc.dealloc(self.0.cast(), layout);
         }
     }
 }
 
-impl Deref for WakerPageRef {
-    type Target = WakerPage;
+// With the `threadunsafe` feature, the refcount and flags are plain integers and `SharedWaker` is
+// an `Rc`, so a page must stay on the thread that created it, and the raw pointer already keeps
+// `SizedWakerPageRef` from being `Send` or `Sync`. Otherwise every field is atomic (the waker is an
+// `Arc<AtomicWaker>`) or `Sync` (the allocator), and sharing a page is as safe as sharing an `Arc`
+// of it.
+#[cfg(not(feature = "threadunsafe"))]
+unsafe impl<const N: usize> Send for SizedWakerPageRef<N> {}
+#[cfg(not(feature = "threadunsafe"))]
+unsafe impl<const N: usize> Sync for SizedWakerPageRef<N> {}
+
+impl<const N: usize> Deref for SizedWakerPageRef<N> {
+    type Target = SizedWakerPage<N>;
 
-    fn deref(&self) -> &WakerPage {
+    fn deref(&self) -> &SizedWakerPage<N> {
         unsafe { self.0.as_ref() }
     }
 }
 
 #[repr(transparent)]
-struct WakerRef(NonNull<u8>);
-
-impl WakerRef {
-    fn base_ptr(&self) -> (NonNull<WakerPage>, usize) {
-        let ptr = self.0.as_ptr();
-
-        let forward_offset = ptr.align_offset(64);
-        let mut base_ptr = ptr;
-        let mut offset = 0;
-        if forward_offset != 0 {
-            offset = 64 - forward_offset;
-            base_ptr = ptr.wrapping_sub(offset);
-        }
-        unsafe { (NonNull::new_unchecked(base_ptr).cast(), offset) }
+struct WakerRef<const N: usize>(usize);
+
+impl<const N: usize> WakerRef<N> {
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(
+        waker_ref_clone::<N>,
+        waker_ref_wake::<N>,
+        waker_ref_wake_by_ref::<N>,
+        waker_ref_drop::<N>,
+    );
+
+    fn base_ptr(&self) -> (NonNull<SizedWakerPage<N>>, usize) {
+        let addr = self.0 & ADDR_MASK;
+        let offset = addr % 64;
+        let base_ptr = (addr - offset) as *mut SizedWakerPage<N>;
+        unsafe { (NonNull::new_unchecked(base_ptr), offset) }
+    }
+
+    fn generation(&self) -> u16 {
+        (self.0 >> GENERATION_SHIFT) as u16
     }
 
     fn wake_by_ref(&self) {
         let (base_ptr, offset) = self.base_ptr();
         let base = unsafe { &*base_ptr.as_ptr() };
-        base.notify(offset);
+        // If the slot's been reused, this waker belongs to a future that's already gone.
+        if base.generation(offset) == self.generation() {
+            base.notify(offset);
+        }
     }
 
     fn wake(self) {
@@ -185,78 +574,153 @@ impl WakerRef {
     }
 
     fn into_raw_waker(self) -> RawWaker {
-        let ptr = self.0.cast().as_ptr() as *const ();
-        let waker = RawWaker::new(ptr, &VTABLE);
+        let waker = RawWaker::new(self.0 as *const (), &Self::VTABLE);
         mem::forget(self);
         waker
     }
 }
 
-unsafe fn waker_ref_clone(ptr: *const ()) -> RawWaker {
-    let p = WakerRef(NonNull::new_unchecked(ptr as *const u8 as *mut u8));
+// The vtable is our entry point from code that may be running on any thread, so check that
+// before we touch the page.
+unsafe fn waker_ref_from_raw<const N: usize>(ptr: *const ()) -> WakerRef<N> {
+    // Don't let a failed check unwind through `WakerRef::drop`, which would touch the refcount.
+    let p = mem::ManuallyDrop::new(WakerRef(ptr as usize));
+    let (base_ptr, _) = p.base_ptr();
+    base_ptr.as_ref().owner.assert_current();
+    mem::ManuallyDrop::into_inner(p)
+}
+
+unsafe fn waker_ref_clone<const N: usize>(ptr: *const ()) -> RawWaker {
+    let p = waker_ref_from_raw::<N>(ptr);
     let q = p.clone();
     mem::forget(p);
     q.into_raw_waker()
 }
 
-unsafe fn waker_ref_wake(ptr: *const ()) {
-    let p = WakerRef(NonNull::new_unchecked(ptr as *const u8 as *mut u8));
+unsafe fn waker_ref_wake<const N: usize>(ptr: *const ()) {
+    let p = waker_ref_from_raw::<N>(ptr);
     p.wake();
 }
 
-unsafe fn waker_ref_wake_by_ref(ptr: *const ()) {
-    let p = WakerRef(NonNull::new_unchecked(ptr as *const u8 as *mut u8));
+unsafe fn waker_ref_wake_by_ref<const N: usize>(ptr: *const ()) {
+    let p = waker_ref_from_raw::<N>(ptr);
     p.wake_by_ref();
     mem::forget(p);
 }
 
-unsafe fn waker_ref_drop(ptr: *const ()) {
-    let p = WakerRef(NonNull::new_unchecked(ptr as *const u8 as *mut u8));
+unsafe fn waker_ref_drop<const N: usize>(ptr: *const ()) {
+    let p = waker_ref_from_raw::<N>(ptr);
     drop(p);
 }
 
-const VTABLE: RawWakerVTable = RawWakerVTable::new(
-    waker_ref_clone,
-    waker_ref_wake,
-    waker_ref_wake_by_ref,
-    waker_ref_drop,
-);
-
-impl Clone for WakerRef {
+impl<const N: usize> Clone for WakerRef<N> {
     fn clone(&self) -> Self {
         let (base_ptr, _) = self.base_ptr();
-        let p = WakerPageRef(base_ptr);
+        let p = SizedWakerPageRef(base_ptr);
         mem::forget(p.clone());
+        #[cfg(test)]
+        add_live_waker(base_ptr.as_ptr() as usize);
         mem::forget(p);
         WakerRef(self.0)
     }
 }
 
-impl Drop for WakerRef {
+impl<const N: usize> Drop for WakerRef<N> {
     fn drop(&mut self) {
         let (base_ptr, _) = self.base_ptr();
+        #[cfg(test)]
+        remove_live_waker(base_ptr.as_ptr() as usize);
         // Decrement the refcount.
-        drop(WakerPageRef(base_ptr));
+        drop(SizedWakerPageRef(base_ptr));
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::{
+        GlobalPageAllocator,
+        NotifyOrder,
+        PageAllocator,
         SharedWaker,
+        SizedWakerPage,
         WakerPage,
     };
-    use std::mem;
+    use crate::fail::Fail;
+    use must_let::must_let;
+    #[cfg(debug_assertions)]
+    use rand::{
+        rngs::SmallRng,
+        Rng,
+        SeedableRng,
+    };
+    use std::{
+        alloc::Layout,
+        iter,
+        mem,
+        ptr::NonNull,
+        sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        task::Waker,
+    };
+
+    // Hands out at most `limit` pages from `Global`, counting what's outstanding.
+    struct CountingAllocator {
+        limit: usize,
+        live: AtomicUsize,
+    }
+
+    impl PageAllocator for CountingAllocator {
+        fn alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+            if self.live.load(Ordering::SeqCst) == self.limit {
+                return None;
+            }
+            self.live.fetch_add(1, Ordering::SeqCst);
+            GlobalPageAllocator.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.live.fetch_sub(1, Ordering::SeqCst);
+            GlobalPageAllocator.dealloc(ptr, layout)
+        }
+    }
+
+    #[cfg(not(feature = "threadunsafe"))]
+    #[test]
+    fn test_marker_traits() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<WakerPage>();
+        assert_send_sync::<super::WakerPageRef>();
+    }
+
+    #[cfg(feature = "threadunsafe")]
+    #[test]
+    fn test_cross_thread_wake() {
+        let p = WakerPage::new(SharedWaker::new()).unwrap();
+        let waker = unsafe { Waker::from_raw(p.raw_waker(0)) };
+        let r = std::thread::spawn(move || {
+            // Leak the waker rather than have unwinding drop it on this thread too.
+            let waker = mem::ManuallyDrop::new(waker);
+            waker.wake_by_ref();
+        })
+        .join();
+        assert!(r.is_err());
+        assert_eq!(p.take_notified(), 0);
+    }
 
     #[test]
     fn test_size() {
         assert_eq!(mem::size_of::<WakerPage>(), 64);
+        assert_eq!(WakerPage::layout(false).align(), 64);
+        assert_eq!(WakerPage::layout(false).size(), 192);
+        assert_eq!(WakerPage::layout(true).size(), 320);
     }
 
     #[test]
     fn test_basic() {
         let waker = SharedWaker::new();
-        let p = WakerPage::new(waker);
+        let p = WakerPage::new(waker).unwrap();
 
         let q = p.waker(0);
         let r = p.waker(63);
@@ -271,4 +735,204 @@ mod tests {
 
         assert_eq!(p.take_notified(), 1 << 16);
     }
+
+    #[test]
+    fn test_notify_mask() {
+        let p = WakerPage::new(SharedWaker::new()).unwrap();
+        p.notify_mask(1 << 0 | 1 << 5 | 1 << 40);
+        assert_eq!(p.take_notified(), 1 << 0 | 1 << 5 | 1 << 40);
+        p.notify_mask(0);
+        assert_eq!(p.take_notified(), 0);
+    }
+
+    #[test]
+    fn test_pending_dropped_count() {
+        let p = WakerPage::new(SharedWaker::new()).unwrap();
+        assert_eq!(p.pending_dropped_count(), 0);
+        for &ix in &[1, 17, 63] {
+            p.mark_dropped(ix);
+        }
+        assert_eq!(p.pending_dropped_count(), 3);
+        assert_eq!(p.take_dropped(), 1 << 1 | 1 << 17 | 1 << 63);
+        assert_eq!(p.pending_dropped_count(), 0);
+    }
+
+    #[test]
+    fn test_small_page() {
+        assert_eq!(SizedWakerPage::<8>::layout(false).size(), 128);
+        let p = SizedWakerPage::<8>::new(SharedWaker::new()).unwrap();
+        let wakers: Vec<Waker> = (0..8)
+            .map(|ix| unsafe { Waker::from_raw(p.raw_waker(ix)) })
+            .collect();
+        wakers[7].wake_by_ref();
+        wakers[3].clone().wake();
+        assert_eq!(p.take_notified(), 1 << 3 | 1 << 7);
+
+        p.mark_completed(7);
+        for w in &wakers {
+            w.wake_by_ref();
+        }
+        assert_eq!(p.take_notified(), 0x7f);
+        assert_eq!(p.refcount.load(), 9);
+        drop(wakers);
+        assert_eq!(p.refcount.load(), 1);
+    }
+
+    #[test]
+    fn test_stale_waker() {
+        let p = WakerPage::new(SharedWaker::new()).unwrap();
+        p.initialize(5);
+        let stale = unsafe { Waker::from_raw(p.raw_waker(5)) };
+        p.clear(5);
+
+        // Reusing the slot bumps its generation, so the old occupant's waker has no effect.
+        p.initialize(5);
+        assert_eq!(p.take_notified(), 1 << 5);
+        stale.wake_by_ref();
+        stale.clone().wake();
+        assert_eq!(p.take_notified(), 0);
+
+        let fresh = unsafe { Waker::from_raw(p.raw_waker(5)) };
+        fresh.wake();
+        assert_eq!(p.take_notified(), 1 << 5);
+    }
+
+    // Exercises every path that touches the refcount. Small enough to run under Miri, which
+    // catches a miscount as a leak or use-after-free.
+    #[test]
+    fn test_refcount() {
+        let iterations = if cfg!(miri) { 64 } else { 10_000 };
+        let p = WakerPage::new(SharedWaker::new()).unwrap();
+        for i in 0..iterations {
+            let ix = i % 64;
+            let waker = unsafe { Waker::from_raw(p.raw_waker(ix)) };
+            let clones: Vec<Waker> = (0..4).map(|_| waker.clone()).collect();
+            assert_eq!(p.refcount.load(), 6);
+
+            waker.wake_by_ref();
+            for (j, clone) in clones.into_iter().enumerate() {
+                if j % 2 == 0 {
+                    clone.wake();
+                } else {
+                    drop(clone);
+                }
+            }
+            assert_eq!(p.refcount.load(), 2);
+            drop(waker);
+            assert_eq!(p.refcount.load(), 1);
+            assert_eq!(p.take_notified(), 1 << ix);
+        }
+
+        // The page outlives `p` as long as a waker does.
+        let waker = unsafe { Waker::from_raw(p.raw_waker(7)) };
+        let q = p.clone();
+        drop(p);
+        waker.wake_by_ref();
+        assert_eq!(q.take_notified(), 1 << 7);
+        drop(q);
+        drop(waker);
+    }
+
+    // Random sequences of clones, drops and notifications, checking the page's bookkeeping
+    // against a model after every step.
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_invariants() {
+        let mut rng = SmallRng::from_seed([7; 16]);
+        let steps = if cfg!(miri) { 256 } else { 20_000 };
+        let p = SizedWakerPage::<48>::new(SharedWaker::new()).unwrap();
+        let mut pages = vec![p.clone()];
+        let mut wakers: Vec<(usize, Waker)> = vec![];
+        let mut completed = 0u64;
+        for _ in 0..steps {
+            let ix = rng.gen_range(0, 48);
+            match rng.gen_range(0, 9) {
+                0 => pages.push(pages[0].clone()),
+                1 if pages.len() > 1 => drop(pages.swap_remove(1)),
+                2 => wakers.push((ix, unsafe { Waker::from_raw(p.raw_waker(ix)) })),
+                3 if !wakers.is_empty() => {
+                    let (ix, w) = &wakers[rng.gen_range(0, wakers.len())];
+                    let w = (*ix, w.clone());
+                    wakers.push(w);
+                },
+                4 if !wakers.is_empty() => {
+                    let (_, w) = wakers.swap_remove(rng.gen_range(0, wakers.len()));
+                    if rng.gen() {
+                        w.wake();
+                    }
+                },
+                5 if !wakers.is_empty() => wakers[rng.gen_range(0, wakers.len())].1.wake_by_ref(),
+                6 => p.notify(ix),
+                7 => {
+                    p.mark_completed(ix);
+                    completed |= 1 << ix;
+                },
+                _ => assert_eq!(p.take_notified() & completed, 0),
+            }
+            p.check_invariants();
+            assert_eq!(
+                super::live_wakers(&*p as *const _ as usize),
+                wakers.len() as u64
+            );
+            assert_eq!(p.refcount.load(), (1 + pages.len() + wakers.len()) as u64);
+        }
+        drop(pages);
+        drop(wakers);
+        assert_eq!(p.refcount.load(), 1);
+    }
+
+    #[test]
+    fn test_custom_allocator() {
+        static POOL: CountingAllocator = CountingAllocator {
+            limit: 2,
+            live: AtomicUsize::new(0),
+        };
+        let p = WakerPage::new_in(SharedWaker::new(), &POOL).unwrap();
+        let q = SizedWakerPage::<8>::new_in(SharedWaker::new(), &POOL).unwrap();
+        assert_eq!(POOL.live.load(Ordering::SeqCst), 2);
+        must_let!(let Err(Fail::OutOfMemory {}) = WakerPage::new_in(SharedWaker::new(), &POOL));
+
+        // Pages go back to the pool once the last reference, page or waker, is gone.
+        let waker = unsafe { Waker::from_raw(p.raw_waker(3)) };
+        drop(p);
+        drop(q);
+        assert_eq!(POOL.live.load(Ordering::SeqCst), 1);
+        waker.wake();
+        assert_eq!(POOL.live.load(Ordering::SeqCst), 0);
+        let p = WakerPage::new_in(SharedWaker::new(), &POOL).unwrap();
+        assert_eq!(POOL.live.load(Ordering::SeqCst), 1);
+        drop(p);
+        assert_eq!(POOL.live.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_fifo_order() {
+        let p = WakerPage::new_ordered(SharedWaker::new(), &GlobalPageAllocator, NotifyOrder::Fifo)
+            .unwrap();
+        let wakers: Vec<Waker> = (0..64)
+            .map(|ix| unsafe { Waker::from_raw(p.raw_waker(ix)) })
+            .collect();
+        wakers[40].wake_by_ref();
+        wakers[3].wake_by_ref();
+        p.notify_mask(1 << 63 | 1 << 3 | 1 << 9);
+        assert_eq!(p.pop_notified(), Some(40));
+
+        // Slot 40 goes to the back of the line if notified again once it's been taken, but
+        // notifying slot 3 while it's still waiting doesn't move it.
+        wakers[40].wake_by_ref();
+        wakers[3].wake_by_ref();
+        p.mark_completed(9);
+        let order: Vec<usize> = iter::from_fn(|| p.pop_notified()).collect();
+        assert_eq!(order, vec![3, 63, 40]);
+        assert_eq!(p.take_notified(), 0);
+
+        // Every slot fits in the ring at once, wherever it's got to.
+        p.clear(9);
+        for w in wakers.iter().rev() {
+            w.wake_by_ref();
+        }
+        p.check_invariants();
+        let order: Vec<usize> = iter::from_fn(|| p.pop_notified()).collect();
+        assert_eq!(order, (0..64).rev().collect::<Vec<_>>());
+    }
 }
@@ -12,6 +12,8 @@ use crate::{
     operations::ResultFuture,
     protocols::{
         arp,
+        dhcp,
+        dns,
         ethernet2::frame::{
             EtherType2,
             Ethernet2Header,
@@ -23,19 +25,26 @@ use crate::{
             PopFuture,
             PushFuture,
         },
+        udp,
         udp::peer::{
             PopFuture as UdpPopFuture,
             UdpOperation,
         },
     },
-    runtime::Runtime,
+    runtime::{
+        Runtime,
+        RxMetadata,
+    },
     scheduler::Operation,
     sync::Bytes,
 };
 use std::{
     future::Future,
     net::Ipv4Addr,
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 #[cfg(test)]
@@ -74,7 +83,23 @@ impl<RT: Runtime> Engine<RT> {
         &self.rt
     }
 
+    /// The earliest instant the stack has timer work to do, for an embedder driving it from its
+    /// own event loop to bound how long it blocks on I/O. `None` means nothing's scheduled, so
+    /// it can block until a frame arrives.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.rt.next_timer_expiry()
+    }
+
     pub fn receive(&mut self, bytes: Bytes) -> Result<(), Fail> {
+        self.receive_with_metadata(bytes, RxMetadata::default())
+    }
+
+    /// Like `receive`, for runtimes that can tell which link the frame arrived on.
+    pub fn receive_with_metadata(
+        &mut self,
+        bytes: Bytes,
+        metadata: RxMetadata,
+    ) -> Result<(), Fail> {
         let _s = static_span!();
         let (header, payload) = Ethernet2Header::parse(bytes)?;
         if self.rt.local_link_addr() != header.dst_addr && !header.dst_addr.is_broadcast() {
@@ -84,8 +109,34 @@ impl<RT: Runtime> Engine<RT> {
         }
         match header.ether_type {
             EtherType2::Arp => self.arp.receive(payload),
-            EtherType2::Ipv4 => self.ipv4.receive(payload),
+            EtherType2::Ipv4 => self.ipv4.receive(payload, &metadata),
+        }
+    }
+
+    /// Receives a burst of frames, coalescing the readiness notifications they trigger. Returns
+    /// the outcome for each frame in order.
+    pub fn receive_batch(
+        &mut self,
+        frames: impl IntoIterator<Item = Bytes>,
+    ) -> Vec<Result<(), Fail>> {
+        let mut results = vec![];
+        let frames = frames.into_iter().map(|f| (f, RxMetadata::default()));
+        self.receive_batch_with_metadata(frames, |r| results.push(r));
+        results
+    }
+
+    /// Like `receive_batch`, for frames along with their metadata, passing each frame's outcome
+    /// to `on_result` as it goes instead of collecting them.
+    pub fn receive_batch_with_metadata(
+        &mut self,
+        frames: impl IntoIterator<Item = (Bytes, RxMetadata)>,
+        mut on_result: impl FnMut(Result<(), Fail>),
+    ) {
+        self.ipv4.udp.begin_batch();
+        for (frame, metadata) in frames {
+            on_result(self.receive_with_metadata(frame, metadata));
         }
+        self.ipv4.udp.end_batch();
     }
 
     pub fn ping(
@@ -96,7 +147,12 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.ping(dest_ipv4_addr, timeout)
     }
 
-    pub fn socket(&mut self, protocol: Protocol) -> FileDescriptor {
+    /// Path MTU to `remote`, as found by `icmpv4::Peer::discover_pmtu`.
+    pub fn discover_pmtu(&self, remote: ipv4::Endpoint) -> impl Future<Output = Result<u16, Fail>> {
+        self.ipv4.discover_pmtu(remote)
+    }
+
+    pub fn socket(&mut self, protocol: Protocol) -> Result<FileDescriptor, Fail> {
         match protocol {
             Protocol::Tcp => self.ipv4.tcp.socket(),
             Protocol::Udp => self.ipv4.udp.socket(),
@@ -108,67 +164,79 @@ impl<RT: Runtime> Engine<RT> {
         fd: FileDescriptor,
         remote_endpoint: ipv4::Endpoint,
     ) -> Operation<RT> {
-        match self.file_table.get(fd) {
-            Some(File::TcpSocket) => Operation::from(self.ipv4.tcp.connect(fd, remote_endpoint)),
-            Some(File::UdpSocket) => {
+        match self.file_table.lookup(fd) {
+            Ok(File::TcpSocket) => Operation::from(self.ipv4.tcp.connect(fd, remote_endpoint)),
+            Ok(File::UdpSocket) => {
                 let udp_op = UdpOperation::Connect(fd, self.ipv4.udp.connect(fd, remote_endpoint));
                 Operation::Udp(udp_op)
             },
-            _ => panic!("TODO: Invalid fd"),
+            Err(e) => Operation::Failed(fd, e),
         }
     }
 
     pub fn bind(&mut self, fd: FileDescriptor, endpoint: ipv4::Endpoint) -> Result<(), Fail> {
-        match self.file_table.get(fd) {
-            Some(File::TcpSocket) => self.ipv4.tcp.bind(fd, endpoint),
-            Some(File::UdpSocket) => self.ipv4.udp.bind(fd, endpoint),
-            _ => panic!("TODO: Invalid fd"),
+        match self.file_table.lookup(fd)? {
+            File::TcpSocket => {
+                // UDP checks for itself, since it has other ways of binding.
+                if endpoint.port.is_privileged() && !self.rt.ipv4_options().privileged {
+                    return Err(Fail::PermissionDenied {
+                        details: "Binding a port below 1024 takes privileges",
+                    });
+                }
+                self.ipv4.tcp.bind(fd, endpoint)
+            },
+            File::UdpSocket => self.ipv4.udp.bind(fd, endpoint),
         }
     }
 
     pub fn accept(&mut self, fd: FileDescriptor) -> Operation<RT> {
-        match self.file_table.get(fd) {
-            Some(File::TcpSocket) => Operation::from(self.ipv4.tcp.accept(fd)),
-            Some(File::UdpSocket) => {
+        match self.file_table.lookup(fd) {
+            Ok(File::TcpSocket) => Operation::from(self.ipv4.tcp.accept(fd)),
+            Ok(File::UdpSocket) => {
                 let udp_op = UdpOperation::Accept(fd, self.ipv4.udp.accept());
                 Operation::Udp(udp_op)
             },
-            _ => panic!("TODO: Invalid fd"),
+            Err(e) => Operation::Failed(fd, e),
         }
     }
 
     pub fn listen(&mut self, fd: FileDescriptor, backlog: usize) -> Result<(), Fail> {
-        match self.file_table.get(fd) {
-            Some(File::TcpSocket) => self.ipv4.tcp.listen(fd, backlog),
-            Some(File::UdpSocket) => Err(Fail::Malformed {
+        match self.file_table.lookup(fd)? {
+            File::TcpSocket => self.ipv4.tcp.listen(fd, backlog),
+            File::UdpSocket => Err(Fail::Malformed {
                 details: "Operation not supported",
             }),
-            _ => panic!("TODO: Invalid fd"),
         }
     }
 
     pub fn push(&mut self, fd: FileDescriptor, buf: Bytes) -> Operation<RT> {
-        match self.file_table.get(fd) {
-            Some(File::TcpSocket) => Operation::from(self.ipv4.tcp.push(fd, buf)),
-            Some(File::UdpSocket) => {
+        match self.file_table.lookup(fd) {
+            Ok(File::TcpSocket) => Operation::from(self.ipv4.tcp.push(fd, buf)),
+            Ok(File::UdpSocket) => {
                 let udp_op = UdpOperation::Push(fd, self.ipv4.udp.push(fd, buf));
                 Operation::Udp(udp_op)
             },
-            _ => panic!("TODO: Invalid fd"),
+            Err(e) => Operation::Failed(fd, e),
         }
     }
 
     pub fn pushto(&mut self, fd: FileDescriptor, buf: Bytes, to: ipv4::Endpoint) -> Operation<RT> {
-        match self.file_table.get(fd) {
-            Some(File::UdpSocket) => {
+        match self.file_table.lookup(fd) {
+            Ok(File::UdpSocket) => {
                 let udp_op = UdpOperation::Push(fd, self.ipv4.udp.pushto(fd, buf, to));
                 Operation::Udp(udp_op)
             },
-            _ => panic!("TODO: Invalid fd"),
+            Ok(File::TcpSocket) => Operation::Failed(
+                fd,
+                Fail::Malformed {
+                    details: "Operation not supported",
+                },
+            ),
+            Err(e) => Operation::Failed(fd, e),
         }
     }
 
-    pub fn udp_push(&mut self, fd: FileDescriptor, buf: Bytes) -> Result<(), Fail> {
+    pub fn udp_push(&mut self, fd: FileDescriptor, buf: Bytes) -> Result<usize, Fail> {
         self.ipv4.udp.push(fd, buf)
     }
 
@@ -176,26 +244,43 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.udp.pop(fd)
     }
 
+    /// UDP-specific APIs that don't fit the generic socket calls above.
+    pub fn udp(&self) -> &udp::Peer<RT> {
+        &self.ipv4.udp
+    }
+
+    pub fn dhcp_client(&self, options: dhcp::Options) -> dhcp::Client<RT> {
+        dhcp::Client::new(self.rt.clone(), self.ipv4.udp.clone(), options)
+    }
+
+    pub fn dns_resolver(&self, options: dns::Options) -> dns::Resolver<RT> {
+        dns::Resolver::new(
+            self.rt.clone(),
+            self.ipv4.udp.clone(),
+            self.ipv4.tcp.clone(),
+            options,
+        )
+    }
+
     pub fn pop(&mut self, fd: FileDescriptor) -> Operation<RT> {
-        match self.file_table.get(fd) {
-            Some(File::TcpSocket) => Operation::from(self.ipv4.tcp.pop(fd)),
-            Some(File::UdpSocket) => {
+        match self.file_table.lookup(fd) {
+            Ok(File::TcpSocket) => Operation::from(self.ipv4.tcp.pop(fd)),
+            Ok(File::UdpSocket) => {
                 let udp_op = UdpOperation::Pop(ResultFuture::new(self.ipv4.udp.pop(fd)));
                 Operation::Udp(udp_op)
             },
-            _ => panic!("TODO: Invalid fd"),
+            Err(e) => Operation::Failed(fd, e),
         }
     }
 
     pub fn close(&mut self, fd: FileDescriptor) -> Result<(), Fail> {
-        match self.file_table.get(fd) {
-            Some(File::TcpSocket) => self.ipv4.tcp.close(fd),
-            Some(File::UdpSocket) => self.ipv4.udp.close(fd),
-            _ => panic!("TODO: Invalid fd"),
+        match self.file_table.lookup(fd)? {
+            File::TcpSocket => self.ipv4.tcp.close(fd),
+            File::UdpSocket => self.ipv4.udp.close(fd),
         }
     }
 
-    pub fn tcp_socket(&mut self) -> FileDescriptor {
+    pub fn tcp_socket(&mut self) -> Result<FileDescriptor, Fail> {
         self.ipv4.tcp.socket()
     }
 
@@ -29,6 +29,15 @@ custom_error! {#[derive(Clone)] pub Fail
     TypeMismatch{details: Str} = "type mismatch ({details})",
     Unsupported{details: Str} = "unsupported ({details})",
     Invalid {details: Str} = "invalid ({details})",
+    Shutdown{} = "socket has been shut down",
+    WouldBlock{} = "operation would block",
+    OutOfMemory{} = "out of memory",
+    PermissionDenied{details: Str} = "permission denied ({details})",
+    HostUnreachable{} = "host unreachable",
+    NetworkUnreachable{} = "network unreachable",
+    OutOfFiles{} = "too many open files",
+    MessageTooLong{} = "message too long",
+    BadFileDescriptor{} = "bad file descriptor",
 }
 
 impl From<IoError> for Fail {
@@ -82,6 +91,122 @@ impl Fail {
             Fail::IoError {} => libc::EIO,
             Fail::BorrowMutError {} => libc::EINVAL,
             Fail::Invalid { .. } => libc::EINVAL,
+            Fail::Shutdown {} => libc::EPIPE,
+            Fail::WouldBlock {} => libc::EWOULDBLOCK,
+            Fail::OutOfMemory {} => libc::ENOMEM,
+            Fail::PermissionDenied { .. } => libc::EACCES,
+            Fail::HostUnreachable {} => libc::EHOSTUNREACH,
+            Fail::NetworkUnreachable {} => libc::ENETUNREACH,
+            Fail::OutOfFiles {} => libc::EMFILE,
+            Fail::MessageTooLong {} => libc::EMSGSIZE,
+            Fail::BadFileDescriptor {} => libc::EBADF,
+        }
+    }
+}
+
+/// Machine-readable `Fail` variant, for logs and tooling that shouldn't parse `Display` output.
+/// Values are stable: new variants get new codes, and retired codes aren't reused.
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ErrorCode {
+    ConnectionAborted = 1,
+    ConnectionRefused = 2,
+    IoError = 3,
+    BorrowMutError = 4,
+    Ignored = 5,
+    Malformed = 6,
+    Misdelivered = 7,
+    OutOfRange = 8,
+    ResourceBusy = 9,
+    ResourceExhausted = 10,
+    ResourceNotFound = 11,
+    Timeout = 12,
+    TypeMismatch = 13,
+    Unsupported = 14,
+    Invalid = 15,
+    Shutdown = 16,
+    WouldBlock = 17,
+    OutOfMemory = 18,
+    PermissionDenied = 19,
+    HostUnreachable = 20,
+    OutOfFiles = 21,
+    MessageTooLong = 22,
+    BadFileDescriptor = 23,
+    NetworkUnreachable = 24,
+}
+
+impl Fail {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Fail::ConnectionAborted {} => ErrorCode::ConnectionAborted,
+            Fail::ConnectionRefused {} => ErrorCode::ConnectionRefused,
+            Fail::IoError {} => ErrorCode::IoError,
+            Fail::BorrowMutError {} => ErrorCode::BorrowMutError,
+            Fail::Ignored { .. } => ErrorCode::Ignored,
+            Fail::Malformed { .. } => ErrorCode::Malformed,
+            Fail::Misdelivered {} => ErrorCode::Misdelivered,
+            Fail::OutOfRange { .. } => ErrorCode::OutOfRange,
+            Fail::ResourceBusy { .. } => ErrorCode::ResourceBusy,
+            Fail::ResourceExhausted { .. } => ErrorCode::ResourceExhausted,
+            Fail::ResourceNotFound { .. } => ErrorCode::ResourceNotFound,
+            Fail::Timeout {} => ErrorCode::Timeout,
+            Fail::TypeMismatch { .. } => ErrorCode::TypeMismatch,
+            Fail::Unsupported { .. } => ErrorCode::Unsupported,
+            Fail::Invalid { .. } => ErrorCode::Invalid,
+            Fail::Shutdown {} => ErrorCode::Shutdown,
+            Fail::WouldBlock {} => ErrorCode::WouldBlock,
+            Fail::OutOfMemory {} => ErrorCode::OutOfMemory,
+            Fail::PermissionDenied { .. } => ErrorCode::PermissionDenied,
+            Fail::HostUnreachable {} => ErrorCode::HostUnreachable,
+            Fail::OutOfFiles {} => ErrorCode::OutOfFiles,
+            Fail::MessageTooLong {} => ErrorCode::MessageTooLong,
+            Fail::BadFileDescriptor {} => ErrorCode::BadFileDescriptor,
+            Fail::NetworkUnreachable {} => ErrorCode::NetworkUnreachable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ErrorCode,
+        Fail,
+    };
+
+    #[test]
+    fn display_and_code() {
+        let cases = [
+            (
+                Fail::Malformed {
+                    details: "Bad checksum",
+                },
+                "encountered a malformed datagram (Bad checksum)",
+                ErrorCode::Malformed,
+                6,
+            ),
+            (
+                Fail::Invalid { details: "Port 0" },
+                "invalid (Port 0)",
+                ErrorCode::Invalid,
+                15,
+            ),
+            (
+                Fail::Timeout {},
+                "an asynchronous operation timed out",
+                ErrorCode::Timeout,
+                12,
+            ),
+            (
+                Fail::BadFileDescriptor {},
+                "bad file descriptor",
+                ErrorCode::BadFileDescriptor,
+                23,
+            ),
+        ];
+        for (fail, display, code, value) in &cases {
+            assert_eq!(fail.to_string(), *display);
+            assert_eq!(fail.code(), *code);
+            assert_eq!(fail.code() as u16, *value);
         }
     }
 }
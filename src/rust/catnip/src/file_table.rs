@@ -1,3 +1,4 @@
+use crate::fail::Fail;
 use slab::Slab;
 use std::{
     cell::RefCell,
@@ -6,6 +7,16 @@ use std::{
 
 pub type FileDescriptor = u32;
 
+// A descriptor's low bits are its slot in the table, starting at 1, and the bits above count how
+// many times the slot has been reused, so a descriptor kept past `free` doesn't alias whatever
+// `alloc` hands out from the slot next. The top bit stays clear so descriptors fit in a `c_int`.
+// A slot whose generation would wrap is retired rather than reused, so no descriptor ever aliases.
+const SLOT_BITS: u32 = 20;
+const GENERATION_MASK: u32 = (1 << (31 - SLOT_BITS)) - 1;
+const MAX_FILES: usize = (1 << SLOT_BITS) - 1;
+// Generation of a retired slot, which no descriptor carries.
+const RETIRED: u32 = u32::MAX;
+
 #[derive(Clone)]
 pub struct FileTable {
     inner: Rc<RefCell<Inner>>,
@@ -19,32 +30,79 @@ pub enum File {
 
 impl FileTable {
     pub fn new() -> Self {
-        let inner = Inner { table: Slab::new() };
+        Self::with_limit(MAX_FILES)
+    }
+
+    /// A table that holds at most `max_files` open files, past which `alloc` fails with
+    /// `OutOfFiles`.
+    pub fn with_limit(max_files: usize) -> Self {
+        let inner = Inner {
+            table: Slab::new(),
+            generations: vec![],
+            retired: 0,
+            max_files: max_files.min(MAX_FILES),
+        };
         Self {
             inner: Rc::new(RefCell::new(inner)),
         }
     }
 
-    pub fn alloc(&self, file: File) -> FileDescriptor {
+    pub fn alloc(&self, file: File) -> Result<FileDescriptor, Fail> {
         let mut inner = self.inner.borrow_mut();
+        if inner.table.len() - inner.retired >= inner.max_files || inner.table.len() >= MAX_FILES {
+            return Err(Fail::OutOfFiles {});
+        }
         let ix = inner.table.insert(file);
-        let file = ix as u32 + 1;
-        file
+        if ix == inner.generations.len() {
+            inner.generations.push(0);
+        }
+        let generation = inner.generations[ix];
+        Ok((generation << SLOT_BITS) | (ix as u32 + 1))
     }
 
     pub fn get(&self, fd: FileDescriptor) -> Option<File> {
-        let ix = fd as usize - 1;
         let inner = self.inner.borrow();
+        let ix = inner.slot(fd)?;
         inner.table.get(ix).cloned()
     }
 
-    pub fn free(&self, fd: FileDescriptor) -> File {
-        let ix = fd as usize - 1;
+    /// Like `get`, but fails with `BadFileDescriptor` for descriptors that are closed, including
+    /// ones whose slot now holds a newer file.
+    pub fn lookup(&self, fd: FileDescriptor) -> Result<File, Fail> {
+        self.get(fd).ok_or(Fail::BadFileDescriptor {})
+    }
+
+    /// Closes `fd`, failing with `BadFileDescriptor` if it's already closed.
+    pub fn free(&self, fd: FileDescriptor) -> Result<File, Fail> {
         let mut inner = self.inner.borrow_mut();
-        inner.table.remove(ix)
+        let ix = inner.slot(fd).ok_or(Fail::BadFileDescriptor {})?;
+        if inner.generations[ix] == GENERATION_MASK {
+            // Keep the slot occupied so `alloc` never hands it out again.
+            inner.generations[ix] = RETIRED;
+            inner.retired += 1;
+            return Ok(inner.table[ix]);
+        }
+        inner.generations[ix] += 1;
+        Ok(inner.table.remove(ix))
     }
 }
 
 struct Inner {
     table: Slab<File>,
+    // Times each slot has been freed, which `alloc` tags its descriptors with.
+    generations: Vec<u32>,
+    // Slots kept out of use because their generation ran out, which don't count as open files.
+    retired: usize,
+    max_files: usize,
+}
+
+impl Inner {
+    // The slot `fd` names, if it was allocated in the slot's current generation.
+    fn slot(&self, fd: FileDescriptor) -> Option<usize> {
+        let ix = (fd & ((1 << SLOT_BITS) - 1)).checked_sub(1)? as usize;
+        if self.generations.get(ix) != Some(&(fd >> SLOT_BITS)) {
+            return None;
+        }
+        Some(ix)
+    }
 }
@@ -121,7 +121,7 @@ impl dmtr_qresult_t {
                     qr_value,
                 }
             },
-            OperationResult::Push => Self {
+            OperationResult::Push(..) => Self {
                 qr_opcode: dmtr_opcode_t::DMTR_OPC_PUSH,
                 qr_qd: qd as c_int,
                 qr_qt: qt,
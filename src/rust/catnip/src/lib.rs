@@ -7,6 +7,7 @@
 #![feature(generators, generator_trait)]
 #![feature(min_const_generics)]
 #![feature(new_uninit)]
+#![feature(once_cell)]
 #![feature(maybe_uninit_uninit_array, maybe_uninit_extra, maybe_uninit_ref)]
 #![feature(never_type)]
 #![feature(wake_trait)]
@@ -9,6 +9,7 @@ use crate::{
         dmtr_qresult_t,
         dmtr_sgarray_t,
     },
+    operations::OperationResult,
     protocols::ipv4::Endpoint,
     runtime::Runtime,
     scheduler::{
@@ -19,6 +20,7 @@ use crate::{
 };
 use libc::c_int;
 use std::{
+    iter,
     slice,
     time::Instant,
 };
@@ -74,7 +76,7 @@ impl<RT: Runtime> LibOS<RT> {
                 details: "Invalid protocol",
             });
         }
-        Ok(self.engine.socket(engine_protocol))
+        self.engine.socket(engine_protocol)
     }
 
     pub fn bind(&mut self, fd: FileDescriptor, endpoint: Endpoint) -> Result<(), Fail> {
@@ -189,6 +191,7 @@ impl<RT: Runtime> LibOS<RT> {
         let (qd, r) = match self.rt.scheduler().take(handle) {
             Operation::Tcp(f) => f.expect_result(),
             Operation::Udp(f) => f.expect_result(),
+            Operation::Failed(fd, e) => (fd, OperationResult::Failed(e)),
             Operation::Background(..) => panic!("Polled background operation"),
         };
         dmtr_qresult_t::pack(r, qd, qt)
@@ -197,11 +200,13 @@ impl<RT: Runtime> LibOS<RT> {
     fn poll_bg_work(&mut self) {
         let _s = static_span!();
         self.rt.scheduler().poll();
-        while let Some(pkt) = self.rt.receive() {
-            if let Err(e) = self.engine.receive(pkt) {
+        let rt = &self.rt;
+        let frames = iter::from_fn(|| rt.receive_with_metadata());
+        self.engine.receive_batch_with_metadata(frames, |r| {
+            if let Err(e) = r {
                 warn!("Dropped packet: {:?}", e);
             }
-        }
+        });
         if self.ts_iters == 0 {
             let _t = static_span!("advance_clock");
             self.rt.advance_clock(Instant::now());
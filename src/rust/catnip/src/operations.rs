@@ -47,7 +47,7 @@ where
 pub enum OperationResult {
     Connect,
     Accept(FileDescriptor),
-    Push,
+    Push(usize),
     Pop(Option<ipv4::Endpoint>, Bytes),
     Failed(Fail),
 }
@@ -20,6 +20,7 @@ use futures::{
 };
 use hashbrown::HashMap;
 use std::{
+    cell::Cell,
     future::Future,
     net::Ipv4Addr,
     time::{
@@ -30,63 +31,182 @@ use std::{
 
 const DUMMY_MAC_ADDRESS: MacAddress = MacAddress::new([0; 6]);
 
+/// Where an ARP cache entry came from, as reported by `ArpPeer::entries`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EntryKind {
+    /// Configured up front; never expires or gets evicted.
+    Static,
+    /// Learned from the network; expires after the cache TTL.
+    Dynamic,
+}
+
 #[derive(Debug, Clone)]
 struct Record {
     link_addr: MacAddress,
     ipv4_addr: Ipv4Addr,
+    // Static entries never expire and are never evicted to make room.
+    is_static: bool,
+    // Logical timestamp of the last lookup, for LRU eviction.
+    last_used: Cell<u64>,
 }
 
 pub struct ArpCache {
     cache: HashTtlCache<Ipv4Addr, Record>,
     rmap: HashMap<MacAddress, Ipv4Addr>,
+    max_entries: Option<usize>,
+    clock: Cell<u64>,
+    // Bumped whenever a lookup might now give a different answer, so callers that hold on to
+    // a resolved address know when to look it up again.
+    generation: u64,
 
-    // TODO: Allow multiple waiters for the same address
-    // TODO: Deregister waiters here when the receiver goes away.
-    waiters: HashMap<Ipv4Addr, Sender<MacAddress>>,
+    // Queries that gave up leave their waiters behind until the address resolves or another
+    // query for it comes along.
+    waiters: HashMap<Ipv4Addr, Vec<Sender<MacAddress>>>,
     arp_disabled: bool,
 }
 
 impl ArpCache {
-    pub fn new(now: Instant, default_ttl: Option<Duration>, arp_disabled: bool) -> ArpCache {
+    pub fn new(
+        now: Instant,
+        default_ttl: Option<Duration>,
+        max_entries: Option<usize>,
+        arp_disabled: bool,
+    ) -> ArpCache {
+        if let Some(n) = max_entries {
+            assert!(n > 0);
+        }
         ArpCache {
             cache: HashTtlCache::new(now, default_ttl),
             rmap: HashMap::default(),
+            max_entries,
+            clock: Cell::new(0),
+            generation: 0,
             waiters: HashMap::default(),
             arp_disabled,
         }
     }
 
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn invalidate(&mut self) {
+        self.generation += 1;
+    }
+
+    fn tick(&self) -> u64 {
+        let t = self.clock.get() + 1;
+        self.clock.set(t);
+        t
+    }
+
+    fn record(&self, ipv4_addr: Ipv4Addr, link_addr: MacAddress, is_static: bool) -> Record {
+        Record {
+            ipv4_addr,
+            link_addr,
+            is_static,
+            last_used: Cell::new(self.tick()),
+        }
+    }
+
+    fn is_static(&self, ipv4_addr: Ipv4Addr) -> bool {
+        self.cache
+            .get(&ipv4_addr)
+            .map(|r| r.is_static)
+            .unwrap_or(false)
+    }
+
+    /// Makes room for a new dynamic entry by evicting the least recently used dynamic one.
+    fn make_room(&mut self, ipv4_addr: Ipv4Addr) {
+        let max_entries = match self.max_entries {
+            Some(n) => n,
+            None => return,
+        };
+        if self.cache.get(&ipv4_addr).is_some() {
+            return;
+        }
+        let mut len = 0;
+        let mut lru: Option<(u64, Ipv4Addr)> = None;
+        for (&k, r) in self.cache.iter() {
+            len += 1;
+            if r.is_static {
+                continue;
+            }
+            let last_used = r.last_used.get();
+            if lru.map(|(t, _)| last_used < t).unwrap_or(true) {
+                lru = Some((last_used, k));
+            }
+        }
+        if len < max_entries {
+            return;
+        }
+        if let Some((_, victim)) = lru {
+            debug!("ARP cache full; evicting `{}`", victim);
+            if let Some(record) = self.cache.remove(&victim) {
+                self.rmap.remove(&record.link_addr);
+            }
+            self.invalidate();
+        }
+    }
+
+    fn wake_waiters(&mut self, ipv4_addr: Ipv4Addr, link_addr: MacAddress) {
+        for sender in self.waiters.remove(&ipv4_addr).unwrap_or_default() {
+            let _ = sender.send(link_addr);
+        }
+    }
+
+    /// Inserts a permanent entry that neither expires nor gets evicted.
+    pub fn insert_static(&mut self, ipv4_addr: Ipv4Addr, link_addr: MacAddress) {
+        let record = self.record(ipv4_addr, link_addr, true);
+        self.wake_waiters(ipv4_addr, link_addr);
+        let previous = self.cache.insert_with_ttl(ipv4_addr, record, None);
+        if previous.map(|r| r.link_addr != link_addr).unwrap_or(false) {
+            self.invalidate();
+        }
+        self.rmap.insert(link_addr, ipv4_addr);
+    }
+
     pub fn insert_with_ttl(
         &mut self,
         ipv4_addr: Ipv4Addr,
         link_addr: MacAddress,
         ttl: Option<Duration>,
     ) -> Option<MacAddress> {
-        let record = Record {
-            ipv4_addr,
-            link_addr,
-        };
+        if self.is_static(ipv4_addr) {
+            return self.get_link_addr(ipv4_addr).cloned();
+        }
+        self.make_room(ipv4_addr);
+        let record = self.record(ipv4_addr, link_addr, false);
 
         let result = self
             .cache
             .insert_with_ttl(ipv4_addr, record, ttl)
             .map(|r| r.link_addr);
-        self.rmap.insert(link_addr, ipv4_addr);
-        if let Some(sender) = self.waiters.remove(&ipv4_addr) {
-            let _ = sender.send(link_addr);
+        if result.map(|l| l != link_addr).unwrap_or(false) {
+            self.invalidate();
         }
+        self.rmap.insert(link_addr, ipv4_addr);
+        self.wake_waiters(ipv4_addr, link_addr);
         result
     }
 
     pub fn insert(&mut self, ipv4_addr: Ipv4Addr, link_addr: MacAddress) -> Option<MacAddress> {
-        let record = Record {
-            ipv4_addr,
-            link_addr,
-        };
-        if let Some(sender) = self.waiters.remove(&ipv4_addr) {
-            let _ = sender.send(link_addr);
+        if self.is_static(ipv4_addr) {
+            // Static entries aren't overridden by what we learn from the network, so that's
+            // what waiters get too.
+            let static_addr = self.get_link_addr(ipv4_addr).cloned();
+            if let Some(static_addr) = static_addr {
+                self.wake_waiters(ipv4_addr, static_addr);
+            }
+            return static_addr;
         }
+        self.make_room(ipv4_addr);
+        let record = self.record(ipv4_addr, link_addr, false);
+        self.wake_waiters(ipv4_addr, link_addr);
         let result = self.cache.insert(ipv4_addr, record).map(|r| r.link_addr);
+        if result.map(|l| l != link_addr).unwrap_or(false) {
+            self.invalidate();
+        }
         self.rmap.insert(link_addr, ipv4_addr);
         result
     }
@@ -94,6 +214,7 @@ impl ArpCache {
     pub fn remove(&mut self, ipv4_addr: Ipv4Addr) {
         if let Some(record) = self.cache.remove(&ipv4_addr) {
             assert!(self.rmap.remove(&record.link_addr).is_some());
+            self.invalidate();
         } else {
             panic!(
                 "attempt to remove unrecognized engine (`{}`) from ARP cache",
@@ -106,7 +227,10 @@ impl ArpCache {
         if self.arp_disabled {
             return Some(&DUMMY_MAC_ADDRESS);
         }
-        let result = self.cache.get(&ipv4_addr).map(|r| &r.link_addr);
+        let result = self.cache.get(&ipv4_addr).map(|r| {
+            r.last_used.set(self.tick());
+            &r.link_addr
+        });
         debug!("`{:?}` -> `{:?}`", ipv4_addr, result);
         result
     }
@@ -115,10 +239,12 @@ impl ArpCache {
         let (tx, rx) = channel();
         if self.arp_disabled {
             let _ = tx.send(DUMMY_MAC_ADDRESS);
-        } else if let Some(r) = self.cache.get(&ipv4_addr) {
-            let _ = tx.send(r.link_addr);
+        } else if let Some(&link_addr) = self.get_link_addr(ipv4_addr) {
+            let _ = tx.send(link_addr);
         } else {
-            assert!(self.waiters.insert(ipv4_addr, tx).is_none());
+            let waiters = self.waiters.entry(ipv4_addr).or_insert_with(Vec::new);
+            waiters.retain(|w| !w.is_canceled());
+            waiters.push(tx);
         }
         rx.map(|r| r.expect("Dropped waiter?"))
     }
@@ -129,11 +255,16 @@ impl ArpCache {
     }
 
     pub fn advance_clock(&mut self, now: Instant) {
+        // Entries may have expired.
+        self.invalidate();
         self.cache.advance_clock(now)
     }
 
     pub fn try_evict(&mut self, count: usize) -> HashMap<Ipv4Addr, MacAddress> {
         let evicted = self.cache.try_evict(count);
+        if !evicted.is_empty() {
+            self.invalidate();
+        }
         let mut result = HashMap::default();
         for (k, v) in &evicted {
             self.rmap.remove(&v.link_addr);
@@ -146,6 +277,7 @@ impl ArpCache {
     pub fn clear(&mut self) {
         self.cache.clear();
         self.rmap.clear();
+        self.invalidate();
     }
 
     pub fn export(&self) -> HashMap<Ipv4Addr, MacAddress> {
@@ -157,6 +289,21 @@ impl ArpCache {
         map
     }
 
+    /// Every live mapping, with its kind and when it expires.
+    pub fn entries(&self) -> Vec<(Ipv4Addr, MacAddress, EntryKind, Option<Instant>)> {
+        self.cache
+            .iter_with_expiry()
+            .map(|(&ipv4_addr, r, expiry)| {
+                let kind = if r.is_static {
+                    EntryKind::Static
+                } else {
+                    EntryKind::Dynamic
+                };
+                (ipv4_addr, r.link_addr, kind, expiry)
+            })
+            .collect()
+    }
+
     pub fn import(&mut self, cache: HashMap<Ipv4Addr, MacAddress>) {
         self.clear();
         for (k, v) in &cache {
@@ -11,7 +11,7 @@ fn with_default_ttl() {
     let now = Instant::now();
     let later = now + Duration::from_secs(1);
 
-    let mut cache = ArpCache::new(now, Some(Duration::from_secs(1)), false);
+    let mut cache = ArpCache::new(now, Some(Duration::from_secs(1)), None, false);
     cache.insert(test_helpers::ALICE_IPV4, test_helpers::ALICE_MAC);
     assert!(cache.get_link_addr(test_helpers::ALICE_IPV4) == Some(&test_helpers::ALICE_MAC));
     assert!(cache.get_ipv4_addr(test_helpers::ALICE_MAC) == Some(&test_helpers::ALICE_IPV4));
@@ -21,3 +21,97 @@ fn with_default_ttl() {
     assert!(evicted.contains_key(&test_helpers::ALICE_IPV4));
     assert!(cache.get_link_addr(test_helpers::ALICE_IPV4).is_none());
 }
+
+#[test]
+fn lru_eviction() {
+    // tests to ensure that a full cache evicts the least recently used dynamic entry and never
+    // a static one.
+    let now = Instant::now();
+    let dave_ipv4 = Ipv4Addr::new(192, 168, 1, 4);
+    let dave_mac = MacAddress::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+    let erin_ipv4 = Ipv4Addr::new(192, 168, 1, 5);
+    let erin_mac = MacAddress::new([0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+
+    let mut cache = ArpCache::new(now, Some(Duration::from_secs(600)), Some(3), false);
+    cache.insert_static(test_helpers::ALICE_IPV4, test_helpers::ALICE_MAC);
+    cache.insert(test_helpers::BOB_IPV4, test_helpers::BOB_MAC);
+    cache.insert(test_helpers::CARRIE_IPV4, test_helpers::CARRIE_MAC);
+
+    // touching bob leaves carrie as the least recently used dynamic entry.
+    assert!(cache.get_link_addr(test_helpers::BOB_IPV4).is_some());
+    cache.insert(dave_ipv4, dave_mac);
+    assert!(cache.get_link_addr(test_helpers::CARRIE_IPV4).is_none());
+    assert!(cache.get_ipv4_addr(test_helpers::CARRIE_MAC).is_none());
+    assert_eq!(cache.export().len(), 3);
+
+    // alice is older than everyone but static, so bob goes next.
+    cache.insert(erin_ipv4, erin_mac);
+    assert!(cache.get_link_addr(test_helpers::BOB_IPV4).is_none());
+    assert_eq!(
+        cache.get_link_addr(test_helpers::ALICE_IPV4),
+        Some(&test_helpers::ALICE_MAC)
+    );
+    assert_eq!(cache.get_link_addr(dave_ipv4), Some(&dave_mac));
+    assert_eq!(cache.get_link_addr(erin_ipv4), Some(&erin_mac));
+    assert_eq!(cache.export().len(), 3);
+
+    // static entries survive anything we learn from the network.
+    cache.insert(test_helpers::ALICE_IPV4, dave_mac);
+    assert_eq!(
+        cache.get_link_addr(test_helpers::ALICE_IPV4),
+        Some(&test_helpers::ALICE_MAC)
+    );
+}
+
+#[test]
+fn static_wakes_waiters() {
+    // tests to ensure that waiters are handed a static entry's address rather than one learned
+    // from the network.
+    let now = Instant::now();
+    let mut cache = ArpCache::new(now, None, None, false);
+    cache.insert_static(test_helpers::ALICE_IPV4, test_helpers::ALICE_MAC);
+
+    let (tx, mut rx) = channel();
+    cache
+        .waiters
+        .entry(test_helpers::ALICE_IPV4)
+        .or_insert_with(Vec::new)
+        .push(tx);
+    cache.insert(test_helpers::ALICE_IPV4, test_helpers::BOB_MAC);
+    assert_eq!(rx.try_recv(), Ok(Some(test_helpers::ALICE_MAC)));
+}
+
+#[test]
+fn entries() {
+    // tests to ensure that inspecting the cache reports each entry's kind and expiry.
+    let now = Instant::now();
+    let ttl = Duration::from_secs(15);
+
+    let mut cache = ArpCache::new(now, Some(ttl), None, false);
+    cache.insert_static(test_helpers::ALICE_IPV4, test_helpers::ALICE_MAC);
+    cache.insert(test_helpers::BOB_IPV4, test_helpers::BOB_MAC);
+
+    let mut entries = cache.entries();
+    entries.sort_by_key(|e| e.0);
+    assert_eq!(
+        entries,
+        vec![
+            (
+                test_helpers::ALICE_IPV4,
+                test_helpers::ALICE_MAC,
+                EntryKind::Static,
+                None
+            ),
+            (
+                test_helpers::BOB_IPV4,
+                test_helpers::BOB_MAC,
+                EntryKind::Dynamic,
+                Some(now + ttl)
+            ),
+        ]
+    );
+
+    // expired entries drop out even before they're evicted.
+    cache.advance_clock(now + ttl);
+    assert_eq!(cache.entries().len(), 1);
+}
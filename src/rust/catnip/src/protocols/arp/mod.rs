@@ -9,5 +9,6 @@ mod peer;
 #[cfg(test)]
 mod tests;
 
+pub use cache::EntryKind;
 pub use options::ArpOptions as Options;
 pub use peer::ArpPeer as Peer;
@@ -13,9 +13,17 @@ pub struct ArpOptions {
     pub cache_ttl: Duration,
     pub request_timeout: Duration,
     pub retry_count: usize,
+    /// Upper bound on cached entries; the least recently used dynamic entry makes way for new
+    /// ones. Entries from `initial_values` are static and never evicted.
+    pub max_entries: usize,
 
     pub initial_values: HashMap<MacAddress, Ipv4Addr>,
     pub disable_arp: bool,
+
+    /// Answer requests for addresses in `proxy_ranges` with our own link address.
+    pub proxy_arp: bool,
+    /// Inclusive `(first, last)` address ranges we answer for when `proxy_arp` is set.
+    pub proxy_ranges: Vec<(Ipv4Addr, Ipv4Addr)>,
 }
 
 impl Default for ArpOptions {
@@ -24,8 +32,11 @@ impl Default for ArpOptions {
             cache_ttl: Duration::from_secs(15),
             request_timeout: Duration::from_secs(20),
             retry_count: 5,
+            max_entries: 1024,
             initial_values: HashMap::new(),
             disable_arp: false,
+            proxy_arp: false,
+            proxy_ranges: vec![],
         }
     }
 }
@@ -48,4 +59,21 @@ impl ArpOptions {
         self.retry_count = value;
         self
     }
+
+    pub fn max_entries(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.max_entries = value;
+        self
+    }
+
+    pub fn proxy_arp(mut self, value: bool) -> Self {
+        self.proxy_arp = value;
+        self
+    }
+
+    pub fn proxy_range(mut self, first: Ipv4Addr, last: Ipv4Addr) -> Self {
+        assert!(first <= last);
+        self.proxy_ranges.push((first, last));
+        self
+    }
 }
@@ -2,7 +2,10 @@
 // Licensed under the MIT license.
 
 use super::{
-    cache::ArpCache,
+    cache::{
+        ArpCache,
+        EntryKind,
+    },
     pdu::{
         ArpMessage,
         ArpOperation,
@@ -41,20 +44,36 @@ pub struct ArpPeer<RT: Runtime> {
     // TODO: Move this to a strong owner that gets polled once.
     cache: Rc<RefCell<ArpCache>>,
     background: Rc<SchedulerHandle>,
+    // Empty unless proxy ARP is enabled.
+    proxy_ranges: Rc<Vec<(Ipv4Addr, Ipv4Addr)>>,
 }
 
 impl<RT: Runtime> ArpPeer<RT> {
     pub fn new(now: Instant, rt: RT) -> Result<ArpPeer<RT>, Fail> {
         let options = rt.arp_options();
-        let cache = Rc::new(RefCell::new(ArpCache::new(now, Some(options.cache_ttl), options.disable_arp)));
-        let handle = rt.spawn(Self::background(rt.clone(), cache.clone()));
+        let cache = Rc::new(RefCell::new(ArpCache::new(
+            now,
+            Some(options.cache_ttl),
+            Some(options.max_entries),
+            options.disable_arp,
+        )));
+        let handle = rt.spawn_named(
+            "arp::background",
+            Self::background(rt.clone(), cache.clone()),
+        );
+        let proxy_ranges = if options.proxy_arp {
+            options.proxy_ranges.clone()
+        } else {
+            vec![]
+        };
         let peer = ArpPeer {
             rt,
             cache,
             background: Rc::new(handle),
+            proxy_ranges: Rc::new(proxy_ranges),
         };
         for (&link_addr, &ipv4_addr) in &options.initial_values {
-            peer.insert(ipv4_addr, link_addr);
+            peer.cache.borrow_mut().insert_static(ipv4_addr, link_addr);
         }
         Ok(peer)
     }
@@ -96,7 +115,10 @@ impl<RT: Runtime> ArpPeer<RT> {
             }
         };
         // from RFC 826: ?Am I the target protocol address?
-        if pdu.target_protocol_addr != self.rt.local_ipv4_addr() {
+        // With proxy ARP, we also answer for the addresses we route for.
+        if pdu.target_protocol_addr != self.rt.local_ipv4_addr()
+            && !self.is_proxied(pdu.target_protocol_addr)
+        {
             if merge_flag {
                 // we did do something.
                 return Ok(());
@@ -131,7 +153,7 @@ impl<RT: Runtime> ArpPeer<RT> {
                     arp_pdu: ArpPdu {
                         operation: ArpOperation::Reply,
                         sender_hardware_addr: self.rt.local_link_addr(),
-                        sender_protocol_addr: self.rt.local_ipv4_addr(),
+                        sender_protocol_addr: pdu.target_protocol_addr,
                         target_hardware_addr: pdu.sender_hardware_addr,
                         target_protocol_addr: pdu.sender_protocol_addr,
                     },
@@ -152,6 +174,18 @@ impl<RT: Runtime> ArpPeer<RT> {
         }
     }
 
+    fn is_proxied(&self, ipv4_addr: Ipv4Addr) -> bool {
+        self.proxy_ranges
+            .iter()
+            .any(|&(first, last)| first <= ipv4_addr && ipv4_addr <= last)
+    }
+
+    /// Changes whenever `try_query` might give a different answer than before, e.g. because an
+    /// entry changed, expired or was evicted.
+    pub fn generation(&self) -> u64 {
+        self.cache.borrow().generation()
+    }
+
     pub fn try_query(&self, ipv4_addr: Ipv4Addr) -> Option<MacAddress> {
         self.cache.borrow().get_link_addr(ipv4_addr).cloned()
     }
@@ -201,6 +235,12 @@ impl<RT: Runtime> ArpPeer<RT> {
         }
     }
 
+    /// Snapshot of the cache for inspection: each mapping, whether it's static or learned, and
+    /// when it expires.
+    pub fn entries(&self) -> Vec<(Ipv4Addr, MacAddress, EntryKind, Option<Instant>)> {
+        self.cache.borrow().entries()
+    }
+
     pub fn export_cache(&self) -> HashMap<Ipv4Addr, MacAddress> {
         self.cache.borrow().export()
     }
@@ -6,6 +6,7 @@ use super::pdu::{
     ArpPdu,
 };
 use crate::{
+    engine::Engine,
     fail::Fail,
     protocols::ethernet2::frame::{
         Ethernet2Header,
@@ -25,6 +26,7 @@ use hashbrown::HashMap;
 use must_let::must_let;
 use std::{
     future::Future,
+    net::Ipv4Addr,
     task::Poll,
     time::{
         Duration,
@@ -169,3 +171,49 @@ fn no_reply() {
 
     must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(fut.as_mut(), &mut ctx));
 }
+
+#[test]
+fn proxy_reply() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    alice.import_arp_cache(HashMap::new());
+
+    let rt =
+        test_helpers::TestRuntime::new("bob", now, test_helpers::BOB_MAC, test_helpers::BOB_IPV4);
+    let options = rt
+        .arp_options()
+        .proxy_arp(true)
+        .proxy_range(Ipv4Addr::new(10, 0, 0, 0), Ipv4Addr::new(10, 0, 0, 255));
+    rt.set_arp_options(options);
+    let mut bob = Engine::new(rt).unwrap();
+
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    // bob answers for an address in the proxied range...
+    let proxied = Ipv4Addr::new(10, 0, 0, 5);
+    let mut fut = alice.arp_query(proxied).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let reply = bob.rt().pop_frame();
+    let (_, payload) = Ethernet2Header::parse(reply.clone()).unwrap();
+    let arp = ArpPdu::parse(payload).unwrap();
+    assert_eq!(arp.operation, ArpOperation::Reply);
+    assert_eq!(arp.sender_protocol_addr, proxied);
+    alice.receive(reply).unwrap();
+    must_let!(let Poll::Ready(Ok(link_addr)) = Future::poll(fut.as_mut(), &mut ctx));
+    assert_eq!(link_addr, test_helpers::BOB_MAC);
+
+    // ...as well as for his own address...
+    let mut fut = alice.arp_query(test_helpers::BOB_IPV4).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(link_addr)) = Future::poll(fut.as_mut(), &mut ctx));
+    assert_eq!(link_addr, test_helpers::BOB_MAC);
+
+    // ...but not for anything outside the range.
+    let mut fut = alice.arp_query(Ipv4Addr::new(10, 0, 1, 5)).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert!(bob.rt().pop_frame_opt().is_none());
+}
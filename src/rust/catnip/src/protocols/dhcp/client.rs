@@ -0,0 +1,293 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    message::{
+        DhcpMessage,
+        DhcpMessageType,
+        BOOTREPLY,
+        BOOTREQUEST,
+        DHCP_CLIENT_PORT,
+        DHCP_SERVER_PORT,
+        FLAG_BROADCAST,
+    },
+    options::DhcpOptions,
+};
+use crate::{
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ip,
+        ipv4,
+        udp,
+    },
+    runtime::Runtime,
+};
+use futures::FutureExt;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+/// Configuration handed out by a DHCP server.
+#[derive(Clone, Debug)]
+pub struct DhcpLease {
+    pub addr: Ipv4Addr,
+    pub server: Ipv4Addr,
+    pub netmask: Option<Ipv4Addr>,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub lease_time: Duration,
+    /// When to start renewing with `server` (T1), relative to when the lease was granted.
+    pub renewal_time: Duration,
+    /// When to fall back to broadcasting for any server (T2), relative to when the lease was
+    /// granted.
+    pub rebinding_time: Duration,
+}
+
+impl DhcpLease {
+    fn from_ack(ack: &DhcpMessage) -> Result<Self, Fail> {
+        let server = ack.server_id.ok_or(Fail::Malformed {
+            details: "DHCPACK without server identifier",
+        })?;
+        let lease_time = ack.lease_time.ok_or(Fail::Malformed {
+            details: "DHCPACK without lease time",
+        })?;
+        // RFC 2131, section 4.4.5: T1 defaults to half the lease and T2 to seven eighths.
+        let renewal_time = ack.renewal_time.unwrap_or(lease_time / 2);
+        let rebinding_time = ack
+            .rebinding_time
+            .unwrap_or((lease_time as u64 * 7 / 8) as u32);
+        Ok(Self {
+            addr: ack.yiaddr,
+            server,
+            netmask: ack.subnet_mask,
+            gateway: ack.routers.first().cloned(),
+            dns_servers: ack.dns_servers.clone(),
+            lease_time: Duration::from_secs(lease_time as u64),
+            renewal_time: Duration::from_secs(renewal_time as u64),
+            rebinding_time: Duration::from_secs(rebinding_time as u64),
+        })
+    }
+}
+
+/// Obtains and maintains the stack's address via DHCP, reconfiguring the runtime as leases are
+/// granted and lost.
+#[derive(Clone)]
+pub struct DhcpClient<RT: Runtime> {
+    rt: RT,
+    udp: udp::Peer<RT>,
+    options: DhcpOptions,
+}
+
+// Closes the client socket however the exchange ends, including when it's dropped midway.
+struct SocketGuard<RT: Runtime> {
+    udp: udp::Peer<RT>,
+    fd: FileDescriptor,
+}
+
+impl<RT: Runtime> Drop for SocketGuard<RT> {
+    fn drop(&mut self) {
+        if let Err(e) = self.udp.close(self.fd) {
+            warn!("Failed to close DHCP socket: {:?}", e);
+        }
+    }
+}
+
+impl<RT: Runtime> DhcpClient<RT> {
+    pub fn new(rt: RT, udp: udp::Peer<RT>, options: DhcpOptions) -> Self {
+        Self { rt, udp, options }
+    }
+
+    /// Runs the DISCOVER/OFFER/REQUEST/ACK exchange and configures the runtime with the
+    /// resulting lease. The lease isn't renewed; use `run` for that.
+    pub fn acquire(&self) -> impl Future<Output = Result<DhcpLease, Fail>> {
+        let self_ = self.clone();
+        async move {
+            let guard = self_.open()?;
+            self_.acquire_on(guard.fd).await
+        }
+    }
+
+    /// Acquires a lease and then keeps it renewed (T1) or rebound (T2), starting over if it
+    /// expires or the server refuses to extend it. Only resolves on an unrecoverable error.
+    pub fn run(&self) -> impl Future<Output = Result<(), Fail>> {
+        let self_ = self.clone();
+        async move {
+            let guard = self_.open()?;
+            loop {
+                let mut lease = match self_.acquire_on(guard.fd).await {
+                    Ok(l) => l,
+                    Err(e @ Fail::Timeout {}) | Err(e @ Fail::ConnectionRefused {}) => {
+                        warn!("Failed to acquire DHCP lease: {:?}", e);
+                        self_.rt.wait(self_.options.timeout).await;
+                        continue;
+                    },
+                    Err(e) => return Err(e),
+                };
+                let mut granted = self_.rt.now();
+                loop {
+                    self_.rt.wait_until(granted + lease.renewal_time).await;
+                    let server = Some(lease.server);
+                    let renewed = match self_.extend(guard.fd, &lease, server).await {
+                        Ok(r) => Ok(r),
+                        Err(Fail::Timeout {}) => {
+                            self_.rt.wait_until(granted + lease.rebinding_time).await;
+                            self_.extend(guard.fd, &lease, None).await
+                        },
+                        Err(e) => Err(e),
+                    };
+                    match renewed {
+                        Ok(r) => {
+                            lease = r;
+                            granted = self_.rt.now();
+                        },
+                        Err(e) => {
+                            warn!("Failed to extend DHCP lease: {:?}", e);
+                            // A NAK revokes the lease immediately; otherwise it's still good
+                            // until it expires.
+                            let expiry = granted + lease.lease_time;
+                            let refused = matches!(e, Fail::ConnectionRefused {});
+                            if !refused && self_.rt.now() < expiry {
+                                self_.rt.wait_until(expiry).await;
+                            }
+                            self_.release_config();
+                            break;
+                        },
+                    }
+                }
+            }
+        }
+    }
+
+    fn open(&self) -> Result<SocketGuard<RT>, Fail> {
+        let guard = SocketGuard {
+            fd: self.udp.socket()?,
+            udp: self.udp.clone(),
+        };
+        let port = ip::Port::try_from(DHCP_CLIENT_PORT)?;
+        self.udp
+            .bind_privileged(guard.fd, ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port))?;
+        let options = self.udp.socket_options(guard.fd)?.broadcast(true);
+        self.udp.set_socket_options(guard.fd, options)?;
+        Ok(guard)
+    }
+
+    async fn acquire_on(&self, fd: FileDescriptor) -> Result<DhcpLease, Fail> {
+        let xid: u32 = self.rt.rng_gen();
+        let discover = self.request(xid, DhcpMessageType::Discover);
+        let offer = self
+            .exchange(fd, &discover, None, &[DhcpMessageType::Offer])
+            .await?;
+
+        let mut request = self.request(xid, DhcpMessageType::Request);
+        request.requested_addr = Some(offer.yiaddr);
+        request.server_id = offer.server_id;
+        let ack = self
+            .exchange(
+                fd,
+                &request,
+                None,
+                &[DhcpMessageType::Ack, DhcpMessageType::Nak],
+            )
+            .await?;
+        self.apply(&ack)
+    }
+
+    // Asks for an extension of `lease`, unicast to `server` when renewing or broadcast when
+    // rebinding.
+    async fn extend(
+        &self,
+        fd: FileDescriptor,
+        lease: &DhcpLease,
+        server: Option<Ipv4Addr>,
+    ) -> Result<DhcpLease, Fail> {
+        let xid: u32 = self.rt.rng_gen();
+        let mut request = self.request(xid, DhcpMessageType::Request);
+        request.flags = 0;
+        request.ciaddr = lease.addr;
+        let ack = self
+            .exchange(
+                fd,
+                &request,
+                server,
+                &[DhcpMessageType::Ack, DhcpMessageType::Nak],
+            )
+            .await?;
+        self.apply(&ack)
+    }
+
+    fn request(&self, xid: u32, message_type: DhcpMessageType) -> DhcpMessage {
+        let mut msg = DhcpMessage::new(BOOTREQUEST, xid, self.rt.local_link_addr(), message_type);
+        msg.flags = FLAG_BROADCAST;
+        msg
+    }
+
+    // Sends `msg` until a reply of one of the `expected` types arrives or we run out of retries.
+    async fn exchange(
+        &self,
+        fd: FileDescriptor,
+        msg: &DhcpMessage,
+        server: Option<Ipv4Addr>,
+        expected: &[DhcpMessageType],
+    ) -> Result<DhcpMessage, Fail> {
+        let dst_addr = server.unwrap_or(Ipv4Addr::BROADCAST);
+        let dst = ipv4::Endpoint::new(dst_addr, ip::Port::try_from(DHCP_SERVER_PORT)?);
+        for attempt in 0..self.options.retries + 1 {
+            self.udp.pushto(fd, msg.serialize(), dst)?;
+            let timeout = self.rt.wait(self.options.timeout).fuse();
+            futures::pin_mut!(timeout);
+            loop {
+                futures::select! {
+                    r = self.udp.pop(fd).fuse() => {
+                        let (_, buf) = r?;
+                        match DhcpMessage::parse(&buf[..]) {
+                            Ok(reply)
+                                if reply.op == BOOTREPLY
+                                    && reply.xid == msg.xid
+                                    && reply.chaddr == msg.chaddr
+                                    && expected.contains(&reply.message_type) =>
+                            {
+                                return Ok(reply)
+                            },
+                            // Replies for other clients or exchanges don't end the attempt.
+                            _ => continue,
+                        }
+                    },
+                    _ = timeout => break,
+                }
+            }
+            warn!(
+                "DHCP {:?} timed out; attempt {}.",
+                msg.message_type,
+                attempt + 1
+            );
+        }
+        Err(Fail::Timeout {})
+    }
+
+    fn apply(&self, ack: &DhcpMessage) -> Result<DhcpLease, Fail> {
+        if ack.message_type == DhcpMessageType::Nak {
+            return Err(Fail::ConnectionRefused {});
+        }
+        let lease = DhcpLease::from_ack(ack)?;
+        self.rt.set_local_ipv4_addr(lease.addr);
+        let mut options = self.rt.ipv4_options();
+        options.netmask = lease.netmask;
+        options.gateway = lease.gateway;
+        options.dns_servers = lease.dns_servers.clone();
+        self.rt.set_ipv4_options(options);
+        Ok(lease)
+    }
+
+    fn release_config(&self) {
+        self.rt.set_local_ipv4_addr(Ipv4Addr::UNSPECIFIED);
+        let mut options = self.rt.ipv4_options();
+        options.netmask = None;
+        options.gateway = None;
+        options.dns_servers = vec![];
+        self.rt.set_ipv4_options(options);
+    }
+}
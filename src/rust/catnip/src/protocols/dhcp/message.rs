@@ -0,0 +1,271 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    fail::Fail,
+    protocols::ethernet2::MacAddress,
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use std::net::Ipv4Addr;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+pub const BOOTREQUEST: u8 = 1;
+pub const BOOTREPLY: u8 = 2;
+// Asks servers to broadcast their replies, since we can't receive unicast before we have an
+// address.
+pub const FLAG_BROADCAST: u16 = 0x8000;
+
+// The fixed BOOTP header, up to and including the `file` field.
+const BOOTP_HEADER_SIZE: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const HTYPE_ETHERNET: u8 = 1;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_REQUESTED_ADDR: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DhcpMessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+}
+
+impl DhcpMessageType {
+    fn code(self) -> u8 {
+        match self {
+            DhcpMessageType::Discover => 1,
+            DhcpMessageType::Offer => 2,
+            DhcpMessageType::Request => 3,
+            DhcpMessageType::Decline => 4,
+            DhcpMessageType::Ack => 5,
+            DhcpMessageType::Nak => 6,
+            DhcpMessageType::Release => 7,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self, Fail> {
+        Ok(match code {
+            1 => DhcpMessageType::Discover,
+            2 => DhcpMessageType::Offer,
+            3 => DhcpMessageType::Request,
+            4 => DhcpMessageType::Decline,
+            5 => DhcpMessageType::Ack,
+            6 => DhcpMessageType::Nak,
+            7 => DhcpMessageType::Release,
+            _ => {
+                return Err(Fail::Unsupported {
+                    details: "Unknown DHCP message type",
+                })
+            },
+        })
+    }
+}
+
+/// A DHCP message, limited to the fields and options the client uses.
+#[derive(Clone, Debug)]
+pub struct DhcpMessage {
+    pub op: u8,
+    pub xid: u32,
+    pub flags: u16,
+    pub ciaddr: Ipv4Addr,
+    pub yiaddr: Ipv4Addr,
+    pub siaddr: Ipv4Addr,
+    pub giaddr: Ipv4Addr,
+    pub chaddr: MacAddress,
+
+    pub message_type: DhcpMessageType,
+    pub requested_addr: Option<Ipv4Addr>,
+    pub server_id: Option<Ipv4Addr>,
+    // All times are in seconds.
+    pub lease_time: Option<u32>,
+    pub renewal_time: Option<u32>,
+    pub rebinding_time: Option<u32>,
+    pub subnet_mask: Option<Ipv4Addr>,
+    pub routers: Vec<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+}
+
+impl DhcpMessage {
+    pub fn new(op: u8, xid: u32, chaddr: MacAddress, message_type: DhcpMessageType) -> Self {
+        Self {
+            op,
+            xid,
+            flags: 0,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            siaddr: Ipv4Addr::UNSPECIFIED,
+            giaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr,
+            message_type,
+            requested_addr: None,
+            server_id: None,
+            lease_time: None,
+            renewal_time: None,
+            rebinding_time: None,
+            subnet_mask: None,
+            routers: vec![],
+            dns_servers: vec![],
+        }
+    }
+
+    pub fn serialize(&self) -> Bytes {
+        let mut buf = vec![0u8; BOOTP_HEADER_SIZE];
+        buf[0] = self.op;
+        buf[1] = HTYPE_ETHERNET;
+        buf[2] = 6;
+        NetworkEndian::write_u32(&mut buf[4..8], self.xid);
+        NetworkEndian::write_u16(&mut buf[10..12], self.flags);
+        buf[12..16].copy_from_slice(&self.ciaddr.octets());
+        buf[16..20].copy_from_slice(&self.yiaddr.octets());
+        buf[20..24].copy_from_slice(&self.siaddr.octets());
+        buf[24..28].copy_from_slice(&self.giaddr.octets());
+        buf[28..34].copy_from_slice(self.chaddr.as_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE);
+
+        buf.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, self.message_type.code()]);
+        let addr_options = [
+            (OPTION_REQUESTED_ADDR, self.requested_addr),
+            (OPTION_SERVER_ID, self.server_id),
+            (OPTION_SUBNET_MASK, self.subnet_mask),
+        ];
+        for &(code, addr) in &addr_options {
+            if let Some(addr) = addr {
+                buf.extend_from_slice(&[code, 4]);
+                buf.extend_from_slice(&addr.octets());
+            }
+        }
+        let time_options = [
+            (OPTION_LEASE_TIME, self.lease_time),
+            (OPTION_RENEWAL_TIME, self.renewal_time),
+            (OPTION_REBINDING_TIME, self.rebinding_time),
+        ];
+        for &(code, time) in &time_options {
+            if let Some(time) = time {
+                let mut value = [0u8; 4];
+                NetworkEndian::write_u32(&mut value, time);
+                buf.extend_from_slice(&[code, 4]);
+                buf.extend_from_slice(&value);
+            }
+        }
+        for &(code, addrs) in &[
+            (OPTION_ROUTER, &self.routers),
+            (OPTION_DNS_SERVER, &self.dns_servers),
+        ] {
+            if !addrs.is_empty() {
+                buf.extend_from_slice(&[code, (addrs.len() * 4) as u8]);
+                for addr in addrs.iter() {
+                    buf.extend_from_slice(&addr.octets());
+                }
+            }
+        }
+        if self.op == BOOTREQUEST {
+            buf.extend_from_slice(&[
+                OPTION_PARAMETER_REQUEST_LIST,
+                3,
+                OPTION_SUBNET_MASK,
+                OPTION_ROUTER,
+                OPTION_DNS_SERVER,
+            ]);
+        }
+        buf.push(OPTION_END);
+        BytesMut::from(&buf[..]).freeze()
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, Fail> {
+        if buf.len() < BOOTP_HEADER_SIZE + MAGIC_COOKIE.len() {
+            return Err(Fail::Malformed {
+                details: "DHCP message too short",
+            });
+        }
+        if buf[1] != HTYPE_ETHERNET || buf[2] != 6 {
+            return Err(Fail::Unsupported {
+                details: "DHCP hardware type isn't Ethernet",
+            });
+        }
+        if buf[BOOTP_HEADER_SIZE..BOOTP_HEADER_SIZE + 4] != MAGIC_COOKIE {
+            return Err(Fail::Malformed {
+                details: "Missing DHCP magic cookie",
+            });
+        }
+        let read_addr = |b: &[u8]| Ipv4Addr::new(b[0], b[1], b[2], b[3]);
+        let mut message_type = None;
+        let mut msg = Self::new(
+            buf[0],
+            NetworkEndian::read_u32(&buf[4..8]),
+            MacAddress::from_bytes(&buf[28..34]),
+            DhcpMessageType::Discover,
+        );
+        msg.flags = NetworkEndian::read_u16(&buf[10..12]);
+        msg.ciaddr = read_addr(&buf[12..16]);
+        msg.yiaddr = read_addr(&buf[16..20]);
+        msg.siaddr = read_addr(&buf[20..24]);
+        msg.giaddr = read_addr(&buf[24..28]);
+
+        let mut pos = BOOTP_HEADER_SIZE + MAGIC_COOKIE.len();
+        while pos < buf.len() {
+            let code = buf[pos];
+            if code == OPTION_END {
+                break;
+            }
+            if code == OPTION_PAD {
+                pos += 1;
+                continue;
+            }
+            if pos + 2 > buf.len() || pos + 2 + buf[pos + 1] as usize > buf.len() {
+                return Err(Fail::Malformed {
+                    details: "DHCP option overruns message",
+                });
+            }
+            let value = &buf[pos + 2..pos + 2 + buf[pos + 1] as usize];
+            pos += 2 + value.len();
+            match (code, value.len()) {
+                (OPTION_MESSAGE_TYPE, 1) => {
+                    message_type = Some(DhcpMessageType::from_code(value[0])?)
+                },
+                (OPTION_REQUESTED_ADDR, 4) => msg.requested_addr = Some(read_addr(value)),
+                (OPTION_SERVER_ID, 4) => msg.server_id = Some(read_addr(value)),
+                (OPTION_SUBNET_MASK, 4) => msg.subnet_mask = Some(read_addr(value)),
+                (OPTION_LEASE_TIME, 4) => msg.lease_time = Some(NetworkEndian::read_u32(value)),
+                (OPTION_RENEWAL_TIME, 4) => msg.renewal_time = Some(NetworkEndian::read_u32(value)),
+                (OPTION_REBINDING_TIME, 4) => {
+                    msg.rebinding_time = Some(NetworkEndian::read_u32(value))
+                },
+                (OPTION_ROUTER, n) if n % 4 == 0 => {
+                    msg.routers = value.chunks(4).map(read_addr).collect()
+                },
+                (OPTION_DNS_SERVER, n) if n % 4 == 0 => {
+                    msg.dns_servers = value.chunks(4).map(read_addr).collect()
+                },
+                // Everything else is ignored.
+                _ => (),
+            }
+        }
+        msg.message_type = message_type.ok_or(Fail::Malformed {
+            details: "Missing DHCP message type",
+        })?;
+        Ok(msg)
+    }
+}
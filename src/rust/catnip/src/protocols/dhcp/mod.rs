@@ -0,0 +1,15 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+mod client;
+mod message;
+mod options;
+
+#[cfg(test)]
+mod tests;
+
+pub use client::{
+    DhcpClient as Client,
+    DhcpLease as Lease,
+};
+pub use options::DhcpOptions as Options;
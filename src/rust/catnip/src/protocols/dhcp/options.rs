@@ -0,0 +1,33 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct DhcpOptions {
+    /// How long to wait for each reply before retransmitting.
+    pub timeout: Duration,
+    pub retries: usize,
+}
+
+impl Default for DhcpOptions {
+    fn default() -> Self {
+        DhcpOptions {
+            timeout: Duration::from_secs(4),
+            retries: 3,
+        }
+    }
+}
+
+impl DhcpOptions {
+    pub fn timeout(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.timeout = value;
+        self
+    }
+
+    pub fn retries(mut self, value: usize) -> Self {
+        self.retries = value;
+        self
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    message::{
+        DhcpMessage,
+        DhcpMessageType,
+        BOOTREPLY,
+        DHCP_CLIENT_PORT,
+        DHCP_SERVER_PORT,
+    },
+    Options,
+};
+use crate::{
+    engine::{
+        Engine,
+        Protocol,
+    },
+    file_table::FileDescriptor,
+    protocols::{
+        ip,
+        ipv4,
+    },
+    runtime::Runtime,
+    test_helpers::{
+        self,
+        TestRuntime,
+    },
+};
+use futures::{
+    task::{
+        noop_waker_ref,
+        Context,
+    },
+    FutureExt,
+};
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    net::Ipv4Addr,
+    pin::Pin,
+    task::Poll,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+fn pop_message(server: &Engine<TestRuntime>, fd: FileDescriptor) -> DhcpMessage {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut fut = server.udp().pop(fd);
+    let result = Future::poll(Pin::new(&mut fut), &mut ctx);
+    must_let!(let Poll::Ready(Ok((_, buf))) = result);
+    DhcpMessage::parse(&buf[..]).unwrap()
+}
+
+fn reply(request: &DhcpMessage, message_type: DhcpMessageType) -> DhcpMessage {
+    let mut msg = DhcpMessage::new(BOOTREPLY, request.xid, request.chaddr, message_type);
+    msg.yiaddr = test_helpers::ALICE_IPV4;
+    msg.server_id = Some(test_helpers::BOB_IPV4);
+    msg.lease_time = Some(3600);
+    msg.renewal_time = Some(1800);
+    msg.rebinding_time = Some(3150);
+    msg.subnet_mask = Some(Ipv4Addr::new(255, 255, 255, 0));
+    msg.routers = vec![Ipv4Addr::new(192, 168, 1, 254)];
+    msg.dns_servers = vec![test_helpers::BOB_IPV4];
+    msg
+}
+
+#[test]
+fn handshake_and_renewal() {
+    let mut now = Instant::now();
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let rt = TestRuntime::new("alice", now, test_helpers::ALICE_MAC, Ipv4Addr::UNSPECIFIED);
+    let mut alice = Engine::new(rt).unwrap();
+    let mut bob = test_helpers::new_bob(now);
+
+    let server_port = ip::Port::try_from(DHCP_SERVER_PORT).unwrap();
+    let client_port = ip::Port::try_from(DHCP_CLIENT_PORT).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(
+        bob_fd,
+        ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, server_port),
+    )
+    .unwrap();
+    let options = bob.udp().socket_options(bob_fd).unwrap().broadcast(true);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    let broadcast = ipv4::Endpoint::new(Ipv4Addr::BROADCAST, client_port);
+
+    let client = alice.dhcp_client(Options::default());
+    let mut fut = client.run().boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    // DISCOVER -> OFFER
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let discover = pop_message(&bob, bob_fd);
+    assert_eq!(discover.message_type, DhcpMessageType::Discover);
+    assert_eq!(discover.chaddr, test_helpers::ALICE_MAC);
+    let offer = reply(&discover, DhcpMessageType::Offer);
+    bob.udp()
+        .pushto(bob_fd, offer.serialize(), broadcast)
+        .unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    // REQUEST -> ACK
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let request = pop_message(&bob, bob_fd);
+    assert_eq!(request.message_type, DhcpMessageType::Request);
+    assert_eq!(request.xid, discover.xid);
+    assert_eq!(request.requested_addr, Some(test_helpers::ALICE_IPV4));
+    assert_eq!(request.server_id, Some(test_helpers::BOB_IPV4));
+    let ack = reply(&request, DhcpMessageType::Ack);
+    bob.udp()
+        .pushto(bob_fd, ack.serialize(), broadcast)
+        .unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    assert_eq!(alice.rt().local_ipv4_addr(), test_helpers::ALICE_IPV4);
+    let options = alice.rt().ipv4_options();
+    assert_eq!(options.netmask, Some(Ipv4Addr::new(255, 255, 255, 0)));
+    assert_eq!(options.gateway, Some(Ipv4Addr::new(192, 168, 1, 254)));
+    assert_eq!(options.dns_servers, vec![test_helpers::BOB_IPV4]);
+    assert!(alice.rt().pop_frame_opt().is_none());
+
+    // At T1 the client renews directly with the server that granted the lease.
+    now += Duration::from_secs(1800);
+    alice.rt().advance_clock(now);
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let renew = pop_message(&bob, bob_fd);
+    assert_eq!(renew.message_type, DhcpMessageType::Request);
+    assert_eq!(renew.ciaddr, test_helpers::ALICE_IPV4);
+    let ack = reply(&renew, DhcpMessageType::Ack);
+    let unicast = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, client_port);
+    bob.udp().pushto(bob_fd, ack.serialize(), unicast).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    assert!(alice.rt().pop_frame_opt().is_none());
+    assert_eq!(alice.rt().local_ipv4_addr(), test_helpers::ALICE_IPV4);
+}
@@ -0,0 +1,172 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    fail::Fail,
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+};
+
+const DNS_HEADER_SIZE: usize = 12;
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 253;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NAME_ERROR: u8 = 3;
+
+const CLASS_IN: u16 = 1;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+        }
+    }
+}
+
+pub fn encode_query(id: u16, name: &str, record_type: RecordType) -> Result<Bytes, Fail> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(Fail::Invalid {
+            details: "DNS name length out of range",
+        });
+    }
+    let mut buf = vec![0u8; DNS_HEADER_SIZE];
+    NetworkEndian::write_u16(&mut buf[0..2], id);
+    NetworkEndian::write_u16(&mut buf[2..4], FLAG_RECURSION_DESIRED);
+    NetworkEndian::write_u16(&mut buf[4..6], 1);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(Fail::Invalid {
+                details: "DNS label length out of range",
+            });
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    let mut trailer = [0u8; 4];
+    NetworkEndian::write_u16(&mut trailer[0..2], record_type.code());
+    NetworkEndian::write_u16(&mut trailer[2..4], CLASS_IN);
+    buf.extend_from_slice(&trailer);
+    Ok(BytesMut::from(&buf[..]).freeze())
+}
+
+#[derive(Debug)]
+pub struct DnsResponse {
+    pub id: u16,
+    pub truncated: bool,
+    pub rcode: u8,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl DnsResponse {
+    pub fn is_name_error(&self) -> bool {
+        self.rcode == RCODE_NAME_ERROR
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, Fail> {
+        if buf.len() < DNS_HEADER_SIZE {
+            return Err(Fail::Malformed {
+                details: "DNS message too short",
+            });
+        }
+        let id = NetworkEndian::read_u16(&buf[0..2]);
+        let flags = NetworkEndian::read_u16(&buf[2..4]);
+        if flags & FLAG_RESPONSE == 0 {
+            return Err(Fail::Malformed {
+                details: "DNS message is not a response",
+            });
+        }
+        let qdcount = NetworkEndian::read_u16(&buf[4..6]);
+        let ancount = NetworkEndian::read_u16(&buf[6..8]);
+
+        let mut pos = DNS_HEADER_SIZE;
+        for _ in 0..qdcount {
+            pos = skip_name(buf, pos)?;
+            pos = checked_advance(buf, pos, 4)?;
+        }
+        let mut addrs = vec![];
+        for _ in 0..ancount {
+            pos = skip_name(buf, pos)?;
+            let fixed = checked_advance(buf, pos, 10)?;
+            let rtype = NetworkEndian::read_u16(&buf[pos..pos + 2]);
+            let class = NetworkEndian::read_u16(&buf[pos + 2..pos + 4]);
+            let rdlength = NetworkEndian::read_u16(&buf[pos + 8..pos + 10]) as usize;
+            let end = checked_advance(buf, fixed, rdlength)?;
+            let rdata = &buf[fixed..end];
+            // Anything else (e.g. the CNAMEs leading up to the addresses) is skipped.
+            match (class, rtype, rdlength) {
+                (CLASS_IN, 1, 4) => {
+                    addrs.push(IpAddr::V4(Ipv4Addr::new(
+                        rdata[0], rdata[1], rdata[2], rdata[3],
+                    )));
+                },
+                (CLASS_IN, 28, 16) => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(rdata);
+                    addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+                },
+                _ => (),
+            }
+            pos = end;
+        }
+        Ok(Self {
+            id,
+            truncated: flags & FLAG_TRUNCATED != 0,
+            rcode: (flags & RCODE_MASK) as u8,
+            addrs,
+        })
+    }
+}
+
+fn checked_advance(buf: &[u8], pos: usize, n: usize) -> Result<usize, Fail> {
+    if pos + n > buf.len() {
+        return Err(Fail::Malformed {
+            details: "DNS message truncated",
+        });
+    }
+    Ok(pos + n)
+}
+
+// Returns the offset just past the name at `pos`. We never need the name itself, so compression
+// pointers end the name without being followed.
+fn skip_name(buf: &[u8], mut pos: usize) -> Result<usize, Fail> {
+    loop {
+        checked_advance(buf, pos, 1)?;
+        let len = buf[pos] as usize;
+        if len == 0 {
+            return Ok(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return checked_advance(buf, pos, 2);
+        }
+        if len > MAX_LABEL_LEN {
+            return Err(Fail::Malformed {
+                details: "Invalid DNS label",
+            });
+        }
+        pos = checked_advance(buf, pos, 1 + len)?;
+    }
+}
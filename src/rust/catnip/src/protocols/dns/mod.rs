@@ -0,0 +1,13 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+mod message;
+mod options;
+mod resolver;
+
+#[cfg(test)]
+mod tests;
+
+pub use message::RecordType;
+pub use options::DnsOptions as Options;
+pub use resolver::DnsResolver as Resolver;
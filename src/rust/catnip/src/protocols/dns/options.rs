@@ -0,0 +1,60 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::ipv4;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct DnsOptions {
+    /// Servers to query, in order of preference. Later servers are only tried once a query to
+    /// the earlier ones has run out of retries.
+    pub servers: Vec<ipv4::Endpoint>,
+    pub timeout: Duration,
+    pub retries: usize,
+    /// Return whatever answers a truncated (TC) response carries instead of failing or
+    /// retrying over TCP.
+    pub accept_truncated: bool,
+    /// Repeat the query over TCP when the response is truncated. A failed TCP query moves on to
+    /// the next server.
+    pub tcp_fallback: bool,
+}
+
+impl Default for DnsOptions {
+    fn default() -> Self {
+        DnsOptions {
+            servers: vec![],
+            timeout: Duration::from_secs(2),
+            retries: 1,
+            accept_truncated: false,
+            tcp_fallback: false,
+        }
+    }
+}
+
+impl DnsOptions {
+    pub fn server(mut self, value: ipv4::Endpoint) -> Self {
+        self.servers.push(value);
+        self
+    }
+
+    pub fn timeout(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.timeout = value;
+        self
+    }
+
+    pub fn retries(mut self, value: usize) -> Self {
+        self.retries = value;
+        self
+    }
+
+    pub fn accept_truncated(mut self, value: bool) -> Self {
+        self.accept_truncated = value;
+        self
+    }
+
+    pub fn tcp_fallback(mut self, value: bool) -> Self {
+        self.tcp_fallback = value;
+        self
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    message::{
+        encode_query,
+        DnsResponse,
+        RecordType,
+    },
+    options::DnsOptions,
+};
+use crate::{
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ipv4,
+        tcp,
+        udp,
+    },
+    runtime::Runtime,
+    sync::BytesMut,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use futures::FutureExt;
+use std::{
+    future::Future,
+    net::IpAddr,
+    time::Duration,
+};
+
+/// Stub resolver that sends queries over a UDP socket of its own, and over TCP when a response
+/// comes back truncated and `DnsOptions::tcp_fallback` is set. Dropping the future returned by
+/// `resolve` cancels the lookup and closes its sockets.
+pub struct DnsResolver<RT: Runtime> {
+    rt: RT,
+    udp: udp::Peer<RT>,
+    tcp: tcp::Peer<RT>,
+    options: DnsOptions,
+}
+
+// Closes the query socket however the lookup ends, including when it's dropped mid-query.
+struct SocketGuard<RT: Runtime> {
+    udp: udp::Peer<RT>,
+    fd: FileDescriptor,
+}
+
+impl<RT: Runtime> Drop for SocketGuard<RT> {
+    fn drop(&mut self) {
+        if let Err(e) = self.udp.close(self.fd) {
+            warn!("Failed to close DNS socket: {:?}", e);
+        }
+    }
+}
+
+// Same as `SocketGuard`, for the connection a truncated query is retried over. If it never got
+// established, closing it abandons the attempt.
+struct TcpSocketGuard<RT: Runtime> {
+    tcp: tcp::Peer<RT>,
+    fd: FileDescriptor,
+}
+
+impl<RT: Runtime> Drop for TcpSocketGuard<RT> {
+    fn drop(&mut self) {
+        if let Err(e) = self.tcp.close(self.fd) {
+            warn!("Failed to close DNS socket: {:?}", e);
+        }
+    }
+}
+
+// Repeats a query over TCP, where messages are framed with a two-byte length prefix (RFC 1035,
+// 4.2.2). `timeout` bounds the whole exchange.
+async fn query_tcp<RT: Runtime>(
+    rt: &RT,
+    tcp: &tcp::Peer<RT>,
+    server: ipv4::Endpoint,
+    name: &str,
+    record_type: RecordType,
+    timeout: Duration,
+) -> Result<DnsResponse, Fail> {
+    let guard = TcpSocketGuard {
+        fd: tcp.socket()?,
+        tcp: tcp.clone(),
+    };
+    let timeout = rt.wait(timeout).fuse();
+    futures::pin_mut!(timeout);
+    futures::select! {
+        r = guard.tcp.connect(guard.fd, server).fuse() => r?,
+        _ = timeout => return Err(Fail::Timeout {}),
+    }
+
+    let id: u16 = rt.rng_gen();
+    let query = encode_query(id, name, record_type)?;
+    let mut framed = BytesMut::zeroed(2 + query.len());
+    NetworkEndian::write_u16(&mut framed[0..2], query.len() as u16);
+    framed[2..].copy_from_slice(&query[..]);
+    guard.tcp.push(guard.fd, framed.freeze()).await?;
+
+    let mut buf = vec![];
+    loop {
+        if buf.len() >= 2 {
+            let len = NetworkEndian::read_u16(&buf[0..2]) as usize;
+            if buf.len() >= 2 + len {
+                let response = DnsResponse::parse(&buf[2..2 + len])?;
+                if response.id != id {
+                    return Err(Fail::Malformed {
+                        details: "DNS response ID mismatch",
+                    });
+                }
+                return Ok(response);
+            }
+        }
+        futures::select! {
+            r = guard.tcp.pop(guard.fd).fuse() => {
+                let segment = r?;
+                if segment.is_empty() {
+                    return Err(Fail::ConnectionAborted {});
+                }
+                buf.extend_from_slice(&segment[..]);
+            },
+            _ = timeout => return Err(Fail::Timeout {}),
+        }
+    }
+}
+
+impl<RT: Runtime> DnsResolver<RT> {
+    pub fn new(rt: RT, udp: udp::Peer<RT>, tcp: tcp::Peer<RT>, options: DnsOptions) -> Self {
+        Self {
+            rt,
+            udp,
+            tcp,
+            options,
+        }
+    }
+
+    pub fn resolve(
+        &self,
+        name: &str,
+        record_type: RecordType,
+    ) -> impl Future<Output = Result<Vec<IpAddr>, Fail>> {
+        let rt = self.rt.clone();
+        let udp = self.udp.clone();
+        let tcp = self.tcp.clone();
+        let options = self.options.clone();
+        let name = name.to_string();
+        async move {
+            if options.servers.is_empty() {
+                return Err(Fail::ResourceNotFound {
+                    details: "No DNS servers configured",
+                });
+            }
+            let guard = SocketGuard {
+                fd: udp.socket()?,
+                udp,
+            };
+            guard.udp.bind_ephemeral(guard.fd)?;
+
+            for &server in &options.servers {
+                for attempt in 0..options.retries + 1 {
+                    let id: u16 = rt.rng_gen();
+                    let query = encode_query(id, &name, record_type)?;
+                    guard.udp.pushto(guard.fd, query, server)?;
+
+                    let timeout = rt.wait(options.timeout).fuse();
+                    futures::pin_mut!(timeout);
+                    let response = loop {
+                        futures::select! {
+                            r = guard.udp.pop(guard.fd).fuse() => {
+                                let (remote, buf) = r?;
+                                if remote != Some(server) {
+                                    continue;
+                                }
+                                match DnsResponse::parse(&buf[..]) {
+                                    Ok(response) if response.id == id => break Some(response),
+                                    // Stale or garbled replies don't end the attempt.
+                                    _ => continue,
+                                }
+                            },
+                            _ = timeout => break None,
+                        }
+                    };
+                    let mut response = match response {
+                        Some(r) => r,
+                        None => {
+                            warn!(
+                                "DNS query to {:?} timed out; attempt {}.",
+                                server,
+                                attempt + 1
+                            );
+                            continue;
+                        },
+                    };
+                    if response.truncated && !options.accept_truncated && options.tcp_fallback {
+                        let r = query_tcp(&rt, &tcp, server, &name, record_type, options.timeout);
+                        response = match r.await {
+                            Ok(r) => r,
+                            Err(e) => {
+                                warn!("DNS query to {:?} over TCP failed: {:?}", server, e);
+                                break;
+                            },
+                        };
+                    }
+                    if response.is_name_error() {
+                        return Err(Fail::ResourceNotFound {
+                            details: "DNS name does not exist",
+                        });
+                    }
+                    if response.rcode != 0 {
+                        // The server couldn't answer; try the next one.
+                        warn!(
+                            "DNS server {:?} failed with rcode {}",
+                            server, response.rcode
+                        );
+                        break;
+                    }
+                    if response.truncated && !options.accept_truncated {
+                        return Err(Fail::Unsupported {
+                            details: "Truncated DNS response",
+                        });
+                    }
+                    return Ok(response.addrs);
+                }
+            }
+            Err(Fail::Timeout {})
+        }
+    }
+}
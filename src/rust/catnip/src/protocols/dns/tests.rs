@@ -0,0 +1,229 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    Options,
+    RecordType,
+};
+use crate::{
+    engine::{
+        Engine,
+        Protocol,
+    },
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ip,
+        ipv4,
+    },
+    runtime::Runtime,
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+    test_helpers::{
+        self,
+        TestRuntime,
+    },
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use futures::{
+    task::{
+        noop_waker_ref,
+        Context,
+    },
+    FutureExt,
+};
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+    },
+    pin::Pin,
+    task::Poll,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+fn server_endpoint(addr: Ipv4Addr) -> ipv4::Endpoint {
+    ipv4::Endpoint::new(addr, ip::Port::try_from(53).unwrap())
+}
+
+fn bind_server(server: &mut Engine<TestRuntime>, addr: Ipv4Addr) -> FileDescriptor {
+    let fd = server.socket(Protocol::Udp).unwrap();
+    server.bind(fd, server_endpoint(addr)).unwrap();
+    fd
+}
+
+// Pops the query the server just received.
+fn pop_query(server: &Engine<TestRuntime>, fd: FileDescriptor) -> (ipv4::Endpoint, Bytes) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut fut = server.udp().pop(fd);
+    let result = Future::poll(Pin::new(&mut fut), &mut ctx);
+    must_let!(let Poll::Ready(Ok((Some(remote), query))) = result);
+    (remote, query)
+}
+
+// Builds a response to `query` with one A record per address in `addrs`, overriding the ID.
+fn response(query: &[u8], id: u16, flags: u16, addrs: &[Ipv4Addr]) -> Bytes {
+    let mut buf = query.to_vec();
+    NetworkEndian::write_u16(&mut buf[0..2], id);
+    NetworkEndian::write_u16(&mut buf[2..4], 0x8180 | flags);
+    NetworkEndian::write_u16(&mut buf[6..8], addrs.len() as u16);
+    for addr in addrs {
+        // Name is a compression pointer to the question at offset 12.
+        buf.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4]);
+        buf.extend_from_slice(&addr.octets());
+    }
+    BytesMut::from(&buf[..]).freeze()
+}
+
+#[test]
+fn resolve_a_record() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let bob_fd = bind_server(&mut bob, test_helpers::BOB_IPV4);
+    let options = Options::default().server(server_endpoint(test_helpers::BOB_IPV4));
+    let resolver = alice.dns_resolver(options);
+    let mut fut = resolver
+        .resolve("www.example.com", RecordType::A)
+        .boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let (remote, query) = pop_query(&bob, bob_fd);
+    let id = NetworkEndian::read_u16(&query[0..2]);
+    let answer = Ipv4Addr::new(93, 184, 216, 34);
+
+    // A reply with the wrong ID is ignored...
+    let stale = response(&query, id.wrapping_add(1), 0, &[Ipv4Addr::new(1, 2, 3, 4)]);
+    bob.udp().pushto(bob_fd, stale, remote).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    // ...while the matching one completes the lookup.
+    bob.udp()
+        .pushto(bob_fd, response(&query, id, 0, &[answer]), remote)
+        .unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(addrs)) = Future::poll(fut.as_mut(), &mut ctx));
+    assert_eq!(addrs, vec![IpAddr::V4(answer)]);
+}
+
+#[test]
+fn failover_to_secondary() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let carrie_fd = bind_server(&mut carrie, test_helpers::CARRIE_IPV4);
+    let options = Options::default()
+        .server(server_endpoint(test_helpers::BOB_IPV4))
+        .server(server_endpoint(test_helpers::CARRIE_IPV4))
+        .timeout(Duration::from_secs(1))
+        .retries(0);
+    let resolver = alice.dns_resolver(options);
+    let mut fut = resolver.resolve("example.com", RecordType::A).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    // Bob never answers.
+    alice.rt().pop_frame();
+    now += Duration::from_secs(2);
+    alice.rt().advance_clock(now);
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    carrie.receive(alice.rt().pop_frame()).unwrap();
+    let (remote, query) = pop_query(&carrie, carrie_fd);
+    let id = NetworkEndian::read_u16(&query[0..2]);
+
+    // A truncated response fails the lookup unless TCP fallback is enabled.
+    carrie
+        .udp()
+        .pushto(carrie_fd, response(&query, id, 0x0200, &[]), remote)
+        .unwrap();
+    alice.receive(carrie.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::Unsupported { .. })) = Future::poll(fut.as_mut(), &mut ctx));
+}
+
+#[test]
+fn truncated_falls_back_to_tcp() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let bob_fd = bind_server(&mut bob, test_helpers::BOB_IPV4);
+    let listen_fd = bob.tcp_socket().unwrap();
+    bob.tcp_bind(listen_fd, server_endpoint(test_helpers::BOB_IPV4))
+        .unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    let options = Options::default()
+        .server(server_endpoint(test_helpers::BOB_IPV4))
+        .tcp_fallback(true);
+    let resolver = alice.dns_resolver(options);
+    let mut fut = resolver
+        .resolve("www.example.com", RecordType::A)
+        .boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let (remote, query) = pop_query(&bob, bob_fd);
+    let id = NetworkEndian::read_u16(&query[0..2]);
+    bob.udp()
+        .pushto(bob_fd, response(&query, id, 0x0200, &[]), remote)
+        .unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    // The truncated response has Alice connect to the same server over TCP.
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(bob_tcp_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+
+    // Once connected, the query goes out with a length prefix...
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let mut pop_future = bob.tcp_pop(bob_tcp_fd);
+    must_let!(let Poll::Ready(Ok(framed)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(
+        NetworkEndian::read_u16(&framed[0..2]) as usize,
+        framed.len() - 2
+    );
+    let query = &framed[2..];
+    let id = NetworkEndian::read_u16(&query[0..2]);
+
+    // ...and so does the answer.
+    let answer = Ipv4Addr::new(93, 184, 216, 34);
+    let reply = response(query, id, 0, &[answer]);
+    let mut framed_reply = vec![0u8; 2];
+    NetworkEndian::write_u16(&mut framed_reply[0..2], reply.len() as u16);
+    framed_reply.extend_from_slice(&reply[..]);
+    let mut push_future = bob.tcp_push(bob_tcp_fd, BytesMut::from(&framed_reply[..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    while let Some(frame) = bob.rt().pop_frame_opt() {
+        alice.receive(frame).unwrap();
+    }
+
+    must_let!(let Poll::Ready(Ok(addrs)) = Future::poll(fut.as_mut(), &mut ctx));
+    assert_eq!(addrs, vec![IpAddr::V4(answer)]);
+}
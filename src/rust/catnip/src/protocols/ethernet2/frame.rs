@@ -14,6 +14,8 @@ use std::convert::{
 };
 
 pub const MIN_PAYLOAD_SIZE: usize = 46;
+/// Largest payload a frame carries, which is also the IPv4 MTU of an Ethernet link.
+pub const MAX_PAYLOAD_SIZE: usize = 1500;
 pub const ETHERNET2_HEADER2_SIZE: usize = 14;
 
 #[repr(u16)]
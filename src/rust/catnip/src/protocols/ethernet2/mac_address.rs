@@ -2,7 +2,10 @@
 // Licensed under the MIT license.
 
 use eui48;
-use std::fmt;
+use std::{
+    fmt,
+    net::Ipv4Addr,
+};
 use crate::fail::Fail;
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
@@ -25,6 +28,14 @@ impl MacAddress {
         MacAddress(eui48::MacAddress::broadcast())
     }
 
+    /// Maps an IPv4 multicast group to its Ethernet address: `01:00:5e` followed by the low 23
+    /// bits of the group (RFC 1112, section 6.4).
+    pub fn from_ipv4_multicast(addr: Ipv4Addr) -> MacAddress {
+        assert!(addr.is_multicast());
+        let octets = addr.octets();
+        MacAddress::new([0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3]])
+    }
+
     pub fn nil() -> MacAddress {
         MacAddress(eui48::MacAddress::nil())
     }
@@ -68,3 +79,22 @@ impl fmt::Debug for MacAddress {
         write!(f, "MacAddress({})", &self.to_canonical())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::MacAddress;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn ipv4_multicast() {
+        let addr = Ipv4Addr::new(224, 0, 0, 1);
+        let expected = MacAddress::new([0x01, 0x00, 0x5e, 0x00, 0x00, 0x01]);
+        assert_eq!(MacAddress::from_ipv4_multicast(addr), expected);
+
+        // Only the low 23 bits survive, so these groups share a MAC.
+        let a = MacAddress::from_ipv4_multicast(Ipv4Addr::new(224, 129, 2, 3));
+        let b = MacAddress::from_ipv4_multicast(Ipv4Addr::new(239, 1, 2, 3));
+        assert_eq!(a, MacAddress::new([0x01, 0x00, 0x5e, 0x01, 0x02, 0x03]));
+        assert_eq!(a, b);
+    }
+}
@@ -7,7 +7,11 @@ use crate::{
             Ethernet2Header,
             MIN_PAYLOAD_SIZE,
         },
-        ipv4::datagram::Ipv4Header,
+        ipv4::datagram::{
+            Ipv4Header,
+            Ipv4Protocol2,
+            IPV4_HEADER2_SIZE,
+        },
     },
     runtime::PacketBuf,
     sync::Bytes,
@@ -18,16 +22,23 @@ use byteorder::{
 };
 use std::{
     cmp,
-    convert::TryInto,
+    convert::{
+        TryFrom,
+        TryInto,
+    },
+    net::Ipv4Addr,
 };
 
 #[allow(unused)]
 const MAX_ICMPV4_DATAGRAM_SIZE: usize = 576;
 
+/// ICMPv4 message types and the fields they carry in the rest of the header. The
+/// `DestinationUnreachable` MTU is only meaningful for code 4 ("fragmentation needed"), where
+/// routers report the largest datagram they can forward (RFC 1191).
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Icmpv4Type2 {
     EchoReply { id: u16, seq_num: u16 },
-    DestinationUnreachable,
+    DestinationUnreachable { next_hop_mtu: u16 },
     SourceQuench,
     RedirectMessage,
     EchoRequest { id: u16, seq_num: u16 },
@@ -48,7 +59,10 @@ impl Icmpv4Type2 {
                 let seq_num = NetworkEndian::read_u16(&rest_of_header[2..4]);
                 Ok(EchoReply { id, seq_num })
             },
-            3 => Ok(DestinationUnreachable),
+            3 => {
+                let next_hop_mtu = NetworkEndian::read_u16(&rest_of_header[2..4]);
+                Ok(DestinationUnreachable { next_hop_mtu })
+            },
             4 => Ok(SourceQuench),
             5 => Ok(RedirectMessage),
             8 => {
@@ -71,11 +85,15 @@ impl Icmpv4Type2 {
     fn serialize(&self) -> (u8, [u8; 4]) {
         use Icmpv4Type2::*;
         match self {
-            EchoReply { .. } => (0, [0u8; 4]),
-            DestinationUnreachable => (3, [0u8; 4]),
+            EchoReply { id, seq_num } => (0, echo_rest_of_header(*id, *seq_num)),
+            DestinationUnreachable { next_hop_mtu } => {
+                let mut rest_of_header = [0u8; 4];
+                NetworkEndian::write_u16(&mut rest_of_header[2..4], *next_hop_mtu);
+                (3, rest_of_header)
+            },
             SourceQuench => (4, [0u8; 4]),
             RedirectMessage => (5, [0u8; 4]),
-            EchoRequest { .. } => (8, [0u8; 4]),
+            EchoRequest { id, seq_num } => (8, echo_rest_of_header(*id, *seq_num)),
             RouterAdvertisement => (9, [0u8; 4]),
             RouterSolicitation => (10, [0u8; 4]),
             TimeExceeded => (11, [0u8; 4]),
@@ -86,18 +104,28 @@ impl Icmpv4Type2 {
     }
 }
 
+fn echo_rest_of_header(id: u16, seq_num: u16) -> [u8; 4] {
+    let mut rest_of_header = [0u8; 4];
+    NetworkEndian::write_u16(&mut rest_of_header[0..2], id);
+    NetworkEndian::write_u16(&mut rest_of_header[2..4], seq_num);
+    rest_of_header
+}
+
 pub struct Icmpv4Message {
     pub ethernet2_hdr: Ethernet2Header,
     pub ipv4_hdr: Ipv4Header,
     pub icmpv4_hdr: Icmpv4Header,
-    // TODO: Add a body enum when we need it.
+    /// Everything after the 8-byte header: the echoed payload, or the start of the offending
+    /// datagram for errors.
+    pub data: Bytes,
 }
 
 impl PacketBuf for Icmpv4Message {
     fn compute_size(&self) -> usize {
         let size = self.ethernet2_hdr.compute_size()
             + self.ipv4_hdr.compute_size()
-            + self.icmpv4_hdr.compute_size();
+            + self.icmpv4_hdr.compute_size()
+            + self.data.len();
 
         // Pad the end of the buffer with zeros if needed.
         cmp::max(size, MIN_PAYLOAD_SIZE)
@@ -113,7 +141,7 @@ impl PacketBuf for Icmpv4Message {
             .serialize(&mut buf[cur_pos..(cur_pos + eth_hdr_size)]);
         cur_pos += eth_hdr_size;
 
-        let ipv4_payload_len = icmpv4_hdr_size;
+        let ipv4_payload_len = icmpv4_hdr_size + self.data.len();
         self.ipv4_hdr.serialize(
             &mut buf[cur_pos..(cur_pos + ipv4_hdr_size)],
             ipv4_payload_len,
@@ -121,9 +149,12 @@ impl PacketBuf for Icmpv4Message {
         cur_pos += ipv4_hdr_size;
 
         self.icmpv4_hdr
-            .serialize(&mut buf[cur_pos..(cur_pos + icmpv4_hdr_size)]);
+            .serialize(&mut buf[cur_pos..(cur_pos + icmpv4_hdr_size)], &self.data);
         cur_pos += icmpv4_hdr_size;
 
+        buf[cur_pos..(cur_pos + self.data.len())].copy_from_slice(&self.data[..]);
+        cur_pos += self.data.len();
+
         // Add Ethernet padding if needed.
         for byte in &mut buf[cur_pos..] {
             *byte = 0;
@@ -133,6 +164,45 @@ impl PacketBuf for Icmpv4Message {
 
 pub const ICMPV4_HEADER2_SIZE: usize = 8;
 
+/// The start of the datagram an ICMPv4 error is about: its IPv4 header, followed by at least the
+/// first 8 bytes of its payload, which hold the ports for UDP and TCP.
+#[derive(Clone, Debug)]
+pub struct Icmpv4Quote {
+    pub protocol: Ipv4Protocol2,
+    pub identification: u16,
+    pub src_addr: Ipv4Addr,
+    pub dst_addr: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+impl Icmpv4Quote {
+    pub fn parse(buf: &[u8]) -> Result<Self, Fail> {
+        if buf.len() < IPV4_HEADER2_SIZE {
+            return Err(Fail::Malformed {
+                details: "ICMPv4 error too short for original header",
+            });
+        }
+        // The quoted header may carry options, and its TOTALLEN covers data that isn't here.
+        let payload_start = (buf[0] & 0xf) as usize * 4;
+        if payload_start < IPV4_HEADER2_SIZE || buf.len() < payload_start + 4 {
+            return Err(Fail::Malformed {
+                details: "ICMPv4 error too short for original ports",
+            });
+        }
+        let src_octets: [u8; 4] = buf[12..16].try_into().unwrap();
+        let dst_octets: [u8; 4] = buf[16..20].try_into().unwrap();
+        Ok(Self {
+            protocol: Ipv4Protocol2::try_from(buf[9])?,
+            identification: NetworkEndian::read_u16(&buf[4..6]),
+            src_addr: Ipv4Addr::from(src_octets),
+            dst_addr: Ipv4Addr::from(dst_octets),
+            src_port: NetworkEndian::read_u16(&buf[payload_start..(payload_start + 2)]),
+            dst_port: NetworkEndian::read_u16(&buf[(payload_start + 2)..(payload_start + 4)]),
+        })
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Icmpv4Header {
     pub icmpv4_type: Icmpv4Type2,
@@ -167,7 +237,7 @@ impl Icmpv4Header {
         Ok((Self { icmpv4_type, code }, data_buf))
     }
 
-    pub fn serialize(&self, buf: &mut [u8]) {
+    pub fn serialize(&self, buf: &mut [u8], body: &[u8]) {
         let buf: &mut [u8; ICMPV4_HEADER2_SIZE] =
             (&mut buf[..ICMPV4_HEADER2_SIZE]).try_into().unwrap();
         let (type_byte, rest_of_header) = self.icmpv4_type.serialize();
@@ -175,7 +245,7 @@ impl Icmpv4Header {
         buf[1] = self.code;
         // Skip the checksum for now.
         buf[4..8].copy_from_slice(&rest_of_header[..]);
-        let checksum = icmpv4_checksum(buf, &[]);
+        let checksum = icmpv4_checksum(buf, body);
         NetworkEndian::write_u16(&mut buf[2..4], checksum);
     }
 }
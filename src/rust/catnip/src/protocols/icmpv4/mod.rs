@@ -4,4 +4,7 @@
 mod datagram;
 mod peer;
 
+#[cfg(test)]
+mod tests;
+
 pub use peer::Icmpv4Peer as Peer;
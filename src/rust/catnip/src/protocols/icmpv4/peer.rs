@@ -3,25 +3,35 @@
 
 use super::datagram::{
     Icmpv4Header,
+    Icmpv4Quote,
     Icmpv4Type2,
 };
 use crate::{
     fail::Fail,
+    file_table::FileDescriptor,
     protocols::{
         arp,
         ethernet2::frame::{
             EtherType2,
             Ethernet2Header,
+            MAX_PAYLOAD_SIZE,
         },
         icmpv4::datagram::Icmpv4Message,
+        ip,
+        ipv4,
         ipv4::datagram::{
             Ipv4Header,
             Ipv4Protocol2,
+            IPV4_HEADER2_SIZE,
         },
+        udp,
     },
     runtime::Runtime,
     scheduler::SchedulerHandle,
-    sync::Bytes,
+    sync::{
+        Bytes,
+        BytesMut,
+    },
 };
 use byteorder::{
     ByteOrder,
@@ -34,6 +44,7 @@ use futures::{
 use hashbrown::HashMap;
 use std::{
     cell::RefCell,
+    convert::TryFrom,
     future::Future,
     net::Ipv4Addr,
     num::Wrapping,
@@ -50,13 +61,30 @@ use futures::channel::{
     },
 };
 
+// Destination unreachable codes (RFC 792) that we act on. The rest mean the host or its network
+// can't be reached.
+const CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+const CODE_PORT_UNREACHABLE: u8 = 3;
+const CODE_FRAGMENTATION_NEEDED: u8 = 4;
+
+// Smallest MTU every IPv4 link must support (RFC 791).
+const MIN_IPV4_MTU: u16 = 68;
+
+// Common path MTUs from RFC 1191, tried in turn when a router reports "fragmentation needed"
+// without a next-hop MTU.
+const MTU_PLATEAUS: [u16; 5] = [1492, 1006, 508, 296, MIN_IPV4_MTU];
+
+const PMTU_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const PMTU_PROBE_RETRIES: usize = 2;
+
 pub struct Icmpv4Peer<RT: Runtime> {
     rt: RT,
     arp: arp::Peer<RT>,
+    udp: udp::Peer<RT>,
 
     #[allow(unused)]
     handle: SchedulerHandle,
-    tx: mpsc::UnboundedSender<(Ipv4Addr, u16, u16)>,
+    tx: mpsc::UnboundedSender<(Ipv4Addr, u16, u16, Bytes)>,
 
     inner: Rc<RefCell<Inner>>,
 }
@@ -64,10 +92,74 @@ pub struct Icmpv4Peer<RT: Runtime> {
 struct Inner {
     requests: HashMap<(u16, u16), Sender<()>>,
     ping_seq_num_counter: Wrapping<u16>,
+    // Path MTU probes waiting on "fragmentation needed" reports, by destination.
+    frag_needed: HashMap<Ipv4Addr, Vec<PmtuProbe>>,
+}
+
+impl Inner {
+    fn withdraw_probes(&mut self, remote: Ipv4Addr, local: ipv4::Endpoint) {
+        if let Some(probes) = self.frag_needed.get_mut(&remote) {
+            probes.retain(|p| p.local != local);
+            if probes.is_empty() {
+                self.frag_needed.remove(&remote);
+            }
+        }
+    }
+}
+
+// The probe a path MTU discovery has in flight, and where to send the next-hop MTU when a router
+// reports that it needs fragmenting.
+struct PmtuProbe {
+    local: ipv4::Endpoint,
+    remote: ipv4::Endpoint,
+    identification: u16,
+    tx: Sender<u16>,
+}
+
+impl PmtuProbe {
+    // Only a report quoting the probe itself counts, so that anyone else can't shrink the path
+    // MTU by sending us one.
+    fn matches(&self, quote: &Icmpv4Quote) -> bool {
+        let local_port: u16 = self.local.port.into();
+        let remote_port: u16 = self.remote.port.into();
+        quote.protocol == Ipv4Protocol2::Udp
+            && quote.identification == self.identification
+            && (quote.src_addr, quote.src_port) == (self.local.addr, local_port)
+            && (quote.dst_addr, quote.dst_port) == (self.remote.addr, remote_port)
+    }
+}
+
+// Closes the probe socket however discovery ends, including when it's dropped mid-probe.
+struct SocketGuard<RT: Runtime> {
+    udp: udp::Peer<RT>,
+    fd: FileDescriptor,
+}
+
+impl<RT: Runtime> Drop for SocketGuard<RT> {
+    fn drop(&mut self) {
+        if let Err(e) = self.udp.close(self.fd) {
+            warn!("Failed to close PMTU probe socket: {:?}", e);
+        }
+    }
+}
+
+// Likewise withdraws the probe still in flight, so later reports about it are ignored.
+struct ProbeGuard {
+    inner: Rc<RefCell<Inner>>,
+    local: ipv4::Endpoint,
+    remote: Ipv4Addr,
+}
+
+impl Drop for ProbeGuard {
+    fn drop(&mut self) {
+        self.inner
+            .borrow_mut()
+            .withdraw_probes(self.remote, self.local);
+    }
 }
 
 impl<RT: Runtime> Icmpv4Peer<RT> {
-    pub fn new(rt: RT, arp: arp::Peer<RT>) -> Icmpv4Peer<RT> {
+    pub fn new(rt: RT, arp: arp::Peer<RT>, udp: udp::Peer<RT>) -> Icmpv4Peer<RT> {
         let (tx, rx) = mpsc::unbounded();
         let inner = Inner {
             requests: HashMap::new(),
@@ -76,13 +168,15 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
             // > Number field starts with the value 0 and is increased by 1 every
             // > time a new Echo Request message is sent.
             ping_seq_num_counter: Wrapping(0),
+            frag_needed: HashMap::new(),
         };
         let inner = Rc::new(RefCell::new(inner));
         let future = Self::background(rt.clone(), arp.clone(), rx);
-        let handle = rt.spawn(future);
+        let handle = rt.spawn_named("icmpv4::background", future);
         Icmpv4Peer {
             rt,
             arp,
+            udp,
             tx,
             handle,
             inner,
@@ -92,9 +186,9 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
     async fn background(
         rt: RT,
         arp: arp::Peer<RT>,
-        mut rx: mpsc::UnboundedReceiver<(Ipv4Addr, u16, u16)>,
+        mut rx: mpsc::UnboundedReceiver<(Ipv4Addr, u16, u16, Bytes)>,
     ) {
-        while let Some((dst_ipv4_addr, id, seq_num)) = rx.next().await {
+        while let Some((dst_ipv4_addr, id, seq_num, data)) = rx.next().await {
             let r: Result<_, Fail> = try {
                 debug!("initiating ARP query");
                 let dst_link_addr = arp.query(dst_ipv4_addr).await?;
@@ -108,7 +202,8 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                         src_addr: rt.local_link_addr(),
                         ether_type: EtherType2::Ipv4,
                     },
-                    ipv4_hdr: Ipv4Header::new(
+                    ipv4_hdr: Ipv4Header::new_outgoing(
+                        &rt,
                         rt.local_ipv4_addr(),
                         dst_ipv4_addr,
                         Ipv4Protocol2::Icmpv4,
@@ -117,6 +212,7 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                         icmpv4_type: Icmpv4Type2::EchoReply { id, seq_num },
                         code: 0,
                     },
+                    data,
                 };
                 rt.transmit(msg);
             };
@@ -130,10 +226,10 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
     }
 
     pub fn receive(&mut self, ipv4_header: &Ipv4Header, buf: Bytes) -> Result<(), Fail> {
-        let (icmpv4_hdr, _) = Icmpv4Header::parse(buf)?;
+        let (icmpv4_hdr, data) = Icmpv4Header::parse(buf)?;
         match icmpv4_hdr.icmpv4_type {
             Icmpv4Type2::EchoRequest { id, seq_num } => {
-                self.reply_to_ping(ipv4_header.src_addr, id, seq_num);
+                self.reply_to_ping(ipv4_header.src_addr, id, seq_num, data);
             },
             Icmpv4Type2::EchoReply { id, seq_num } => {
                 let mut inner = self.inner.borrow_mut();
@@ -141,6 +237,44 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                     let _ = tx.send(());
                 }
             },
+            Icmpv4Type2::DestinationUnreachable { next_hop_mtu } => {
+                let quote = Icmpv4Quote::parse(&data)?;
+                if icmpv4_hdr.code == CODE_FRAGMENTATION_NEEDED {
+                    let mut inner = self.inner.borrow_mut();
+                    let probes = inner.frag_needed.get_mut(&quote.dst_addr);
+                    let ix = probes
+                        .as_ref()
+                        .and_then(|probes| probes.iter().position(|p| p.matches(&quote)));
+                    let (probes, ix) = match (probes, ix) {
+                        (Some(probes), Some(ix)) => (probes, ix),
+                        _ => {
+                            return Err(Fail::Ignored {
+                                details: "Fragmentation needed doesn't quote a probe",
+                            })
+                        },
+                    };
+                    let probe = probes.swap_remove(ix);
+                    let _ = probe.tx.send(next_hop_mtu);
+                    if probes.is_empty() {
+                        inner.frag_needed.remove(&quote.dst_addr);
+                    }
+                    return Ok(());
+                }
+                if quote.protocol != Ipv4Protocol2::Udp {
+                    return Err(Fail::Unsupported {
+                        details: "ICMPv4 error for non-UDP datagram",
+                    });
+                }
+                let error = match icmpv4_hdr.code {
+                    CODE_PROTOCOL_UNREACHABLE | CODE_PORT_UNREACHABLE => Fail::ConnectionRefused {},
+                    _ => Fail::HostUnreachable {},
+                };
+                let local_port = ip::Port::try_from(quote.src_port)?;
+                let remote_port = ip::Port::try_from(quote.dst_port)?;
+                let local = ipv4::Endpoint::new(quote.src_addr, local_port);
+                let remote = ipv4::Endpoint::new(quote.dst_addr, remote_port);
+                self.udp.receive_icmp_error(local, remote, error)?;
+            },
             _ => {
                 warn!("Unsupported ICMPv4 message: {:?}", icmpv4_hdr);
             },
@@ -158,7 +292,7 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
             let mut state = 0xFFFF as u32;
             let addr_octets = self.rt.local_ipv4_addr().octets();
             state += NetworkEndian::read_u16(&addr_octets[0..2]) as u32;
-            state += NetworkEndian::read_u16(&addr_octets[3..4]) as u32;
+            state += NetworkEndian::read_u16(&addr_octets[2..4]) as u32;
 
             let mut pid_buf = [0u8; 4];
             NetworkEndian::write_u32(&mut pid_buf[..], process::id());
@@ -197,7 +331,8 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                     src_addr: rt.local_link_addr(),
                     ether_type: EtherType2::Ipv4,
                 },
-                ipv4_hdr: Ipv4Header::new(
+                ipv4_hdr: Ipv4Header::new_outgoing(
+                    &rt,
                     rt.local_ipv4_addr(),
                     dst_ipv4_addr,
                     Ipv4Protocol2::Icmpv4,
@@ -206,6 +341,7 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                     icmpv4_type: Icmpv4Type2::EchoRequest { id, seq_num },
                     code: 0,
                 },
+                data: Bytes::empty(),
             };
             rt.transmit(msg);
             let rx = {
@@ -217,14 +353,92 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
             // TODO: Handle cancellation here and unregister the completion in `requests`.
             futures::select! {
                 _ = rx.fuse() => Ok(rt.now() - t0),
-                _ = rt.wait(timeout).fuse() => Err(Fail::Timeout {}),
+                _ = rt.wait(timeout).fuse() => {
+                    inner.borrow_mut().requests.remove(&(id, seq_num));
+                    Err(Fail::Timeout {})
+                },
+            }
+        }
+    }
+
+    /// Finds the largest datagram that reaches `remote` unfragmented, by sending DF-set UDP
+    /// probes and shrinking them whenever a router reports "fragmentation needed". A probe that
+    /// draws no report after its retries is taken to fit, so without any ICMP feedback this
+    /// returns the link MTU.
+    pub fn discover_pmtu(&self, remote: ipv4::Endpoint) -> impl Future<Output = Result<u16, Fail>> {
+        let rt = self.rt.clone();
+        let udp = self.udp.clone();
+        let inner = self.inner.clone();
+        async move {
+            let guard = SocketGuard {
+                fd: udp.socket()?,
+                udp,
+            };
+            let options = guard.udp.socket_options(guard.fd)?.dont_fragment(true);
+            guard.udp.set_socket_options(guard.fd, options)?;
+            let local = guard.udp.bind_ephemeral(guard.fd)?;
+            let _probe_guard = ProbeGuard {
+                inner: inner.clone(),
+                local,
+                remote: remote.addr,
+            };
+
+            let mut mtu = MAX_PAYLOAD_SIZE as u16;
+            let mut attempts = 0;
+            while attempts <= PMTU_PROBE_RETRIES {
+                // Each probe supersedes the last, so only a report about this one counts.
+                let identification = rt.next_ipv4_identification();
+                let rx = {
+                    let (tx, rx) = channel();
+                    let mut inner = inner.borrow_mut();
+                    inner.withdraw_probes(remote.addr, local);
+                    let probe = PmtuProbe {
+                        local,
+                        remote,
+                        identification,
+                        tx,
+                    };
+                    inner
+                        .frag_needed
+                        .entry(remote.addr)
+                        .or_insert_with(Vec::new)
+                        .push(probe);
+                    rx
+                };
+                let probe_len = mtu as usize - IPV4_HEADER2_SIZE - udp::UDP_HEADER2_SIZE;
+                let probe = BytesMut::zeroed(probe_len).freeze();
+                let send_opts = udp::SendOpts {
+                    identification: Some(identification),
+                    ..Default::default()
+                };
+                guard.udp.pushto_with(guard.fd, probe, remote, send_opts)?;
+
+                futures::select! {
+                    r = rx.fuse() => {
+                        let next_hop_mtu = r.unwrap_or(0);
+                        let next_mtu = if next_hop_mtu >= MIN_IPV4_MTU && next_hop_mtu < mtu {
+                            next_hop_mtu
+                        } else {
+                            // Routers predating RFC 1191 leave the MTU zeroed.
+                            match MTU_PLATEAUS.iter().find(|&&p| p < mtu) {
+                                Some(&p) => p,
+                                None => return Ok(mtu),
+                            }
+                        };
+                        mtu = next_mtu;
+                        attempts = 0;
+                    },
+                    _ = rt.wait(PMTU_PROBE_TIMEOUT).fuse() => attempts += 1,
+                }
             }
+            Ok(mtu)
         }
     }
 
-    pub fn reply_to_ping(&mut self, dest_ipv4_addr: Ipv4Addr, id: u16, seq_num: u16) {
+    /// Queues an echo reply carrying the request's identifier, sequence number and payload.
+    pub fn reply_to_ping(&mut self, dest_ipv4_addr: Ipv4Addr, id: u16, seq_num: u16, data: Bytes) {
         self.tx
-            .unbounded_send((dest_ipv4_addr, id, seq_num))
+            .unbounded_send((dest_ipv4_addr, id, seq_num, data))
             .unwrap();
     }
 }
@@ -0,0 +1,331 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::datagram::{
+    Icmpv4Header,
+    Icmpv4Message,
+    Icmpv4Type2,
+};
+use crate::{
+    engine::{
+        Engine,
+        Protocol,
+    },
+    fail::Fail,
+    protocols::{
+        ethernet2::frame::{
+            EtherType2,
+            Ethernet2Header,
+            ETHERNET2_HEADER2_SIZE,
+        },
+        ip,
+        ipv4,
+        ipv4::datagram::{
+            Ipv4Header,
+            Ipv4Protocol2,
+            IPV4_FLAG_DONT_FRAGMENT,
+            IPV4_HEADER2_SIZE,
+        },
+    },
+    runtime::Runtime,
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+    test_helpers::{
+        self,
+        TestRuntime,
+    },
+};
+use futures::{
+    task::{
+        noop_waker_ref,
+        Context,
+    },
+    FutureExt,
+};
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    task::Poll,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+fn port(n: u16) -> ip::Port {
+    ip::Port::try_from(n).unwrap()
+}
+
+fn bob_endpoint() -> ipv4::Endpoint {
+    ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(9))
+}
+
+// Pops alice's next probe, checking it's DF-set, and returns its IPv4 datagram.
+fn pop_probe(alice: &Engine<TestRuntime>) -> Bytes {
+    let (_, datagram) = alice.rt().pop_frame().split(ETHERNET2_HEADER2_SIZE);
+    let (ipv4_hdr, _) = Ipv4Header::parse(datagram.clone()).unwrap();
+    assert_eq!(ipv4_hdr.protocol, Ipv4Protocol2::Udp);
+    assert_ne!(ipv4_hdr.flags & IPV4_FLAG_DONT_FRAGMENT, 0);
+    datagram
+}
+
+// Has bob send alice an ICMPv4 message, returning the frame.
+fn bob_to_alice(bob: &Engine<TestRuntime>, icmpv4_hdr: Icmpv4Header, data: Bytes) -> Bytes {
+    bob.rt().transmit(Icmpv4Message {
+        ethernet2_hdr: Ethernet2Header {
+            dst_addr: test_helpers::ALICE_MAC,
+            src_addr: test_helpers::BOB_MAC,
+            ether_type: EtherType2::Ipv4,
+        },
+        ipv4_hdr: Ipv4Header::new(
+            test_helpers::BOB_IPV4,
+            test_helpers::ALICE_IPV4,
+            Ipv4Protocol2::Icmpv4,
+        ),
+        icmpv4_hdr,
+        data,
+    });
+    bob.rt().pop_frame()
+}
+
+// Has bob bounce `datagram` back to alice as destination unreachable, quoting its header and
+// ports.
+fn unreachable(bob: &Engine<TestRuntime>, datagram: &Bytes, code: u8, next_hop_mtu: u16) -> Bytes {
+    let (quoted, _) = datagram.clone().split(IPV4_HEADER2_SIZE + 8);
+    let icmpv4_hdr = Icmpv4Header {
+        icmpv4_type: Icmpv4Type2::DestinationUnreachable { next_hop_mtu },
+        code,
+    };
+    bob_to_alice(bob, icmpv4_hdr, quoted)
+}
+
+// Has bob, standing in for a router, report that `probe` needs fragmenting.
+fn frag_needed(bob: &Engine<TestRuntime>, probe: &Bytes, next_hop_mtu: u16) -> Bytes {
+    unreachable(bob, probe, 4, next_hop_mtu)
+}
+
+#[test]
+fn reply_to_ping() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let bob = test_helpers::new_bob(now);
+    let payload = BytesMut::from(&b"abcdefghijklmnopqrstuvwxyz012345"[..]).freeze();
+
+    let request = Icmpv4Header {
+        icmpv4_type: Icmpv4Type2::EchoRequest {
+            id: 0x1234,
+            seq_num: 7,
+        },
+        code: 0,
+    };
+    alice
+        .receive(bob_to_alice(&bob, request, payload.clone()))
+        .unwrap();
+    alice.rt().poll_scheduler();
+
+    let (ethernet2_hdr, datagram) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    assert_eq!(ethernet2_hdr.dst_addr, test_helpers::BOB_MAC);
+    let (ipv4_hdr, segment) = Ipv4Header::parse(datagram).unwrap();
+    assert_eq!(ipv4_hdr.dst_addr, test_helpers::BOB_IPV4);
+    let (icmpv4_hdr, data) = Icmpv4Header::parse(segment).unwrap();
+    assert_eq!(
+        icmpv4_hdr.icmpv4_type,
+        Icmpv4Type2::EchoReply {
+            id: 0x1234,
+            seq_num: 7
+        }
+    );
+    assert_eq!(data, payload);
+}
+
+#[test]
+fn ping() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let mut fut = alice.ping(test_helpers::BOB_IPV4, None).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    now += Duration::from_millis(3);
+    alice.rt().advance_clock(now);
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let r = Future::poll(fut.as_mut(), &mut ctx);
+    must_let!(let Poll::Ready(Ok(rtt)) = r);
+    assert_eq!(rtt, Duration::from_millis(3));
+
+    // Without a reply, the ping times out.
+    let timeout = Duration::from_secs(1);
+    let mut fut = alice
+        .ping(test_helpers::BOB_IPV4, Some(timeout))
+        .boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    let _ = alice.rt().pop_frame();
+    now += timeout;
+    alice.rt().advance_clock(now);
+    let r = Future::poll(fut.as_mut(), &mut ctx);
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = r);
+}
+
+#[test]
+fn discover_pmtu() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let mut fut = alice.discover_pmtu(bob_endpoint()).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    let probe = pop_probe(&alice);
+    assert_eq!(probe.len(), 1500);
+
+    // A report that doesn't quote the probe, down to its identification, is ignored...
+    let mut spoofed = BytesMut::from(&probe[..]);
+    spoofed[4] ^= 0xff;
+    let r = alice.receive(frag_needed(&bob, &spoofed.freeze(), 576));
+    must_let!(let Err(Fail::Ignored { .. }) = r);
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    assert!(alice.rt().pop_frame_opt().is_none());
+
+    // ...while a reported next-hop MTU is tried next...
+    alice.receive(frag_needed(&bob, &probe, 1400)).unwrap();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    let superseded = probe;
+    let probe = pop_probe(&alice);
+    assert_eq!(probe.len(), 1400);
+
+    // ...reports about the probe it replaced no longer count...
+    let r = alice.receive(frag_needed(&bob, &superseded, 576));
+    must_let!(let Err(Fail::Ignored { .. }) = r);
+
+    // ...and a zeroed one drops to the next plateau below.
+    alice.receive(frag_needed(&bob, &probe, 0)).unwrap();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    let mut probe = pop_probe(&alice);
+    assert_eq!(probe.len(), 1006);
+
+    // Once the probe goes unanswered through its retries, it's taken to fit.
+    for _ in 0..2 {
+        now += Duration::from_secs(1);
+        alice.rt().advance_clock(now);
+        assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+        probe = pop_probe(&alice);
+        assert_eq!(probe.len(), 1006);
+    }
+    now += Duration::from_secs(1);
+    alice.rt().advance_clock(now);
+    must_let!(let Poll::Ready(Ok(1006)) = Future::poll(fut.as_mut(), &mut ctx));
+
+    // Nothing waits on the last probe once discovery is over.
+    let r = alice.receive(frag_needed(&bob, &probe, 576));
+    must_let!(let Err(Fail::Ignored { .. }) = r);
+}
+
+#[test]
+fn discover_pmtu_without_feedback() {
+    let mut now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let mut fut = alice.discover_pmtu(bob_endpoint()).boxed_local();
+    for _ in 0..3 {
+        assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+        assert_eq!(pop_probe(&alice).len(), 1500);
+        now += Duration::from_secs(1);
+        alice.rt().advance_clock(now);
+    }
+    must_let!(let Poll::Ready(Ok(1500)) = Future::poll(fut.as_mut(), &mut ctx));
+}
+
+#[test]
+fn port_unreachable() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let bob = test_helpers::new_bob(now);
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(fd, alice_addr).unwrap();
+    alice.udp().connect(fd, bob_endpoint()).unwrap();
+    alice.udp().push(fd, buf.clone()).unwrap();
+    let (_, datagram) = alice.rt().pop_frame().split(ETHERNET2_HEADER2_SIZE);
+
+    // Errors only reach the socket connected across the quoted 4-tuple.
+    let other_fd = alice.socket(Protocol::Udp).unwrap();
+    alice
+        .bind(
+            other_fd,
+            ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(81)),
+        )
+        .unwrap();
+    alice.udp().connect(other_fd, bob_endpoint()).unwrap();
+
+    alice.receive(unreachable(&bob, &datagram, 3, 0)).unwrap();
+    must_let!(let Err(Fail::ConnectionRefused {}) = alice.udp().push(fd, buf.clone()));
+    alice.udp().push(other_fd, buf.clone()).unwrap();
+
+    // The error is reported once.
+    alice.udp().push(fd, buf.clone()).unwrap();
+
+    // A pending pop picks it up too.
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut fut = alice.udp().pop(fd);
+    assert!(Future::poll(Pin::new(&mut fut), &mut ctx).is_pending());
+    alice.receive(unreachable(&bob, &datagram, 1, 0)).unwrap();
+    let r = Future::poll(Pin::new(&mut fut), &mut ctx);
+    must_let!(let Poll::Ready(Err(Fail::HostUnreachable {})) = r);
+
+    // Unconnected sockets don't take errors.
+    let unconnected_fd = alice.socket(Protocol::Udp).unwrap();
+    alice
+        .bind(
+            unconnected_fd,
+            ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(82)),
+        )
+        .unwrap();
+    alice
+        .udp()
+        .pushto(unconnected_fd, buf.clone(), bob_endpoint())
+        .unwrap();
+    let (_, datagram) = alice.rt().pop_frame().split(ETHERNET2_HEADER2_SIZE);
+    let r = alice.receive(unreachable(&bob, &datagram, 3, 0));
+    must_let!(let Err(Fail::Ignored { .. }) = r);
+    alice.udp().push(fd, buf).unwrap();
+}
+
+#[test]
+fn next_error() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    let fd = alice.socket(Protocol::Udp).unwrap();
+    alice
+        .bind(fd, ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80)))
+        .unwrap();
+    alice.udp().connect(fd, bob_endpoint()).unwrap();
+    let mut fut = alice.udp().next_error(fd).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    alice.udp().push(fd, buf).unwrap();
+    let (_, datagram) = alice.rt().pop_frame().split(ETHERNET2_HEADER2_SIZE);
+    alice.receive(unreachable(&bob, &datagram, 3, 0)).unwrap();
+    let r = Future::poll(fut.as_mut(), &mut ctx);
+    must_let!(let Poll::Ready(Ok((remote, Fail::ConnectionRefused {}))) = r);
+    assert_eq!(remote, bob_endpoint());
+
+    // Closing the socket ends the wait.
+    let mut fut = alice.udp().next_error(fd).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    alice.udp().close(fd).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::Shutdown {})) = Future::poll(fut.as_mut(), &mut ctx));
+}
@@ -9,6 +9,7 @@ use std::{
 use uniset::BitSet;
 
 const FIRST_PRIVATE_PORT: u16 = 49152;
+const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, Display, Ord, PartialOrd)]
 pub struct Port(NonZeroU16);
@@ -37,6 +38,11 @@ impl Port {
     pub fn is_private(self) -> bool {
         self.0.get() >= FIRST_PRIVATE_PORT
     }
+
+    /// Ports below 1024 are reserved for privileged processes, by Unix convention.
+    pub fn is_privileged(self) -> bool {
+        self.0.get() < FIRST_UNPRIVILEGED_PORT
+    }
 }
 
 pub struct EphemeralPorts {
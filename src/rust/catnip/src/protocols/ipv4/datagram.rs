@@ -1,5 +1,6 @@
 use crate::{
     fail::Fail,
+    runtime::Runtime,
     sync::Bytes,
 };
 use byteorder::{
@@ -21,6 +22,10 @@ pub const IPV4_HEADER2_SIZE: usize = 20;
 pub const DEFAULT_IPV4_TTL: u8 = 64;
 pub const IPV4_IHL_NO_OPTIONS: u8 = 5;
 pub const IPV4_VERSION: u8 = 4;
+/// "Don't fragment" bit of `Ipv4Header::flags`.
+pub const IPV4_FLAG_DONT_FRAGMENT: u8 = 0b010;
+/// "More fragments" bit of `Ipv4Header::flags`.
+pub const IPV4_FLAG_MORE_FRAGMENTS: u8 = 0b001;
 
 #[repr(u8)]
 #[derive(FromPrimitive, Copy, Clone, PartialEq, Eq, Debug)]
@@ -43,6 +48,7 @@ impl TryFrom<u8> for Ipv4Protocol2 {
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct Ipv4Header {
     // [ version 4 bits ] [ IHL 4 bits ]
     // The user shouldn't be able to mutate the version, so we parse it out but don't include it
@@ -96,19 +102,42 @@ impl Ipv4Header {
             identification: 0,
             flags: 0,
             fragment_offset: 0,
-            time_to_live: 0,
+            time_to_live: DEFAULT_IPV4_TTL,
             protocol,
             src_addr,
             dst_addr,
         }
     }
 
+    /// Header for a datagram originated by this stack, filled in from the runtime's IPv4
+    /// configuration. Each call consumes a fresh identification, so build the header once per
+    /// datagram and reuse it for all of its fragments.
+    pub fn new_outgoing<RT: Runtime>(
+        rt: &RT,
+        src_addr: Ipv4Addr,
+        dst_addr: Ipv4Addr,
+        protocol: Ipv4Protocol2,
+    ) -> Self {
+        let options = rt.ipv4_options();
+        Self {
+            identification: rt.next_ipv4_identification(),
+            time_to_live: options.default_ttl,
+            ..Self::new(src_addr, dst_addr, protocol)
+        }
+    }
+
     pub fn compute_size(&self) -> usize {
         // We don't support IPv4 options, so this is always 20.
         IPV4_HEADER2_SIZE
     }
 
     pub fn parse(buf: Bytes) -> Result<(Self, Bytes), Fail> {
+        Self::parse_with(buf, true)
+    }
+
+    /// Like `parse`, but only verifies the header checksum if `verify_checksum` is set, for
+    /// datagrams the NIC has already checked.
+    pub fn parse_with(buf: Bytes, verify_checksum: bool) -> Result<(Self, Bytes), Fail> {
         if buf.len() < IPV4_HEADER2_SIZE {
             return Err(Fail::Malformed {
                 details: "Datagram too small",
@@ -158,7 +187,10 @@ impl Ipv4Header {
         let flags = (NetworkEndian::read_u16(&hdr_buf[6..8]) >> 13) as u8;
 
         let fragment_offset = NetworkEndian::read_u16(&hdr_buf[6..8]) & 0x1fff;
-        if fragment_offset != 0 {
+        // We don't reassemble, so every fragment goes, the first one included: passed up on its
+        // own, it'd look like a whole (truncated) datagram. With nothing kept between fragments,
+        // overlapping ones can't corrupt anything either.
+        if fragment_offset != 0 || flags & IPV4_FLAG_MORE_FRAGMENTS != 0 {
             return Err(Fail::Unsupported {
                 details: "IPv4 fragmentation is unsupported",
             });
@@ -173,7 +205,7 @@ impl Ipv4Header {
                 details: "IPv4 checksum is 0xFFFF",
             });
         }
-        if header_checksum != ipv4_checksum(&hdr_buf[..]) {
+        if verify_checksum && header_checksum != ipv4_checksum(&hdr_buf[..]) {
             return Err(Fail::Malformed {
                 details: "Invalid IPv4 checksum",
             });
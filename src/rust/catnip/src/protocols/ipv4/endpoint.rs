@@ -22,4 +22,15 @@ impl Ipv4Endpoint {
     pub fn port(&self) -> ip::Port {
         self.port
     }
+
+    /// Whether a socket can bind here. Groups are joined through the socket's multicast options
+    /// rather than bound, and broadcast is never anyone's local address.
+    pub fn is_bindable(&self) -> bool {
+        !self.addr.is_multicast() && !self.addr.is_broadcast()
+    }
+
+    /// Whether datagrams can be addressed here; the unspecified address names no host.
+    pub fn is_destination(&self) -> bool {
+        !self.addr.is_unspecified()
+    }
 }
@@ -4,7 +4,9 @@
 // mod checksum;
 pub mod datagram;
 mod endpoint;
+mod options;
 mod peer;
 
 pub use endpoint::Ipv4Endpoint as Endpoint;
+pub use options::Ipv4Options as Options;
 pub use peer::Ipv4Peer as Peer;
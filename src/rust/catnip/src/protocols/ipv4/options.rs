@@ -0,0 +1,83 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::datagram::DEFAULT_IPV4_TTL;
+use std::net::Ipv4Addr;
+
+#[derive(Clone, Debug)]
+pub struct Ipv4Options {
+    /// TTL for outgoing datagrams that don't have a per-socket override.
+    pub default_ttl: u8,
+    /// Drops received datagrams claiming to come from our own address, which can only be
+    /// spoofed. Turn this off where traffic is deliberately hairpinned back to us.
+    pub drop_martians: bool,
+    /// Lets applications bind ports below 1024. Turn this off when running untrusted workloads,
+    /// and their binds to those ports fail with `PermissionDenied`. Sockets the stack opens for
+    /// itself, like DHCP's, aren't affected.
+    pub privileged: bool,
+    /// Unicast addresses we also answer on besides the runtime's `local_ipv4_addr`, like aliases
+    /// on an interface. Datagrams to any other address are rejected as misdelivered.
+    pub secondary_addrs: Vec<Ipv4Addr>,
+
+    // Network configuration, either static or learned from DHCP.
+    pub netmask: Option<Ipv4Addr>,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+}
+
+impl Default for Ipv4Options {
+    fn default() -> Self {
+        Ipv4Options {
+            default_ttl: DEFAULT_IPV4_TTL,
+            drop_martians: true,
+            privileged: true,
+            secondary_addrs: vec![],
+            netmask: None,
+            gateway: None,
+            dns_servers: vec![],
+        }
+    }
+}
+
+impl Ipv4Options {
+    pub fn default_ttl(mut self, value: u8) -> Self {
+        assert!(value > 0);
+        self.default_ttl = value;
+        self
+    }
+
+    pub fn drop_martians(mut self, value: bool) -> Self {
+        self.drop_martians = value;
+        self
+    }
+
+    pub fn privileged(mut self, value: bool) -> Self {
+        self.privileged = value;
+        self
+    }
+
+    pub fn secondary_addr(mut self, value: Ipv4Addr) -> Self {
+        assert!(!value.is_unspecified());
+        assert!(!value.is_broadcast());
+        assert!(!value.is_multicast());
+        self.secondary_addrs.push(value);
+        self
+    }
+
+    pub fn netmask(mut self, value: Ipv4Addr) -> Self {
+        self.netmask = Some(value);
+        self
+    }
+
+    pub fn gateway(mut self, value: Ipv4Addr) -> Self {
+        assert!(!value.is_unspecified());
+        assert!(!value.is_broadcast());
+        self.gateway = Some(value);
+        self
+    }
+
+    pub fn dns_server(mut self, value: Ipv4Addr) -> Self {
+        self.dns_servers.push(value);
+        self
+    }
+}
@@ -1,9 +1,12 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::datagram::{
-    Ipv4Header,
-    Ipv4Protocol2,
+use super::{
+    datagram::{
+        Ipv4Header,
+        Ipv4Protocol2,
+    },
+    Endpoint,
 };
 #[cfg(test)]
 use crate::file_table::FileDescriptor;
@@ -16,7 +19,10 @@ use crate::{
         tcp,
         udp,
     },
-    runtime::Runtime,
+    runtime::{
+        Runtime,
+        RxMetadata,
+    },
     sync::Bytes,
 };
 use std::{
@@ -35,7 +41,7 @@ pub struct Ipv4Peer<RT: Runtime> {
 impl<RT: Runtime> Ipv4Peer<RT> {
     pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Ipv4Peer<RT> {
         let udp = udp::Peer::new(rt.clone(), arp.clone(), file_table.clone());
-        let icmpv4 = icmpv4::Peer::new(rt.clone(), arp.clone());
+        let icmpv4 = icmpv4::Peer::new(rt.clone(), arp.clone(), udp.clone());
         let tcp = tcp::Peer::new(rt.clone(), arp, file_table);
         Ipv4Peer {
             rt,
@@ -45,15 +51,19 @@ impl<RT: Runtime> Ipv4Peer<RT> {
         }
     }
 
-    pub fn receive(&mut self, buf: Bytes) -> Result<(), Fail> {
-        let (header, payload) = Ipv4Header::parse(buf)?;
-        if header.dst_addr != self.rt.local_ipv4_addr() && !header.dst_addr.is_broadcast() {
+    pub fn receive(&mut self, buf: Bytes, metadata: &RxMetadata) -> Result<(), Fail> {
+        let (header, payload) = Ipv4Header::parse_with(buf, !metadata.checksum_validated)?;
+        let dst = header.dst_addr;
+        if dst != self.rt.local_ipv4_addr()
+            && !dst.is_broadcast()
+            && !self.rt.ipv4_options().secondary_addrs.contains(&dst)
+        {
             return Err(Fail::Misdelivered {});
         }
         match header.protocol {
             Ipv4Protocol2::Icmpv4 => self.icmpv4.receive(&header, payload),
             Ipv4Protocol2::Tcp => self.tcp.receive(&header, payload),
-            Ipv4Protocol2::Udp => self.udp.receive(&header, payload),
+            Ipv4Protocol2::Udp => self.udp.receive(&header, payload, metadata),
         }
     }
 
@@ -64,6 +74,10 @@ impl<RT: Runtime> Ipv4Peer<RT> {
     ) -> impl Future<Output = Result<Duration, Fail>> {
         self.icmpv4.ping(dest_ipv4_addr, timeout)
     }
+
+    pub fn discover_pmtu(&self, remote: Endpoint) -> impl Future<Output = Result<u16, Fail>> {
+        self.icmpv4.discover_pmtu(remote)
+    }
 }
 
 #[cfg(test)]
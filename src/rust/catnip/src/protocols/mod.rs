@@ -2,6 +2,8 @@
 // Licensed under the MIT license.
 
 pub mod arp;
+pub mod dhcp;
+pub mod dns;
 pub mod ethernet2;
 pub mod icmpv4;
 pub mod ip;
@@ -87,7 +87,7 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             arp.clone(),
             result.clone(),
         );
-        let handle = rt.spawn(future);
+        let handle = rt.spawn_named("tcp::active_open", future);
 
         // TODO: Add fast path here when remote is already in the ARP cache (and subtract one retry).
         Self {
@@ -147,7 +147,12 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
                 src_addr: self.rt.local_link_addr(),
                 ether_type: EtherType2::Ipv4,
             },
-            ipv4_hdr: Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp),
+            ipv4_hdr: Ipv4Header::new_outgoing(
+                &self.rt,
+                self.local.addr,
+                self.remote.addr,
+                Ipv4Protocol2::Tcp,
+            ),
             tcp_hdr,
             data: Bytes::empty(),
         };
@@ -225,7 +230,12 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
                         src_addr: rt.local_link_addr(),
                         ether_type: EtherType2::Ipv4,
                     },
-                    ipv4_hdr: Ipv4Header::new(local.addr, remote.addr, Ipv4Protocol2::Tcp),
+                    ipv4_hdr: Ipv4Header::new_outgoing(
+                        &rt,
+                        local.addr,
+                        remote.addr,
+                        Ipv4Protocol2::Tcp,
+                    ),
                     tcp_hdr,
                     data: Bytes::empty(),
                 };
@@ -34,7 +34,7 @@ impl<RT: Runtime> EstablishedSocket<RT> {
     pub fn new(cb: ControlBlock<RT>) -> Self {
         let cb = Rc::new(cb);
         let future = background(cb.clone());
-        let handle = cb.rt.spawn(future);
+        let handle = cb.rt.spawn_named("tcp::established", future);
         Self {
             cb: cb.clone(),
             background_work: handle,
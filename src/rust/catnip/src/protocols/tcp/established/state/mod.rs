@@ -95,7 +95,12 @@ impl<RT: Runtime> ControlBlock<RT> {
                 src_addr: self.rt.local_link_addr(),
                 ether_type: EtherType2::Ipv4,
             },
-            ipv4_hdr: Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp),
+            ipv4_hdr: Ipv4Header::new_outgoing(
+                &self.rt,
+                self.local.addr,
+                self.remote.addr,
+                Ipv4Protocol2::Tcp,
+            ),
             tcp_hdr: header,
             data,
         };
@@ -94,7 +94,7 @@ impl<RT: Runtime> TcpOperation<RT> {
             Push(ResultFuture {
                 future,
                 done: Some(Ok(())),
-            }) => (future.fd, OperationResult::Push),
+            }) => (future.fd, OperationResult::Push(future.len)),
             Push(ResultFuture {
                 future,
                 done: Some(Err(e)),
@@ -172,6 +172,8 @@ impl<RT: Runtime> Future for AcceptFuture<RT> {
 pub struct PushFuture<RT: Runtime> {
     pub fd: FileDescriptor,
     pub err: Option<Fail>,
+    // TCP queues the whole buffer or nothing, so this is what a successful push reports.
+    pub len: usize,
     pub _marker: std::marker::PhantomData<RT>,
 }
 
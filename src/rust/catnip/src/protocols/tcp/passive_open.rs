@@ -205,7 +205,7 @@ impl<RT: Runtime> PassiveSocket<RT> {
             self.arp.clone(),
             self.ready.clone(),
         );
-        let handle = self.rt.spawn(future);
+        let handle = self.rt.spawn_named("tcp::passive_open", future);
 
         let mut window_scale = 1;
         let mut mss = FALLBACK_MSS;
@@ -273,7 +273,12 @@ impl<RT: Runtime> PassiveSocket<RT> {
                         src_addr: rt.local_link_addr(),
                         ether_type: EtherType2::Ipv4,
                     },
-                    ipv4_hdr: Ipv4Header::new(local.addr, remote.addr, Ipv4Protocol2::Tcp),
+                    ipv4_hdr: Ipv4Header::new_outgoing(
+                        &rt,
+                        local.addr,
+                        remote.addr,
+                        Ipv4Protocol2::Tcp,
+                    ),
                     tcp_hdr,
                     data: Bytes::empty(),
                 };
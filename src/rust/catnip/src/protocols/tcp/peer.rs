@@ -52,6 +52,7 @@ use std::{
     time::Duration,
 };
 
+#[derive(Clone)]
 pub struct Peer<RT: Runtime> {
     pub(super) inner: Rc<RefCell<Inner<RT>>>,
 }
@@ -63,14 +64,14 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
-    pub fn socket(&self) -> FileDescriptor {
+    pub fn socket(&self) -> Result<FileDescriptor, Fail> {
         let mut inner = self.inner.borrow_mut();
-        let fd = inner.file_table.alloc(File::TcpSocket);
+        let fd = inner.file_table.alloc(File::TcpSocket)?;
         assert!(inner
             .sockets
             .insert(fd, Socket::Inactive { local: None })
             .is_none());
-        fd
+        Ok(fd)
     }
 
     pub fn bind(&self, fd: FileDescriptor, addr: ipv4::Endpoint) -> Result<(), Fail> {
@@ -133,7 +134,7 @@ impl<RT: Runtime> Peer<RT> {
                     details: "Socket not listening",
                 }))
             },
-            None => return Poll::Ready(Err(Fail::Malformed { details: "Bad FD" })),
+            None => return Poll::Ready(Err(Fail::BadFileDescriptor {})),
         };
         let passive = inner
             .passive
@@ -146,7 +147,11 @@ impl<RT: Runtime> Peer<RT> {
         };
         let established = EstablishedSocket::new(cb);
 
-        let fd = inner.file_table.alloc(File::TcpSocket);
+        // Without a descriptor to hand out, the accepted connection is dropped.
+        let fd = match inner.file_table.alloc(File::TcpSocket) {
+            Ok(fd) => fd,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
         let key = (established.cb.local.clone(), established.cb.remote.clone());
 
         let socket = Socket::Established {
@@ -219,7 +224,7 @@ impl<RT: Runtime> Peer<RT> {
                     details: "Socket not established",
                 })
             },
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
+            None => return Err(Fail::BadFileDescriptor {}),
         };
         match inner.established.get(&key) {
             Some(ref s) => s.peek(),
@@ -238,7 +243,7 @@ impl<RT: Runtime> Peer<RT> {
                     details: "Recv: Socket not established",
                 })
             },
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
+            None => return Err(Fail::BadFileDescriptor {}),
         };
         match inner.established.get(&key) {
             Some(ref s) => s.recv(),
@@ -257,7 +262,7 @@ impl<RT: Runtime> Peer<RT> {
                     details: "Recv: Socket not established",
                 }))
             },
-            None => return Poll::Ready(Err(Fail::Malformed { details: "Bad FD" })),
+            None => return Poll::Ready(Err(Fail::BadFileDescriptor {})),
         };
         match inner.established.get(&key) {
             Some(ref s) => s.poll_recv(ctx),
@@ -268,6 +273,7 @@ impl<RT: Runtime> Peer<RT> {
     }
 
     pub fn push(&self, fd: FileDescriptor, buf: Bytes) -> PushFuture<RT> {
+        let len = buf.len();
         let err = match self.send(fd, buf) {
             Ok(()) => None,
             Err(e) => Some(e),
@@ -275,6 +281,7 @@ impl<RT: Runtime> Peer<RT> {
         PushFuture {
             fd,
             err,
+            len,
             _marker: std::marker::PhantomData,
         }
     }
@@ -295,7 +302,7 @@ impl<RT: Runtime> Peer<RT> {
                     details: "Socket not established",
                 })
             },
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
+            None => return Err(Fail::BadFileDescriptor {}),
         };
         match inner.established.get(&key) {
             Some(ref s) => s.send(buf),
@@ -307,8 +314,12 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
+    /// Starts closing an established connection. Sockets that never got that far are released
+    /// along with their descriptor right away: a listener stops accepting, dropping connections
+    /// it hasn't handed out yet, and a connection attempt is abandoned, failing its
+    /// `ConnectFuture`.
     pub fn close(&self, fd: FileDescriptor) -> Result<(), Fail> {
-        let inner = self.inner.borrow_mut();
+        let mut inner = self.inner.borrow_mut();
         match inner.sockets.get(&fd) {
             Some(Socket::Established { local, remote }) => {
                 let key = (local.clone(), remote.clone());
@@ -322,10 +333,19 @@ impl<RT: Runtime> Peer<RT> {
                 }
             },
             Some(..) => {
-                // TODO: Implement close for listening sockets.
-                unimplemented!();
+                inner.file_table.free(fd)?;
+                match inner.sockets.remove(&fd) {
+                    Some(Socket::Listening { local }) => {
+                        inner.passive.remove(&local);
+                    },
+                    Some(Socket::Connecting { local, remote }) => {
+                        inner.connecting.remove(&(local, remote));
+                        inner.ephemeral_ports.free(local.port);
+                    },
+                    _ => (),
+                }
             },
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
+            None => return Err(Fail::BadFileDescriptor {}),
         }
         Ok(())
     }
@@ -339,7 +359,7 @@ impl<RT: Runtime> Peer<RT> {
                     details: "Socket not established",
                 })
             },
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
+            None => return Err(Fail::BadFileDescriptor {}),
         };
         match inner.established.get(&key) {
             Some(ref s) => Ok(s.remote_mss()),
@@ -360,7 +380,7 @@ impl<RT: Runtime> Peer<RT> {
                     details: "Socket not established",
                 })
             },
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
+            None => return Err(Fail::BadFileDescriptor {}),
         };
         match inner.established.get(&key) {
             Some(ref s) => Ok(s.current_rto()),
@@ -381,7 +401,7 @@ impl<RT: Runtime> Peer<RT> {
                     details: "Socket not established",
                 })
             },
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
+            None => return Err(Fail::BadFileDescriptor {}),
         };
         match inner.established.get(&key) {
             Some(ref s) => Ok(s.endpoints()),
@@ -492,7 +512,12 @@ impl<RT: Runtime> Inner<RT> {
                 src_addr: self.rt.local_link_addr(),
                 ether_type: EtherType2::Ipv4,
             },
-            ipv4_hdr: Ipv4Header::new(local.addr, remote.addr, Ipv4Protocol2::Tcp),
+            ipv4_hdr: Ipv4Header::new_outgoing(
+                &self.rt,
+                local.addr,
+                remote.addr,
+                Ipv4Protocol2::Tcp,
+            ),
             tcp_hdr,
             data: Bytes::empty(),
         };
@@ -513,7 +538,7 @@ impl<RT: Runtime> Inner<RT> {
                     details: "Socket not connecting",
                 }))
             },
-            None => return Poll::Ready(Err(Fail::Malformed { details: "Bad FD" })),
+            None => return Poll::Ready(Err(Fail::BadFileDescriptor {})),
         };
 
         let result = {
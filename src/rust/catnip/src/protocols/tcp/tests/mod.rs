@@ -1,7 +1,11 @@
 use crate::{
+    fail::Fail,
     protocols::{
+        ethernet2::frame::Ethernet2Header,
         ip,
         ipv4,
+        ipv4::datagram::Ipv4Header,
+        tcp::segment::TcpHeader,
     },
     sync::BytesMut,
     test_helpers,
@@ -31,12 +35,12 @@ fn test_connect() {
     let listen_port = ip::Port::try_from(80).unwrap();
     let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
 
-    let listen_fd = bob.tcp_socket();
+    let listen_fd = bob.tcp_socket().unwrap();
     bob.tcp_bind(listen_fd, listen_addr).unwrap();
     bob.tcp_listen(listen_fd, 1).unwrap();
     let mut accept_future = bob.tcp_accept(listen_fd);
 
-    let alice_fd = alice.tcp_socket();
+    let alice_fd = alice.tcp_socket().unwrap();
     let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
 
     // Send the SYN from Alice to Bob
@@ -66,3 +70,43 @@ fn test_connect() {
     must_let!(let Poll::Ready(Ok(received_buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
     assert_eq!(received_buf, buf);
 }
+
+#[test]
+fn test_close_unestablished() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    // A socket that was never used just goes away.
+    let fd = alice.tcp_socket().unwrap();
+    alice.tcp_close(fd).unwrap();
+    must_let!(let Err(Fail::BadFileDescriptor {}) = alice.tcp_close(fd));
+
+    // Closing a listener lets another one take its address.
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket().unwrap();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    bob.tcp_close(listen_fd).unwrap();
+    let listen_fd = bob.tcp_socket().unwrap();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+
+    // Closing a connection attempt fails it, and the SYN+ACK that comes back gets a RST.
+    let alice_fd = alice.tcp_socket().unwrap();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    alice.tcp_close(alice_fd).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::BadFileDescriptor {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let (_, payload) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, segment) = Ipv4Header::parse(payload).unwrap();
+    let (tcp_hdr, _) = TcpHeader::parse(&ipv4_hdr, segment).unwrap();
+    assert!(tcp_hdr.rst);
+}
@@ -28,6 +28,27 @@ use std::{
 
 pub const UDP_HEADER2_SIZE: usize = 8;
 
+/// Why `UdpHeader::parse` rejected a segment.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    Truncated,
+    BadLength,
+    BadChecksum,
+    BadPort,
+}
+
+impl From<ParseError> for Fail {
+    fn from(e: ParseError) -> Self {
+        let details = match e {
+            ParseError::Truncated => "UDP segment too small",
+            ParseError::BadLength => "UDP length mismatch",
+            ParseError::BadChecksum => "UDP checksum mismatch",
+            ParseError::BadPort => "UDP destination port is zero",
+        };
+        Fail::Malformed { details }
+    }
+}
+
 pub struct UdpHeader {
     pub src_port: Option<ip::Port>,
     pub dst_port: ip::Port,
@@ -93,29 +114,38 @@ impl UdpHeader {
         UDP_HEADER2_SIZE
     }
 
-    pub fn parse(ipv4_header: &Ipv4Header, buf: Bytes) -> Result<(Self, Bytes), Fail> {
+    pub fn parse(ipv4_header: &Ipv4Header, buf: Bytes) -> Result<(Self, Bytes), ParseError> {
+        Self::parse_with(ipv4_header, buf, true)
+    }
+
+    /// Like `parse`, but only verifies the checksum if `verify_checksum` is set, for segments
+    /// the NIC has already checked.
+    pub fn parse_with(
+        ipv4_header: &Ipv4Header,
+        buf: Bytes,
+        verify_checksum: bool,
+    ) -> Result<(Self, Bytes), ParseError> {
         if buf.len() < UDP_HEADER2_SIZE {
-            return Err(Fail::Malformed {
-                details: "UDP segment too small",
-            });
+            return Err(ParseError::Truncated);
         }
         let (hdr_buf, data_buf) = buf.split(UDP_HEADER2_SIZE);
 
         let src_port = ip::Port::try_from(NetworkEndian::read_u16(&hdr_buf[0..2])).ok();
-        let dst_port = ip::Port::try_from(NetworkEndian::read_u16(&hdr_buf[2..4]))?;
+        let dst_port = ip::Port::try_from(NetworkEndian::read_u16(&hdr_buf[2..4]))
+            .map_err(|_| ParseError::BadPort)?;
 
+        // `buf` is the IPv4 payload, already cut to the total length less the IPv4 header, so a
+        // length claiming more or fewer bytes than that means truncation or a crafted packet.
+        // Either way nothing is sized from it: the payload handed on is always a slice of `buf`.
         let length = NetworkEndian::read_u16(&hdr_buf[4..6]) as usize;
         if length != hdr_buf.len() + data_buf.len() {
-            return Err(Fail::Malformed {
-                details: "UDP length mismatch",
-            });
+            return Err(ParseError::BadLength);
         }
 
         let checksum = NetworkEndian::read_u16(&hdr_buf[6..8]);
-        if checksum != 0 && checksum != udp_checksum(&ipv4_header, &hdr_buf[..], &data_buf[..]) {
-            return Err(Fail::Malformed {
-                details: "UDP checksum mismatch",
-            });
+        let verify_checksum = verify_checksum && checksum != 0;
+        if verify_checksum && checksum != udp_checksum(&ipv4_header, &hdr_buf[..], &data_buf[..]) {
+            return Err(ParseError::BadChecksum);
         }
 
         let header = Self { src_port, dst_port };
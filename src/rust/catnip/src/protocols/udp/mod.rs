@@ -2,9 +2,39 @@
 // Licensed under the MIT license.
 
 mod datagram;
+mod options;
 pub mod peer;
+mod poller;
 
 #[cfg(test)]
 mod tests;
 
-pub use peer::UdpPeer as Peer;
+pub use datagram::{
+    ParseError,
+    UDP_HEADER2_SIZE,
+};
+pub use options::{
+    EarlyDrop,
+    Ecn,
+    Pacing,
+    PacingMode,
+    SendOpts,
+    UdpSocketOptions as SocketOptions,
+    MAX_SOCKET_BUFFER,
+};
+pub use peer::{
+    PendingDatagrams,
+    ReceiveOutcome,
+    ReceivedDatagram,
+    SendHandle,
+    SendStatus,
+    SendTimestamps,
+    SocketState,
+    UdpPeer as Peer,
+    UdpPeerMetrics,
+    UdpStats,
+};
+pub use poller::{
+    UdpEvent,
+    UdpPoller,
+};
@@ -0,0 +1,377 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::fail::Fail;
+use std::{
+    cmp,
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+/// ECN codepoint carried in the low two bits of the IPv4 TOS byte (RFC 3168).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Ecn {
+    NotEct,
+    Ect1,
+    Ect0,
+    Ce,
+}
+
+impl Ecn {
+    pub fn from_bits(bits: u8) -> Self {
+        match bits & 3 {
+            0 => Ecn::NotEct,
+            1 => Ecn::Ect1,
+            2 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+
+    pub fn bits(self) -> u8 {
+        match self {
+            Ecn::NotEct => 0,
+            Ecn::Ect1 => 1,
+            Ecn::Ect0 => 2,
+            Ecn::Ce => 3,
+        }
+    }
+}
+
+impl Default for Ecn {
+    fn default() -> Self {
+        Ecn::NotEct
+    }
+}
+
+/// Header fields `UdpPeer::pushto_with` sets for a single datagram, like `sendmsg` ancillary
+/// data. Fields left `None` come from the socket as usual.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SendOpts {
+    pub ttl: Option<u8>,
+    /// The whole TOS byte, DSCP and ECN.
+    pub tos: Option<u8>,
+    /// Overrides just the ECN bits, even of `tos`.
+    pub ecn: Option<Ecn>,
+    /// The IPv4 identification, for callers that need to recognize the datagram when an ICMPv4
+    /// error quotes it.
+    pub identification: Option<u16>,
+}
+
+/// What `push` does with a datagram that the socket's `Pacing` bucket can't cover yet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PacingMode {
+    /// Fails the push with `WouldBlock`, leaving the caller to retry once the bucket refills.
+    WouldBlock,
+    /// Queues the datagram and sends it once the bucket has refilled enough to cover it.
+    Queue,
+}
+
+/// Token bucket capping a socket's send rate. It holds up to `burst` bytes and refills at `rate`
+/// bytes per second, and every datagram sent takes its payload length out of it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Pacing {
+    pub rate: u64,
+    pub burst: usize,
+    pub mode: PacingMode,
+}
+
+/// Random Early Detection for a socket's receive queue. Once the queue holds `min` payload bytes,
+/// arriving datagrams are dropped with a probability that rises linearly to `max_probability` as
+/// it fills to `max` bytes, past which every one is dropped. Senders that back off on loss then
+/// slow down before the queue overflows outright.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EarlyDrop {
+    pub min: usize,
+    pub max: usize,
+    pub max_probability: f64,
+}
+
+impl EarlyDrop {
+    /// Chance of dropping a datagram that arrives while `queued` bytes are waiting.
+    pub fn drop_probability(&self, queued: usize) -> f64 {
+        if queued < self.min {
+            0.0
+        } else if queued >= self.max {
+            1.0
+        } else {
+            let fill = (queued - self.min) as f64 / (self.max - self.min) as f64;
+            self.max_probability * fill
+        }
+    }
+}
+
+const DEFAULT_SEND_BUFFER_HIGH: usize = 256 * 1024;
+const DEFAULT_SEND_BUFFER_LOW: usize = 128 * 1024;
+const DEFAULT_RECV_BUFFER_CAPACITY: usize = 8;
+// Keeps multicast on the local link unless the socket asks for more.
+const DEFAULT_MULTICAST_TTL: u8 = 1;
+
+/// Largest size `UdpSocketOptions::rcvbuf` and `sndbuf` accept before doubling, like Linux's
+/// `net.core.rmem_max` and `wmem_max`.
+pub const MAX_SOCKET_BUFFER: usize = 4 * 1024 * 1024;
+// Smallest effective size either accepts, so a socket can always hold a full-sized datagram.
+const MIN_SOCKET_BUFFER: usize = 2048;
+
+/// Per-socket settings, read with `UdpPeer::socket_options` and applied with
+/// `UdpPeer::set_socket_options`.
+#[derive(Clone, Debug)]
+pub struct UdpSocketOptions {
+    /// Codepoint stamped on every datagram sent from the socket.
+    pub ecn: Ecn,
+    /// Sends that would queue more than this many bytes behind pending ARP resolution or pacing
+    /// fail with `WouldBlock`, like `SO_SNDBUF`.
+    pub send_buffer_high: usize,
+    /// Once blocked, the socket is reported writable again when its queue drains to this many
+    /// bytes.
+    pub send_buffer_low: usize,
+    /// Restricts the socket to one link, like `SO_BINDTODEVICE`: datagrams are only sent on and
+    /// accepted from the link with this index.
+    pub link_index: Option<usize>,
+    /// Allows sending to the broadcast address, like `SO_BROADCAST`.
+    pub broadcast: bool,
+    /// Groups joined with `join_multicast`, like `IP_ADD_MEMBERSHIP`. Only these groups can be
+    /// sent to.
+    pub multicast_groups: Vec<Ipv4Addr>,
+    /// Also delivers multicast sends to our own sockets on the destination port, like
+    /// `IP_MULTICAST_LOOP`. On by default.
+    pub multicast_loop: bool,
+    /// TTL for datagrams sent to multicast groups, like `IP_MULTICAST_TTL`. Unicast datagrams
+    /// use the stack's default TTL instead.
+    pub multicast_ttl: u8,
+    /// Lets sockets share a local endpoint, like `SO_REUSEPORT`, with incoming flows spread among
+    /// them. Every socket on the endpoint must set this before binding.
+    pub reuse_port: bool,
+    /// Drops datagrams that arrive while the receive queue holds payload bytes and this one would
+    /// take it past this many, like `SO_RCVBUF`. Unbounded by default.
+    pub recv_buffer_limit: Option<usize>,
+    /// Starts dropping arrivals at random as the receive queue fills, rather than only once it's
+    /// full. Off by default.
+    pub early_drop: Option<EarlyDrop>,
+    /// Datagrams the receive queue has room for when the socket is bound. The queue still grows
+    /// past this, but sizing it for the expected burst avoids reallocating mid-burst.
+    pub recv_buffer_capacity: usize,
+    /// Keeps each received datagram's IPv4 header in `ReceivedDatagram::ipv4_hdr`, at the cost
+    /// of storing it alongside the payload.
+    pub recv_header: bool,
+    /// Stamps each received datagram with when it arrived, in `ReceivedDatagram::timestamp`,
+    /// like `SO_TIMESTAMP`.
+    pub recv_timestamp: bool,
+    /// Records when each datagram was pushed, resolved and transmitted, for
+    /// `UdpPeer::send_timestamps`.
+    pub timestamp_sends: bool,
+    /// Lets `UdpPeer::pushto_from` send from addresses other than ours, like `IP_FREEBIND` (or
+    /// `IP_TRANSPARENT`) does for tunnels and test harnesses.
+    pub freebind: bool,
+    /// Has `UdpPeer::pushto` on a wildcard-bound socket send from the address the socket's last
+    /// datagram arrived on, like passing `IP_PKTINFO` back to `sendmsg`, so a server's replies
+    /// come from whichever of our addresses its client used. Broadcast and multicast arrivals
+    /// don't count.
+    pub reply_from_received: bool,
+    /// Sets the IPv4 "don't fragment" bit, like `IP_PMTUDISC_DO`, so routers that can't forward
+    /// a datagram whole report their MTU instead.
+    pub dont_fragment: bool,
+    /// Has `UdpPeer::connect` fail with `NetworkUnreachable` for peers outside our subnet when
+    /// there's no gateway, instead of leaving the first send to time out in ARP. Off by default,
+    /// since proxy ARP can make such peers reachable anyway, and nothing's checked until
+    /// `ipv4::Options::netmask` is set.
+    pub check_route: bool,
+    /// Gives up on a destination that hasn't answered ARP after this long, dropping the
+    /// datagrams queued for it, instead of waiting out ARP's own retries.
+    pub arp_timeout: Option<Duration>,
+    /// Fails pops that wait longer than this with `Timeout`, like `SO_RCVTIMEO`. `pop_timeout`
+    /// overrides it for a single pop.
+    pub recv_timeout: Option<Duration>,
+    /// Limits how fast the socket sends, for applications that mustn't burst. Unlimited by
+    /// default.
+    pub pacing: Option<Pacing>,
+}
+
+impl Default for UdpSocketOptions {
+    fn default() -> Self {
+        UdpSocketOptions {
+            ecn: Ecn::default(),
+            send_buffer_high: DEFAULT_SEND_BUFFER_HIGH,
+            send_buffer_low: DEFAULT_SEND_BUFFER_LOW,
+            link_index: None,
+            broadcast: false,
+            multicast_groups: vec![],
+            multicast_loop: true,
+            multicast_ttl: DEFAULT_MULTICAST_TTL,
+            reuse_port: false,
+            recv_buffer_limit: None,
+            early_drop: None,
+            recv_buffer_capacity: DEFAULT_RECV_BUFFER_CAPACITY,
+            recv_header: false,
+            recv_timestamp: false,
+            timestamp_sends: false,
+            freebind: false,
+            reply_from_received: false,
+            dont_fragment: false,
+            check_route: false,
+            arp_timeout: None,
+            recv_timeout: None,
+            pacing: None,
+        }
+    }
+}
+
+impl UdpSocketOptions {
+    pub fn send_buffer(mut self, high: usize, low: usize) -> Self {
+        assert!(low <= high);
+        self.send_buffer_high = high;
+        self.send_buffer_low = low;
+        self
+    }
+
+    /// Sizes the send buffer like `setsockopt(SO_SNDBUF)`: `bytes` is clamped to
+    /// `MAX_SOCKET_BUFFER`, then doubled to leave room for bookkeeping, as Linux does. The result
+    /// becomes `send_buffer_high`, with the low watermark at half of it.
+    pub fn sndbuf(mut self, bytes: usize) -> Self {
+        let high = socket_buffer_size(bytes);
+        self.send_buffer_high = high;
+        self.send_buffer_low = high / 2;
+        self
+    }
+
+    /// Bounds the receive queue like `setsockopt(SO_RCVBUF)`, clamping and doubling `bytes` the
+    /// same way as `sndbuf`. The effective size is read back from `recv_buffer_limit`.
+    pub fn rcvbuf(mut self, bytes: usize) -> Self {
+        self.recv_buffer_limit = Some(socket_buffer_size(bytes));
+        self
+    }
+
+    pub fn ecn(mut self, value: Ecn) -> Self {
+        self.ecn = value;
+        self
+    }
+
+    pub fn bind_to_link(mut self, link_index: usize) -> Self {
+        self.link_index = Some(link_index);
+        self
+    }
+
+    pub fn broadcast(mut self, value: bool) -> Self {
+        self.broadcast = value;
+        self
+    }
+
+    pub fn recv_buffer_capacity(mut self, datagrams: usize) -> Self {
+        self.recv_buffer_capacity = datagrams;
+        self
+    }
+
+    pub fn recv_header(mut self, value: bool) -> Self {
+        self.recv_header = value;
+        self
+    }
+
+    pub fn recv_timestamp(mut self, value: bool) -> Self {
+        self.recv_timestamp = value;
+        self
+    }
+
+    pub fn timestamp_sends(mut self, value: bool) -> Self {
+        self.timestamp_sends = value;
+        self
+    }
+
+    pub fn freebind(mut self, value: bool) -> Self {
+        self.freebind = value;
+        self
+    }
+
+    pub fn reply_from_received(mut self, value: bool) -> Self {
+        self.reply_from_received = value;
+        self
+    }
+
+    pub fn dont_fragment(mut self, value: bool) -> Self {
+        self.dont_fragment = value;
+        self
+    }
+
+    pub fn check_route(mut self, value: bool) -> Self {
+        self.check_route = value;
+        self
+    }
+
+    pub fn arp_timeout(mut self, value: Duration) -> Self {
+        self.arp_timeout = Some(value);
+        self
+    }
+
+    pub fn early_drop(mut self, min: usize, max: usize, max_probability: f64) -> Self {
+        self.early_drop = Some(EarlyDrop {
+            min,
+            max,
+            max_probability,
+        });
+        self
+    }
+
+    pub fn recv_timeout(mut self, value: Duration) -> Self {
+        self.recv_timeout = Some(value);
+        self
+    }
+
+    pub fn pacing(mut self, rate: u64, burst: usize, mode: PacingMode) -> Self {
+        self.pacing = Some(Pacing { rate, burst, mode });
+        self
+    }
+
+    pub fn reuse_port(mut self, value: bool) -> Self {
+        self.reuse_port = value;
+        self
+    }
+
+    pub fn multicast_loop(mut self, value: bool) -> Self {
+        self.multicast_loop = value;
+        self
+    }
+
+    pub fn multicast_ttl(mut self, value: u8) -> Self {
+        self.multicast_ttl = value;
+        self
+    }
+
+    pub fn join_multicast(mut self, group: Ipv4Addr) -> Self {
+        assert!(group.is_multicast());
+        if !self.multicast_groups.contains(&group) {
+            self.multicast_groups.push(group);
+        }
+        self
+    }
+
+    // What `UdpPeer::set_socket_options` checks before applying the options, whether they were
+    // set through the builders or the fields.
+    pub(super) fn validate(&self) -> Result<(), Fail> {
+        if self.arp_timeout == Some(Duration::new(0, 0)) {
+            return Err(Fail::Invalid {
+                details: "ARP timeout must be positive",
+            });
+        }
+        if let Some(d) = self.early_drop {
+            // Also rules out a NaN probability.
+            if d.min >= d.max || !(d.max_probability > 0.0 && d.max_probability <= 1.0) {
+                return Err(Fail::Invalid {
+                    details: "Early drop needs min < max and a probability in (0, 1]",
+                });
+            }
+        }
+        if self.recv_timeout == Some(Duration::new(0, 0)) {
+            return Err(Fail::Invalid {
+                details: "Receive timeout must be positive",
+            });
+        }
+        if self.pacing.map_or(false, |p| p.rate == 0) {
+            return Err(Fail::Invalid {
+                details: "Pacing rate must be positive",
+            });
+        }
+        Ok(())
+    }
+}
+
+fn socket_buffer_size(bytes: usize) -> usize {
+    cmp::max(cmp::min(bytes, MAX_SOCKET_BUFFER) * 2, MIN_SOCKET_BUFFER)
+}
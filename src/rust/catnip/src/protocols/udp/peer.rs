@@ -1,11 +1,29 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::datagram::{
-    UdpDatagram,
-    UdpHeader,
+use super::{
+    datagram::{
+        ParseError,
+        UdpDatagram,
+        UdpHeader,
+        UDP_HEADER2_SIZE,
+    },
+    options::{
+        EarlyDrop,
+        Ecn,
+        Pacing,
+        PacingMode,
+        SendOpts,
+        UdpSocketOptions,
+    },
+    poller::{
+        ReadinessBatch,
+        ReadinessSlot,
+        UdpPoller,
+    },
 };
 use crate::{
+    collections::watched::WatchedValue,
     fail::Fail,
     file_table::{
         File,
@@ -18,34 +36,63 @@ use crate::{
     },
     protocols::{
         arp,
-        ethernet2::frame::{
-            EtherType2,
-            Ethernet2Header,
+        ethernet2::{
+            frame::{
+                EtherType2,
+                Ethernet2Header,
+                MAX_PAYLOAD_SIZE,
+            },
+            MacAddress,
+        },
+        ip::{
+            self,
+            port::EphemeralPorts,
         },
         ipv4,
         ipv4::datagram::{
             Ipv4Header,
             Ipv4Protocol2,
+            IPV4_FLAG_DONT_FRAGMENT,
+            IPV4_HEADER2_SIZE,
         },
     },
-    runtime::Runtime,
+    runtime::{
+        Runtime,
+        RxMetadata,
+    },
     scheduler::SchedulerHandle,
     sync::Bytes,
 };
-use futures_intrusive::{
-    buffer::GrowingHeapBuf,
-    channel::shared::{
-        generic_channel,
-        GenericReceiver,
-        GenericSender,
-    },
-    NoopLock,
+use futures::{
+    channel::mpsc,
+    future,
+    stream::FuturesUnordered,
+    FutureExt,
+    StreamExt,
 };
 use hashbrown::HashMap;
 use std::{
-    cell::RefCell,
-    collections::VecDeque,
+    cell::{
+        Cell,
+        RefCell,
+    },
+    cmp,
+    collections::{
+        hash_map::DefaultHasher,
+        VecDeque,
+    },
+    convert::TryFrom,
+    fmt,
     future::Future,
+    hash::{
+        Hash,
+        Hasher,
+    },
+    net::{
+        Ipv4Addr,
+        Shutdown,
+    },
+    ops::RangeInclusive,
     pin::Pin,
     rc::Rc,
     task::{
@@ -53,28 +100,509 @@ use std::{
         Poll,
         Waker,
     },
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
+/// The stack's UDP sockets. Clones are handles onto the same sockets rather than new peers, so
+/// the libOS dispatcher and anything else that needs UDP can each hold one. They all allocate
+/// from the `FileTable` the peer was made with, and the background send task runs until the
+/// last clone is dropped.
+#[derive(Clone)]
 pub struct UdpPeer<RT: Runtime> {
     inner: Rc<RefCell<Inner<RT>>>,
 }
 
+/// A datagram queued on a bound socket, along with what we know about how it arrived.
+#[derive(Clone, Debug)]
+pub struct ReceivedDatagram {
+    pub remote: Option<ipv4::Endpoint>,
+    /// Destination address from the IPv4 header, like `IP_PKTINFO`. For a wildcard-bound socket
+    /// this tells us which of our addresses (or a broadcast address) the peer used.
+    pub local_addr: Ipv4Addr,
+    /// Destination port, which only tells us something for sockets from `raw_listen` or
+    /// `bind_range`. Only the empty datagram a socket shut down for reading pops leaves it
+    /// `None`.
+    pub local_port: Option<ip::Port>,
+    pub ecn: Ecn,
+    /// The full IPv4 header, if the socket set the `recv_header` option.
+    pub ipv4_hdr: Option<Ipv4Header>,
+    /// When the datagram arrived, if the socket set the `recv_timestamp` option.
+    pub timestamp: Option<Instant>,
+    pub data: Bytes,
+}
+
+/// When a datagram reached each stage of the send path, for sockets with the `timestamp_sends`
+/// option.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SendTimestamps {
+    pub pushed: Instant,
+    /// When the link address was known: right away if it was cached, otherwise once ARP answered.
+    pub resolved: Instant,
+    pub transmitted: Instant,
+}
+
+impl SendTimestamps {
+    pub fn arp_latency(&self) -> Duration {
+        self.resolved - self.pushed
+    }
+}
+
+/// What a socket still had queued when `UdpPeer::drain_pending` took it.
+#[derive(Clone, Debug)]
+pub struct PendingDatagrams {
+    /// Received but not yet popped, oldest first.
+    pub received: Vec<ReceivedDatagram>,
+    /// Sent but still waiting on ARP, with their destinations.
+    pub unsent: Vec<(ipv4::Endpoint, Bytes)>,
+}
+
+/// What `UdpPeer::try_receive` did with a segment.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReceiveOutcome {
+    Delivered,
+    /// Nobody's bound to the destination port.
+    DroppedUnbound,
+    /// The socket's queue was already at its `recv_buffer_limit`.
+    DroppedBufferFull,
+    /// Dropped at random by the socket's `early_drop` as its queue filled.
+    DroppedEarly,
+    Malformed(ParseError),
+    /// Turned away by the socket it was addressed to, or spoofed from our own address.
+    Filtered {
+        details: &'static str,
+    },
+}
+
+impl ReceiveOutcome {
+    /// The `Result` `UdpPeer::receive` reports this as.
+    pub fn into_result(self) -> Result<(), Fail> {
+        match self {
+            ReceiveOutcome::Delivered => Ok(()),
+            ReceiveOutcome::DroppedUnbound => Err(Fail::Malformed {
+                details: "Port not bound",
+            }),
+            ReceiveOutcome::DroppedBufferFull => Err(Fail::Ignored {
+                details: "Receive buffer full",
+            }),
+            ReceiveOutcome::DroppedEarly => Err(Fail::Ignored {
+                details: "Receive buffer filling",
+            }),
+            ReceiveOutcome::Malformed(e) => Err(e.into()),
+            ReceiveOutcome::Filtered { details } => Err(Fail::Ignored { details }),
+        }
+    }
+}
+
+/// A snapshot of a socket's state, from `UdpPeer::socket_state`.
+#[derive(Clone, Debug)]
+pub struct SocketState {
+    pub is_bound: bool,
+    pub is_connected: bool,
+    pub local: Option<ipv4::Endpoint>,
+    pub remote: Option<ipv4::Endpoint>,
+    pub options: UdpSocketOptions,
+}
+
+// Number of `SendTimestamps` kept per socket; older ones are discarded.
+const MAX_SEND_TIMESTAMPS: usize = 32;
+
+/// Stack-wide counters from `UdpPeer::metrics`, for exporters to scrape. Byte counts are of
+/// payloads.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct UdpPeerMetrics {
+    pub sockets: usize,
+    pub bound_endpoints: usize,
+    pub rx_datagrams: u64,
+    pub rx_bytes: u64,
+    /// Segments `receive` didn't queue on a socket, whatever the reason.
+    pub rx_dropped: u64,
+    pub tx_datagrams: u64,
+    pub tx_bytes: u64,
+    /// Datagrams queued behind ARP resolution that never got an answer.
+    pub tx_dropped: u64,
+    /// Datagrams waiting on ARP resolution or pacing.
+    pub queued_datagrams: usize,
+}
+
+// The traffic half of `UdpPeerMetrics`, shared with `background` for the datagrams it sends.
+#[derive(Default)]
+struct Traffic {
+    rx_datagrams: Cell<u64>,
+    rx_bytes: Cell<u64>,
+    rx_dropped: Cell<u64>,
+    tx_datagrams: Cell<u64>,
+    tx_bytes: Cell<u64>,
+    tx_dropped: Cell<u64>,
+}
+
+impl Traffic {
+    fn sent(&self, len: usize) {
+        self.tx_datagrams.set(self.tx_datagrams.get() + 1);
+        self.tx_bytes.set(self.tx_bytes.get() + len as u64);
+    }
+}
+
+/// Segments `receive` rejected as malformed or spoofed, by reason. Datagrams dropped for other
+/// reasons (no listener, shutdown, and so on) aren't counted here.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct UdpStats {
+    /// Datagrams from our own address, dropped under `ipv4::Options::drop_martians`.
+    pub martians: u64,
+    pub truncated: u64,
+    pub bad_length: u64,
+    pub bad_checksum: u64,
+    pub bad_port: u64,
+}
+
 struct Listener {
-    buf: VecDeque<(Option<ipv4::Endpoint>, Bytes)>,
-    waker: Option<Waker>,
+    buf: VecDeque<ReceivedDatagram>,
+    // Payload bytes in `buf`, checked against `recv_buffer_limit`.
+    queued_bytes: usize,
+    // Every task waiting on a pop, since more than one may await the same socket.
+    wakers: Vec<Waker>,
+    readiness: Option<ReadinessSlot>,
+    // Set by `shutdown(Read)`: nothing new is queued, and pops return an empty datagram once
+    // `buf` runs dry.
+    read_shutdown: bool,
+    // Mirrors the owning socket's `link_index` option, since receive only finds the listener.
+    link_index: Option<usize>,
+    // Mirrors the owning socket's `recv_buffer_limit`, `early_drop`, `recv_header` and
+    // `recv_timestamp` options.
+    recv_buffer_limit: Option<usize>,
+    early_drop: Option<EarlyDrop>,
+    recv_header: bool,
+    recv_timestamp: bool,
+    // Mirrors the owning socket's `remote`; a connected socket only accepts datagrams from it.
+    remote: Option<ipv4::Endpoint>,
+    // Unicast destination of the latest datagram popped, for `reply_from_received`.
+    last_local_addr: Option<Ipv4Addr>,
+    // Set when the socket is closed, after which pops fail with `Shutdown`.
+    closed: bool,
+    // Set when the socket is unbound, after which pops fail like they would on a socket that was
+    // never bound.
+    unbound: bool,
+    // Set from an ICMPv4 error about a datagram we sent, like `SO_ERROR`. The next push or pop
+    // fails with it instead.
+    error: Option<Fail>,
+}
+
+impl Listener {
+    fn new(options: &UdpSocketOptions, remote: Option<ipv4::Endpoint>) -> Self {
+        Self {
+            buf: VecDeque::with_capacity(options.recv_buffer_capacity),
+            queued_bytes: 0,
+            wakers: vec![],
+            readiness: None,
+            read_shutdown: false,
+            link_index: options.link_index,
+            recv_buffer_limit: options.recv_buffer_limit,
+            early_drop: options.early_drop,
+            recv_header: options.recv_header,
+            recv_timestamp: options.recv_timestamp,
+            remote,
+            last_local_addr: None,
+            closed: false,
+            unbound: false,
+            error: None,
+        }
+    }
+
+    fn wake(&mut self) {
+        for w in self.wakers.drain(..) {
+            w.wake();
+        }
+    }
+
+    // Ready once a pop would complete right away, whether with a datagram, an error, or the
+    // empty datagram of a read shutdown. Otherwise `ctx` is woken when that changes.
+    fn poll_readable(&mut self, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        if self.closed {
+            return Poll::Ready(Err(Fail::Shutdown {}));
+        }
+        if self.unbound {
+            return Poll::Ready(Err(Fail::Malformed {
+                details: "Socket not bound",
+            }));
+        }
+        if !self.buf.is_empty() || self.error.is_some() || self.read_shutdown {
+            return Poll::Ready(Ok(()));
+        }
+        let waker = ctx.waker();
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+        Poll::Pending
+    }
+
+    fn pop(&mut self) -> Option<ReceivedDatagram> {
+        let datagram = self.buf.pop_front()?;
+        self.queued_bytes -= datagram.data.len();
+        let dst = datagram.local_addr;
+        if !dst.is_broadcast() && !dst.is_multicast() && !dst.is_unspecified() {
+            self.last_local_addr = Some(dst);
+        }
+        Some(datagram)
+    }
+}
+
+// Summarizes the receive queue rather than dumping it, which keeps payloads out of logs.
+impl fmt::Debug for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Listener")
+            .field("queued", &self.buf.len())
+            .field("remote", &self.remote)
+            .field("read_shutdown", &self.read_shutdown)
+            .field("closed", &self.closed)
+            .field("unbound", &self.unbound)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+// Bytes a socket has handed to the background task that are still waiting on ARP resolution
+// or pacing.
+struct SendBuffer {
+    queued: Cell<usize>,
+    // Number of datagrams behind `queued`, which `close_graceful` waits on.
+    in_flight: WatchedValue<usize>,
+    // The same count across every socket on the peer, which `flush` waits on.
+    pending: Rc<WatchedValue<usize>>,
+    // Set by `close`, after which the background task discards this socket's datagrams.
+    dropped: Cell<bool>,
+    // Set when a send was refused with `WouldBlock`, and cleared when we report the socket
+    // writable again.
+    blocked: Cell<bool>,
+    readiness: RefCell<Option<ReadinessSlot>>,
+    // Only allocated on the first timestamped send, so sockets without `timestamp_sends` don't pay
+    // for it.
+    timestamps: RefCell<Option<VecDeque<SendTimestamps>>>,
+    // Started on the socket's first paced send.
+    bucket: RefCell<Option<TokenBucket>>,
+    // The latest error about a datagram the socket sent, for `take_send_error` and `next_error`:
+    // either the background task couldn't send it, or an ICMPv4 error came back about it.
+    error: RefCell<Option<(ipv4::Endpoint, Fail)>>,
+    error_waker: RefCell<Option<Waker>>,
+    // Bumped whenever the socket's remote, options, binding or write side changes, which makes
+    // any `SendHandle` taken before stale.
+    epoch: Cell<u64>,
+}
+
+impl SendBuffer {
+    fn new(pending: Rc<WatchedValue<usize>>) -> Self {
+        Self {
+            queued: Cell::new(0),
+            in_flight: WatchedValue::new(0),
+            pending,
+            dropped: Cell::new(false),
+            blocked: Cell::new(false),
+            readiness: RefCell::new(None),
+            timestamps: RefCell::new(None),
+            bucket: RefCell::new(None),
+            error: RefCell::new(None),
+            error_waker: RefCell::new(None),
+            epoch: Cell::new(0),
+        }
+    }
+
+    fn invalidate_handles(&self) {
+        self.epoch.set(self.epoch.get() + 1);
+    }
+
+    fn set_error(&self, remote: ipv4::Endpoint, error: Fail) {
+        *self.error.borrow_mut() = Some((remote, error));
+        if let Some(w) = self.error_waker.borrow_mut().take() {
+            w.wake();
+        }
+    }
+
+    fn record(&self, pushed: Instant, resolved: Instant, transmitted: Instant) {
+        let mut timestamps = self.timestamps.borrow_mut();
+        let timestamps =
+            timestamps.get_or_insert_with(|| VecDeque::with_capacity(MAX_SEND_TIMESTAMPS));
+        if timestamps.len() == MAX_SEND_TIMESTAMPS {
+            timestamps.pop_front();
+        }
+        timestamps.push_back(SendTimestamps {
+            pushed,
+            resolved,
+            transmitted,
+        });
+    }
+
+    fn release(&self, len: usize, low_watermark: usize) {
+        let queued = self.queued.get() - len;
+        self.queued.set(queued);
+        self.in_flight.modify(|n| n - 1);
+        self.pending.modify(|n| n - 1);
+        if self.blocked.get() && queued <= low_watermark {
+            self.blocked.set(false);
+            if let Some(ref r) = *self.readiness.borrow() {
+                r.notify();
+            }
+        }
+    }
+}
+
+// Byte tokens for `UdpSocketOptions::pacing`. Queued sends borrow past zero, so the debt orders
+// them: each is released once the bucket has refilled to cover it.
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(pacing: &Pacing, now: Instant) -> Self {
+        Self {
+            tokens: pacing.burst as f64,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, pacing: &Pacing, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * pacing.rate as f64).min(pacing.burst as f64);
+        self.refilled_at = now;
+    }
+
+    // How long until the bucket covers `len` more bytes, or zero if it already does. A datagram
+    // bigger than the burst goes as soon as the bucket is full.
+    fn wait(&self, pacing: &Pacing, len: usize) -> Duration {
+        let needed = cmp::min(len, pacing.burst) as f64 - self.tokens;
+        if needed <= 0.0 {
+            return Duration::new(0, 0);
+        }
+        Duration::from_secs_f64(needed / pacing.rate as f64)
+    }
+
+    fn take(&mut self, len: usize) {
+        self.tokens -= len as f64;
+    }
 }
 
-#[derive(Debug)]
 struct Socket {
-    // `bind(2)` fixes a local address
+    // `bind(2)` fixes a local address, and gives us somewhere to queue received datagrams
     local: Option<ipv4::Endpoint>,
+    listener: Option<Rc<RefCell<Listener>>>,
     // `connect(2)` fixes a remote address
     remote: Option<ipv4::Endpoint>,
+    options: UdpSocketOptions,
+    // Whether `local` came from `bind_ephemeral` and its port goes back to the pool on close.
+    ephemeral: bool,
+    // Every port `bind_range` took on `local`'s address, starting with `local`'s own.
+    ports: Option<RangeInclusive<u16>>,
+    // Address from `bind_address`, which gets a port on the first `connect` or `pushto`.
+    bind_addr: Option<Ipv4Addr>,
+    // Link address of `remote`, tagged with the ARP generation it was resolved in.
+    link_addr: Option<(MacAddress, u64)>,
+    write_shutdown: bool,
+    send_buffer: Rc<SendBuffer>,
+}
+
+impl Socket {
+    // Changes the peer that sends go to and receives are filtered by, forgetting its link
+    // address.
+    fn set_remote(&mut self, remote: Option<ipv4::Endpoint>) {
+        self.remote = remote;
+        self.link_addr = None;
+        self.send_buffer.invalidate_handles();
+        if let Some(ref listener) = self.listener {
+            listener.borrow_mut().remote = remote;
+        }
+    }
+}
+
+/// What happened to a datagram that was accepted for sending.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SendStatus {
+    /// Handed to the runtime to transmit.
+    Sent,
+    /// Waiting on ARP resolution or the socket's pacing. If it can't be sent after all, the
+    /// error comes back through `take_send_error` and `next_error`, and `flush` waits for it
+    /// either way.
+    Queued,
+}
+
+/// A connected socket's send path, from `UdpPeer::send_handle`.
+pub struct SendHandle<RT: Runtime> {
+    inner: Rc<RefCell<Inner<RT>>>,
+    local: Option<ipv4::Endpoint>,
+    remote: ipv4::Endpoint,
+    options: UdpSocketOptions,
+    listener: Option<Rc<RefCell<Listener>>>,
+    send_buffer: Rc<SendBuffer>,
+    // The socket's `SendBuffer::epoch` when we were taken.
+    epoch: u64,
+    link_addr: Cell<Option<(MacAddress, u64)>>,
+}
+
+impl<RT: Runtime> SendHandle<RT> {
+    /// Like `UdpPeer::push` on the socket the handle was taken from. Fails with
+    /// `BadFileDescriptor` once that socket is closed, and with `Invalid` once it has changed
+    /// since.
+    pub fn push(&self, buf: Bytes) -> Result<usize, Fail> {
+        if self.send_buffer.dropped.get() {
+            return Err(Fail::BadFileDescriptor {});
+        }
+        if self.send_buffer.epoch.get() != self.epoch {
+            return Err(Fail::Invalid {
+                details: "Socket changed since the send handle was taken",
+            });
+        }
+        let error = self
+            .listener
+            .as_ref()
+            .and_then(|l| l.borrow_mut().error.take());
+        if let Some(e) = error {
+            return Err(e);
+        }
+        check_size(&self.options, buf.len())?;
+        let mut inner = self.inner.borrow_mut();
+        let generation = inner.arp.generation();
+        let link_addr = match self.link_addr.get() {
+            Some((link_addr, g)) if g == generation => Some(link_addr),
+            _ => {
+                let link_addr = inner.resolve_link_addr(self.remote.addr);
+                self.link_addr.set(link_addr.map(|l| (l, generation)));
+                link_addr
+            },
+        };
+        let len = buf.len();
+        let req = OutgoingReq {
+            src_addr: inner.rt.local_ipv4_addr(),
+            local: self.local,
+            remote: self.remote,
+            options: self.options.clone(),
+            send_buffer: self.send_buffer.clone(),
+            buf,
+            pushed_at: None,
+            send_opts: SendOpts::default(),
+        };
+        inner.send_datagram(req, link_addr)?;
+        Ok(len)
+    }
 }
 
-type OutgoingReq = (Option<ipv4::Endpoint>, ipv4::Endpoint, Bytes);
-type OutgoingSender = GenericSender<NoopLock, OutgoingReq, GrowingHeapBuf<OutgoingReq>>;
-type OutgoingReceiver = GenericReceiver<NoopLock, OutgoingReq, GrowingHeapBuf<OutgoingReq>>;
+struct OutgoingReq {
+    src_addr: Ipv4Addr,
+    local: Option<ipv4::Endpoint>,
+    remote: ipv4::Endpoint,
+    options: UdpSocketOptions,
+    send_buffer: Rc<SendBuffer>,
+    buf: Bytes,
+    // Filled in by `send_datagram` if the socket timestamps its sends.
+    pushed_at: Option<Instant>,
+    send_opts: SendOpts,
+}
+
+// Datagrams waiting on ARP, by destination and in the order they were pushed. While a
+// destination has anything queued here, later sends to it queue up behind, even once its link
+// address is known, so they can't overtake.
+type Deferred = Rc<RefCell<HashMap<Ipv4Addr, VecDeque<OutgoingReq>>>>;
+
+type Paced = (Instant, OutgoingReq, Option<MacAddress>);
 
 struct Inner<RT: Runtime> {
     #[allow(unused)]
@@ -84,25 +612,94 @@ struct Inner<RT: Runtime> {
     file_table: FileTable,
 
     sockets: HashMap<FileDescriptor, Socket>,
-    bound: HashMap<ipv4::Endpoint, Rc<RefCell<Listener>>>,
+    // Sockets bound to each endpoint, in bind order. There's more than one only when they all set
+    // `reuse_port`.
+    bound: HashMap<ipv4::Endpoint, Vec<FileDescriptor>>,
+    // Sockets from `raw_listen`, each taking datagrams for a range of ports nobody's bound to.
+    raw: Vec<(RangeInclusive<ip::Port>, FileDescriptor)>,
+    ephemeral_ports: EphemeralPorts,
+    // Set between `begin_batch` and `end_batch`, where receives defer poller notifications.
+    batch: RefCell<Option<ReadinessBatch>>,
+    stats: UdpStats,
+    traffic: Rc<Traffic>,
+    // Datagrams waiting on ARP or pacing, shared with every socket's `SendBuffer`.
+    pending: Rc<WatchedValue<usize>>,
 
-    outgoing: OutgoingSender,
+    deferred: Deferred,
+    // Destinations for `background` to resolve, each sent when its first datagram is deferred.
+    resolve_tx: mpsc::UnboundedSender<(Ipv4Addr, Option<Duration>)>,
+    // Paced datagrams for `background` to send at the given time, with their link address if
+    // it was already known.
+    pace_tx: mpsc::UnboundedSender<Paced>,
+    // Never read, but dropping it (along with the last `UdpPeer` clone) cancels `background`, so
+    // the task doesn't outlive the peer. Anything still queued behind ARP is dropped with it.
     #[allow(unused)]
     handle: SchedulerHandle,
 }
 
+impl<RT: Runtime> fmt::Debug for UdpPeer<RT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.inner.try_borrow() {
+            Ok(inner) => fmt::Debug::fmt(&*inner, f),
+            Err(..) => f.write_str("UdpPeer { <borrowed> }"),
+        }
+    }
+}
+
+// The runtime, ARP peer, channels and scheduler handle don't say much about the peer's state,
+// so this sticks to its sockets and what they have queued.
+impl<RT: Runtime> fmt::Debug for Inner<RT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut bound: Vec<_> = self.bound.iter().collect();
+        bound.sort_by_key(|&(_, fds)| fds.first().cloned());
+        let mut listeners: Vec<_> = self
+            .sockets
+            .iter()
+            .filter_map(|(fd, s)| s.listener.as_ref().map(|l| (fd, l.borrow())))
+            .collect();
+        listeners.sort_by_key(|&(fd, _)| *fd);
+        let deferred: usize = self.deferred.borrow().values().map(VecDeque::len).sum();
+        f.debug_struct("UdpPeer")
+            .field("sockets", &self.sockets.len())
+            .field("bound", &bound)
+            .field("raw", &self.raw)
+            .field("listeners", &listeners)
+            .field("deferred", &deferred)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
 impl<RT: Runtime> UdpPeer<RT> {
     pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Self {
-        let (tx, rx) = generic_channel(16);
-        let future = Self::background(rt.clone(), arp.clone(), rx);
-        let handle = rt.spawn(future);
+        let deferred = Rc::new(RefCell::new(HashMap::new()));
+        let (tx, rx) = mpsc::unbounded();
+        let (pace_tx, pace_rx) = mpsc::unbounded();
+        let traffic = Rc::new(Traffic::default());
+        let future = Self::background(
+            rt.clone(),
+            arp.clone(),
+            deferred.clone(),
+            traffic.clone(),
+            rx,
+            pace_rx,
+        );
+        let handle = rt.spawn_named("udp::background", future);
         let inner = Inner {
             rt,
             arp,
             file_table,
             sockets: HashMap::new(),
             bound: HashMap::new(),
-            outgoing: tx,
+            raw: vec![],
+            ephemeral_ports: EphemeralPorts::new(),
+            batch: RefCell::new(None),
+            stats: UdpStats::default(),
+            traffic,
+            pending: Rc::new(WatchedValue::new(0)),
+            deferred,
+            resolve_tx: tx,
+            pace_tx,
             handle,
         };
         Self {
@@ -110,205 +707,1745 @@ impl<RT: Runtime> UdpPeer<RT> {
         }
     }
 
-    async fn background(rt: RT, arp: arp::Peer<RT>, rx: OutgoingReceiver) {
-        while let Some((local, remote, buf)) = rx.receive().await {
-            let r: Result<_, Fail> = try {
-                let link_addr = arp.query(remote.addr).await?;
-                let datagram = UdpDatagram {
-                    ethernet2_hdr: Ethernet2Header {
-                        dst_addr: link_addr,
-                        src_addr: rt.local_link_addr(),
-                        ether_type: EtherType2::Ipv4,
-                    },
-                    ipv4_hdr: Ipv4Header::new(
-                        rt.local_ipv4_addr(),
-                        remote.addr,
-                        Ipv4Protocol2::Udp,
-                    ),
-                    udp_hdr: UdpHeader {
-                        src_port: local.map(|l| l.port),
-                        dst_port: remote.port,
-                    },
-                    data: buf,
-                };
-                rt.transmit(datagram);
-            };
-            if let Err(e) = r {
-                warn!("Failed to send UDP message: {:?}", e);
+    async fn background(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        deferred: Deferred,
+        traffic: Rc<Traffic>,
+        mut rx: mpsc::UnboundedReceiver<(Ipv4Addr, Option<Duration>)>,
+        mut pace_rx: mpsc::UnboundedReceiver<Paced>,
+    ) {
+        // Each destination has one resolution in flight, so one that never answers doesn't hold
+        // up sends to the others.
+        let mut resolving = FuturesUnordered::new();
+        let mut releasing = FuturesUnordered::new();
+        loop {
+            futures::select! {
+                r = rx.next().fuse() => {
+                    let (addr, timeout) = match r {
+                        Some(r) => r,
+                        None => return,
+                    };
+                    resolving.push(resolve(rt.clone(), arp.clone(), addr, timeout));
+                },
+                (addr, r) = resolving.select_next_some() => {
+                    let reqs = deferred.borrow_mut().remove(&addr).unwrap_or_default();
+                    for req in reqs {
+                        Self::send_resolved(&rt, &traffic, req, r.clone());
+                    }
+                },
+                r = pace_rx.next().fuse() => {
+                    let (when, req, link_addr) = match r {
+                        Some(r) => r,
+                        None => return,
+                    };
+                    releasing.push(release(rt.clone(), when, req, link_addr));
+                },
+                // Once released, a paced datagram goes out like any other send.
+                (req, link_addr) = releasing.select_next_some() => {
+                    let addr = req.remote.addr;
+                    let mut deferred = deferred.borrow_mut();
+                    let link_addr = link_addr.or_else(|| arp.try_query(addr));
+                    match (deferred.get_mut(&addr), link_addr) {
+                        (Some(queue), _) => queue.push_back(req),
+                        (None, Some(link_addr)) => {
+                            Self::send_resolved(&rt, &traffic, req, Ok(link_addr))
+                        },
+                        (None, None) => {
+                            let timeout = req.options.arp_timeout;
+                            deferred.insert(addr, VecDeque::from(vec![req]));
+                            resolving.push(resolve(rt.clone(), arp.clone(), addr, timeout));
+                        },
+                    }
+                },
             }
         }
     }
 
+    fn send_resolved(
+        rt: &RT,
+        traffic: &Traffic,
+        req: OutgoingReq,
+        link_addr: Result<MacAddress, Fail>,
+    ) {
+        let send_buffer = &req.send_buffer;
+        match link_addr {
+            Ok(link_addr) => {
+                let resolved = req.pushed_at.map(|pushed| (pushed, rt.now()));
+                if !send_buffer.dropped.get() {
+                    transmit(rt, &req.options, build_udp_datagram(rt, &req, link_addr));
+                    traffic.sent(req.buf.len());
+                    if let Some((pushed, resolved)) = resolved {
+                        send_buffer.record(pushed, resolved, rt.now());
+                    }
+                }
+            },
+            Err(e) => {
+                warn!("Failed to send UDP message: {:?}", e);
+                traffic.tx_dropped.set(traffic.tx_dropped.get() + 1);
+                send_buffer.set_error(req.remote, e);
+            },
+        }
+        send_buffer.release(req.buf.len(), req.options.send_buffer_low);
+    }
+
     pub fn accept(&self) -> Fail {
         Fail::Malformed {
             details: "Operation not supported",
         }
     }
 
-    pub fn socket(&self) -> FileDescriptor {
+    /// Fails with `OutOfFiles` once the file table is full.
+    pub fn socket(&self) -> Result<FileDescriptor, Fail> {
         let mut inner = self.inner.borrow_mut();
-        let fd = inner.file_table.alloc(File::UdpSocket);
+        let fd = inner.file_table.alloc(File::UdpSocket)?;
+        // The table never hands out a descriptor twice, so nothing can be in `sockets` under it.
+        debug_assert!(!inner.sockets.contains_key(&fd));
         let socket = Socket {
             local: None,
+            listener: None,
             remote: None,
+            options: UdpSocketOptions::default(),
+            ephemeral: false,
+            ports: None,
+            bind_addr: None,
+            link_addr: None,
+            write_shutdown: false,
+            send_buffer: Rc::new(SendBuffer::new(inner.pending.clone())),
         };
-        assert!(inner.sockets.insert(fd, socket).is_none());
-        fd
+        inner.sockets.insert(fd, socket);
+        Ok(fd)
     }
 
+    /// Binds `fd` to `addr`. Ports below 1024 fail with `PermissionDenied` unless the IPv4
+    /// options allow `privileged` binds.
     pub fn bind(&self, fd: FileDescriptor, addr: ipv4::Endpoint) -> Result<(), Fail> {
+        self.bind_with(fd, addr, false)
+    }
+
+    /// Like `bind`, but ignores the `privileged` option, for sockets the stack opens for itself
+    /// like DHCP's.
+    pub(crate) fn bind_privileged(
+        &self,
+        fd: FileDescriptor,
+        addr: ipv4::Endpoint,
+    ) -> Result<(), Fail> {
+        self.bind_with(fd, addr, true)
+    }
+
+    fn bind_with(
+        &self,
+        fd: FileDescriptor,
+        addr: ipv4::Endpoint,
+        privileged: bool,
+    ) -> Result<(), Fail> {
+        if !addr.is_bindable() {
+            return Err(Fail::Invalid {
+                details: "Can't bind to a broadcast or multicast address",
+            });
+        }
         let mut inner = self.inner.borrow_mut();
-        if inner.bound.contains_key(&addr) {
+        let inner = &mut *inner;
+        let reuse_port = match inner.sockets.get(&fd) {
+            Some(Socket {
+                local: None,
+                listener: None,
+                options,
+                ..
+            }) => options.reuse_port,
+            None => return Err(Fail::BadFileDescriptor {}),
+            _ => {
+                return Err(Fail::Malformed {
+                    details: "Socket already bound",
+                })
+            },
+        };
+        if !privileged {
+            inner.check_privileged(addr.port)?;
+        }
+        let sockets = &inner.sockets;
+        let group = inner.bound.entry(addr).or_insert_with(Vec::new);
+        // Sharing an endpoint takes every socket on it opting in, like `SO_REUSEPORT`.
+        let shareable = reuse_port
+            && group
+                .iter()
+                .all(|fd| sockets.get(fd).map_or(false, |s| s.options.reuse_port));
+        if !group.is_empty() && !shareable {
             return Err(Fail::Malformed {
                 details: "Port already listening",
             });
         }
-        match inner.sockets.get_mut(&fd) {
-            Some(Socket { ref mut local, .. }) if local.is_none() => {
-                *local = Some(addr);
-            },
+        group.push(fd);
+        let socket = inner.sockets.get_mut(&fd).unwrap();
+        let listener = Listener::new(&socket.options, socket.remote);
+        socket.local = Some(addr);
+        socket.listener = Some(Rc::new(RefCell::new(listener)));
+        socket.send_buffer.invalidate_handles();
+        Ok(())
+    }
+
+    /// Binds `fd` to every port in `ports` on `addr` at once, for services like RTP that want a
+    /// whole range without a socket per port. Either every port is free and taken, or `fd` is
+    /// left unbound. Pops return datagrams for any of them, with `local_port` saying which. The
+    /// ports can't be shared with `reuse_port`, and are subject to the `privileged` option like
+    /// `bind`'s.
+    pub fn bind_range(
+        &self,
+        fd: FileDescriptor,
+        addr: Ipv4Addr,
+        ports: RangeInclusive<u16>,
+    ) -> Result<(), Fail> {
+        if ports.is_empty() {
+            return Err(Fail::OutOfRange {
+                details: "Empty port range",
+            });
+        }
+        let local = ipv4::Endpoint::new(addr, ip::Port::try_from(*ports.start())?);
+        if !local.is_bindable() {
+            return Err(Fail::Invalid {
+                details: "Can't bind to a broadcast or multicast address",
+            });
+        }
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        match inner.sockets.get(&fd) {
+            Some(Socket {
+                local: None,
+                listener: None,
+                ..
+            }) => (),
+            None => return Err(Fail::BadFileDescriptor {}),
             _ => {
                 return Err(Fail::Malformed {
-                    details: "Invalid file descriptor on bind",
+                    details: "Socket already bound",
                 })
             },
         }
-        let listener = Listener {
-            buf: VecDeque::new(),
-            waker: None,
+        inner.check_privileged(local.port)?;
+        // The range starts above zero, so every port in it is valid.
+        let endpoint = |p: u16| ipv4::Endpoint::new(addr, ip::Port::try_from(p).unwrap());
+        let bound = &inner.bound;
+        let taken = ports
+            .clone()
+            .any(|p| bound.get(&endpoint(p)).map_or(false, |g| !g.is_empty()));
+        if taken {
+            return Err(Fail::Malformed {
+                details: "Port already listening",
+            });
+        }
+        for p in ports.clone() {
+            inner
+                .bound
+                .entry(endpoint(p))
+                .or_insert_with(Vec::new)
+                .push(fd);
+        }
+        let socket = inner.sockets.get_mut(&fd).unwrap();
+        let listener = Listener::new(&socket.options, socket.remote);
+        socket.local = Some(local);
+        socket.ports = Some(ports);
+        socket.listener = Some(Rc::new(RefCell::new(listener)));
+        socket.send_buffer.invalidate_handles();
+        Ok(())
+    }
+
+    /// Has `fd` take every datagram for a port in `ports` that no socket is bound to, along with
+    /// its IPv4 header (`recv_header` is turned on), for diagnostic tools tapping traffic. `fd`
+    /// mustn't be bound, and its range mustn't overlap another raw listener's. Ranges reaching
+    /// below 1024 are subject to the `privileged` option like `bind`'s.
+    pub fn raw_listen(
+        &self,
+        fd: FileDescriptor,
+        ports: RangeInclusive<ip::Port>,
+    ) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        if ports.is_empty() {
+            return Err(Fail::OutOfRange {
+                details: "Empty port range",
+            });
+        }
+        match inner.sockets.get(&fd) {
+            Some(..) => inner.check_privileged(*ports.start())?,
+            None => return Err(Fail::BadFileDescriptor {}),
+        }
+        let overlaps =
+            |r: &RangeInclusive<ip::Port>| r.start() <= ports.end() && ports.start() <= r.end();
+        if inner.raw.iter().any(|(r, _)| overlaps(r)) {
+            return Err(Fail::Malformed {
+                details: "Port range already raw listening",
+            });
+        }
+        let socket = match inner.sockets.get_mut(&fd) {
+            Some(s) if s.local.is_none() && s.listener.is_none() => s,
+            None => return Err(Fail::BadFileDescriptor {}),
+            _ => {
+                return Err(Fail::Malformed {
+                    details: "Socket already bound",
+                })
+            },
         };
-        assert!(inner
-            .bound
-            .insert(addr, Rc::new(RefCell::new(listener)))
-            .is_none());
+        socket.options.recv_header = true;
+        let listener = Listener::new(&socket.options, socket.remote);
+        socket.listener = Some(Rc::new(RefCell::new(listener)));
+        socket.send_buffer.invalidate_handles();
+        inner.raw.push((ports, fd));
         Ok(())
     }
 
-    pub fn connect(&self, fd: FileDescriptor, addr: ipv4::Endpoint) -> Result<(), Fail> {
+    /// Binds `fd` to our address and a port from the private range, for clients that don't care
+    /// which local port they use.
+    pub fn bind_ephemeral(&self, fd: FileDescriptor) -> Result<ipv4::Endpoint, Fail> {
+        let local_addr = self.inner.borrow().rt.local_ipv4_addr();
+        self.bind_ephemeral_on(fd, local_addr)
+    }
+
+    /// Fixes the address `fd` binds to without picking a port yet, like
+    /// `IP_BIND_ADDRESS_NO_PORT`. The first `connect` or `pushto` binds it to `addr` and a
+    /// private port that's free there.
+    pub fn bind_address(&self, fd: FileDescriptor, addr: Ipv4Addr) -> Result<(), Fail> {
+        if addr.is_broadcast() || addr.is_multicast() {
+            return Err(Fail::Invalid {
+                details: "Can't bind to a broadcast or multicast address",
+            });
+        }
         let mut inner = self.inner.borrow_mut();
         match inner.sockets.get_mut(&fd) {
-            Some(Socket { ref mut remote, .. }) if remote.is_none() => {
-                *remote = Some(addr);
+            Some(socket) if socket.local.is_none() && socket.listener.is_none() => {
+                socket.bind_addr = Some(addr);
                 Ok(())
             },
+            None => Err(Fail::BadFileDescriptor {}),
             _ => Err(Fail::Malformed {
-                details: "Invalid file descriptor on connect",
+                details: "Socket already bound",
             }),
         }
     }
 
-    pub fn receive(&self, ipv4_header: &Ipv4Header, buf: Bytes) -> Result<(), Fail> {
-        let (hdr, data) = UdpHeader::parse(ipv4_header, buf)?;
-        let local = ipv4::Endpoint::new(ipv4_header.dst_addr, hdr.dst_port);
-        let remote = hdr
-            .src_port
-            .map(|p| ipv4::Endpoint::new(ipv4_header.src_addr, p));
-
-        // TODO: Send ICMPv4 error in this condition.
-        let mut inner = self.inner.borrow_mut();
-        let listener = inner.bound.get_mut(&local).ok_or_else(|| Fail::Malformed {
-            details: "Port not bound",
-        })?;
-        let mut l = listener.borrow_mut();
-        l.buf.push_back((remote, data));
-        l.waker.take().map(|w| w.wake());
-        Ok(())
+    // Binds `fd` to a private port that's free on `local_addr`.
+    fn bind_ephemeral_on(
+        &self,
+        fd: FileDescriptor,
+        local_addr: Ipv4Addr,
+    ) -> Result<ipv4::Endpoint, Fail> {
+        let port = {
+            let mut inner = self.inner.borrow_mut();
+            loop {
+                let port = inner.ephemeral_ports.alloc()?;
+                // Skip ports that were bound explicitly.
+                if !inner
+                    .bound
+                    .contains_key(&ipv4::Endpoint::new(local_addr, port))
+                {
+                    break port;
+                }
+            }
+        };
+        let addr = ipv4::Endpoint::new(local_addr, port);
+        if let Err(e) = self.bind(fd, addr) {
+            self.inner.borrow_mut().ephemeral_ports.free(port);
+            return Err(e);
+        }
+        self.inner
+            .borrow_mut()
+            .sockets
+            .get_mut(&fd)
+            .unwrap()
+            .ephemeral = true;
+        Ok(addr)
     }
 
-    pub fn push(&self, fd: FileDescriptor, buf: Bytes) -> Result<(), Fail> {
-        let inner = self.inner.borrow();
-        let (local, remote) = match inner.sockets.get(&fd) {
-            Some(Socket {
-                local,
-                remote: Some(remote),
-            }) => (*local, *remote),
-            _ => {
-                return Err(Fail::Malformed {
-                    details: "Invalid file descriptor on push",
-                })
-            },
+    // Binds `fd` now if `bind_address` left it waiting on a port.
+    fn bind_deferred(&self, fd: FileDescriptor) -> Result<(), Fail> {
+        let bind_addr = match self.inner.borrow().sockets.get(&fd) {
+            Some(s) if s.local.is_none() && s.listener.is_none() => s.bind_addr,
+            _ => None,
         };
-        inner.send_datagram(buf, local, remote)
+        if let Some(addr) = bind_addr {
+            self.bind_ephemeral_on(fd, addr)?;
+        }
+        Ok(())
     }
 
-    pub fn pushto(&self, fd: FileDescriptor, buf: Bytes, to: ipv4::Endpoint) -> Result<(), Fail> {
-        let inner = self.inner.borrow();
-        let local = match inner.sockets.get(&fd) {
-            Some(Socket { local, .. }) => *local,
-            _ => {
-                return Err(Fail::Malformed {
-                    details: "Invalid file descriptor on pushto",
-                })
+    /// Fixes the socket's peer, replacing any earlier one as Linux does. Connecting to the
+    /// unspecified address dissolves the connection instead, like `AF_UNSPEC`.
+    pub fn connect(&self, fd: FileDescriptor, addr: ipv4::Endpoint) -> Result<(), Fail> {
+        if addr.addr.is_broadcast() || addr.addr.is_multicast() {
+            return Err(Fail::PermissionDenied {
+                details: "Can't connect to a broadcast or multicast address",
+            });
+        }
+        let remote = Some(addr).filter(|a| !a.addr.is_unspecified());
+        if let Some(remote) = remote {
+            self.check_route(fd, remote.addr)?;
+            self.bind_deferred(fd)?;
+        }
+        let mut inner = self.inner.borrow_mut();
+        match inner.sockets.get_mut(&fd) {
+            Some(socket) => {
+                socket.set_remote(remote);
+                Ok(())
             },
-        };
-        inner.send_datagram(buf, local, to)
+            None => Err(Fail::BadFileDescriptor {}),
+        }
     }
 
-    pub fn pop(&self, fd: FileDescriptor) -> PopFuture {
+    // Under `check_route`, fails if `addr` is off our subnet with no gateway to reach it through.
+    fn check_route(&self, fd: FileDescriptor, addr: Ipv4Addr) -> Result<(), Fail> {
         let inner = self.inner.borrow();
-        let listener = match inner.sockets.get(&fd) {
-            Some(Socket {
-                local: Some(local), ..
-            }) => Ok(inner.bound.get(&local).unwrap().clone()),
-            _ => Err(Fail::Malformed {
-                details: "Invalid file descriptor",
-            }),
+        let check = match inner.sockets.get(&fd) {
+            Some(socket) => socket.options.check_route,
+            None => return Err(Fail::BadFileDescriptor {}),
         };
-        PopFuture { listener, fd }
+        let ipv4_options = inner.rt.ipv4_options();
+        let netmask = match ipv4_options.netmask {
+            Some(netmask) if check && ipv4_options.gateway.is_none() => u32::from(netmask),
+            _ => return Ok(()),
+        };
+        let local = u32::from(inner.rt.local_ipv4_addr());
+        if u32::from(addr) & netmask != local & netmask {
+            return Err(Fail::NetworkUnreachable {});
+        }
+        Ok(())
     }
 
-    pub fn close(&self, fd: FileDescriptor) -> Result<(), Fail> {
+    /// Dissolves `fd`'s connection, so it can again send anywhere and receive from any peer.
+    pub fn disconnect(&self, fd: FileDescriptor) -> Result<(), Fail> {
         let mut inner = self.inner.borrow_mut();
-        let socket = match inner.sockets.remove(&fd) {
-            Some(s) => s,
-            None => {
-                return Err(Fail::Malformed {
-                    details: "Invalid file descriptor",
-                })
+        match inner.sockets.get_mut(&fd) {
+            Some(socket) if socket.remote.is_some() => {
+                socket.set_remote(None);
+                Ok(())
             },
-        };
-        if let Some(local) = socket.local {
-            assert!(inner.bound.remove(&local).is_some());
+            Some(..) => Err(Fail::Malformed {
+                details: "Socket not connected",
+            }),
+            None => Err(Fail::BadFileDescriptor {}),
         }
-        inner.file_table.free(fd);
-        Ok(())
     }
-}
 
-impl<RT: Runtime> Inner<RT> {
-    fn send_datagram(&self, buf: Bytes, local: Option<ipv4::Endpoint>, remote: ipv4::Endpoint) -> Result<(), Fail> {
-        // First, try to send the packet immediately.
-        if let Some(link_addr) = self.arp.try_query(remote.addr) {
-            let datagram = UdpDatagram {
-                ethernet2_hdr: Ethernet2Header {
-                    dst_addr: link_addr,
-                    src_addr: self.rt.local_link_addr(),
-                    ether_type: EtherType2::Ipv4,
+    /// Like `connect`, but also waits for ARP to resolve `addr`, so the first `push` goes out
+    /// without queueing behind the query. If ARP gives up, this fails with `HostUnreachable`
+    /// and leaves the socket unconnected.
+    pub fn connect_resolved(
+        &self,
+        fd: FileDescriptor,
+        addr: ipv4::Endpoint,
+    ) -> impl Future<Output = Result<(), Fail>> {
+        let peer = self.clone();
+        let connected = self.connect(fd, addr);
+        let query = self.inner.borrow().arp.query(addr.addr);
+        async move {
+            connected?;
+            if addr.addr.is_unspecified() {
+                return Ok(());
+            }
+            let resolved = query.await;
+            let mut inner = peer.inner.borrow_mut();
+            let generation = inner.arp.generation();
+            let socket = match inner.sockets.get_mut(&fd) {
+                Some(s) if s.remote == Some(addr) => s,
+                // Closed while we were waiting.
+                _ => return Err(Fail::Shutdown {}),
+            };
+            match resolved {
+                Ok(link_addr) => {
+                    socket.link_addr = Some((link_addr, generation));
+                    Ok(())
                 },
-                ipv4_hdr: Ipv4Header::new(
-                    self.rt.local_ipv4_addr(),
-                    remote.addr,
-                    Ipv4Protocol2::Udp,
-                ),
-                udp_hdr: UdpHeader {
-                    src_port: local.map(|l| l.port),
-                    dst_port: remote.port,
+                Err(..) => {
+                    socket.set_remote(None);
+                    Err(Fail::HostUnreachable {})
                 },
-                data: buf,
-            };
-            self.rt.transmit(datagram);
-        }
-        // Otherwise defer to the async path.
-        else {
-            self.outgoing.try_send((local, remote, buf)).unwrap();
+            }
+        }
+    }
+
+    pub fn receive(
+        &self,
+        ipv4_header: &Ipv4Header,
+        buf: Bytes,
+        metadata: &RxMetadata,
+    ) -> Result<(), Fail> {
+        self.try_receive(ipv4_header, buf, metadata).into_result()
+    }
+
+    /// Like `receive`, but says what became of the segment, so a demux layer feeding a batch
+    /// can keep precise counts.
+    pub fn try_receive(
+        &self,
+        ipv4_header: &Ipv4Header,
+        buf: Bytes,
+        metadata: &RxMetadata,
+    ) -> ReceiveOutcome {
+        let len = buf.len();
+        let outcome = self.receive_segment(ipv4_header, buf, metadata);
+        let inner = self.inner.borrow();
+        let traffic = &inner.traffic;
+        match outcome {
+            // A delivered segment's length was checked against its header's.
+            ReceiveOutcome::Delivered => {
+                traffic.rx_datagrams.set(traffic.rx_datagrams.get() + 1);
+                let bytes = (len - UDP_HEADER2_SIZE) as u64;
+                traffic.rx_bytes.set(traffic.rx_bytes.get() + bytes);
+            },
+            _ => traffic.rx_dropped.set(traffic.rx_dropped.get() + 1),
+        }
+        outcome
+    }
+
+    fn receive_segment(
+        &self,
+        ipv4_header: &Ipv4Header,
+        buf: Bytes,
+        metadata: &RxMetadata,
+    ) -> ReceiveOutcome {
+        {
+            let mut inner = self.inner.borrow_mut();
+            let rt = &inner.rt;
+            if ipv4_header.src_addr == rt.local_ipv4_addr() && rt.ipv4_options().drop_martians {
+                inner.stats.martians += 1;
+                return ReceiveOutcome::Filtered {
+                    details: "Datagram spoofed from our own address",
+                };
+            }
+        }
+        let verify_checksum = !metadata.checksum_validated;
+        let (hdr, data) = match UdpHeader::parse_with(ipv4_header, buf, verify_checksum) {
+            Ok(r) => r,
+            Err(e) => {
+                let stats = &mut self.inner.borrow_mut().stats;
+                match e {
+                    ParseError::Truncated => stats.truncated += 1,
+                    ParseError::BadLength => stats.bad_length += 1,
+                    ParseError::BadChecksum => stats.bad_checksum += 1,
+                    ParseError::BadPort => stats.bad_port += 1,
+                }
+                return ReceiveOutcome::Malformed(e);
+            },
+        };
+        let local = ipv4::Endpoint::new(ipv4_header.dst_addr, hdr.dst_port);
+        let remote = hdr
+            .src_port
+            .map(|p| ipv4::Endpoint::new(ipv4_header.src_addr, p));
+
+        // TODO: Send ICMPv4 error if nobody's listening.
+        self.inner
+            .borrow()
+            .deliver(ipv4_header, local, remote, data, metadata)
+    }
+
+    /// Hands an ICMPv4 error about a datagram sent from `local` to `remote` to the socket
+    /// connected between them, whose next `push` or `pop` then fails with `error`. Unconnected
+    /// sockets may be talking to many peers, so errors about their datagrams are dropped.
+    pub fn receive_icmp_error(
+        &self,
+        local: ipv4::Endpoint,
+        remote: ipv4::Endpoint,
+        error: Fail,
+    ) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        let wildcard = ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, local.port);
+        let group = inner
+            .bound
+            .get(&local)
+            .or_else(|| inner.bound.get(&wildcard))
+            .ok_or(Fail::Ignored {
+                details: "ICMPv4 error for unbound port",
+            })?;
+        for &fd in group {
+            let mut l = match inner.listener(fd) {
+                Ok(l) => l.borrow_mut(),
+                Err(..) => continue,
+            };
+            if l.remote == Some(remote) {
+                inner.sockets[&fd]
+                    .send_buffer
+                    .set_error(remote, error.clone());
+                l.error = Some(error);
+                l.wake();
+                return Ok(());
+            }
+        }
+        Err(Fail::Ignored {
+            details: "ICMPv4 error for unconnected socket",
+        })
+    }
+
+    /// Defers poller notifications from `receive` until `end_batch`, so a burst of datagrams
+    /// readies its sockets with one atomic update per waker page.
+    pub fn begin_batch(&self) {
+        let inner = self.inner.borrow();
+        let mut batch = inner.batch.borrow_mut();
+        assert!(batch.is_none(), "UDP receive batch already in progress");
+        *batch = Some(ReadinessBatch::new());
+    }
+
+    pub fn end_batch(&self) {
+        let batch = self.inner.borrow().batch.borrow_mut().take();
+        if let Some(batch) = batch {
+            batch.flush();
+        }
+    }
+
+    pub fn stats(&self) -> UdpStats {
+        self.inner.borrow().stats
+    }
+
+    pub fn metrics(&self) -> UdpPeerMetrics {
+        let inner = self.inner.borrow();
+        let traffic = &inner.traffic;
+        let queued_datagrams = inner
+            .sockets
+            .values()
+            .map(|s| s.send_buffer.in_flight.get())
+            .sum();
+        UdpPeerMetrics {
+            sockets: inner.sockets.len(),
+            bound_endpoints: inner.bound.len(),
+            rx_datagrams: traffic.rx_datagrams.get(),
+            rx_bytes: traffic.rx_bytes.get(),
+            rx_dropped: traffic.rx_dropped.get(),
+            tx_datagrams: traffic.tx_datagrams.get(),
+            tx_bytes: traffic.tx_bytes.get(),
+            tx_dropped: traffic.tx_dropped.get(),
+            queued_datagrams,
+        }
+    }
+
+    pub fn recv_queue_len(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        let listener = inner.listener(fd)?;
+        let len = listener.borrow().buf.len();
+        Ok(len)
+    }
+
+    /// Takes everything `fd` still has queued in either direction, so an embedder can log or
+    /// salvage it before `close` drops it. Paced datagrams waiting on their bucket stay queued.
+    pub fn drain_pending(&self, fd: FileDescriptor) -> Result<PendingDatagrams, Fail> {
+        let inner = self.inner.borrow();
+        let socket = inner.sockets.get(&fd).ok_or(Fail::BadFileDescriptor {})?;
+        let received = match socket.listener {
+            Some(ref listener) => {
+                let mut l = listener.borrow_mut();
+                l.queued_bytes = 0;
+                l.buf.drain(..).collect()
+            },
+            None => vec![],
+        };
+        let mut unsent = vec![];
+        // Emptied queues stay put, so later sends still wait on the resolution in flight.
+        for queue in inner.deferred.borrow_mut().values_mut() {
+            let (ours, theirs): (VecDeque<_>, VecDeque<_>) = queue
+                .drain(..)
+                .partition(|req| Rc::ptr_eq(&req.send_buffer, &socket.send_buffer));
+            *queue = theirs;
+            for req in ours {
+                req.send_buffer
+                    .release(req.buf.len(), req.options.send_buffer_low);
+                unsent.push((req.remote, req.buf));
+            }
+        }
+        Ok(PendingDatagrams { received, unsent })
+    }
+
+    /// Datagrams `fd`'s receive queue can hold before it has to reallocate.
+    pub fn recv_queue_capacity(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        let listener = inner.listener(fd)?;
+        let capacity = listener.borrow().buf.capacity();
+        Ok(capacity)
+    }
+
+    pub fn recv_queue_bytes(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        let listener = inner.listener(fd)?;
+        let bytes = listener.borrow().queued_bytes;
+        Ok(bytes)
+    }
+
+    pub fn socket_state(&self, fd: FileDescriptor) -> Result<SocketState, Fail> {
+        let inner = self.inner.borrow();
+        let socket = match inner.sockets.get(&fd) {
+            Some(socket) => socket,
+            None => return Err(Fail::BadFileDescriptor {}),
+        };
+        let is_bound = socket
+            .local
+            .and_then(|local| inner.bound.get(&local))
+            .map_or(false, |group| group.contains(&fd));
+        Ok(SocketState {
+            is_bound,
+            is_connected: socket.remote.is_some(),
+            local: socket.local,
+            remote: socket.remote,
+            options: socket.options.clone(),
+        })
+    }
+
+    pub fn socket_options(&self, fd: FileDescriptor) -> Result<UdpSocketOptions, Fail> {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(socket) => Ok(socket.options.clone()),
+            None => Err(Fail::BadFileDescriptor {}),
+        }
+    }
+
+    pub fn set_socket_options(
+        &self,
+        fd: FileDescriptor,
+        options: UdpSocketOptions,
+    ) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        match inner.sockets.get_mut(&fd) {
+            Some(socket) => {
+                options.validate()?;
+                if let Some(ref listener) = socket.listener {
+                    let mut l = listener.borrow_mut();
+                    l.link_index = options.link_index;
+                    l.recv_buffer_limit = options.recv_buffer_limit;
+                    l.early_drop = options.early_drop;
+                    l.recv_header = options.recv_header;
+                    l.recv_timestamp = options.recv_timestamp;
+                }
+                socket.options = options;
+                socket.send_buffer.invalidate_handles();
+                Ok(())
+            },
+            None => Err(Fail::BadFileDescriptor {}),
+        }
+    }
+
+    /// Registers a bound socket with `poller`. If datagrams are already queued, the socket is
+    /// reported readable on the next `poll`. Closing the socket withdraws anything `poll` hasn't
+    /// reported yet, but it keeps its place in `poller` until it's deregistered.
+    pub fn register(&self, poller: &mut UdpPoller, fd: FileDescriptor) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        let listener = inner.listener(fd)?;
+        let mut l = listener.borrow_mut();
+        let (readable, writable) = poller.register(fd)?;
+        if !l.buf.is_empty() {
+            readable.notify();
+        }
+        l.readiness = Some(readable);
+        *inner.sockets[&fd].send_buffer.readiness.borrow_mut() = Some(writable);
+        Ok(())
+    }
+
+    pub fn deregister(&self, poller: &mut UdpPoller, fd: FileDescriptor) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        poller.deregister(fd)?;
+        if let Ok(listener) = inner.listener(fd) {
+            listener.borrow_mut().readiness = None;
+        }
+        if let Some(socket) = inner.sockets.get(&fd) {
+            *socket.send_buffer.readiness.borrow_mut() = None;
         }
         Ok(())
     }
+
+    /// Bytes `fd` has queued behind pending ARP resolution or pacing.
+    pub fn send_queue_bytes(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(socket) => Ok(socket.send_buffer.queued.get()),
+            None => Err(Fail::BadFileDescriptor {}),
+        }
+    }
+
+    /// Timestamps for the most recent datagrams `fd` sent with the `timestamp_sends` option,
+    /// oldest first.
+    pub fn send_timestamps(&self, fd: FileDescriptor) -> Result<Vec<SendTimestamps>, Fail> {
+        let inner = self.inner.borrow();
+        let socket = inner.sockets.get(&fd).ok_or(Fail::BadFileDescriptor {})?;
+        let timestamps = socket.send_buffer.timestamps.borrow();
+        Ok(timestamps.iter().flatten().cloned().collect())
+    }
+
+    /// Takes the latest error about a datagram `fd` sent, along with its destination: either the
+    /// background task gave up on it, or an ICMPv4 error came back about it. `push` and `pushto`
+    /// only report what fails right away, so a datagram that was queued behind ARP and then
+    /// given up on is only reported here.
+    pub fn take_send_error(
+        &self,
+        fd: FileDescriptor,
+    ) -> Result<Option<(ipv4::Endpoint, Fail)>, Fail> {
+        let inner = self.inner.borrow();
+        let socket = inner.sockets.get(&fd).ok_or(Fail::BadFileDescriptor {})?;
+        let error = socket.send_buffer.error.borrow_mut().take();
+        Ok(error)
+    }
+
+    /// Waits for the next error `take_send_error` would return, like reading `IP_RECVERR`'s
+    /// error queue, instead of finding out on the next send. Fails with `Shutdown` once `fd` is
+    /// closed.
+    pub fn next_error(
+        &self,
+        fd: FileDescriptor,
+    ) -> impl Future<Output = Result<(ipv4::Endpoint, Fail), Fail>> {
+        let send_buffer = match self.inner.borrow().sockets.get(&fd) {
+            Some(socket) => Ok(socket.send_buffer.clone()),
+            None => Err(Fail::BadFileDescriptor {}),
+        };
+        future::poll_fn(move |ctx| {
+            let send_buffer = match send_buffer {
+                Ok(ref s) => s,
+                Err(ref e) => return Poll::Ready(Err(e.clone())),
+            };
+            if let Some(error) = send_buffer.error.borrow_mut().take() {
+                return Poll::Ready(Ok(error));
+            }
+            if send_buffer.dropped.get() {
+                return Poll::Ready(Err(Fail::Shutdown {}));
+            }
+            *send_buffer.error_waker.borrow_mut() = Some(ctx.waker().clone());
+            Poll::Pending
+        })
+    }
+
+    /// Stops further receives, sends or both on `fd`. The fd stays allocated until `close`.
+    pub fn shutdown(&self, fd: FileDescriptor, how: Shutdown) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let socket = inner
+            .sockets
+            .get_mut(&fd)
+            .ok_or(Fail::BadFileDescriptor {})?;
+        if how != Shutdown::Read {
+            socket.write_shutdown = true;
+            socket.send_buffer.invalidate_handles();
+        }
+        if how != Shutdown::Write {
+            if let Ok(listener) = inner.listener(fd) {
+                let mut l = listener.borrow_mut();
+                l.read_shutdown = true;
+                l.wake();
+                if let Some(ref r) = l.readiness {
+                    r.notify();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases `fd`'s local endpoint (or raw listening range) for another socket to bind, and
+    /// drops whatever it has queued. Pops waiting on it fail as if it had never been bound, and
+    /// it can be bound again.
+    pub fn unbind(&self, fd: FileDescriptor) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        let socket = inner
+            .sockets
+            .get_mut(&fd)
+            .ok_or(Fail::BadFileDescriptor {})?;
+        let listener = socket.listener.take().ok_or(Fail::Malformed {
+            details: "Socket not bound",
+        })?;
+        let local = socket.local.take();
+        let ports = socket.ports.take();
+        let ephemeral = socket.ephemeral;
+        socket.ephemeral = false;
+        socket.send_buffer.invalidate_handles();
+        {
+            let mut l = listener.borrow_mut();
+            l.unbound = true;
+            l.wake();
+            if let Some(ref r) = l.readiness {
+                r.notify();
+            }
+        }
+        inner.release_endpoint(fd, local, ports, ephemeral);
+        Ok(())
+    }
+
+    /// Sends `buf` to the socket's connected peer, returning its length. UDP datagrams are never
+    /// partially sent, so that's always the whole payload.
+    pub fn push(&self, fd: FileDescriptor, buf: Bytes) -> Result<usize, Fail> {
+        let len = buf.len();
+        self.push_status(fd, buf).map(|_| len)
+    }
+
+    /// Like `push`, but reports whether the datagram went out or is waiting on ARP resolution
+    /// or pacing.
+    pub fn push_status(&self, fd: FileDescriptor, buf: Bytes) -> Result<SendStatus, Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let generation = inner.arp.generation();
+        let (local, remote, options, cached, send_buffer) = match inner.sockets.get(&fd) {
+            Some(Socket {
+                write_shutdown: true,
+                ..
+            }) => return Err(Fail::Shutdown {}),
+            Some(Socket {
+                local,
+                remote: Some(remote),
+                options,
+                link_addr,
+                send_buffer,
+                listener,
+                ..
+            }) => {
+                let error = listener.as_ref().and_then(|l| l.borrow_mut().error.take());
+                if let Some(e) = error {
+                    return Err(e);
+                }
+                check_size(options, buf.len())?;
+                (
+                    *local,
+                    *remote,
+                    options.clone(),
+                    *link_addr,
+                    send_buffer.clone(),
+                )
+            },
+            None => return Err(Fail::BadFileDescriptor {}),
+            _ => {
+                return Err(Fail::Malformed {
+                    details: "Socket not connected",
+                })
+            },
+        };
+        // Connected sockets reuse the remote's link address until the ARP cache changes.
+        let link_addr = match cached {
+            Some((link_addr, g)) if g == generation => Some(link_addr),
+            _ => {
+                let link_addr = inner.resolve_link_addr(remote.addr);
+                let socket = inner.sockets.get_mut(&fd).unwrap();
+                socket.link_addr = link_addr.map(|l| (l, generation));
+                link_addr
+            },
+        };
+        let req = OutgoingReq {
+            src_addr: inner.rt.local_ipv4_addr(),
+            local,
+            remote,
+            options,
+            send_buffer,
+            buf,
+            pushed_at: None,
+            send_opts: SendOpts::default(),
+        };
+        inner.send_datagram(req, link_addr)
+    }
+
+    /// Takes `fd`'s connected send path once, so repeated sends to its peer skip the socket
+    /// table lookup `push` does each time. The handle goes stale if the socket is closed,
+    /// reconnected, rebound, reconfigured or shut down for writing; its sends then fail and a
+    /// new one has to be taken.
+    pub fn send_handle(&self, fd: FileDescriptor) -> Result<SendHandle<RT>, Fail> {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(Socket {
+                write_shutdown: true,
+                ..
+            }) => Err(Fail::Shutdown {}),
+            Some(Socket {
+                local,
+                remote: Some(remote),
+                options,
+                link_addr,
+                send_buffer,
+                listener,
+                ..
+            }) => Ok(SendHandle {
+                inner: self.inner.clone(),
+                local: *local,
+                remote: *remote,
+                options: options.clone(),
+                listener: listener.clone(),
+                send_buffer: send_buffer.clone(),
+                epoch: send_buffer.epoch.get(),
+                link_addr: Cell::new(*link_addr),
+            }),
+            None => Err(Fail::BadFileDescriptor {}),
+            _ => Err(Fail::Malformed {
+                details: "Socket not connected",
+            }),
+        }
+    }
+
+    pub fn pushto(
+        &self,
+        fd: FileDescriptor,
+        buf: Bytes,
+        to: ipv4::Endpoint,
+    ) -> Result<usize, Fail> {
+        self.pushto_with(fd, buf, to, SendOpts::default())
+    }
+
+    /// Like `pushto`, but reports whether the datagram went out or is waiting on ARP resolution
+    /// or pacing.
+    pub fn pushto_status(
+        &self,
+        fd: FileDescriptor,
+        buf: Bytes,
+        to: ipv4::Endpoint,
+    ) -> Result<SendStatus, Fail> {
+        let src_addr = self.inner.borrow().source_addr(fd)?;
+        self.pushto_with_src(fd, buf, src_addr, to, SendOpts::default())
+    }
+
+    /// Like `pushto`, but with `send_opts` overriding the socket's header fields for just this
+    /// datagram. Traceroute-style tools can vary the TTL per probe this way without touching
+    /// the socket's options.
+    pub fn pushto_with(
+        &self,
+        fd: FileDescriptor,
+        buf: Bytes,
+        to: ipv4::Endpoint,
+        send_opts: SendOpts,
+    ) -> Result<usize, Fail> {
+        let src_addr = self.inner.borrow().source_addr(fd)?;
+        let len = buf.len();
+        self.pushto_with_src(fd, buf, src_addr, to, send_opts)
+            .map(|_| len)
+    }
+
+    /// Like `pushto`, but sends from `from` rather than our configured address, e.g. to reply
+    /// from the `local_addr` a wildcard-bound socket received a request on. Addresses that
+    /// aren't ours, configured or secondary, need the `freebind` option.
+    pub fn pushto_from(
+        &self,
+        fd: FileDescriptor,
+        buf: Bytes,
+        from: Ipv4Addr,
+        to: ipv4::Endpoint,
+    ) -> Result<usize, Fail> {
+        if from.is_unspecified() || from.is_broadcast() || from.is_multicast() {
+            return Err(Fail::Malformed {
+                details: "Source address must be unicast",
+            });
+        }
+        self.inner.borrow().check_source(fd, from)?;
+        let len = buf.len();
+        self.pushto_with_src(fd, buf, from, to, SendOpts::default())
+            .map(|_| len)
+    }
+
+    fn pushto_with_src(
+        &self,
+        fd: FileDescriptor,
+        buf: Bytes,
+        src_addr: Ipv4Addr,
+        to: ipv4::Endpoint,
+        send_opts: SendOpts,
+    ) -> Result<SendStatus, Fail> {
+        if !to.is_destination() {
+            return Err(Fail::Invalid {
+                details: "Can't send to the unspecified address",
+            });
+        }
+        self.bind_deferred(fd)?;
+        let inner = self.inner.borrow();
+        let (local, options, send_buffer) = match inner.sockets.get(&fd) {
+            Some(Socket {
+                write_shutdown: true,
+                ..
+            }) => return Err(Fail::Shutdown {}),
+            Some(Socket {
+                local,
+                remote,
+                options,
+                send_buffer,
+                ..
+            }) => {
+                check_group_send(remote.is_some(), options, to.addr)?;
+                check_size(options, buf.len())?;
+                (*local, options.clone(), send_buffer.clone())
+            },
+            None => return Err(Fail::BadFileDescriptor {}),
+        };
+        let link_addr = inner.resolve_link_addr(to.addr);
+        let looped = if to.addr.is_multicast() && options.multicast_loop {
+            let mut ipv4_hdr = Ipv4Header::new(src_addr, to.addr, Ipv4Protocol2::Udp);
+            ipv4_hdr.ecn = send_opts.ecn.unwrap_or(options.ecn).bits();
+            // Loop back on the link we're sending on.
+            let metadata = RxMetadata {
+                link_index: options.link_index.unwrap_or_default(),
+                ..RxMetadata::default()
+            };
+            Some((ipv4_hdr, buf.clone(), metadata))
+        } else {
+            None
+        };
+        let req = OutgoingReq {
+            src_addr,
+            local,
+            remote: to,
+            options,
+            send_buffer,
+            buf,
+            pushed_at: None,
+            send_opts,
+        };
+        let status = inner.send_datagram(req, link_addr)?;
+        if let Some((ipv4_hdr, buf, metadata)) = looped {
+            let remote = local.map(|l| ipv4::Endpoint::new(src_addr, l.port));
+            // Having no local listener doesn't make the send itself fail.
+            let _ = inner.deliver(&ipv4_hdr, to, remote, buf, &metadata);
+        }
+        Ok(status)
+    }
+
+    /// Waits until `pop` would complete right away, like `poll(2)` for `POLLIN`, without taking
+    /// anything off the receive queue. Lets a server accept its first datagram on a socket
+    /// before deciding how to read it. Errors `pop` would report, and read shutdowns, count too.
+    pub fn wait_readable(&self, fd: FileDescriptor) -> impl Future<Output = Result<(), Fail>> {
+        let listener = self.pop_listener(fd);
+        future::poll_fn(move |ctx| match listener {
+            Ok(ref l) => l.borrow_mut().poll_readable(ctx),
+            Err(ref e) => Poll::Ready(Err(e.clone())),
+        })
+    }
+
+    /// Waits for the next datagram, for at most the socket's `recv_timeout` if it has one.
+    pub fn pop(&self, fd: FileDescriptor) -> PopFuture {
+        let listener = self.pop_listener(fd);
+        let timer = self.recv_timer(fd, None);
+        PopFuture {
+            listener,
+            fd,
+            timer,
+        }
+    }
+
+    /// Like `pop`, but fails with `Timeout` if nothing arrives within `timeout`, whatever the
+    /// socket's `recv_timeout`.
+    pub fn pop_timeout(&self, fd: FileDescriptor, timeout: Duration) -> PopFuture {
+        let listener = self.pop_listener(fd);
+        let timer = self.recv_timer(fd, Some(timeout));
+        PopFuture {
+            listener,
+            fd,
+            timer,
+        }
+    }
+
+    /// Like `pop`, but resolves to the full `ReceivedDatagram` rather than just its source and
+    /// payload.
+    pub fn pop_datagram(&self, fd: FileDescriptor) -> PopDatagramFuture {
+        let listener = self.pop_listener(fd);
+        let timer = self.recv_timer(fd, None);
+        PopDatagramFuture {
+            listener,
+            fd,
+            timer,
+        }
+    }
+
+    fn recv_timer(&self, fd: FileDescriptor, timeout: Option<Duration>) -> RecvTimer {
+        let inner = self.inner.borrow();
+        let timeout = timeout.or_else(|| inner.sockets.get(&fd)?.options.recv_timeout)?;
+        Some(Box::pin(inner.rt.wait(timeout)))
+    }
+
+    /// Copies the next queued datagram into `buf` without waiting, returning how many bytes were
+    /// copied, whether the datagram had to be truncated to fit (like `MSG_TRUNC`), and its
+    /// source. Whatever didn't fit is discarded. Fails with `WouldBlock` if nothing is queued;
+    /// `pop_into` waits instead.
+    pub fn recv_into(
+        &self,
+        fd: FileDescriptor,
+        buf: &mut [u8],
+    ) -> Result<(usize, bool, Option<ipv4::Endpoint>), Fail> {
+        let inner = self.inner.borrow();
+        let mut listener = inner.listener(fd)?.borrow_mut();
+        if let Some(e) = listener.error.take() {
+            return Err(e);
+        }
+        let datagram = match listener.pop() {
+            Some(d) => d,
+            None if listener.read_shutdown => return Ok((0, false, None)),
+            None => return Err(Fail::WouldBlock {}),
+        };
+        Ok(copy_datagram(&datagram, buf))
+    }
+
+    /// Returns the source and payload of the next queued datagram without taking it off the
+    /// queue, like `MSG_PEEK`. Otherwise behaves like `recv_into`, except that a pending error
+    /// is reported without being cleared.
+    pub fn peek(&self, fd: FileDescriptor) -> Result<(Option<ipv4::Endpoint>, Bytes), Fail> {
+        let inner = self.inner.borrow();
+        let listener = inner.listener(fd)?.borrow();
+        if let Some(ref e) = listener.error {
+            return Err(e.clone());
+        }
+        match listener.buf.front() {
+            Some(d) => Ok((d.remote, d.data.clone())),
+            None if listener.read_shutdown => Ok((None, Bytes::empty())),
+            None => Err(Fail::WouldBlock {}),
+        }
+    }
+
+    /// Like `recv_into`, but waits for a datagram if none is queued, for at most the socket's
+    /// `recv_timeout` if it has one.
+    pub fn pop_into<'a>(
+        &self,
+        fd: FileDescriptor,
+        buf: &'a mut [u8],
+    ) -> impl Future<Output = Result<(usize, bool, Option<ipv4::Endpoint>), Fail>> + 'a {
+        let pop = self.pop_datagram(fd);
+        async move {
+            let datagram = pop.await?;
+            Ok(copy_datagram(&datagram, buf))
+        }
+    }
+
+    // The socket holds its listener from `bind` on, so this is one lookup, with nothing to keep
+    // consistent with `bound`.
+    fn pop_listener(&self, fd: FileDescriptor) -> Result<Rc<RefCell<Listener>>, Fail> {
+        self.inner.borrow().listener(fd).map(Rc::clone)
+    }
+
+    pub fn close(&self, fd: FileDescriptor) -> Result<(), Fail> {
+        self.inner.borrow_mut().close(fd)
+    }
+
+    /// Closes every socket, failing their pending pops with `Shutdown`. Datagrams the background
+    /// task still holds for these sockets are dropped.
+    pub fn close_all(&self) {
+        let mut inner = self.inner.borrow_mut();
+        let fds: Vec<_> = inner.sockets.keys().cloned().collect();
+        for fd in fds {
+            inner.close(fd).unwrap();
+        }
+    }
+
+    /// Like `close`, but first waits up to `timeout` for datagrams still awaiting ARP resolution
+    /// to go out. Further sends on `fd` fail with `Shutdown` while we wait. If the timeout fires,
+    /// the remaining datagrams are dropped and the fd is freed anyway.
+    pub fn close_graceful(
+        &self,
+        fd: FileDescriptor,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<(), Fail>> {
+        let peer = self.clone();
+        let send_buffer = match self.inner.borrow_mut().sockets.get_mut(&fd) {
+            Some(socket) => {
+                socket.write_shutdown = true;
+                socket.send_buffer.invalidate_handles();
+                Ok(socket.send_buffer.clone())
+            },
+            None => Err(Fail::BadFileDescriptor {}),
+        };
+        let rt = self.inner.borrow().rt.clone();
+        async move {
+            let send_buffer = send_buffer?;
+            let timeout = rt.wait(timeout).fuse();
+            futures::pin_mut!(timeout);
+            let drained = loop {
+                let (in_flight, in_flight_changed) = send_buffer.in_flight.watch();
+                if in_flight == 0 {
+                    break true;
+                }
+                futures::pin_mut!(in_flight_changed);
+                futures::select_biased! {
+                    _ = in_flight_changed => continue,
+                    _ = timeout => break false,
+                }
+            };
+            // Someone else may have closed `fd`, and it may even have been reused since.
+            let still_open = match peer.inner.borrow().sockets.get(&fd) {
+                Some(socket) => Rc::ptr_eq(&socket.send_buffer, &send_buffer),
+                None => false,
+            };
+            if !still_open {
+                return Err(Fail::Malformed {
+                    details: "Socket closed during close_graceful",
+                });
+            }
+            peer.close(fd)?;
+            if !drained {
+                return Err(Fail::Timeout {});
+            }
+            Ok(())
+        }
+    }
+
+    /// Resolves once every datagram on the peer that was waiting on ARP or pacing has been sent
+    /// or dropped, e.g. before measuring or shutting down after a burst of deferred sends. Sends
+    /// deferred while it waits are waited on too.
+    pub fn flush(&self) -> impl Future<Output = ()> {
+        let pending = self.inner.borrow().pending.clone();
+        async move {
+            loop {
+                let (queued, queued_changed) = pending.watch();
+                if queued == 0 {
+                    return;
+                }
+                queued_changed.await;
+            }
+        }
+    }
+}
+
+impl<RT: Runtime> Inner<RT> {
+    // Picks the socket a datagram from `remote` to `local` goes to, most specific first: one
+    // connected to `remote`, then an unconnected socket bound to `local`'s address, then one bound
+    // to the wildcard address, which is the only way to receive broadcasts (or anything at all
+    // before we're configured with an address). Only when nobody's bound to the port does it go
+    // to a raw listener.
+    fn demux(
+        &self,
+        local: ipv4::Endpoint,
+        remote: Option<ipv4::Endpoint>,
+        rx_queue: Option<usize>,
+    ) -> Result<FileDescriptor, ReceiveOutcome> {
+        let wildcard = ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, local.port);
+        let specific = self.bound.get(&local);
+        let any = if local != wildcard {
+            self.bound.get(&wildcard)
+        } else {
+            None
+        };
+        // This runs for every datagram, so walk the groups in place rather than collecting them.
+        let groups = move || specific.into_iter().chain(any);
+        // `close` removes a socket from its group and the group from `bound` together, but an
+        // empty group would only mean nobody can take the datagram, so it's not worth panicking
+        // over.
+        if groups().all(|g| g.is_empty()) {
+            let raw = self.raw.iter().find(|(r, _)| r.contains(&local.port));
+            return raw.map(|&(_, fd)| fd).ok_or(ReceiveOutcome::DroppedUnbound);
+        }
+        let connected_to = |fd: &FileDescriptor| self.sockets.get(fd).and_then(|s| s.remote);
+        // A datagram without a source port can't be from the peer a socket connected to.
+        if remote.is_some() {
+            let matching = groups()
+                .flat_map(|g| g.iter())
+                .find(|fd| connected_to(fd) == remote);
+            if let Some(&fd) = matching {
+                return Ok(fd);
+            }
+        }
+        for group in groups() {
+            let unconnected = || group.iter().filter(|fd| connected_to(fd).is_none());
+            let ix = match unconnected().count() {
+                0 => continue,
+                1 => 0,
+                n => {
+                    // Spread flows across a `reuse_port` group by their RX queue, or else their
+                    // 4-tuple, so each flow keeps landing on the same socket for as long as the
+                    // group doesn't change.
+                    if let Some(q) = rx_queue {
+                        q % n
+                    } else {
+                        let mut hasher = DefaultHasher::new();
+                        (remote, local).hash(&mut hasher);
+                        (hasher.finish() % n as u64) as usize
+                    }
+                },
+            };
+            return Ok(*unconnected().nth(ix).unwrap());
+        }
+        Err(ReceiveOutcome::Filtered {
+            details: "Datagram not from connected peer",
+        })
+    }
+
+    // Queues a parsed datagram on the socket it's addressed to.
+    fn deliver(
+        &self,
+        ipv4_header: &Ipv4Header,
+        local: ipv4::Endpoint,
+        remote: Option<ipv4::Endpoint>,
+        data: Bytes,
+        metadata: &RxMetadata,
+    ) -> ReceiveOutcome {
+        let fd = match self.demux(local, remote, metadata.rx_queue) {
+            Ok(fd) => fd,
+            Err(outcome) => return outcome,
+        };
+        let mut l = match self.listener(fd) {
+            Ok(l) => l.borrow_mut(),
+            Err(..) => {
+                return ReceiveOutcome::Filtered {
+                    details: "Socket closed",
+                }
+            },
+        };
+        if l.read_shutdown {
+            return ReceiveOutcome::Filtered {
+                details: "Socket shut down for reading",
+            };
+        }
+        if l.link_index.map_or(false, |i| i != metadata.link_index) {
+            return ReceiveOutcome::Filtered {
+                details: "Socket bound to a different link",
+            };
+        }
+        let queued = l.queued_bytes + data.len();
+        let full = l.recv_buffer_limit.map_or(false, |max| queued > max);
+        if l.queued_bytes > 0 && full {
+            return ReceiveOutcome::DroppedBufferFull;
+        }
+        if let Some(early_drop) = l.early_drop {
+            let p = early_drop.drop_probability(l.queued_bytes);
+            if p > 0.0 && self.rt.rng_gen::<f64>() < p {
+                return ReceiveOutcome::DroppedEarly;
+            }
+        }
+        let ipv4_hdr = if l.recv_header {
+            Some(ipv4_header.clone())
+        } else {
+            None
+        };
+        let timestamp = if l.recv_timestamp {
+            Some(self.rt.now())
+        } else {
+            None
+        };
+        l.queued_bytes += data.len();
+        l.buf.push_back(ReceivedDatagram {
+            remote,
+            local_addr: ipv4_header.dst_addr,
+            local_port: Some(local.port),
+            ecn: Ecn::from_bits(ipv4_header.ecn),
+            ipv4_hdr,
+            timestamp,
+            data,
+        });
+        l.wake();
+        if let Some(ref r) = l.readiness {
+            match *self.batch.borrow_mut() {
+                Some(ref mut batch) => batch.add(r),
+                None => r.notify(),
+            }
+        }
+        ReceiveOutcome::Delivered
+    }
+
+    fn close(&mut self, fd: FileDescriptor) -> Result<(), Fail> {
+        let socket = match self.sockets.remove(&fd) {
+            Some(s) => s,
+            None => return Err(Fail::BadFileDescriptor {}),
+        };
+        if let Some(listener) = socket.listener {
+            let mut l = listener.borrow_mut();
+            l.closed = true;
+            l.wake();
+            if let Some(r) = l.readiness.take() {
+                r.clear();
+            }
+        }
+        if let Some(r) = socket.send_buffer.readiness.borrow_mut().take() {
+            r.clear();
+        }
+        self.release_endpoint(fd, socket.local, socket.ports, socket.ephemeral);
+        // Something else sharing the table may have freed it already. Its slot never comes back
+        // under this descriptor, so if it's still open it's ours.
+        if self.file_table.get(fd).is_some() {
+            self.file_table.free(fd)?;
+        }
+        socket.send_buffer.dropped.set(true);
+        if let Some(w) = socket.send_buffer.error_waker.borrow_mut().take() {
+            w.wake();
+        }
+        Ok(())
+    }
+
+    // Takes `fd` off the endpoints it was bound to, or its raw listening range, and returns an
+    // ephemeral port to the pool.
+    fn release_endpoint(
+        &mut self,
+        fd: FileDescriptor,
+        local: Option<ipv4::Endpoint>,
+        ports: Option<RangeInclusive<u16>>,
+        ephemeral: bool,
+    ) {
+        if let Some(local) = local {
+            let ports = ports.unwrap_or_else(|| {
+                let port: u16 = local.port.into();
+                port..=port
+            });
+            for port in ports {
+                let endpoint = ipv4::Endpoint::new(local.addr, ip::Port::try_from(port).unwrap());
+                if let Some(group) = self.bound.get_mut(&endpoint) {
+                    group.retain(|&f| f != fd);
+                    if group.is_empty() {
+                        self.bound.remove(&endpoint);
+                    }
+                }
+            }
+            if ephemeral {
+                self.ephemeral_ports.free(local.port);
+            }
+        }
+        self.raw.retain(|&(_, f)| f != fd);
+    }
+
+    // Where `reply_from_received` has a wildcard-bound socket send from, once it's received a
+    // unicast datagram.
+    fn reply_addr(&self, fd: FileDescriptor) -> Option<Ipv4Addr> {
+        let socket = self.sockets.get(&fd)?;
+        let wildcard = socket.local.map_or(false, |l| l.addr.is_unspecified());
+        if !socket.options.reply_from_received || !wildcard {
+            return None;
+        }
+        socket.listener.as_ref()?.borrow().last_local_addr
+    }
+
+    // Where `pushto` sends from: our configured address, unless `reply_addr` says otherwise.
+    fn source_addr(&self, fd: FileDescriptor) -> Result<Ipv4Addr, Fail> {
+        match self.reply_addr(fd) {
+            Some(addr) => {
+                // We may have dropped the address since the datagram arrived on it.
+                self.check_source(fd, addr)?;
+                Ok(addr)
+            },
+            None => Ok(self.rt.local_ipv4_addr()),
+        }
+    }
+
+    // Sending from an address that isn't ours takes the `freebind` option.
+    fn check_source(&self, fd: FileDescriptor, from: Ipv4Addr) -> Result<(), Fail> {
+        // Leave unknown fds for `pushto_with_src` to reject.
+        let freebind = self.sockets.get(&fd).map_or(true, |s| s.options.freebind);
+        let ours = from == self.rt.local_ipv4_addr()
+            || self.rt.ipv4_options().secondary_addrs.contains(&from);
+        if !ours && !freebind {
+            return Err(Fail::PermissionDenied {
+                details: "Sending from a foreign address needs freebind",
+            });
+        }
+        Ok(())
+    }
+
+    // Binds to ports below 1024 take the `privileged` option.
+    fn check_privileged(&self, port: ip::Port) -> Result<(), Fail> {
+        if port.is_privileged() && !self.rt.ipv4_options().privileged {
+            return Err(Fail::PermissionDenied {
+                details: "Binding a port below 1024 takes privileges",
+            });
+        }
+        Ok(())
+    }
+
+    fn listener(&self, fd: FileDescriptor) -> Result<&Rc<RefCell<Listener>>, Fail> {
+        match self.sockets.get(&fd) {
+            Some(Socket {
+                listener: Some(listener),
+                ..
+            }) => Ok(listener),
+            Some(..) => Err(Fail::Malformed {
+                details: "Socket not bound",
+            }),
+            None => Err(Fail::BadFileDescriptor {}),
+        }
+    }
+
+    fn resolve_link_addr(&self, addr: Ipv4Addr) -> Option<MacAddress> {
+        // Broadcast and multicast have no ARP mapping, so derive their link address directly.
+        if addr.is_broadcast() {
+            Some(MacAddress::broadcast())
+        } else if addr.is_multicast() {
+            Some(MacAddress::from_ipv4_multicast(addr))
+        } else {
+            self.arp.try_query(addr)
+        }
+    }
+
+    // `link_addr` is the already-resolved link address of `remote`, if there is one.
+    fn send_datagram(
+        &self,
+        mut req: OutgoingReq,
+        link_addr: Option<MacAddress>,
+    ) -> Result<SendStatus, Fail> {
+        if req.options.timestamp_sends {
+            req.pushed_at = Some(self.rt.now());
+        }
+        // Sends the socket's pacing bucket can't cover yet either wait for it to refill or fail.
+        let release_at = match req.options.pacing {
+            Some(pacing) => {
+                let now = self.rt.now();
+                let mut bucket = req.send_buffer.bucket.borrow_mut();
+                let bucket = bucket.get_or_insert_with(|| TokenBucket::new(&pacing, now));
+                bucket.refill(&pacing, now);
+                let wait = bucket.wait(&pacing, req.buf.len());
+                match pacing.mode {
+                    _ if wait == Duration::new(0, 0) => None,
+                    PacingMode::WouldBlock => return Err(Fail::WouldBlock {}),
+                    PacingMode::Queue => Some(now + wait),
+                }
+            },
+            None => None,
+        };
+        let mut deferred = self.deferred.borrow_mut();
+        // First, try to send the packet immediately.
+        let link_addr = link_addr.filter(|_| !deferred.contains_key(&req.remote.addr));
+        if release_at.is_some() || link_addr.is_none() {
+            let send_buffer = &req.send_buffer;
+            let queued = send_buffer.queued.get();
+            if queued > 0 && queued + req.buf.len() > req.options.send_buffer_high {
+                send_buffer.blocked.set(true);
+                return Err(Fail::WouldBlock {});
+            }
+        }
+        if req.options.pacing.is_some() {
+            if let Some(ref mut bucket) = *req.send_buffer.bucket.borrow_mut() {
+                bucket.take(req.buf.len());
+            }
+        }
+        match (release_at, link_addr) {
+            (None, Some(link_addr)) => {
+                let datagram = build_udp_datagram(&self.rt, &req, link_addr);
+                transmit(&self.rt, &req.options, datagram);
+                self.traffic.sent(req.buf.len());
+                if let Some(pushed) = req.pushed_at {
+                    req.send_buffer.record(pushed, pushed, self.rt.now());
+                }
+                Ok(SendStatus::Sent)
+            },
+            // Otherwise defer to the async path.
+            (release_at, link_addr) => {
+                let send_buffer = &req.send_buffer;
+                let queued = send_buffer.queued.get() + req.buf.len();
+                send_buffer.queued.set(queued);
+                send_buffer.in_flight.modify(|n| n + 1);
+                send_buffer.pending.modify(|n| n + 1);
+                let addr = req.remote.addr;
+                let timeout = req.options.arp_timeout;
+                match (release_at, deferred.get_mut(&addr)) {
+                    (Some(when), _) => self.pace_tx.unbounded_send((when, req, link_addr)).unwrap(),
+                    (None, Some(queue)) => queue.push_back(req),
+                    (None, None) => {
+                        deferred.insert(addr, VecDeque::from(vec![req]));
+                        self.resolve_tx.unbounded_send((addr, timeout)).unwrap();
+                    },
+                }
+                Ok(SendStatus::Queued)
+            },
+        }
+    }
+}
+
+// Both the immediate and deferred send paths build their datagrams here, so header changes
+// only need making once.
+fn build_udp_datagram<RT: Runtime>(
+    rt: &RT,
+    req: &OutgoingReq,
+    link_addr: MacAddress,
+) -> UdpDatagram {
+    UdpDatagram {
+        ethernet2_hdr: Ethernet2Header {
+            dst_addr: link_addr,
+            src_addr: rt.local_link_addr(),
+            ether_type: EtherType2::Ipv4,
+        },
+        ipv4_hdr: ipv4_header(rt, req),
+        udp_hdr: UdpHeader {
+            src_port: req.local.map(|l| l.port),
+            dst_port: req.remote.port,
+        },
+        data: req.buf.clone(),
+    }
+}
+
+fn ipv4_header<RT: Runtime>(rt: &RT, req: &OutgoingReq) -> Ipv4Header {
+    let (options, send_opts) = (&req.options, &req.send_opts);
+    let mut hdr = Ipv4Header::new_outgoing(rt, req.src_addr, req.remote.addr, Ipv4Protocol2::Udp);
+    hdr.ecn = options.ecn.bits();
+    if options.dont_fragment {
+        hdr.flags |= IPV4_FLAG_DONT_FRAGMENT;
+    }
+    if req.remote.addr.is_multicast() {
+        hdr.time_to_live = options.multicast_ttl;
+    }
+    if let Some(tos) = send_opts.tos {
+        hdr.dscp = tos >> 2;
+        hdr.ecn = tos & 3;
+    }
+    if let Some(ecn) = send_opts.ecn {
+        hdr.ecn = ecn.bits();
+    }
+    if let Some(ttl) = send_opts.ttl {
+        hdr.time_to_live = ttl;
+    }
+    if let Some(identification) = send_opts.identification {
+        hdr.identification = identification;
+    }
+    hdr
+}
+
+// Payloads we can send: what fits in a single IPv4 datagram, since we don't fragment, or in one
+// frame if the socket sets the "don't fragment" bit.
+fn check_size(options: &UdpSocketOptions, len: usize) -> Result<(), Fail> {
+    let max_ipv4_size = if options.dont_fragment {
+        MAX_PAYLOAD_SIZE
+    } else {
+        u16::MAX as usize
+    };
+    if len > max_ipv4_size - IPV4_HEADER2_SIZE - UDP_HEADER2_SIZE {
+        return Err(Fail::MessageTooLong {});
+    }
+    Ok(())
+}
+
+// Broadcast and multicast sends need an explicit opt-in, and are never allowed on connected
+// sockets, so a misdirected send can't flood the link.
+fn check_group_send(connected: bool, options: &UdpSocketOptions, to: Ipv4Addr) -> Result<(), Fail> {
+    let allowed = if to.is_broadcast() {
+        options.broadcast
+    } else if to.is_multicast() {
+        options.multicast_groups.contains(&to)
+    } else {
+        return Ok(());
+    };
+    if connected || !allowed {
+        return Err(Fail::PermissionDenied {
+            details: "Broadcast or multicast send not enabled on socket",
+        });
+    }
+    Ok(())
 }
 
+// Resolves `addr` for the background task, which needs to know which destination finished.
+async fn resolve<RT: Runtime>(
+    rt: RT,
+    arp: arp::Peer<RT>,
+    addr: Ipv4Addr,
+    timeout: Option<Duration>,
+) -> (Ipv4Addr, Result<MacAddress, Fail>) {
+    let query = arp.query(addr).fuse();
+    futures::pin_mut!(query);
+    let r = match timeout {
+        Some(timeout) => {
+            futures::select! {
+                r = query => r,
+                _ = rt.wait(timeout).fuse() => Err(Fail::Timeout {}),
+            }
+        },
+        None => query.await,
+    };
+    (addr, r)
+}
+
+// Holds a paced datagram for the background task until its bucket covers it.
+async fn release<RT: Runtime>(
+    rt: RT,
+    when: Instant,
+    req: OutgoingReq,
+    link_addr: Option<MacAddress>,
+) -> (OutgoingReq, Option<MacAddress>) {
+    rt.wait_until(when).await;
+    (req, link_addr)
+}
+
+fn transmit<RT: Runtime>(rt: &RT, options: &UdpSocketOptions, datagram: UdpDatagram) {
+    match options.link_index {
+        Some(link_index) => rt.transmit_on_link(link_index, datagram),
+        None => rt.transmit(datagram),
+    }
+}
+
+// Fails a pop with `Timeout` once it fires.
+type RecvTimer = Option<Pin<Box<dyn Future<Output = ()>>>>;
+
+/// Resolves to the next datagram's source and payload. A zero-length datagram still carries its
+/// sender, so `(Some(remote), empty)` is a real datagram while `(None, empty)` after
+/// `Shutdown::Read` means no more are coming.
 pub struct PopFuture {
     pub fd: FileDescriptor,
     listener: Result<Rc<RefCell<Listener>>, Fail>,
+    timer: RecvTimer,
 }
 
 impl Future for PopFuture {
@@ -316,26 +2453,90 @@ impl Future for PopFuture {
 
     fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
         let self_ = self.get_mut();
-        match self_.listener {
-            Err(ref e) => Poll::Ready(Err(e.clone())),
-            Ok(ref l) => {
-                let mut listener = l.borrow_mut();
-                match listener.buf.pop_front() {
-                    Some(r) => return Poll::Ready(Ok(r)),
-                    None => (),
-                }
-                let waker = ctx.waker();
-                listener.waker = Some(waker.clone());
-                Poll::Pending
-            },
+        match poll_timed(&self_.listener, &mut self_.timer, ctx) {
+            Poll::Ready(Ok(d)) => Poll::Ready(Ok((d.remote, d.data))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
         }
     }
 }
 
+pub struct PopDatagramFuture {
+    pub fd: FileDescriptor,
+    listener: Result<Rc<RefCell<Listener>>, Fail>,
+    timer: RecvTimer,
+}
+
+impl Future for PopDatagramFuture {
+    type Output = Result<ReceivedDatagram, Fail>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let self_ = self.get_mut();
+        poll_timed(&self_.listener, &mut self_.timer, ctx)
+    }
+}
+
+// A datagram that's already queued wins over a timer that's fired.
+fn poll_timed(
+    listener: &Result<Rc<RefCell<Listener>>, Fail>,
+    timer: &mut RecvTimer,
+    ctx: &mut Context,
+) -> Poll<Result<ReceivedDatagram, Fail>> {
+    if let Poll::Ready(r) = poll_datagram(listener, ctx) {
+        return Poll::Ready(r);
+    }
+    match timer {
+        Some(t) if Future::poll(t.as_mut(), ctx).is_ready() => Poll::Ready(Err(Fail::Timeout {})),
+        _ => Poll::Pending,
+    }
+}
+
+fn poll_datagram(
+    listener: &Result<Rc<RefCell<Listener>>, Fail>,
+    ctx: &mut Context,
+) -> Poll<Result<ReceivedDatagram, Fail>> {
+    match listener {
+        Err(e) => Poll::Ready(Err(e.clone())),
+        Ok(l) => {
+            let mut listener = l.borrow_mut();
+            match listener.poll_readable(ctx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            if let Some(e) = listener.error.take() {
+                return Poll::Ready(Err(e));
+            }
+            if let Some(r) = listener.pop() {
+                return Poll::Ready(Ok(r));
+            }
+            // Nothing queued on a readable socket means it was shut down for reading.
+            Poll::Ready(Ok(ReceivedDatagram {
+                remote: None,
+                local_addr: Ipv4Addr::UNSPECIFIED,
+                local_port: None,
+                ecn: Ecn::NotEct,
+                ipv4_hdr: None,
+                timestamp: None,
+                data: Bytes::empty(),
+            }))
+        },
+    }
+}
+
+fn copy_datagram(
+    datagram: &ReceivedDatagram,
+    buf: &mut [u8],
+) -> (usize, bool, Option<ipv4::Endpoint>) {
+    let len = cmp::min(buf.len(), datagram.data.len());
+    buf[..len].copy_from_slice(&datagram.data[..len]);
+    (len, len < datagram.data.len(), datagram.remote)
+}
+
 pub enum UdpOperation {
     Accept(FileDescriptor, Fail),
     Connect(FileDescriptor, Result<(), Fail>),
-    Push(FileDescriptor, Result<(), Fail>),
+    Push(FileDescriptor, Result<usize, Fail>),
     Pop(ResultFuture<PopFuture>),
 }
 
@@ -359,7 +2560,7 @@ impl UdpOperation {
             | UdpOperation::Connect(fd, Err(e))
             | UdpOperation::Accept(fd, e) => (fd, OperationResult::Failed(e)),
             UdpOperation::Connect(fd, Ok(())) => (fd, OperationResult::Connect),
-            UdpOperation::Push(fd, Ok(())) => (fd, OperationResult::Push),
+            UdpOperation::Push(fd, Ok(len)) => (fd, OperationResult::Push(len)),
 
             UdpOperation::Pop(ResultFuture {
                 future,
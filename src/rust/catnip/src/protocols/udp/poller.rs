@@ -0,0 +1,184 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    collections::waker_page::{
+        iter_set_bits,
+        WakerPage,
+        WakerPageRef,
+        WAKER_PAGE_SIZE,
+    },
+    fail::Fail,
+    file_table::FileDescriptor,
+    sync::SharedWaker,
+};
+use hashbrown::HashMap;
+use slab::Slab;
+
+/// Readiness reported by `UdpPoller::poll` for a single socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UdpEvent {
+    pub fd: FileDescriptor,
+    pub readable: bool,
+    // Sends only block once the socket's send buffer fills up, so this is only reported when a
+    // socket that returned `WouldBlock` drains below its low watermark.
+    pub writable: bool,
+}
+
+/// Slot in a poller's waker pages that a bound socket flags when it becomes readable or writable.
+pub(super) struct ReadinessSlot {
+    page: WakerPageRef,
+    subpage_ix: usize,
+}
+
+impl ReadinessSlot {
+    pub(super) fn notify(&self) {
+        self.page.notify(self.subpage_ix);
+    }
+
+    // Withdraws a notification `poll` hasn't reported yet.
+    pub(super) fn clear(&self) {
+        self.page.clear(self.subpage_ix);
+    }
+}
+
+/// Collects notifications while a batch of frames is received, then flushes them with one
+/// `notify_mask` per page instead of one `notify` per datagram.
+pub(super) struct ReadinessBatch {
+    pages: Vec<(WakerPageRef, u64)>,
+}
+
+impl ReadinessBatch {
+    pub(super) fn new() -> Self {
+        Self { pages: vec![] }
+    }
+
+    pub(super) fn add(&mut self, slot: &ReadinessSlot) {
+        let bit = 1 << slot.subpage_ix;
+        match self
+            .pages
+            .iter_mut()
+            .find(|(p, _)| WakerPageRef::ptr_eq(p, &slot.page))
+        {
+            Some((_, mask)) => *mask |= bit,
+            None => self.pages.push((slot.page.clone(), bit)),
+        }
+    }
+
+    pub(super) fn flush(self) {
+        for (page, mask) in self.pages {
+            page.notify_mask(mask);
+        }
+    }
+}
+
+/// Aggregates readiness across many UDP sockets. Each registered fd owns a pair of bits (readable,
+/// then writable) in a set of `WakerPage`s, so `poll` only does work proportional to the number of
+/// ready sockets.
+pub struct UdpPoller {
+    waker: SharedWaker,
+    pages: Vec<WakerPageRef>,
+    slots: Slab<FileDescriptor>,
+    fds: HashMap<FileDescriptor, usize>,
+}
+
+impl Default for UdpPoller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UdpPoller {
+    pub fn new() -> Self {
+        Self {
+            waker: SharedWaker::new(),
+            pages: vec![],
+            slots: Slab::new(),
+            fds: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.fds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    /// Returns the slots to notify when `fd` becomes readable and writable, respectively.
+    pub(super) fn register(
+        &mut self,
+        fd: FileDescriptor,
+    ) -> Result<(ReadinessSlot, ReadinessSlot), Fail> {
+        if self.fds.contains_key(&fd) {
+            return Err(Fail::Malformed {
+                details: "File descriptor already registered",
+            });
+        }
+        let slot = self.slots.insert(fd);
+        while 2 * slot + 1 >= self.pages.len() * WAKER_PAGE_SIZE {
+            match WakerPage::new(self.waker.clone()) {
+                Ok(page) => self.pages.push(page),
+                Err(e) => {
+                    self.slots.remove(slot);
+                    return Err(e);
+                },
+            }
+        }
+        assert!(self.fds.insert(fd, slot).is_none());
+        let readable = self.readiness_slot(2 * slot);
+        let writable = self.readiness_slot(2 * slot + 1);
+        Ok((readable, writable))
+    }
+
+    pub(super) fn deregister(&mut self, fd: FileDescriptor) -> Result<(), Fail> {
+        let slot = self.fds.remove(&fd).ok_or(Fail::Malformed {
+            details: "File descriptor not registered",
+        })?;
+        for &bit in &[2 * slot, 2 * slot + 1] {
+            let (page, subpage_ix) = self.page(bit);
+            page.clear(subpage_ix);
+        }
+        self.slots.remove(slot);
+        Ok(())
+    }
+
+    /// Returns the sockets that became readable or writable since the last call.
+    pub fn poll(&mut self) -> Vec<UdpEvent> {
+        let mut events: Vec<UdpEvent> = vec![];
+        for (page_ix, page) in self.pages.iter().enumerate() {
+            for subpage_ix in iter_set_bits(page.take_notified()) {
+                let bit = page_ix * WAKER_PAGE_SIZE + subpage_ix;
+                let fd = match self.slots.get(bit / 2) {
+                    Some(&fd) => fd,
+                    None => continue,
+                };
+                let writable = bit % 2 == 1;
+                // A socket's two bits are adjacent, so its readable event comes right before.
+                match events.last_mut() {
+                    Some(e) if e.fd == fd => e.writable |= writable,
+                    _ => events.push(UdpEvent {
+                        fd,
+                        readable: !writable,
+                        writable,
+                    }),
+                }
+            }
+        }
+        events
+    }
+
+    fn readiness_slot(&self, bit: usize) -> ReadinessSlot {
+        let (page, subpage_ix) = self.page(bit);
+        page.clear(subpage_ix);
+        ReadinessSlot {
+            page: page.clone(),
+            subpage_ix,
+        }
+    }
+
+    fn page(&self, bit: usize) -> (&WakerPageRef, usize) {
+        (&self.pages[bit / WAKER_PAGE_SIZE], bit % WAKER_PAGE_SIZE)
+    }
+}
@@ -123,3 +123,2806 @@
 //     // assert_eq!(next_hop_mtu, &0u16);
 //     // todo: validate `context`
 // }
+
+use super::{
+    Ecn,
+    PacingMode,
+    ParseError,
+    Peer,
+    ReceiveOutcome,
+    SendOpts,
+    SendStatus,
+    UdpEvent,
+    UdpPoller,
+    MAX_SOCKET_BUFFER,
+};
+use crate::{
+    engine::Protocol,
+    fail::Fail,
+    file_table::{
+        File,
+        FileTable,
+    },
+    operations::OperationResult,
+    protocols::{
+        arp,
+        ethernet2::{
+            frame::{
+                EtherType2,
+                Ethernet2Header,
+                ETHERNET2_HEADER2_SIZE,
+            },
+            MacAddress,
+        },
+        ip,
+        ipv4::{
+            self,
+            datagram::{
+                Ipv4Header,
+                DEFAULT_IPV4_TTL,
+            },
+        },
+    },
+    runtime::{
+        Runtime,
+        RxMetadata,
+    },
+    scheduler::Operation,
+    sync::{
+        BufferPool,
+        Bytes,
+        BytesMut,
+    },
+    test_helpers,
+};
+use futures::task::{
+    self,
+    noop_waker_ref,
+    ArcWake,
+    Context,
+};
+use hashbrown::HashMap;
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    net::{
+        Ipv4Addr,
+        Shutdown,
+    },
+    pin::Pin,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Arc,
+    },
+    task::Poll,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+fn port(n: u16) -> ip::Port {
+    ip::Port::try_from(n).unwrap()
+}
+
+#[test]
+fn poller_reports_only_ready_sockets() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let mut poller = UdpPoller::new();
+    let mut fds = vec![];
+    for i in 0..100 {
+        let fd = alice.socket(Protocol::Udp).unwrap();
+        let local = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(1000 + i));
+        alice.bind(fd, local).unwrap();
+        alice.udp().register(&mut poller, fd).unwrap();
+        fds.push(fd);
+    }
+    assert_eq!(poller.len(), 100);
+    assert!(poller.poll().is_empty());
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    for &i in &[3, 42, 97] {
+        let remote = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(1000 + i));
+        bob.udp().pushto(bob_fd, buf.clone(), remote).unwrap();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+
+    let mut ready: Vec<_> = poller.poll().into_iter().map(|e| e.fd).collect();
+    ready.sort();
+    assert_eq!(ready, vec![fds[3], fds[42], fds[97]]);
+    assert!(poller.poll().is_empty());
+
+    // Closing a ready socket withdraws its readiness, but it stays registered until deregistered.
+    let remote = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(1005));
+    bob.udp().pushto(bob_fd, buf, remote).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.udp().close(fds[5]).unwrap();
+    assert!(poller.poll().is_empty());
+    assert_eq!(poller.len(), 100);
+    alice.udp().deregister(&mut poller, fds[5]).unwrap();
+    assert_eq!(poller.len(), 99);
+}
+
+#[test]
+fn reuse_port_spreads_flows() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let mut fds = vec![];
+    for _ in 0..2 {
+        let fd = alice.socket(Protocol::Udp).unwrap();
+        let options = alice.udp().socket_options(fd).unwrap().reuse_port(true);
+        alice.udp().set_socket_options(fd, options).unwrap();
+        alice.bind(fd, alice_addr).unwrap();
+        fds.push(fd);
+    }
+    // Sockets that didn't opt in can't join the group.
+    let other_fd = alice.socket(Protocol::Udp).unwrap();
+    must_let!(let Err(Fail::Malformed { .. }) = alice.bind(other_fd, alice_addr));
+
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    let mut counts = vec![0; fds.len()];
+    for i in 0..16 {
+        let bob_fd = bob.socket(Protocol::Udp).unwrap();
+        let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(1000 + i));
+        bob.bind(bob_fd, bob_addr).unwrap();
+        let mut target = None;
+        for _ in 0..3 {
+            bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+            alice.receive(bob.rt().pop_frame()).unwrap();
+            let lens: Vec<_> = fds
+                .iter()
+                .map(|&fd| alice.udp().recv_queue_len(fd).unwrap())
+                .collect();
+            let ix = (0..fds.len()).find(|&j| lens[j] > counts[j]).unwrap();
+            // Every datagram in a flow lands on the same socket.
+            assert_eq!(*target.get_or_insert(ix), ix);
+            counts[ix] += 1;
+        }
+    }
+    assert_eq!(counts.iter().sum::<usize>(), 48);
+    assert!(counts.iter().all(|&c| c > 0));
+}
+
+#[test]
+fn reuse_port_follows_rx_queue() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let mut fds = vec![];
+    for _ in 0..3 {
+        let fd = alice.socket(Protocol::Udp).unwrap();
+        let options = alice.udp().socket_options(fd).unwrap().reuse_port(true);
+        alice.udp().set_socket_options(fd, options).unwrap();
+        alice.bind(fd, alice_addr).unwrap();
+        fds.push(fd);
+    }
+
+    // Each flow arrives on one queue, and lands on that queue's socket whatever its 4-tuple.
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    let mut counts = vec![0; fds.len()];
+    for i in 0..12 {
+        let bob_fd = bob.socket(Protocol::Udp).unwrap();
+        let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(1000 + i));
+        bob.bind(bob_fd, bob_addr).unwrap();
+        let queue = i as usize % 4;
+        let metadata = RxMetadata {
+            rx_queue: Some(queue),
+            ..RxMetadata::default()
+        };
+        for _ in 0..3 {
+            bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+            alice
+                .receive_with_metadata(bob.rt().pop_frame(), metadata)
+                .unwrap();
+            counts[queue % fds.len()] += 1;
+            for (j, &fd) in fds.iter().enumerate() {
+                assert_eq!(alice.udp().recv_queue_len(fd).unwrap(), counts[j]);
+            }
+        }
+    }
+    assert_eq!(counts, vec![18, 9, 9]);
+}
+
+#[test]
+fn receive_after_close() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let mut fds = vec![];
+    for _ in 0..2 {
+        let fd = alice.socket(Protocol::Udp).unwrap();
+        let options = alice.udp().socket_options(fd).unwrap().reuse_port(true);
+        alice.udp().set_socket_options(fd, options).unwrap();
+        alice.bind(fd, alice_addr).unwrap();
+        fds.push(fd);
+    }
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    let mut frames = vec![];
+    for i in 0..8 {
+        let bob_fd = bob.socket(Protocol::Udp).unwrap();
+        let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(1000 + i));
+        bob.bind(bob_fd, bob_addr).unwrap();
+        bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+        frames.push(bob.rt().pop_frame());
+    }
+
+    // Flows that hashed to the closed socket move to the one that's left.
+    alice.udp().close(fds[0]).unwrap();
+    for frame in &frames {
+        alice.receive(frame.clone()).unwrap();
+    }
+    assert_eq!(alice.udp().recv_queue_len(fds[1]).unwrap(), frames.len());
+
+    alice.udp().close(fds[1]).unwrap();
+    for frame in frames {
+        must_let!(let Err(Fail::Malformed { .. }) = alice.receive(frame));
+    }
+}
+
+#[test]
+fn pop_needs_bind() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let fd = alice.socket(Protocol::Udp).unwrap();
+    let mut fut = alice.udp().pop(fd);
+    let r = Future::poll(Pin::new(&mut fut), &mut ctx);
+    must_let!(let Poll::Ready(Err(Fail::Malformed { details: "Socket not bound" })) = r);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    alice.bind(fd, alice_addr).unwrap();
+    let mut fut = alice.udp().pop(fd);
+    assert!(Future::poll(Pin::new(&mut fut), &mut ctx).is_pending());
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok((_, data))) = Future::poll(Pin::new(&mut fut), &mut ctx));
+    assert_eq!(data, buf);
+}
+
+#[test]
+fn recv_queue_len_and_bytes() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    assert!(alice.udp().recv_queue_len(alice_fd).is_err());
+    assert!(alice.udp().recv_queue_bytes(alice_fd).is_err());
+    alice.bind(alice_fd, alice_addr).unwrap();
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 0);
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    for size in &[1, 10, 100] {
+        let buf = BytesMut::from(&vec![0u8; *size][..]).freeze();
+        bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 3);
+    assert_eq!(alice.udp().recv_queue_bytes(alice_fd).unwrap(), 111);
+}
+
+#[test]
+fn counts_malformed_segments() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    let (_, payload) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, segment) = Ipv4Header::parse(payload).unwrap();
+    let metadata = RxMetadata::default();
+
+    let (truncated, _) = segment.clone().split(4);
+    let r = alice.udp().receive(&ipv4_hdr, truncated, &metadata);
+    must_let!(let Err(Fail::Malformed { .. }) = r);
+    let mut corrupted = BytesMut::from(&segment[..]);
+    corrupted[8] ^= 0xff;
+    let corrupted = corrupted.freeze();
+    let r = alice.udp().receive(&ipv4_hdr, corrupted, &metadata);
+    must_let!(let Err(Fail::Malformed { .. }) = r);
+    alice.udp().receive(&ipv4_hdr, segment, &metadata).unwrap();
+
+    let stats = alice.udp().stats();
+    assert_eq!(stats.truncated, 1);
+    assert_eq!(stats.bad_checksum, 1);
+    assert_eq!(stats.bad_length, 0);
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 1);
+}
+
+#[test]
+fn hardware_validated_checksums() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+
+    // Break both the IPv4 header checksum and the UDP checksum.
+    let mut frame = BytesMut::from(&bob.rt().pop_frame()[..]);
+    let ipv4_start = ETHERNET2_HEADER2_SIZE;
+    frame[ipv4_start + 10] ^= 0x01;
+    frame[ipv4_start + 20 + 6] ^= 0x01;
+    let frame = frame.freeze();
+    let r = alice.receive(frame.clone());
+    must_let!(let Err(Fail::Malformed { .. }) = r);
+
+    // A runtime whose NIC checked them says so, and we take its word for it.
+    let metadata = RxMetadata {
+        checksum_validated: true,
+        ..RxMetadata::default()
+    };
+    alice.receive_with_metadata(frame, metadata).unwrap();
+    assert_eq!(alice.udp().stats().bad_checksum, 0);
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 1);
+}
+
+#[test]
+fn length_must_match_payload() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    let (_, payload) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, segment) = Ipv4Header::parse(payload).unwrap();
+    let metadata = RxMetadata::default();
+
+    // Claiming more or fewer bytes than the IPv4 payload holds is rejected.
+    for &length in &[12u8, 10] {
+        let mut mismatched = BytesMut::from(&segment[..]);
+        mismatched[5] = length;
+        let r = alice
+            .udp()
+            .receive(&ipv4_hdr, mismatched.freeze(), &metadata);
+        must_let!(let Err(Fail::Malformed { details: "UDP length mismatch" }) = r);
+    }
+    assert_eq!(segment[5], 11);
+    alice.udp().receive(&ipv4_hdr, segment, &metadata).unwrap();
+
+    assert_eq!(alice.udp().stats().bad_length, 2);
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 1);
+}
+
+#[test]
+fn preallocated_recv_queue() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let default_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let default_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(default_fd, default_addr).unwrap();
+    let sized_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(81));
+    let sized_fd = alice.socket(Protocol::Udp).unwrap();
+    let options = alice
+        .udp()
+        .socket_options(sized_fd)
+        .unwrap()
+        .recv_buffer_capacity(64);
+    alice.udp().set_socket_options(sized_fd, options).unwrap();
+    alice.bind(sized_fd, sized_addr).unwrap();
+
+    // Count how often each queue reallocates over a 64-datagram burst.
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    let mut reallocs = vec![];
+    for &(fd, addr) in &[(default_fd, default_addr), (sized_fd, sized_addr)] {
+        let mut capacity = alice.udp().recv_queue_capacity(fd).unwrap();
+        let mut count = 0;
+        for _ in 0..64 {
+            bob.udp().pushto(bob_fd, buf.clone(), addr).unwrap();
+            alice.receive(bob.rt().pop_frame()).unwrap();
+            let c = alice.udp().recv_queue_capacity(fd).unwrap();
+            if c != capacity {
+                capacity = c;
+                count += 1;
+            }
+        }
+        reallocs.push(count);
+    }
+    assert!(reallocs[0] > 0);
+    assert_eq!(reallocs[1], 0);
+}
+
+#[test]
+fn recv_into_truncates() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(8080));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(bob_fd, bob_addr).unwrap();
+
+    let mut buf = [0u8; 4];
+    must_let!(let Err(Fail::WouldBlock {}) = alice.udp().recv_into(alice_fd, &mut buf));
+    for data in &[&[1u8, 2, 3, 4, 5, 6, 7, 8][..], &[9, 10][..]] {
+        let data = BytesMut::from(*data).freeze();
+        bob.udp().pushto(bob_fd, data, alice_addr).unwrap();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+
+    let r = alice.udp().recv_into(alice_fd, &mut buf).unwrap();
+    assert_eq!(r, (4, true, Some(bob_addr)));
+    assert_eq!(buf, [1, 2, 3, 4]);
+    // The rest of the first datagram is gone, not left for the next read.
+    let r = alice.udp().recv_into(alice_fd, &mut buf).unwrap();
+    assert_eq!(r, (2, false, Some(bob_addr)));
+    assert_eq!(&buf[..2], &[9, 10]);
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 0);
+}
+
+#[test]
+fn recv_header() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    let (_, payload) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    let (mut ipv4_hdr, segment) = Ipv4Header::parse(payload).unwrap();
+    ipv4_hdr.time_to_live = 17;
+    let metadata = RxMetadata::default();
+
+    // Headers are only kept once the socket asks for them.
+    alice
+        .udp()
+        .receive(&ipv4_hdr, segment.clone(), &metadata)
+        .unwrap();
+    let options = alice
+        .udp()
+        .socket_options(alice_fd)
+        .unwrap()
+        .recv_header(true);
+    alice.udp().set_socket_options(alice_fd, options).unwrap();
+    alice.udp().receive(&ipv4_hdr, segment, &metadata).unwrap();
+
+    let mut fut = alice.udp().pop_datagram(alice_fd);
+    must_let!(let Poll::Ready(Ok(datagram)) = Future::poll(Pin::new(&mut fut), &mut ctx));
+    assert!(datagram.ipv4_hdr.is_none());
+    let mut fut = alice.udp().pop_datagram(alice_fd);
+    must_let!(let Poll::Ready(Ok(datagram)) = Future::poll(Pin::new(&mut fut), &mut ctx));
+    must_let!(let Some(hdr) = datagram.ipv4_hdr);
+    assert_eq!(hdr.time_to_live, 17);
+    assert_eq!(hdr.src_addr, test_helpers::BOB_IPV4);
+    assert_eq!(hdr.identification, ipv4_hdr.identification);
+}
+
+#[test]
+fn recv_timestamp() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let options = alice
+        .udp()
+        .socket_options(alice_fd)
+        .unwrap()
+        .recv_timestamp(true);
+    alice.udp().set_socket_options(alice_fd, options).unwrap();
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    let frame = bob.rt().pop_frame();
+    now += Duration::from_millis(7);
+    alice.rt().advance_clock(now);
+    alice.receive(frame).unwrap();
+    alice.rt().advance_clock(now + Duration::from_millis(1));
+
+    // Only datagrams received after the option was set carry a timestamp.
+    let mut fut = alice.udp().pop_datagram(alice_fd);
+    must_let!(let Poll::Ready(Ok(datagram)) = Future::poll(Pin::new(&mut fut), &mut ctx));
+    assert!(datagram.timestamp.is_none());
+    let mut fut = alice.udp().pop_datagram(alice_fd);
+    must_let!(let Poll::Ready(Ok(datagram)) = Future::poll(Pin::new(&mut fut), &mut ctx));
+    assert_eq!(datagram.timestamp, Some(now));
+}
+
+#[test]
+fn configured_ttl() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+    let frame = bob.rt().pop_frame();
+    let (_, payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+    let (ipv4_hdr, _) = Ipv4Header::parse(payload).unwrap();
+    assert_eq!(ipv4_hdr.time_to_live, DEFAULT_IPV4_TTL);
+    alice.receive(frame).unwrap();
+
+    let options = ipv4::Options::default().default_ttl(7);
+    bob.rt().set_ipv4_options(options);
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    let frame = bob.rt().pop_frame();
+    let (_, payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+    let (ipv4_hdr, _) = Ipv4Header::parse(payload).unwrap();
+    assert_eq!(ipv4_hdr.time_to_live, 7);
+    alice.receive(frame).unwrap();
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 2);
+}
+
+#[test]
+fn per_datagram_overrides() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let options = bob.udp().socket_options(bob_fd).unwrap().ecn(Ecn::Ect1);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    let pop_ipv4_hdr = || {
+        let (_, payload) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+        Ipv4Header::parse(payload).unwrap().0
+    };
+
+    for ttl in 1..4 {
+        let send_opts = SendOpts {
+            ttl: Some(ttl),
+            ..Default::default()
+        };
+        bob.udp()
+            .pushto_with(bob_fd, buf.clone(), alice_addr, send_opts)
+            .unwrap();
+        let ipv4_hdr = pop_ipv4_hdr();
+        assert_eq!(ipv4_hdr.time_to_live, ttl);
+        assert_eq!(ipv4_hdr.ecn, Ecn::Ect1.bits());
+    }
+
+    // ECN wins over the TOS byte's low bits.
+    let send_opts = SendOpts {
+        tos: Some(0xb8 | 1),
+        ecn: Some(Ecn::Ce),
+        ..Default::default()
+    };
+    bob.udp()
+        .pushto_with(bob_fd, buf.clone(), alice_addr, send_opts)
+        .unwrap();
+    let ipv4_hdr = pop_ipv4_hdr();
+    assert_eq!((ipv4_hdr.dscp, ipv4_hdr.ecn), (0xb8 >> 2, Ecn::Ce.bits()));
+
+    // The socket itself is left as it was.
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    let ipv4_hdr = pop_ipv4_hdr();
+    assert_eq!(ipv4_hdr.time_to_live, DEFAULT_IPV4_TTL);
+    assert_eq!((ipv4_hdr.dscp, ipv4_hdr.ecn), (0, Ecn::Ect1.bits()));
+}
+
+#[test]
+fn identification_increments() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    let mut ids = vec![];
+    for _ in 0..3 {
+        bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+        let frame = bob.rt().pop_frame();
+        let (_, payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+        let (ipv4_hdr, _) = Ipv4Header::parse(payload).unwrap();
+        ids.push(ipv4_hdr.identification);
+        alice.receive(frame).unwrap();
+    }
+    assert_eq!(ids[1], ids[0].wrapping_add(1));
+    assert_eq!(ids[2], ids[1].wrapping_add(1));
+
+    let rt = bob.rt();
+    let next = rt.next_ipv4_identification();
+    for _ in 0..u16::MAX {
+        rt.next_ipv4_identification();
+    }
+    assert_eq!(rt.next_ipv4_identification(), next);
+}
+
+#[test]
+fn ecn_round_trip() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let options = bob.udp().socket_options(bob_fd).unwrap().ecn(Ecn::Ect0);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+
+    let mut fut = alice.udp().pop_datagram(alice_fd);
+    must_let!(let Poll::Ready(Ok(datagram)) = Future::poll(Pin::new(&mut fut), &mut ctx));
+    assert_eq!(datagram.ecn, Ecn::Ect0);
+    assert_eq!(&datagram.data[..], &[1, 2, 3]);
+}
+
+#[test]
+fn multicast_skips_arp() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+
+    let group = Ipv4Addr::new(224, 0, 0, 251);
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let options = bob
+        .udp()
+        .socket_options(bob_fd)
+        .unwrap()
+        .join_multicast(group);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp()
+        .pushto(bob_fd, buf, ipv4::Endpoint::new(group, port(5353)))
+        .unwrap();
+
+    // The datagram goes out immediately rather than waiting on an ARP query.
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    assert_eq!(ethernet2_hdr.ether_type, EtherType2::Ipv4);
+    assert_eq!(
+        ethernet2_hdr.dst_addr,
+        MacAddress::from_ipv4_multicast(group)
+    );
+    assert!(bob.rt().pop_frame_opt().is_none());
+}
+
+#[test]
+fn multicast_loop() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+
+    let group = Ipv4Addr::new(224, 0, 0, 251);
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let options = bob
+        .udp()
+        .socket_options(bob_fd)
+        .unwrap()
+        .join_multicast(group);
+    assert!(options.multicast_loop);
+    bob.udp()
+        .set_socket_options(bob_fd, options.clone())
+        .unwrap();
+    let wildcard = ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port(5353));
+    bob.bind(bob_fd, wildcard).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    let to = ipv4::Endpoint::new(group, port(5353));
+
+    // Looped back to ourselves as well as sent on the wire.
+    bob.udp().pushto(bob_fd, buf.clone(), to).unwrap();
+    bob.rt().pop_frame();
+    let mut data = [0u8; 3];
+    let r = bob.udp().recv_into(bob_fd, &mut data).unwrap();
+    let from = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(5353));
+    assert_eq!(r, (3, false, Some(from)));
+
+    let options = options.multicast_loop(false);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    bob.udp().pushto(bob_fd, buf, to).unwrap();
+    bob.rt().pop_frame();
+    assert_eq!(bob.udp().recv_queue_len(bob_fd).unwrap(), 0);
+}
+
+#[test]
+fn multicast_ttl() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+
+    let group = Ipv4Addr::new(224, 0, 0, 251);
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let options = bob
+        .udp()
+        .socket_options(bob_fd)
+        .unwrap()
+        .join_multicast(group);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    let multicast = ipv4::Endpoint::new(group, port(5353));
+    let unicast = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+
+    let mut ttls = vec![];
+    for &ttl in &[None, Some(4)] {
+        if let Some(ttl) = ttl {
+            let options = bob.udp().socket_options(bob_fd).unwrap().multicast_ttl(ttl);
+            bob.udp().set_socket_options(bob_fd, options).unwrap();
+        }
+        for &to in &[multicast, unicast] {
+            bob.udp().pushto(bob_fd, buf.clone(), to).unwrap();
+            let (_, payload) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+            let (ipv4_hdr, _) = Ipv4Header::parse(payload).unwrap();
+            ttls.push(ipv4_hdr.time_to_live);
+        }
+    }
+    assert_eq!(ttls, vec![1, DEFAULT_IPV4_TTL, 4, DEFAULT_IPV4_TTL]);
+    assert_eq!(bob.udp().socket_options(bob_fd).unwrap().multicast_ttl, 4);
+}
+
+#[test]
+fn group_sends_need_opt_in() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let broadcast = ipv4::Endpoint::new(Ipv4Addr::BROADCAST, port(67));
+    let group = Ipv4Addr::new(224, 0, 0, 251);
+    let multicast = ipv4::Endpoint::new(group, port(5353));
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let r = bob.udp().pushto(bob_fd, buf.clone(), broadcast);
+    must_let!(let Err(Fail::PermissionDenied { .. }) = r);
+    let r = bob.udp().pushto(bob_fd, buf.clone(), multicast);
+    must_let!(let Err(Fail::PermissionDenied { .. }) = r);
+    assert!(bob.rt().pop_frame_opt().is_none());
+
+    let options = bob
+        .udp()
+        .socket_options(bob_fd)
+        .unwrap()
+        .broadcast(true)
+        .join_multicast(group);
+    bob.udp()
+        .set_socket_options(bob_fd, options.clone())
+        .unwrap();
+    let sent = bob.udp().pushto(bob_fd, buf.clone(), broadcast).unwrap();
+    assert_eq!(sent, buf.len());
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    assert_eq!(ethernet2_hdr.dst_addr, MacAddress::broadcast());
+    bob.udp().pushto(bob_fd, buf.clone(), multicast).unwrap();
+    bob.rt().pop_frame();
+
+    // Even opted in, a connected socket can't send to a group.
+    let connected_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.udp().set_socket_options(connected_fd, options).unwrap();
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    bob.udp().connect(connected_fd, alice_addr).unwrap();
+    let r = bob.udp().pushto(connected_fd, buf.clone(), broadcast);
+    must_let!(let Err(Fail::PermissionDenied { .. }) = r);
+    let r = bob.udp().pushto(connected_fd, buf, multicast);
+    must_let!(let Err(Fail::PermissionDenied { .. }) = r);
+    let r = bob.udp().connect(bob_fd, broadcast);
+    must_let!(let Err(Fail::PermissionDenied { .. }) = r);
+}
+
+#[test]
+fn connected_push_tracks_arp_changes() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.udp().connect(bob_fd, alice_addr).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    for _ in 0..2 {
+        bob.udp().push(bob_fd, buf.clone()).unwrap();
+        let (ethernet2_hdr, _) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+        assert_eq!(ethernet2_hdr.dst_addr, test_helpers::ALICE_MAC);
+    }
+
+    // Alice moves to carrie's link address; the cached address must not outlive the change.
+    let mut cache = HashMap::new();
+    cache.insert(test_helpers::ALICE_IPV4, test_helpers::CARRIE_MAC);
+    bob.import_arp_cache(cache);
+    bob.udp().push(bob_fd, buf).unwrap();
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    assert_eq!(ethernet2_hdr.dst_addr, test_helpers::CARRIE_MAC);
+}
+
+#[test]
+fn connected_filters_by_source() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_other = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(81));
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(8080));
+    let connected_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(connected_fd, alice_addr).unwrap();
+    alice.udp().connect(connected_fd, bob_addr).unwrap();
+    let unconnected_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(unconnected_fd, alice_other).unwrap();
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(bob_fd, bob_addr).unwrap();
+    let anon_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    // Datagrams without a source port only reach sockets that aren't connected.
+    bob.udp().pushto(anon_fd, buf.clone(), alice_addr).unwrap();
+    must_let!(let Err(Fail::Ignored { .. }) = alice.receive(bob.rt().pop_frame()));
+    assert_eq!(alice.udp().recv_queue_len(connected_fd).unwrap(), 0);
+    bob.udp().pushto(anon_fd, buf.clone(), alice_other).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.udp().recv_queue_len(unconnected_fd).unwrap(), 1);
+
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.udp().recv_queue_len(connected_fd).unwrap(), 1);
+}
+
+#[test]
+fn socket_state() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(8080));
+
+    let fd = alice.socket(Protocol::Udp).unwrap();
+    let state = alice.udp().socket_state(fd).unwrap();
+    assert!(!state.is_bound && !state.is_connected);
+    assert_eq!((state.local, state.remote), (None, None));
+
+    alice.bind(fd, alice_addr).unwrap();
+    let state = alice.udp().socket_state(fd).unwrap();
+    assert!(state.is_bound && !state.is_connected);
+    assert_eq!((state.local, state.remote), (Some(alice_addr), None));
+
+    alice.udp().connect(fd, bob_addr).unwrap();
+    let state = alice.udp().socket_state(fd).unwrap();
+    assert!(state.is_bound && state.is_connected);
+    assert_eq!(state.remote, Some(bob_addr));
+    assert!(!state.options.broadcast);
+
+    alice.udp().close(fd).unwrap();
+    must_let!(let Err(Fail::BadFileDescriptor {}) = alice.udp().socket_state(fd));
+}
+
+#[test]
+fn reconnect() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(8080));
+    let bob_other = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(8081));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(bob_fd, bob_addr).unwrap();
+    let bob_other_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(bob_other_fd, bob_other).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    // Connecting again replaces the peer, both for sends and for filtering receives.
+    alice.udp().connect(alice_fd, bob_addr).unwrap();
+    alice.udp().connect(alice_fd, bob_other).unwrap();
+    alice.udp().push(alice_fd, buf.clone()).unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(bob.udp().recv_queue_len(bob_fd).unwrap(), 0);
+    assert_eq!(bob.udp().recv_queue_len(bob_other_fd).unwrap(), 1);
+    bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+    must_let!(let Err(Fail::Ignored { .. }) = alice.receive(bob.rt().pop_frame()));
+
+    // Connecting to the unspecified address dissolves the connection.
+    let unspecified = ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port(1));
+    alice.udp().connect(alice_fd, unspecified).unwrap();
+    must_let!(let Err(Fail::Malformed { .. }) = alice.udp().push(alice_fd, buf.clone()));
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 1);
+}
+
+#[test]
+fn disconnect() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(8080));
+    let carrie_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(8080));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    must_let!(let Err(Fail::Malformed { .. }) = alice.udp().disconnect(alice_fd));
+    alice.udp().connect(alice_fd, bob_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(bob_fd, bob_addr).unwrap();
+    let carrie_fd = carrie.socket(Protocol::Udp).unwrap();
+    carrie.bind(carrie_fd, carrie_addr).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    carrie
+        .udp()
+        .pushto(carrie_fd, buf.clone(), alice_addr)
+        .unwrap();
+    let frame = carrie.rt().pop_frame();
+    must_let!(let Err(Fail::Ignored { .. }) = alice.receive(frame.clone()));
+
+    // Once disconnected, anyone's datagrams get through and sends need a destination again.
+    alice.udp().disconnect(alice_fd).unwrap();
+    alice.receive(frame).unwrap();
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 1);
+    must_let!(let Err(Fail::Malformed { .. }) = alice.udp().push(alice_fd, buf.clone()));
+    alice.udp().pushto(alice_fd, buf, bob_addr).unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(bob.udp().recv_queue_len(bob_fd).unwrap(), 1);
+}
+
+#[test]
+fn connect_resolved() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.import_arp_cache(HashMap::new());
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    // Once resolved, the first push goes straight out.
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let fut = bob.udp().connect_resolved(bob_fd, alice_addr);
+    futures::pin_mut!(fut);
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(fut.as_mut(), &mut ctx));
+    bob.udp().push(bob_fd, buf.clone()).unwrap();
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    assert_eq!(ethernet2_hdr.dst_addr, test_helpers::ALICE_MAC);
+
+    // Nobody answers for this one, so the socket is left unconnected.
+    let nobody = ipv4::Endpoint::new(Ipv4Addr::new(192, 168, 1, 99), port(80));
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    let fut = bob.udp().connect_resolved(fd, nobody);
+    futures::pin_mut!(fut);
+    let options = bob.rt().arp_options();
+    for _ in 0..options.retry_count + 1 {
+        assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+        now += options.request_timeout;
+        bob.rt().advance_clock(now);
+    }
+    let r = Future::poll(fut.as_mut(), &mut ctx);
+    must_let!(let Poll::Ready(Err(Fail::HostUnreachable {})) = r);
+    must_let!(let Err(Fail::Malformed { .. }) = bob.udp().push(fd, buf));
+}
+
+#[test]
+fn shutdown_write() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(bob_fd, bob_addr).unwrap();
+    bob.udp().connect(bob_fd, alice_addr).unwrap();
+    bob.udp().shutdown(bob_fd, Shutdown::Write).unwrap();
+
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    must_let!(let Err(Fail::Shutdown {}) = bob.udp().push(bob_fd, buf.clone()));
+    must_let!(let Err(Fail::Shutdown {}) = bob.udp().pushto(bob_fd, buf.clone(), alice_addr));
+    assert!(bob.rt().pop_frame_opt().is_none());
+
+    // Receiving still works.
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.udp().pushto(alice_fd, buf, bob_addr).unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(bob.udp().recv_queue_len(bob_fd).unwrap(), 1);
+}
+
+#[test]
+fn shutdown_read() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(bob_fd, bob_addr).unwrap();
+
+    let mut fut = alice.udp().pop(alice_fd);
+    assert!(Future::poll(Pin::new(&mut fut), &mut ctx).is_pending());
+    alice.udp().shutdown(alice_fd, Shutdown::Read).unwrap();
+    let result = Future::poll(Pin::new(&mut fut), &mut ctx);
+    must_let!(let Poll::Ready(Ok((None, data))) = result);
+    assert!(data.is_empty());
+
+    // Nothing new is queued, but sending still works.
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+    must_let!(let Err(Fail::Ignored { .. }) = alice.receive(bob.rt().pop_frame()));
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 0);
+    alice.udp().pushto(alice_fd, buf, bob_addr).unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(bob.udp().recv_queue_len(bob_fd).unwrap(), 1);
+}
+
+#[test]
+fn max_datagram_size() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = |len: usize| BytesMut::zeroed(len).freeze();
+
+    bob.udp().pushto(fd, buf(65507), alice_addr).unwrap();
+    bob.rt().pop_frame();
+    let r = bob.udp().pushto(fd, buf(65508), alice_addr);
+    must_let!(let Err(Fail::MessageTooLong {}) = r);
+    assert!(bob.rt().pop_frame_opt().is_none());
+
+    // Without fragmentation, the datagram has to fit in one frame.
+    let options = bob.udp().socket_options(fd).unwrap().dont_fragment(true);
+    bob.udp().set_socket_options(fd, options).unwrap();
+    bob.udp().connect(fd, alice_addr).unwrap();
+    bob.udp().push(fd, buf(1472)).unwrap();
+    bob.rt().pop_frame();
+    must_let!(let Err(Fail::MessageTooLong {}) = bob.udp().push(fd, buf(1473)));
+    assert_eq!(bob.udp().send_queue_bytes(fd).unwrap(), 0);
+}
+
+#[test]
+fn zero_length_datagram() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(8080));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(bob_fd, bob_addr).unwrap();
+
+    bob.udp()
+        .pushto(bob_fd, Bytes::empty(), alice_addr)
+        .unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 1);
+    assert_eq!(alice.udp().recv_queue_bytes(alice_fd).unwrap(), 0);
+
+    let mut fut = alice.udp().pop(alice_fd);
+    let result = Future::poll(Pin::new(&mut fut), &mut ctx);
+    must_let!(let Poll::Ready(Ok((Some(remote), data))) = result);
+    assert_eq!(remote, bob_addr);
+    assert!(data.is_empty());
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 0);
+}
+
+#[test]
+fn send_buffer_watermarks() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    bob.import_arp_cache(HashMap::new());
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    bob.bind(bob_fd, bob_addr).unwrap();
+    let carrie_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(80));
+    bob.udp().connect(bob_fd, carrie_addr).unwrap();
+    let options = bob.udp().socket_options(bob_fd).unwrap().send_buffer(10, 5);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    let mut poller = UdpPoller::new();
+    bob.udp().register(&mut poller, bob_fd).unwrap();
+
+    // Carrie isn't in the ARP cache, so sends queue up until the high watermark.
+    let buf = BytesMut::from(&[0u8; 6][..]).freeze();
+    bob.udp().push(bob_fd, buf.clone()).unwrap();
+    must_let!(let Err(Fail::WouldBlock {}) = bob.udp().push(bob_fd, buf.clone()));
+    assert_eq!(bob.udp().send_queue_bytes(bob_fd).unwrap(), 6);
+    assert!(poller.poll().is_empty());
+
+    // Resolving carrie drains the queue, which makes the socket writable again.
+    bob.rt().poll_scheduler();
+    carrie.receive(bob.rt().pop_frame()).unwrap();
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    assert_eq!(ethernet2_hdr.dst_addr, test_helpers::CARRIE_MAC);
+    assert_eq!(bob.udp().send_queue_bytes(bob_fd).unwrap(), 0);
+    assert_eq!(
+        poller.poll(),
+        vec![UdpEvent {
+            fd: bob_fd,
+            readable: false,
+            writable: true,
+        }]
+    );
+    bob.udp().push(bob_fd, buf).unwrap();
+}
+
+#[test]
+fn close_graceful_drains_queue() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    bob.import_arp_cache(HashMap::new());
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let carrie_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(80));
+    let buf = BytesMut::from(&[0u8; 6][..]).freeze();
+
+    // A plain `close` abandons the datagram that's waiting on ARP.
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    bob.udp().pushto(fd, buf.clone(), carrie_addr).unwrap();
+    bob.udp().close(fd).unwrap();
+    bob.rt().poll_scheduler();
+    carrie.receive(bob.rt().pop_frame()).unwrap();
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    assert!(bob.rt().pop_frame_opt().is_none());
+
+    // `close_graceful` holds the fd until the queued datagram goes out.
+    bob.import_arp_cache(HashMap::new());
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.udp().pushto(bob_fd, buf.clone(), carrie_addr).unwrap();
+    let mut close = bob.udp().close_graceful(bob_fd, Duration::from_secs(1));
+    futures::pin_mut!(close);
+    assert!(Future::poll(close.as_mut(), &mut ctx).is_pending());
+    must_let!(let Err(Fail::Shutdown {}) = bob.udp().pushto(bob_fd, buf, carrie_addr));
+
+    bob.rt().poll_scheduler();
+    carrie.receive(bob.rt().pop_frame()).unwrap();
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    assert_eq!(ethernet2_hdr.dst_addr, test_helpers::CARRIE_MAC);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(close.as_mut(), &mut ctx));
+    assert!(bob.udp().send_queue_bytes(bob_fd).is_err());
+}
+
+#[test]
+fn slow_arp_does_not_block_others() {
+    let mut now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    bob.import_arp_cache(HashMap::new());
+    let nobody = ipv4::Endpoint::new(Ipv4Addr::new(192, 168, 1, 99), port(80));
+    let carrie_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(80));
+    let buf = BytesMut::from(&[0u8; 6][..]).freeze();
+
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    let options = bob
+        .udp()
+        .socket_options(fd)
+        .unwrap()
+        .arp_timeout(Duration::from_secs(2));
+    let r = bob
+        .udp()
+        .set_socket_options(fd, options.clone().arp_timeout(Duration::new(0, 0)));
+    must_let!(let Err(Fail::Invalid { .. }) = r);
+    bob.udp().set_socket_options(fd, options).unwrap();
+    bob.udp().pushto(fd, buf.clone(), nobody).unwrap();
+    bob.udp().pushto(fd, buf.clone(), carrie_addr).unwrap();
+
+    // Both destinations are resolved at once, so carrie's answer lets her datagram out while
+    // nobody's is still waiting.
+    bob.rt().poll_scheduler();
+    while let Some(frame) = bob.rt().pop_frame_opt() {
+        let _ = carrie.receive(frame);
+    }
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    assert_eq!(ethernet2_hdr.dst_addr, test_helpers::CARRIE_MAC);
+    assert_eq!(bob.udp().send_queue_bytes(fd).unwrap(), buf.len());
+
+    // The socket's ARP timeout gives up on nobody before ARP's own retries run out.
+    for _ in 0..2 {
+        now += Duration::from_secs(1);
+        bob.rt().advance_clock(now);
+        bob.rt().poll_scheduler();
+    }
+    assert_eq!(bob.udp().send_queue_bytes(fd).unwrap(), 0);
+
+    // Trying again starts a fresh resolution.
+    while bob.rt().pop_frame_opt().is_some() {}
+    bob.udp().pushto(fd, buf, nobody).unwrap();
+    bob.rt().poll_scheduler();
+    assert!(bob.rt().pop_frame_opt().is_some());
+}
+
+#[test]
+fn deferred_send_error() {
+    let mut now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let nobody = ipv4::Endpoint::new(Ipv4Addr::new(192, 168, 1, 99), port(80));
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    bob.udp().pushto(fd, buf, nobody).unwrap();
+    bob.rt().poll_scheduler();
+    assert!(bob.udp().take_send_error(fd).unwrap().is_none());
+
+    let options = bob.rt().arp_options();
+    for _ in 0..options.retry_count + 1 {
+        now += options.request_timeout;
+        bob.rt().advance_clock(now);
+        bob.rt().poll_scheduler();
+    }
+    must_let!(let Some((remote, _)) = bob.udp().take_send_error(fd).unwrap());
+    assert_eq!(remote, nobody);
+    assert!(bob.udp().take_send_error(fd).unwrap().is_none());
+}
+
+#[test]
+fn drain_pending() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let nobody = ipv4::Endpoint::new(Ipv4Addr::new(192, 168, 1, 99), port(80));
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let buf = |b: u8| BytesMut::from(&[b][..]).freeze();
+
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let other_fd = alice.socket(Protocol::Udp).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    for b in 0..2 {
+        bob.udp().pushto(bob_fd, buf(b), alice_addr).unwrap();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+    alice.udp().pushto(alice_fd, buf(2), nobody).unwrap();
+    alice.udp().pushto(other_fd, buf(3), nobody).unwrap();
+    alice.rt().poll_scheduler();
+
+    let pending = alice.udp().drain_pending(alice_fd).unwrap();
+    let received: Vec<_> = pending.received.iter().map(|d| d.data[0]).collect();
+    assert_eq!(received, vec![0, 1]);
+    assert_eq!(pending.unsent.len(), 1);
+    assert_eq!(pending.unsent[0], (nobody, buf(2)));
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 0);
+    assert_eq!(alice.udp().send_queue_bytes(alice_fd).unwrap(), 0);
+
+    // Other sockets keep theirs.
+    assert_eq!(alice.udp().send_queue_bytes(other_fd).unwrap(), 1);
+    let pending = alice.udp().drain_pending(alice_fd).unwrap();
+    assert!(pending.received.is_empty() && pending.unsent.is_empty());
+}
+
+#[test]
+fn deferred_sends_keep_order() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    let mut cache = HashMap::new();
+    cache.insert(test_helpers::ALICE_IPV4, test_helpers::ALICE_MAC);
+    bob.import_arp_cache(cache);
+    let nobody = ipv4::Endpoint::new(Ipv4Addr::new(192, 168, 1, 99), port(80));
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let carrie_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(80));
+    let carrie_fd = carrie.socket(Protocol::Udp).unwrap();
+    carrie.bind(carrie_fd, carrie_addr).unwrap();
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = |b: u8| BytesMut::from(&[b][..]).freeze();
+
+    // A destination we can already reach doesn't wait on the ones that are resolving.
+    bob.udp().pushto(fd, buf(0), nobody).unwrap();
+    bob.udp().pushto(fd, buf(1), carrie_addr).unwrap();
+    bob.udp().pushto(fd, buf(2), alice_addr).unwrap();
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    assert_eq!(ethernet2_hdr.dst_addr, test_helpers::ALICE_MAC);
+
+    // Once carrie answers, a new send to her still queues behind the one waiting on her.
+    bob.rt().poll_scheduler();
+    while let Some(frame) = bob.rt().pop_frame_opt() {
+        let _ = carrie.receive(frame);
+    }
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    bob.udp().pushto(fd, buf(3), carrie_addr).unwrap();
+    assert!(bob.rt().pop_frame_opt().is_none());
+
+    bob.rt().poll_scheduler();
+    carrie.receive(bob.rt().pop_frame()).unwrap();
+    carrie.receive(bob.rt().pop_frame()).unwrap();
+    let mut data = [0u8; 1];
+    for &expected in &[1, 3] {
+        carrie.udp().recv_into(carrie_fd, &mut data).unwrap();
+        assert_eq!(data[0], expected);
+    }
+    assert_eq!(bob.udp().send_queue_bytes(fd).unwrap(), 1);
+}
+
+#[test]
+fn fast_and_deferred_paths_match() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.import_arp_cache(HashMap::new());
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(fd, ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(8080)))
+        .unwrap();
+    let options = bob
+        .udp()
+        .socket_options(fd)
+        .unwrap()
+        .ecn(Ecn::Ect0)
+        .dont_fragment(true);
+    bob.udp().set_socket_options(fd, options).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    // The first send waits on ARP, and the second goes straight out.
+    bob.udp().pushto(fd, buf.clone(), alice_addr).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let deferred = bob.rt().pop_frame();
+    bob.udp().pushto(fd, buf, alice_addr).unwrap();
+    let fast = bob.rt().pop_frame();
+
+    // Only the IPv4 identification, and so the header checksum, differ.
+    let masked = |frame: &Bytes| {
+        let mut frame = frame.to_vec();
+        for i in &[4, 5, 10, 11] {
+            frame[ETHERNET2_HEADER2_SIZE + i] = 0;
+        }
+        frame
+    };
+    assert_ne!(deferred, fast);
+    assert_eq!(masked(&deferred), masked(&fast));
+}
+
+#[test]
+fn pacing() {
+    let mut now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let buf = BytesMut::from(&[0u8; 100][..]).freeze();
+
+    // At 1000 bytes/sec, a 100 byte datagram goes every 100ms once the burst is spent.
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    let options = bob
+        .udp()
+        .socket_options(fd)
+        .unwrap()
+        .pacing(1000, 200, PacingMode::Queue);
+    let mut stopped = options.clone();
+    stopped.pacing.as_mut().unwrap().rate = 0;
+    must_let!(let Err(Fail::Invalid { .. }) = bob.udp().set_socket_options(fd, stopped));
+    bob.udp().set_socket_options(fd, options).unwrap();
+    for _ in 0..5 {
+        bob.udp().pushto(fd, buf.clone(), alice_addr).unwrap();
+    }
+    bob.rt().poll_scheduler();
+    bob.rt().pop_frame();
+    bob.rt().pop_frame();
+    assert!(bob.rt().pop_frame_opt().is_none());
+    assert_eq!(bob.udp().send_queue_bytes(fd).unwrap(), 300);
+    for _ in 0..3 {
+        bob.rt().advance_clock(now + Duration::from_millis(99));
+        bob.rt().poll_scheduler();
+        assert!(bob.rt().pop_frame_opt().is_none());
+        now += Duration::from_millis(100);
+        bob.rt().advance_clock(now);
+        bob.rt().poll_scheduler();
+        bob.rt().pop_frame();
+        assert!(bob.rt().pop_frame_opt().is_none());
+    }
+    assert_eq!(bob.udp().send_queue_bytes(fd).unwrap(), 0);
+
+    // Otherwise, sends past the rate are refused until the bucket refills.
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    let options = bob
+        .udp()
+        .socket_options(fd)
+        .unwrap()
+        .pacing(1000, 100, PacingMode::WouldBlock);
+    bob.udp().set_socket_options(fd, options).unwrap();
+    bob.udp().pushto(fd, buf.clone(), alice_addr).unwrap();
+    bob.rt().pop_frame();
+    must_let!(let Err(Fail::WouldBlock {}) = bob.udp().pushto(fd, buf.clone(), alice_addr));
+    now += Duration::from_millis(100);
+    bob.rt().advance_clock(now);
+    bob.udp().pushto(fd, buf, alice_addr).unwrap();
+    bob.rt().pop_frame();
+}
+
+#[test]
+fn close_all() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    bob.import_arp_cache(HashMap::new());
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    let carrie_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(80));
+    let buf = BytesMut::from(&[0u8; 6][..]).freeze();
+
+    let bound_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(bound_fd, bob_addr).unwrap();
+    let mut pop = bob.udp().pop(bound_fd);
+    assert!(Future::poll(Pin::new(&mut pop), &mut ctx).is_pending());
+    let sending_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.udp().pushto(sending_fd, buf, carrie_addr).unwrap();
+    bob.rt().poll_scheduler();
+
+    bob.udp().close_all();
+    must_let!(let Poll::Ready(Err(Fail::Shutdown {})) = Future::poll(Pin::new(&mut pop), &mut ctx));
+    assert!(bob.udp().send_queue_bytes(sending_fd).is_err());
+    bob.udp().close_all();
+
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(fd, bob_addr).unwrap();
+    assert_eq!(bob.udp().recv_queue_len(fd).unwrap(), 0);
+}
+
+#[test]
+fn send_timestamps() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let carrie_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(80));
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+    bob.rt().pop_frame();
+    assert!(bob.udp().send_timestamps(bob_fd).unwrap().is_empty());
+
+    let options = bob
+        .udp()
+        .socket_options(bob_fd)
+        .unwrap()
+        .timestamp_sends(true);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+    bob.rt().pop_frame();
+
+    // Carrie takes a while to answer the ARP query.
+    let mut cache = HashMap::new();
+    cache.insert(test_helpers::ALICE_IPV4, test_helpers::ALICE_MAC);
+    bob.import_arp_cache(cache);
+    bob.udp().pushto(bob_fd, buf, carrie_addr).unwrap();
+    bob.rt().poll_scheduler();
+    let later = now + Duration::from_millis(5);
+    bob.rt().advance_clock(later);
+    carrie.receive(bob.rt().pop_frame()).unwrap();
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    bob.rt().pop_frame();
+
+    let timestamps = bob.udp().send_timestamps(bob_fd).unwrap();
+    assert_eq!(timestamps.len(), 2);
+    assert_eq!(timestamps[0].arp_latency(), Duration::from_secs(0));
+    assert_eq!(timestamps[1].arp_latency(), Duration::from_millis(5));
+    assert_eq!(timestamps[1].transmitted, later);
+}
+
+#[test]
+fn bind_to_link() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let options = bob.udp().socket_options(bob_fd).unwrap().bind_to_link(1);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    bob.bind(bob_fd, bob_addr).unwrap();
+
+    // Only frames that came in on link 1 reach the socket.
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    alice.udp().pushto(alice_fd, buf.clone(), bob_addr).unwrap();
+    let frame = alice.rt().pop_frame();
+    let link0 = RxMetadata::default();
+    must_let!(let Err(Fail::Ignored { .. }) = bob.receive_with_metadata(frame.clone(), link0));
+    assert_eq!(bob.udp().recv_queue_len(bob_fd).unwrap(), 0);
+    let link1 = RxMetadata {
+        link_index: 1,
+        ..RxMetadata::default()
+    };
+    bob.receive_with_metadata(frame, link1).unwrap();
+    assert_eq!(bob.udp().recv_queue_len(bob_fd).unwrap(), 1);
+
+    // And everything it sends goes out on link 1.
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    assert!(bob.rt().pop_frame_opt().is_none());
+    let frame = bob.rt().pop_frame_on_link(1).unwrap();
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(frame).unwrap();
+    assert_eq!(ethernet2_hdr.dst_addr, test_helpers::ALICE_MAC);
+}
+
+#[test]
+fn reports_local_addr() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let wildcard = ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port(80));
+    alice.bind(alice_fd, wildcard).unwrap();
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    bob.bind(bob_fd, bob_addr).unwrap();
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+
+    let mut fut = alice.udp().pop_datagram(alice_fd);
+    must_let!(let Poll::Ready(Ok(datagram)) = Future::poll(Pin::new(&mut fut), &mut ctx));
+    assert_eq!(datagram.local_addr, test_helpers::ALICE_IPV4);
+    assert_eq!(datagram.remote, Some(bob_addr));
+
+    // Replying from the reported address gets the right source even though the socket's
+    // wildcard-bound.
+    let remote = datagram.remote.unwrap();
+    let r = alice
+        .udp()
+        .pushto_from(alice_fd, buf.clone(), Ipv4Addr::BROADCAST, remote);
+    must_let!(let Err(Fail::Malformed { .. }) = r);
+    alice
+        .udp()
+        .pushto_from(alice_fd, buf, datagram.local_addr, remote)
+        .unwrap();
+    let (_, payload) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, _) = Ipv4Header::parse(payload).unwrap();
+    assert_eq!(ipv4_hdr.src_addr, test_helpers::ALICE_IPV4);
+}
+
+#[test]
+fn freebind() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(
+        bob_fd,
+        ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80)),
+    )
+    .unwrap();
+    let spoofed = Ipv4Addr::new(10, 0, 0, 1);
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    let r = bob
+        .udp()
+        .pushto_from(bob_fd, buf.clone(), spoofed, alice_addr);
+    must_let!(let Err(Fail::PermissionDenied { .. }) = r);
+    assert!(bob.rt().pop_frame_opt().is_none());
+
+    let options = bob.udp().socket_options(bob_fd).unwrap().freebind(true);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    bob.udp()
+        .pushto_from(bob_fd, buf, spoofed, alice_addr)
+        .unwrap();
+    let frame = bob.rt().pop_frame();
+    let (_, payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+    let (ipv4_hdr, _) = Ipv4Header::parse(payload).unwrap();
+    assert_eq!(ipv4_hdr.src_addr, spoofed);
+
+    // Both checksums cover the spoofed address, so alice takes it as is.
+    alice.receive(frame).unwrap();
+    let expected = ipv4::Endpoint::new(spoofed, port(80));
+    let mut buf = [0u8; 3];
+    let r = alice.udp().recv_into(alice_fd, &mut buf).unwrap();
+    assert_eq!(r, (3, false, Some(expected)));
+}
+
+#[test]
+fn drops_martians() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let options = bob.udp().socket_options(bob_fd).unwrap().freebind(true);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp()
+        .pushto_from(bob_fd, buf, test_helpers::ALICE_IPV4, alice_addr)
+        .unwrap();
+    let frame = bob.rt().pop_frame();
+
+    must_let!(let Err(Fail::Ignored { .. }) = alice.receive(frame.clone()));
+    assert_eq!(alice.udp().stats().martians, 1);
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 0);
+
+    // Hairpinning deployments can opt out.
+    let options = alice.rt().ipv4_options().drop_martians(false);
+    alice.rt().set_ipv4_options(options);
+    alice.receive(frame).unwrap();
+    assert_eq!(alice.udp().stats().martians, 1);
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 1);
+}
+
+#[test]
+fn batched_receive_readiness() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let mut poller = UdpPoller::new();
+    let mut fds = vec![];
+    for i in 0..40 {
+        let fd = alice.socket(Protocol::Udp).unwrap();
+        let local = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(1000 + i));
+        alice.bind(fd, local).unwrap();
+        alice.udp().register(&mut poller, fd).unwrap();
+        fds.push(fd);
+    }
+
+    // Sockets 2 and 35 land on different waker pages, and socket 35 gets two datagrams.
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    let mut frames = vec![];
+    for &i in &[2, 35, 35] {
+        let remote = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(1000 + i));
+        bob.udp().pushto(bob_fd, buf.clone(), remote).unwrap();
+        frames.push(bob.rt().pop_frame());
+    }
+    let results = alice.receive_batch(frames);
+    assert!(results.iter().all(|r| r.is_ok()));
+
+    let mut ready: Vec<_> = poller.poll().into_iter().map(|e| e.fd).collect();
+    ready.sort();
+    assert_eq!(ready, vec![fds[2], fds[35]]);
+    assert_eq!(alice.udp().recv_queue_len(fds[35]).unwrap(), 2);
+}
+
+#[test]
+fn out_of_files() {
+    let now = Instant::now();
+    let rt =
+        test_helpers::TestRuntime::new("bob", now, test_helpers::BOB_MAC, test_helpers::BOB_IPV4);
+    let arp = arp::Peer::new(now, rt.clone()).unwrap();
+    let udp = Peer::new(rt, arp, FileTable::with_limit(2));
+
+    let fd = udp.socket().unwrap();
+    udp.socket().unwrap();
+    must_let!(let Err(Fail::OutOfFiles {}) = udp.socket());
+
+    // Closing a socket frees its descriptor for the next one.
+    udp.close(fd).unwrap();
+    udp.socket().unwrap();
+}
+
+#[test]
+fn clones_share_sockets() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let rt =
+        test_helpers::TestRuntime::new("bob", now, test_helpers::BOB_MAC, test_helpers::BOB_IPV4);
+    let arp = arp::Peer::new(now, rt.clone()).unwrap();
+    let udp = Peer::new(rt, arp, FileTable::new());
+    let clone = udp.clone();
+
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    let fd = udp.socket().unwrap();
+    udp.bind(fd, bob_addr).unwrap();
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    alice.udp().pushto(alice_fd, buf.clone(), bob_addr).unwrap();
+    let (_, payload) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, segment) = Ipv4Header::parse(payload).unwrap();
+    clone
+        .receive(&ipv4_hdr, segment, &RxMetadata::default())
+        .unwrap();
+
+    assert_eq!(udp.recv_queue_len(fd).unwrap(), 1);
+    let mut data = [0u8; 3];
+    clone.recv_into(fd, &mut data).unwrap();
+    assert_eq!(&data[..], &buf[..]);
+}
+
+#[test]
+fn debug_summary() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(fd, alice_addr).unwrap();
+    alice.socket(Protocol::Udp).unwrap();
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&b"secret"[..]).freeze();
+    for _ in 0..3 {
+        bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+
+    let debug = format!("{:?}", alice.udp());
+    assert!(debug.contains("sockets: 2"));
+    assert!(debug.contains("queued: 3"));
+    assert!(debug.contains(&format!("{:?}", alice_addr)));
+    assert!(!debug.contains("secret"));
+}
+
+#[test]
+fn drop_cancels_background() {
+    let now = Instant::now();
+    let rt =
+        test_helpers::TestRuntime::new("bob", now, test_helpers::BOB_MAC, test_helpers::BOB_IPV4);
+    let arp = arp::Peer::new(now, rt.clone()).unwrap();
+    let baseline = rt.scheduler().live_count();
+
+    let udp = Peer::new(rt.clone(), arp, FileTable::new());
+    assert_eq!(rt.scheduler().live_count(), baseline + 1);
+    let fd = udp.socket().unwrap();
+    // Leave a send stuck behind ARP for an address nobody answers for.
+    let remote = ipv4::Endpoint::new(Ipv4Addr::new(192, 168, 1, 99), port(80));
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    udp.pushto(fd, buf, remote).unwrap();
+    rt.poll_scheduler();
+    assert_eq!(udp.send_queue_bytes(fd).unwrap(), 3);
+
+    let clone = udp.clone();
+    drop(udp);
+    assert_eq!(rt.scheduler().live_count(), baseline + 1);
+    drop(clone);
+    assert_eq!(rt.scheduler().live_count(), baseline);
+    rt.poll_scheduler();
+    assert_eq!(rt.scheduler().pending_dropped_count(), 0);
+}
+
+#[test]
+fn try_receive_outcomes() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(bob_fd, bob_addr).unwrap();
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    alice.udp().pushto(alice_fd, buf, bob_addr).unwrap();
+    let (_, payload) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, segment) = Ipv4Header::parse(payload).unwrap();
+    let metadata = RxMetadata::default();
+
+    let r = bob.udp().try_receive(&ipv4_hdr, segment.clone(), &metadata);
+    assert_eq!(r, ReceiveOutcome::Delivered);
+    assert_eq!(bob.udp().recv_queue_len(bob_fd).unwrap(), 1);
+
+    let truncated = BytesMut::from(&segment[..4]).freeze();
+    let r = bob.udp().try_receive(&ipv4_hdr, truncated, &metadata);
+    assert_eq!(r, ReceiveOutcome::Malformed(ParseError::Truncated));
+    let mut corrupted = BytesMut::from(&segment[..]);
+    corrupted[8] ^= 0xff;
+    let r = bob
+        .udp()
+        .try_receive(&ipv4_hdr, corrupted.freeze(), &metadata);
+    assert_eq!(r, ReceiveOutcome::Malformed(ParseError::BadChecksum));
+
+    // Once connected elsewhere, the socket turns away the same datagram.
+    let elsewhere = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(80));
+    bob.udp().connect(bob_fd, elsewhere).unwrap();
+    let r = bob.udp().try_receive(&ipv4_hdr, segment.clone(), &metadata);
+    must_let!(let ReceiveOutcome::Filtered { .. } = r);
+    assert!(r.into_result().is_err());
+
+    bob.udp().close(bob_fd).unwrap();
+    let r = bob.udp().try_receive(&ipv4_hdr, segment, &metadata);
+    assert_eq!(r, ReceiveOutcome::DroppedUnbound);
+    must_let!(let Err(Fail::Malformed { details: "Port not bound" }) = r.into_result());
+    assert_eq!(bob.udp().recv_queue_len(bob_fd).ok(), None);
+}
+
+#[test]
+fn socket_buffer_sizes() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    // Sizes read back doubled, and clamped before doubling.
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let options = alice
+        .udp()
+        .socket_options(alice_fd)
+        .unwrap()
+        .rcvbuf(3000)
+        .sndbuf(10 * MAX_SOCKET_BUFFER);
+    alice.udp().set_socket_options(alice_fd, options).unwrap();
+    let options = alice.udp().socket_options(alice_fd).unwrap();
+    assert_eq!(options.recv_buffer_limit, Some(6000));
+    assert_eq!(options.send_buffer_high, 2 * MAX_SOCKET_BUFFER);
+    assert_eq!(options.send_buffer_low, MAX_SOCKET_BUFFER);
+    let options = options.rcvbuf(10 * MAX_SOCKET_BUFFER).sndbuf(1000);
+    assert_eq!(options.recv_buffer_limit, Some(2 * MAX_SOCKET_BUFFER));
+    assert_eq!(options.send_buffer_high, 2048);
+
+    // Past the limit, datagrams are dropped until the queue drains.
+    let options = options.rcvbuf(3000);
+    alice.udp().set_socket_options(alice_fd, options).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[7u8; 1400][..]).freeze();
+    for _ in 0..5 {
+        bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+    }
+    let mut dropped = 0;
+    for _ in 0..5 {
+        let (_, payload) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+        let (ipv4_hdr, segment) = Ipv4Header::parse(payload).unwrap();
+        let metadata = RxMetadata::default();
+        match alice.udp().try_receive(&ipv4_hdr, segment, &metadata) {
+            ReceiveOutcome::Delivered => (),
+            ReceiveOutcome::DroppedBufferFull => dropped += 1,
+            r => panic!("Unexpected outcome {:?}", r),
+        }
+    }
+    assert_eq!(dropped, 1);
+    assert_eq!(alice.udp().recv_queue_bytes(alice_fd).unwrap(), 5600);
+
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut pop = alice.udp().pop(alice_fd);
+    must_let!(let Poll::Ready(Ok(..)) = Future::poll(Pin::new(&mut pop), &mut ctx));
+    assert_eq!(alice.udp().recv_queue_bytes(alice_fd).unwrap(), 4200);
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 4);
+}
+
+#[test]
+fn connected_socket_wins_demux() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let carrie_fd = carrie.socket(Protocol::Udp).unwrap();
+    let wildcard = ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port(80));
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    // Sharing the wildcard endpoint, and with the connected socket on the specific address.
+    for &connected_addr in &[wildcard, bob_addr] {
+        let listener_fd = bob.socket(Protocol::Udp).unwrap();
+        let connected_fd = bob.socket(Protocol::Udp).unwrap();
+        for &fd in &[listener_fd, connected_fd] {
+            let options = bob.udp().socket_options(fd).unwrap().reuse_port(true);
+            bob.udp().set_socket_options(fd, options).unwrap();
+        }
+        bob.bind(listener_fd, wildcard).unwrap();
+        bob.bind(connected_fd, connected_addr).unwrap();
+        bob.udp().connect(connected_fd, alice_addr).unwrap();
+
+        for _ in 0..4 {
+            alice.udp().pushto(alice_fd, buf.clone(), bob_addr).unwrap();
+            bob.receive(alice.rt().pop_frame()).unwrap();
+            carrie
+                .udp()
+                .pushto(carrie_fd, buf.clone(), bob_addr)
+                .unwrap();
+            bob.receive(carrie.rt().pop_frame()).unwrap();
+        }
+        assert_eq!(bob.udp().recv_queue_len(connected_fd).unwrap(), 4);
+        assert_eq!(bob.udp().recv_queue_len(listener_fd).unwrap(), 4);
+        let received = bob.udp().drain_pending(listener_fd).unwrap().received;
+        let carrie_addr = received[0].remote.unwrap();
+        assert_eq!(carrie_addr.addr, test_helpers::CARRIE_IPV4);
+        assert!(received.iter().all(|d| d.remote == Some(carrie_addr)));
+
+        bob.udp().close(listener_fd).unwrap();
+        bob.udp().close(connected_fd).unwrap();
+    }
+}
+
+#[test]
+fn raw_listen() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let raw_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.udp()
+        .raw_listen(raw_fd, port(5000)..=port(5010))
+        .unwrap();
+    let other_fd = bob.socket(Protocol::Udp).unwrap();
+    let r = bob.udp().raw_listen(other_fd, port(5010)..=port(5020));
+    must_let!(let Err(Fail::Malformed { .. }) = r);
+    let r = bob.udp().raw_listen(raw_fd, port(6000)..=port(6000));
+    must_let!(let Err(Fail::Malformed { .. }) = r);
+    // A bound socket still gets its own port's datagrams.
+    let bound_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(5005));
+    bob.bind(other_fd, bound_addr).unwrap();
+
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    for &p in &[5000, 5005, 5007, 5010, 5011] {
+        let to = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(p));
+        alice.udp().pushto(alice_fd, buf.clone(), to).unwrap();
+        let r = bob.receive(alice.rt().pop_frame());
+        assert_eq!(r.is_ok(), p != 5011);
+    }
+    assert_eq!(bob.udp().recv_queue_len(other_fd).unwrap(), 1);
+    let received = bob.udp().drain_pending(raw_fd).unwrap().received;
+    let ports: Vec<_> = received.iter().map(|d| d.local_port.unwrap()).collect();
+    assert_eq!(ports, vec![port(5000), port(5007), port(5010)]);
+    for d in &received {
+        let hdr = d.ipv4_hdr.as_ref().unwrap();
+        assert_eq!(hdr.src_addr, test_helpers::ALICE_IPV4);
+        assert_eq!(&d.data[..], &[1, 2, 3]);
+    }
+
+    // Closing the raw listener frees its range.
+    bob.udp().close(raw_fd).unwrap();
+    let to = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(5000));
+    alice.udp().pushto(alice_fd, buf, to).unwrap();
+    assert!(bob.receive(alice.rt().pop_frame()).is_err());
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    bob.udp().raw_listen(fd, port(1)..=port(65535)).unwrap();
+}
+
+#[test]
+fn privileged_ports() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+
+    // Allowed by default.
+    let closed_fd = alice.socket(Protocol::Udp).unwrap();
+    let addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(53));
+    alice.bind(closed_fd, addr).unwrap();
+    alice.udp().close(closed_fd).unwrap();
+
+    let options = alice.rt().ipv4_options().privileged(false);
+    alice.rt().set_ipv4_options(options);
+    let fd = alice.socket(Protocol::Udp).unwrap();
+    must_let!(let Err(Fail::PermissionDenied { .. }) = alice.bind(fd, addr));
+    must_let!(let Err(Fail::PermissionDenied { .. }) = alice.udp().bind(fd, addr));
+    let r = alice
+        .udp()
+        .bind_range(fd, test_helpers::ALICE_IPV4, 1000..=1100);
+    must_let!(let Err(Fail::PermissionDenied { .. }) = r);
+    let r = alice.udp().raw_listen(fd, port(1)..=port(65535));
+    must_let!(let Err(Fail::PermissionDenied { .. }) = r);
+    let tcp_fd = alice.socket(Protocol::Tcp).unwrap();
+    let r = alice.bind(
+        tcp_fd,
+        ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80)),
+    );
+    must_let!(let Err(Fail::PermissionDenied { .. }) = r);
+    // A bad descriptor is reported as such, whatever the port.
+    must_let!(let Err(Fail::BadFileDescriptor {}) = alice.bind(closed_fd, addr));
+    must_let!(let Err(Fail::BadFileDescriptor {}) = alice.udp().bind(closed_fd, addr));
+
+    let addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(1024));
+    alice.bind(fd, addr).unwrap();
+    // The stack's own sockets can still bind anything.
+    let internal_fd = alice.udp().socket().unwrap();
+    let addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(68));
+    alice.udp().bind_privileged(internal_fd, addr).unwrap();
+}
+
+#[test]
+fn connect_checks_route() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let off_subnet = ipv4::Endpoint::new(Ipv4Addr::new(10, 0, 0, 1), port(80));
+    let on_subnet = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    let fd = alice.socket(Protocol::Udp).unwrap();
+
+    // Nothing's checked until the socket opts in and we know our netmask.
+    alice.udp().connect(fd, off_subnet).unwrap();
+    let options = alice.udp().socket_options(fd).unwrap().check_route(true);
+    alice.udp().set_socket_options(fd, options).unwrap();
+    alice.udp().connect(fd, off_subnet).unwrap();
+
+    let ipv4_options = alice
+        .rt()
+        .ipv4_options()
+        .netmask(Ipv4Addr::new(255, 255, 255, 0));
+    alice.rt().set_ipv4_options(ipv4_options.clone());
+    must_let!(let Err(Fail::NetworkUnreachable {}) = alice.udp().connect(fd, off_subnet));
+    alice.udp().connect(fd, on_subnet).unwrap();
+
+    // Other sockets still rely on proxy ARP.
+    let other_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.udp().connect(other_fd, off_subnet).unwrap();
+
+    // With a gateway, the peer's reachable through it.
+    alice
+        .rt()
+        .set_ipv4_options(ipv4_options.gateway(Ipv4Addr::new(192, 168, 1, 254)));
+    alice.udp().connect(fd, off_subnet).unwrap();
+}
+
+#[test]
+fn peer_metrics() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let nobody = ipv4::Endpoint::new(Ipv4Addr::new(192, 168, 1, 99), port(80));
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let unbound = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(81));
+
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    alice.socket(Protocol::Udp).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    for &(len, to) in &[(3, alice_addr), (5, alice_addr), (7, unbound)] {
+        let buf = BytesMut::from(&vec![0u8; len][..]).freeze();
+        bob.udp().pushto(bob_fd, buf, to).unwrap();
+        let _ = alice.receive(bob.rt().pop_frame());
+    }
+    let buf = BytesMut::from(&[1u8, 2][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, nobody).unwrap();
+    bob.rt().poll_scheduler();
+
+    let metrics = alice.udp().metrics();
+    assert_eq!(metrics.sockets, 2);
+    assert_eq!(metrics.bound_endpoints, 1);
+    assert_eq!(metrics.rx_datagrams, 2);
+    assert_eq!(metrics.rx_bytes, 8);
+    assert_eq!(metrics.rx_dropped, 1);
+    assert_eq!(metrics.tx_datagrams, 0);
+    let metrics = bob.udp().metrics();
+    assert_eq!(metrics.tx_datagrams, 3);
+    assert_eq!(metrics.tx_bytes, 15);
+    assert_eq!(metrics.queued_datagrams, 1);
+
+    let options = bob.rt().arp_options();
+    for _ in 0..options.retry_count + 1 {
+        now += options.request_timeout;
+        bob.rt().advance_clock(now);
+        bob.rt().poll_scheduler();
+    }
+    let metrics = bob.udp().metrics();
+    assert_eq!(metrics.tx_datagrams, 3);
+    assert_eq!(metrics.tx_dropped, 1);
+    assert_eq!(metrics.queued_datagrams, 0);
+}
+
+#[test]
+fn reply_from_received() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let secondary = Ipv4Addr::new(192, 168, 1, 50);
+    let options = bob.rt().ipv4_options().secondary_addr(secondary);
+    bob.rt().set_ipv4_options(options);
+
+    let wildcard = ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port(80));
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let options = bob
+        .udp()
+        .socket_options(bob_fd)
+        .unwrap()
+        .reply_from_received(true);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    bob.bind(bob_fd, wildcard).unwrap();
+
+    // Nothing's arrived yet, so replies come from our configured address.
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    let reply = |bob: &test_helpers::TestEngine| -> Result<Ipv4Addr, Fail> {
+        bob.udp().pushto(bob_fd, buf.clone(), alice_addr)?;
+        let (_, payload) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+        Ok(Ipv4Header::parse(payload).unwrap().0.src_addr)
+    };
+    let reply_src = |bob: &test_helpers::TestEngine| reply(bob).unwrap();
+    assert_eq!(reply_src(&bob), test_helpers::BOB_IPV4);
+
+    // Alice has no route to the secondary address, so readdress her requests to it, fixing up
+    // the IPv4 checksum and dropping the UDP one rather than fixing it up too.
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let to = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    let request = |dst: Ipv4Addr| {
+        alice.udp().pushto(alice_fd, buf.clone(), to).unwrap();
+        let frame = alice.rt().pop_frame();
+        let (_, payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+        let (mut ipv4_hdr, segment) = Ipv4Header::parse(payload).unwrap();
+        ipv4_hdr.dst_addr = dst;
+        let mut frame = BytesMut::from(&frame[..]);
+        let udp_start = ETHERNET2_HEADER2_SIZE + 20;
+        ipv4_hdr.serialize(&mut frame[ETHERNET2_HEADER2_SIZE..udp_start], segment.len());
+        frame[udp_start + 6] = 0;
+        frame[udp_start + 7] = 0;
+        frame.freeze()
+    };
+    let r = bob.receive(request(Ipv4Addr::new(192, 168, 1, 51)));
+    must_let!(let Err(Fail::Misdelivered { .. }) = r);
+    bob.receive(request(secondary)).unwrap();
+
+    // Follow it with a broadcast and then a request to our configured address.
+    let broadcast = ipv4::Endpoint::new(Ipv4Addr::BROADCAST, port(80));
+    let options = alice
+        .udp()
+        .socket_options(alice_fd)
+        .unwrap()
+        .broadcast(true);
+    alice.udp().set_socket_options(alice_fd, options).unwrap();
+    alice
+        .udp()
+        .pushto(alice_fd, buf.clone(), broadcast)
+        .unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.receive(request(test_helpers::BOB_IPV4)).unwrap();
+    assert_eq!(bob.udp().recv_queue_len(bob_fd).unwrap(), 3);
+
+    // Replies follow the datagram last popped, not the last one queued, and broadcasts don't
+    // change it.
+    let mut recv_buf = [0u8; 3];
+    assert_eq!(reply_src(&bob), test_helpers::BOB_IPV4);
+    bob.udp().recv_into(bob_fd, &mut recv_buf).unwrap();
+    assert_eq!(reply_src(&bob), secondary);
+    bob.udp().recv_into(bob_fd, &mut recv_buf).unwrap();
+    assert_eq!(reply_src(&bob), secondary);
+    bob.udp().recv_into(bob_fd, &mut recv_buf).unwrap();
+    assert_eq!(reply_src(&bob), test_helpers::BOB_IPV4);
+
+    // Once we've dropped the secondary address, replying from it takes `freebind`.
+    bob.receive(request(secondary)).unwrap();
+    bob.udp().recv_into(bob_fd, &mut recv_buf).unwrap();
+    let mut options = bob.rt().ipv4_options();
+    options.secondary_addrs.clear();
+    bob.rt().set_ipv4_options(options);
+    must_let!(let Err(Fail::PermissionDenied { .. }) = reply(&bob));
+    let options = bob.udp().socket_options(bob_fd).unwrap().freebind(true);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    assert_eq!(reply_src(&bob), secondary);
+
+    // Sockets without the option ignore it.
+    let options = bob
+        .udp()
+        .socket_options(bob_fd)
+        .unwrap()
+        .reply_from_received(false);
+    bob.udp().set_socket_options(bob_fd, options).unwrap();
+    assert_eq!(reply_src(&bob), test_helpers::BOB_IPV4);
+}
+
+#[test]
+fn unbind_fails_parked_pop() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let mut pop = alice.udp().pop(alice_fd);
+    assert!(Future::poll(Pin::new(&mut pop), &mut ctx).is_pending());
+
+    alice.udp().unbind(alice_fd).unwrap();
+    let r = Future::poll(Pin::new(&mut pop), &mut ctx);
+    must_let!(let Poll::Ready(Err(Fail::Malformed { details: "Socket not bound" })) = r);
+    must_let!(let Err(Fail::Malformed { .. }) = alice.udp().unbind(alice_fd));
+
+    // The endpoint's free for another socket, and this one can bind again elsewhere.
+    let other_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(other_fd, alice_addr).unwrap();
+    let new_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(81));
+    alice.bind(alice_fd, new_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, new_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let mut pop = alice.udp().pop(alice_fd);
+    must_let!(let Poll::Ready(Ok(..)) = Future::poll(Pin::new(&mut pop), &mut ctx));
+}
+
+#[test]
+fn concurrent_pops_all_woken() {
+    struct Flag(AtomicBool);
+    impl ArcWake for Flag {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+
+    let flags: Vec<Arc<Flag>> = (0..2)
+        .map(|_| Arc::new(Flag(AtomicBool::new(false))))
+        .collect();
+    let wakers: Vec<_> = flags.iter().map(|f| task::waker(f.clone())).collect();
+    let mut pops: Vec<_> = (0..2).map(|_| alice.udp().pop(alice_fd)).collect();
+    for (pop, waker) in pops.iter_mut().zip(&wakers) {
+        let mut ctx = Context::from_waker(waker);
+        assert!(Future::poll(Pin::new(pop), &mut ctx).is_pending());
+    }
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert!(flags.iter().all(|f| f.0.swap(false, Ordering::SeqCst)));
+
+    // Only one of them gets the datagram, and the other waits on the next.
+    let mut ctx = Context::from_waker(&wakers[0]);
+    must_let!(let Poll::Ready(Ok(..)) = Future::poll(Pin::new(&mut pops[0]), &mut ctx));
+    let mut ctx = Context::from_waker(&wakers[1]);
+    assert!(Future::poll(Pin::new(&mut pops[1]), &mut ctx).is_pending());
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert!(!flags[0].0.load(Ordering::SeqCst));
+    assert!(flags[1].0.load(Ordering::SeqCst));
+    must_let!(let Poll::Ready(Ok(..)) = Future::poll(Pin::new(&mut pops[1]), &mut ctx));
+}
+
+#[test]
+fn push_reports_bytes_sent() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    must_let!(let Operation::Udp(op) = bob.pushto(bob_fd, buf.clone(), alice_addr));
+    must_let!(let (fd, OperationResult::Push(3)) = op.expect_result());
+    assert_eq!(fd, bob_fd);
+
+    bob.udp().connect(bob_fd, alice_addr).unwrap();
+    let small = BytesMut::from(&[4u8, 5][..]).freeze();
+    must_let!(let Operation::Udp(op) = bob.push(bob_fd, small));
+    must_let!(let (_, OperationResult::Push(2)) = op.expect_result());
+
+    // Failed sends complete with the error instead.
+    bob.udp().shutdown(bob_fd, Shutdown::Write).unwrap();
+    must_let!(let Operation::Udp(op) = bob.push(bob_fd, buf));
+    must_let!(let (_, OperationResult::Failed(Fail::Shutdown {})) = op.expect_result());
+}
+
+#[test]
+fn invalid_endpoints_rejected() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let group = Ipv4Addr::new(224, 0, 0, 251);
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    let r = bob.udp().bind(fd, ipv4::Endpoint::new(group, port(5353)));
+    must_let!(let Err(Fail::Invalid { .. }) = r);
+    let r = bob
+        .udp()
+        .bind(fd, ipv4::Endpoint::new(Ipv4Addr::BROADCAST, port(67)));
+    must_let!(let Err(Fail::Invalid { .. }) = r);
+    let r = bob.udp().pushto(
+        fd,
+        buf.clone(),
+        ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port(80)),
+    );
+    must_let!(let Err(Fail::Invalid { .. }) = r);
+    let from = test_helpers::BOB_IPV4;
+    let r = bob.udp().pushto_from(
+        fd,
+        buf.clone(),
+        from,
+        ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port(80)),
+    );
+    must_let!(let Err(Fail::Invalid { .. }) = r);
+    let r = bob
+        .udp()
+        .connect(fd, ipv4::Endpoint::new(group, port(5353)));
+    must_let!(let Err(Fail::PermissionDenied { .. }) = r);
+    assert!(bob.rt().pop_frame_opt().is_none());
+
+    // The rejected binds left the socket free, and groups are still joined through its options.
+    bob.udp()
+        .bind(fd, ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port(5353)))
+        .unwrap();
+    let options = bob.udp().socket_options(fd).unwrap().join_multicast(group);
+    bob.udp().set_socket_options(fd, options).unwrap();
+    let r = bob
+        .udp()
+        .pushto(fd, buf, ipv4::Endpoint::new(group, port(5353)));
+    assert_eq!(r.unwrap(), 3);
+}
+
+#[test]
+fn flush_awaits_deferred_sends() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    bob.import_arp_cache(HashMap::new());
+    let carrie_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(80));
+    let carrie_fd = carrie.socket(Protocol::Udp).unwrap();
+    carrie.bind(carrie_fd, carrie_addr).unwrap();
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = |b: u8| BytesMut::from(&[b][..]).freeze();
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    // Nothing's queued yet.
+    let mut flush = Box::pin(bob.udp().flush());
+    assert!(Future::poll(flush.as_mut(), &mut ctx).is_ready());
+
+    bob.udp().pushto(fd, buf(0), carrie_addr).unwrap();
+    bob.udp().pushto(fd, buf(1), carrie_addr).unwrap();
+    let mut flush = Box::pin(bob.udp().flush());
+    assert!(Future::poll(flush.as_mut(), &mut ctx).is_pending());
+
+    bob.rt().poll_scheduler();
+    carrie.receive(bob.rt().pop_frame()).unwrap();
+    assert!(Future::poll(flush.as_mut(), &mut ctx).is_pending());
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    assert!(Future::poll(flush.as_mut(), &mut ctx).is_ready());
+    for _ in 0..2 {
+        carrie.receive(bob.rt().pop_frame()).unwrap();
+    }
+    assert!(bob.rt().pop_frame_opt().is_none());
+    assert_eq!(carrie.udp().recv_queue_len(carrie_fd).unwrap(), 2);
+}
+
+#[test]
+fn stale_fd_rejected() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+
+    let stale_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.close(stale_fd).unwrap();
+    // The new socket takes the freed slot, but under a different descriptor.
+    let fd = alice.socket(Protocol::Udp).unwrap();
+    assert_ne!(fd, stale_fd);
+    alice.bind(fd, alice_addr).unwrap();
+
+    must_let!(let Err(Fail::BadFileDescriptor {}) = alice.bind(stale_fd, alice_addr));
+    must_let!(let Err(Fail::BadFileDescriptor {}) = alice.udp().socket_state(stale_fd));
+    must_let!(let Err(Fail::BadFileDescriptor {}) = alice.close(stale_fd));
+    assert!(alice.udp().socket_state(fd).unwrap().is_bound);
+
+    // Operations on it fail rather than panic.
+    let buf = BytesMut::from(&[1u8][..]).freeze();
+    must_let!(let Operation::Failed(_, Fail::BadFileDescriptor {}) = alice.push(stale_fd, buf.clone()));
+    must_let!(let Operation::Failed(_, Fail::BadFileDescriptor {}) = alice.pushto(stale_fd, buf, alice_addr));
+    must_let!(let Operation::Failed(_, Fail::BadFileDescriptor {}) = alice.pop(stale_fd));
+    must_let!(let Operation::Failed(_, Fail::BadFileDescriptor {}) = alice.connect(stale_fd, alice_addr));
+}
+
+#[test]
+fn recv_timeout() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let options = alice
+        .udp()
+        .socket_options(alice_fd)
+        .unwrap()
+        .recv_timeout(Duration::from_secs(1));
+    let r = alice
+        .udp()
+        .set_socket_options(alice_fd, options.clone().recv_timeout(Duration::new(0, 0)));
+    must_let!(let Err(Fail::Invalid { .. }) = r);
+    alice.udp().set_socket_options(alice_fd, options).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    // Every pop waits at most the socket's timeout, except where the call asks for its own.
+    let mut data = [0u8; 2];
+    let mut pop = alice.udp().pop(alice_fd);
+    let mut pop_into = Box::pin(alice.udp().pop_into(alice_fd, &mut data));
+    let mut patient = alice.udp().pop_timeout(alice_fd, Duration::from_secs(5));
+    assert!(Future::poll(Pin::new(&mut pop), &mut ctx).is_pending());
+    assert!(Future::poll(pop_into.as_mut(), &mut ctx).is_pending());
+    assert!(Future::poll(Pin::new(&mut patient), &mut ctx).is_pending());
+    now += Duration::from_secs(1);
+    alice.rt().advance_clock(now);
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(Pin::new(&mut pop), &mut ctx));
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(pop_into.as_mut(), &mut ctx));
+    assert!(Future::poll(Pin::new(&mut patient), &mut ctx).is_pending());
+    drop(pop_into);
+
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(..)) = Future::poll(Pin::new(&mut patient), &mut ctx));
+
+    // One that's still waiting when a datagram arrives copies it in.
+    let mut pop_into = Box::pin(alice.udp().pop_into(alice_fd, &mut data));
+    assert!(Future::poll(pop_into.as_mut(), &mut ctx).is_pending());
+    let buf = BytesMut::from(&[4u8, 5, 6][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok((2, true, Some(..)))) = Future::poll(pop_into.as_mut(), &mut ctx));
+    drop(pop_into);
+    assert_eq!(data, [4, 5]);
+}
+
+#[test]
+fn early_drop() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let options = alice
+        .udp()
+        .socket_options(alice_fd)
+        .unwrap()
+        .early_drop(100, 300, 0.5);
+    for &(min, max, p) in &[(300, 100, 0.5), (100, 300, 0.0), (100, 300, f64::NAN)] {
+        let invalid = options.clone().early_drop(min, max, p);
+        let r = alice.udp().set_socket_options(alice_fd, invalid);
+        must_let!(let Err(Fail::Invalid { .. }) = r);
+    }
+    alice.udp().set_socket_options(alice_fd, options).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[7u8; 10][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    let (_, payload) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, segment) = Ipv4Header::parse(payload).unwrap();
+    let metadata = RxMetadata::default();
+    let mut data = [0u8; 10];
+
+    // Hold the queue at each level, popping whatever gets through, and count what's dropped.
+    let mut drops = vec![];
+    for &level in &[50, 150, 250, 300] {
+        while alice.udp().recv_queue_bytes(alice_fd).unwrap() < level {
+            let _ = alice
+                .udp()
+                .try_receive(&ipv4_hdr, segment.clone(), &metadata);
+        }
+        let mut dropped = 0;
+        for _ in 0..400 {
+            match alice
+                .udp()
+                .try_receive(&ipv4_hdr, segment.clone(), &metadata)
+            {
+                ReceiveOutcome::Delivered => {
+                    alice.udp().recv_into(alice_fd, &mut data).unwrap();
+                },
+                ReceiveOutcome::DroppedEarly => dropped += 1,
+                r => panic!("Unexpected outcome {:?}", r),
+            }
+        }
+        drops.push(dropped);
+    }
+    assert_eq!(drops[0], 0);
+    assert!(drops[1] > 0);
+    assert!(drops[1] < drops[2]);
+    assert!(drops[2] < drops[3]);
+    assert_eq!(drops[3], 400);
+    assert_eq!(alice.udp().recv_queue_bytes(alice_fd).unwrap(), 300);
+}
+
+#[test]
+fn send_handle() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let buf = |b: u8| BytesMut::from(&[b][..]).freeze();
+
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    must_let!(let Err(Fail::Malformed { .. }) = bob.udp().send_handle(fd));
+    bob.udp().connect(fd, alice_addr).unwrap();
+    let handle = bob.udp().send_handle(fd).unwrap();
+    for i in 0..3 {
+        assert_eq!(handle.push(buf(i)).unwrap(), 1);
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 3);
+
+    // Changing the socket invalidates the handle, but not a new one.
+    let options = bob.udp().socket_options(fd).unwrap().dont_fragment(true);
+    bob.udp().set_socket_options(fd, options).unwrap();
+    must_let!(let Err(Fail::Invalid { .. }) = handle.push(buf(3)));
+    let handle = bob.udp().send_handle(fd).unwrap();
+    handle.push(buf(3)).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+
+    bob.close(fd).unwrap();
+    must_let!(let Err(Fail::BadFileDescriptor {}) = handle.push(buf(4)));
+    assert!(bob.rt().pop_frame_opt().is_none());
+}
+
+#[test]
+fn send_status() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    let mut cache = HashMap::new();
+    cache.insert(test_helpers::ALICE_IPV4, test_helpers::ALICE_MAC);
+    bob.import_arp_cache(cache);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let carrie_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(80));
+    let buf = BytesMut::from(&[1u8][..]).freeze();
+
+    // Alice is in Bob's ARP cache, but Carrie isn't.
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    let r = bob.udp().pushto_status(fd, buf.clone(), alice_addr);
+    must_let!(let Ok(SendStatus::Sent) = r);
+    bob.rt().pop_frame();
+    let r = bob.udp().pushto_status(fd, buf.clone(), carrie_addr);
+    must_let!(let Ok(SendStatus::Queued) = r);
+    assert!(bob.rt().pop_frame_opt().is_none());
+
+    // The datagram goes out once Carrie answers.
+    bob.rt().poll_scheduler();
+    carrie.receive(bob.rt().pop_frame()).unwrap();
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    bob.rt().pop_frame();
+    bob.udp().connect(fd, carrie_addr).unwrap();
+    must_let!(let Ok(SendStatus::Sent) = bob.udp().push_status(fd, buf));
+}
+
+#[test]
+fn bind_range() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let bob_ipv4 = test_helpers::BOB_IPV4;
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    // One port already taken fails the whole range, and takes none of the others.
+    let other_fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(other_fd, ipv4::Endpoint::new(bob_ipv4, port(5003)))
+        .unwrap();
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    let r = bob.udp().bind_range(fd, bob_ipv4, 5000..=5003);
+    must_let!(let Err(Fail::Malformed { .. }) = r);
+    assert!(!bob.udp().socket_state(fd).unwrap().is_bound);
+    let r = bob.udp().bind_range(fd, bob_ipv4, 0..=3);
+    must_let!(let Err(Fail::OutOfRange { .. }) = r);
+    bob.udp().bind_range(fd, bob_ipv4, 5000..=5002).unwrap();
+
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    for &p in &[5002, 5000, 5003] {
+        let buf = BytesMut::from(&[1u8][..]).freeze();
+        let to = ipv4::Endpoint::new(bob_ipv4, port(p));
+        alice.udp().pushto(alice_fd, buf, to).unwrap();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+    }
+    for &p in &[5002, 5000] {
+        let mut pop = bob.udp().pop_datagram(fd);
+        must_let!(let Poll::Ready(Ok(d)) = Future::poll(Pin::new(&mut pop), &mut ctx));
+        assert_eq!(d.local_port, Some(port(p)));
+    }
+    assert_eq!(bob.udp().recv_queue_len(other_fd).unwrap(), 1);
+
+    // Closing the socket frees the whole range.
+    bob.udp().close(fd).unwrap();
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    bob.bind(fd, ipv4::Endpoint::new(bob_ipv4, port(5001)))
+        .unwrap();
+}
+
+#[test]
+fn bind_address_defers_port() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+
+    let fd = bob.socket(Protocol::Udp).unwrap();
+    bob.udp().bind_address(fd, test_helpers::BOB_IPV4).unwrap();
+    assert!(!bob.udp().socket_state(fd).unwrap().is_bound);
+    bob.udp().connect(fd, alice_addr).unwrap();
+    let state = bob.udp().socket_state(fd).unwrap();
+    must_let!(let Some(local) = state.local);
+    assert_eq!(local.addr, test_helpers::BOB_IPV4);
+    assert!(local.port.is_private());
+
+    let buf = BytesMut::from(&[1u8][..]).freeze();
+    bob.udp().push(fd, buf).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let received = alice.udp().drain_pending(alice_fd).unwrap().received;
+    assert_eq!(received[0].remote, Some(local));
+
+    // Once bound, there's no address to fix any more.
+    let r = bob.udp().bind_address(fd, test_helpers::BOB_IPV4);
+    must_let!(let Err(Fail::Malformed { .. }) = r);
+}
+
+#[test]
+fn fragments_dropped() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[0u8; 32][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    let frame = bob.rt().pop_frame();
+
+    // A first fragment, then out of order ones overlapping it and each other. Leave the
+    // checksums to a NIC that "validated" them.
+    let metadata = RxMetadata {
+        checksum_validated: true,
+        ..RxMetadata::default()
+    };
+    for &(more, offset) in &[(true, 0u16), (false, 2), (true, 1), (true, 0)] {
+        let mut fragment = BytesMut::from(&frame[..]);
+        let flags = if more { 0x2000 } else { 0 };
+        let bytes = (flags | offset).to_be_bytes();
+        fragment[ETHERNET2_HEADER2_SIZE + 6..ETHERNET2_HEADER2_SIZE + 8].copy_from_slice(&bytes);
+        let r = alice.receive_with_metadata(fragment.freeze(), metadata);
+        must_let!(let Err(Fail::Unsupported { .. }) = r);
+    }
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 0);
+    alice.receive_with_metadata(frame, metadata).unwrap();
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 1);
+}
+
+#[test]
+fn reused_fd_rejected() {
+    let now = Instant::now();
+    let rt =
+        test_helpers::TestRuntime::new("bob", now, test_helpers::BOB_MAC, test_helpers::BOB_IPV4);
+    let arp = arp::Peer::new(now, rt.clone()).unwrap();
+    // With a single open file at a time, the table keeps reusing one slot until it retires it.
+    let file_table = FileTable::with_limit(1);
+    let udp = Peer::new(rt, arp, file_table.clone());
+    let fd = udp.socket().unwrap();
+
+    // Something else sharing the table frees our descriptor, and however often we open and close
+    // sockets afterwards, none of them gets it back.
+    file_table.free(fd).unwrap();
+    must_let!(let Err(Fail::BadFileDescriptor { .. }) = file_table.free(fd));
+    for _ in 0..5000 {
+        let other = udp.socket().unwrap();
+        assert_ne!(other, fd);
+        udp.close(other).unwrap();
+    }
+
+    // Neither does TCP. Our socket is untouched, and closing it leaves TCP's descriptor alone.
+    let tcp_fd = file_table.alloc(File::TcpSocket).unwrap();
+    assert_ne!(tcp_fd, fd);
+    let addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    udp.bind(fd, addr).unwrap();
+    assert_eq!(udp.socket_state(fd).unwrap().local, Some(addr));
+    udp.close(fd).unwrap();
+    assert_eq!(file_table.get(tcp_fd), Some(File::TcpSocket));
+}
+
+#[test]
+fn pooled_frames() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let pool = BufferPool::new(64, 2);
+    bob.rt().set_frame_pool(Some(pool.clone()));
+
+    // Alice's payloads point into the pooled frames they arrived in. The third frame is sent with
+    // the pool empty, so it's allocated as usual.
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    for i in 0..3 {
+        let buf = BytesMut::from(&[i; 10][..]).freeze();
+        bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+    assert_eq!(pool.available(), 0);
+
+    let received = alice.udp().drain_pending(alice_fd).unwrap().received;
+    assert_eq!(received.len(), 3);
+    for (i, datagram) in received.iter().enumerate() {
+        assert_eq!(&datagram.data[..], &[i as u8; 10][..]);
+    }
+    // A buffer only goes back once every `Bytes` pointing into it is gone.
+    let (first, rest) = received[0].data.clone().split(5);
+    drop(received);
+    assert_eq!(pool.available(), 1);
+    drop(first);
+    assert_eq!(pool.available(), 1);
+    drop(rest);
+    assert_eq!(pool.available(), 2);
+}
+
+#[test]
+fn wait_readable() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut unbound = alice.udp().wait_readable(alice_fd);
+    must_let!(let Poll::Ready(Err(Fail::Malformed { .. })) = Future::poll(Pin::new(&mut unbound), &mut ctx));
+    alice.bind(alice_fd, alice_addr).unwrap();
+    must_let!(let Err(Fail::WouldBlock {}) = alice.udp().peek(alice_fd));
+
+    let mut ready = alice.udp().wait_readable(alice_fd);
+    assert!(Future::poll(Pin::new(&mut ready), &mut ctx).is_pending());
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf.clone(), alice_addr).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut ready), &mut ctx));
+
+    // The datagram's still there, for peeking at, waiting again or popping.
+    must_let!(let Ok((Some(_), peeked)) = alice.udp().peek(alice_fd));
+    assert_eq!(peeked, buf);
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 1);
+    let mut again = alice.udp().wait_readable(alice_fd);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut again), &mut ctx));
+    let mut pop = alice.udp().pop(alice_fd);
+    must_let!(let Poll::Ready(Ok((_, data))) = Future::poll(Pin::new(&mut pop), &mut ctx));
+    assert_eq!(data, buf);
+
+    let mut closed = alice.udp().wait_readable(alice_fd);
+    alice.udp().close(alice_fd).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::Shutdown {})) = Future::poll(Pin::new(&mut closed), &mut ctx));
+}
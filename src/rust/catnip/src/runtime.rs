@@ -1,9 +1,11 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 use crate::{
+    fail::Fail,
     protocols::{
         arp,
         ethernet2::MacAddress,
+        ipv4,
         tcp,
     },
     scheduler::{
@@ -31,25 +33,84 @@ pub trait PacketBuf {
     fn serialize(&self, buf: &mut [u8]);
 }
 
+/// Details the runtime knows about a received frame beyond its bytes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RxMetadata {
+    /// Index of the link (interface) the frame arrived on. Single-link runtimes always report 0.
+    pub link_index: usize,
+    /// Set when the NIC already validated the frame's IPv4 and UDP checksums, so we can skip
+    /// recomputing them.
+    pub checksum_validated: bool,
+    /// RX queue the NIC steered the frame to, for multi-queue runtimes. The NIC hashes a flow to
+    /// one queue, so a `reuse_port` group maps queue `q` to its `q % n`th socket (in bind order)
+    /// instead of hashing the flow again, keeping each socket fed by the same queue. Like the
+    /// 4-tuple hash, that's only stable while the group's membership is: a socket joining or
+    /// closing remaps flows across the whole group. Single-queue runtimes leave this `None`.
+    pub rx_queue: Option<usize>,
+}
+
 pub trait Runtime: Clone + Unpin + 'static {
     fn advance_clock(&self, now: Instant);
     fn transmit(&self, pkt: impl PacketBuf);
     fn receive(&self) -> Option<Bytes>;
 
+    /// Transmits `pkt` on a specific link. Runtimes with a single link can ignore the index.
+    fn transmit_on_link(&self, link_index: usize, pkt: impl PacketBuf) {
+        let _ = link_index;
+        self.transmit(pkt)
+    }
+
+    /// Like `receive`, but also reports which link the frame came in on.
+    fn receive_with_metadata(&self) -> Option<(Bytes, RxMetadata)> {
+        self.receive().map(|buf| (buf, RxMetadata::default()))
+    }
+
     fn local_link_addr(&self) -> MacAddress;
     fn local_ipv4_addr(&self) -> Ipv4Addr;
     fn arp_options(&self) -> arp::Options;
+    fn ipv4_options(&self) -> ipv4::Options;
+
+    /// Reconfigures the stack's address, e.g. once DHCP has obtained a lease.
+    fn set_local_ipv4_addr(&self, addr: Ipv4Addr);
+    fn set_ipv4_options(&self, options: ipv4::Options);
     fn tcp_options(&self) -> tcp::Options;
 
     type WaitFuture: Future<Output = ()>;
     fn wait(&self, duration: Duration) -> Self::WaitFuture;
     fn wait_until(&self, when: Instant) -> Self::WaitFuture;
     fn now(&self) -> Instant;
+    /// When the earliest pending `wait` or `wait_until` expires, if any.
+    fn next_timer_expiry(&self) -> Option<Instant>;
 
     fn rng_gen<T>(&self) -> T
     where
         Standard: Distribution<T>;
 
+    /// Returns the identification to stamp on the next IPv4 datagram we originate. Successive
+    /// calls yield successive values, wrapping around at `u16::MAX`.
+    fn next_ipv4_identification(&self) -> u16;
+
     fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) -> SchedulerHandle;
+
+    /// Like `spawn`, but fails with `OutOfMemory` instead of panicking if the scheduler can't
+    /// grow to fit the task.
+    fn try_spawn<F: Future<Output = ()> + 'static>(
+        &self,
+        future: F,
+    ) -> Result<SchedulerHandle, Fail> {
+        self.scheduler()
+            .try_insert(Operation::Background(Box::pin(future)))
+    }
+
+    /// Like `spawn`, but tags the task with `label` for `Scheduler::tasks`.
+    fn spawn_named<F: Future<Output = ()> + 'static>(
+        &self,
+        label: &'static str,
+        future: F,
+    ) -> SchedulerHandle {
+        self.scheduler()
+            .insert_named(Operation::Background(Box::pin(future)), label)
+    }
+
     fn scheduler(&self) -> &Scheduler<Operation<Self>>;
 }
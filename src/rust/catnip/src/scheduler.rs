@@ -5,10 +5,16 @@
 //
 use crate::{
     collections::waker_page::{
+        iter_set_bits,
+        GlobalPageAllocator,
+        NotifyOrder,
+        PageAllocator,
         WakerPage,
         WakerPageRef,
         WAKER_PAGE_SIZE,
     },
+    fail::Fail,
+    file_table::FileDescriptor,
     protocols::{
         tcp::operations::TcpOperation,
         udp::peer::UdpOperation,
@@ -16,9 +22,11 @@ use crate::{
     runtime::Runtime,
     sync::SharedWaker,
 };
-use gen_iter::gen_iter;
 use std::{
-    cell::RefCell,
+    cell::{
+        RefCell,
+        RefMut,
+    },
     future::Future,
     pin::Pin,
     rc::Rc,
@@ -35,6 +43,9 @@ pub enum Operation<RT: Runtime> {
     // These are all stored inline to prevent hitting the allocator on insertion/removal.
     Tcp(TcpOperation<RT>),
     Udp(UdpOperation),
+    // Fails as soon as it's polled, for calls rejected before reaching a protocol, like ones on
+    // a closed fd.
+    Failed(FileDescriptor, Fail),
 
     // These are expected to have long lifetimes and be large enough to justify another allocation.
     Background(Pin<Box<dyn Future<Output = ()>>>),
@@ -47,6 +58,7 @@ impl<RT: Runtime> Future for Operation<RT> {
         match self.get_mut() {
             Operation::Tcp(ref mut f) => Future::poll(Pin::new(f), ctx),
             Operation::Udp(ref mut f) => Future::poll(Pin::new(f), ctx),
+            Operation::Failed(..) => Poll::Ready(()),
             Operation::Background(ref mut f) => Future::poll(Pin::new(f), ctx),
         }
     }
@@ -58,16 +70,38 @@ impl<T: Into<TcpOperation<RT>>, RT: Runtime> From<T> for Operation<RT> {
     }
 }
 
-// Adapted from https://lemire.me/blog/2018/02/21/iterating-over-set-bits-quickly/
-fn iter_set_bits(mut bitset: u64) -> impl Iterator<Item = usize> {
-    gen_iter!({
-        while bitset != 0 {
-            // `bitset & -bitset` returns a bitset with only the lowest significant bit set
-            let t = bitset & bitset.wrapping_neg();
-            yield bitset.trailing_zeros() as usize;
-            bitset ^= t;
+/// Snapshot of a single occupied scheduler slot, for debugging stuck tasks.
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    pub index: u64,
+    pub label: Option<&'static str>,
+    pub notified: bool,
+    pub completed: bool,
+    pub dropped: bool,
+}
+
+/// Gives up the rest of the current task's turn. Awaiting this re-notifies the task's slot and
+/// returns to the scheduler, so the task resumes on a later `poll` after other ready tasks run.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        let self_ = self.get_mut();
+        if self_.yielded {
+            return Poll::Ready(());
         }
-    })
+        self_.yielded = true;
+        ctx.waker().wake_by_ref();
+        Poll::Pending
+    }
 }
 
 pub struct SchedulerHandle {
@@ -109,10 +143,31 @@ impl<F: Future<Output = ()> + Unpin> Clone for Scheduler<F> {
 
 impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
     pub fn new() -> Self {
+        Self::with_page_allocator(&GlobalPageAllocator)
+    }
+
+    /// Like `new`, but takes waker pages from `page_allocator`, for runtimes that keep a pool
+    /// of them.
+    pub fn with_page_allocator(page_allocator: &'static dyn PageAllocator) -> Self {
+        Self::with_config(page_allocator, NotifyOrder::Bits)
+    }
+
+    /// Like `new`, but with a choice of the order notified tasks on a page are polled in. With
+    /// `NotifyOrder::Fifo`, they're polled in the order they were woken rather than by slot.
+    pub fn with_notify_order(notify_order: NotifyOrder) -> Self {
+        Self::with_config(&GlobalPageAllocator, notify_order)
+    }
+
+    fn with_config(page_allocator: &'static dyn PageAllocator, notify_order: NotifyOrder) -> Self {
         let inner = Inner {
             slab: PinSlab::new(),
             pages: vec![],
+            page_allocator,
+            notify_order,
             root_waker: SharedWaker::new(),
+            cursor: 0,
+            len: 0,
+            labels: vec![],
         };
         Self {
             inner: Rc::new(RefCell::new(inner)),
@@ -125,6 +180,8 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
         let (page, subpage_ix) = inner.page(key);
         assert!(!page.was_dropped(subpage_ix));
         page.clear(subpage_ix);
+        inner.len -= 1;
+        inner.labels[key as usize] = None;
         inner.slab.remove_unpin(key as usize).unwrap()
     }
 
@@ -142,62 +199,204 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
     }
 
     pub fn insert(&self, future: F) -> SchedulerHandle {
+        self.try_insert(future)
+            .expect("Failed to allocate a waker page")
+    }
+
+    pub fn insert_named(&self, future: F, label: &'static str) -> SchedulerHandle {
+        self.try_insert_named(future, label)
+            .expect("Failed to allocate a waker page")
+    }
+
+    /// Like `insert`, but fails with `OutOfMemory` rather than panicking if we need another
+    /// waker page and can't allocate one. `future` is dropped in that case.
+    pub fn try_insert(&self, future: F) -> Result<SchedulerHandle, Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let key = inner.insert(future)?;
+        let (page, _) = inner.page(key);
+        Ok(SchedulerHandle {
+            key: Some(key),
+            waker_page: page.clone(),
+        })
+    }
+
+    pub fn try_insert_named(
+        &self,
+        future: F,
+        label: &'static str,
+    ) -> Result<SchedulerHandle, Fail> {
         let mut inner = self.inner.borrow_mut();
-        let key = inner.insert(future);
+        let key = inner.insert(future)?;
+        inner.labels[key as usize] = Some(label);
         let (page, _) = inner.page(key);
-        SchedulerHandle {
+        Ok(SchedulerHandle {
             key: Some(key),
             waker_page: page.clone(),
+        })
+    }
+
+    /// Number of futures still owned by a handle, whether or not they've completed.
+    pub fn live_count(&self) -> usize {
+        let inner = self.inner.borrow();
+        let dropped: usize = inner
+            .pages
+            .iter()
+            .map(|p| p.dropped_bits().count_ones() as usize)
+            .sum();
+        inner.len - dropped
+    }
+
+    /// Number of futures that have completed but whose result hasn't been taken.
+    pub fn completed_count(&self) -> usize {
+        let inner = self.inner.borrow();
+        inner.pages.iter().map(|p| p.completed_count()).sum()
+    }
+
+    /// Number of dropped futures still waiting to be reaped by `poll`. This should stay near zero;
+    /// if it keeps growing, nothing is polling the scheduler.
+    pub fn pending_dropped_count(&self) -> u32 {
+        let inner = self.inner.borrow();
+        inner.pages.iter().map(|p| p.pending_dropped_count()).sum()
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.inner.borrow().pages.len()
+    }
+
+    /// Lists every occupied slot along with its label and waker state.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        let inner = self.inner.borrow();
+        let mut tasks = vec![];
+        for ix in 0..inner.pages.len() * WAKER_PAGE_SIZE {
+            if inner.slab.get(ix).is_none() {
+                continue;
+            }
+            let (page, subpage_ix) = inner.page(ix as u64);
+            tasks.push(TaskInfo {
+                index: ix as u64,
+                label: inner.labels[ix],
+                notified: page.is_notified(subpage_ix),
+                completed: page.has_completed(subpage_ix),
+                dropped: page.was_dropped(subpage_ix),
+            });
         }
+        tasks
     }
 
     pub fn poll(&self) {
         let _s = static_span!();
         let mut inner = self.inner.borrow_mut();
         // inner.root_waker.register(ctx.waker());
-        for page_ix in 0..inner.pages.len() {
-            let (notified, dropped) = {
-                let page = &mut inner.pages[page_ix];
-                (page.take_notified(), page.take_dropped())
-            };
-            if notified != 0 {
-                for subpage_ix in iter_set_bits(notified) {
-                    let ix = page_ix * WAKER_PAGE_SIZE + subpage_ix;
-                    let waker =
-                        unsafe { Waker::from_raw(inner.pages[page_ix].raw_waker(subpage_ix)) };
-                    let mut sub_ctx = Context::from_waker(&waker);
-
-                    let pinned_ref = inner.slab.get_pin_mut(ix).unwrap();
-                    let pinned_ptr = unsafe { Pin::into_inner_unchecked(pinned_ref) as *mut _ };
-
-                    drop(inner);
-                    let pinned_ref = unsafe { Pin::new_unchecked(&mut *pinned_ptr) };
-                    let poll_result = {
-                        Future::poll(pinned_ref, &mut sub_ctx)
-                    };
-                    inner = self.inner.borrow_mut();
-
-                    match poll_result {
-                        Poll::Ready(()) => inner.pages[page_ix].mark_completed(subpage_ix),
-                        Poll::Pending => (),
+        let num_pages = inner.pages.len();
+        if num_pages == 0 {
+            return;
+        }
+        // Resume scanning from the cursor rather than from zero, so futures at high indices
+        // get their turn at the front of the pass under sustained load. The starting page is
+        // visited twice: once for the bits at or after the cursor, and once at the end of the
+        // pass for the bits that precede it.
+        let (start_page, start_subpage) = match inner.cursor {
+            c if c < num_pages * WAKER_PAGE_SIZE => (c / WAKER_PAGE_SIZE, c % WAKER_PAGE_SIZE),
+            _ => (0, 0),
+        };
+        let high_mask = !0u64 << start_subpage;
+        let mut first_polled = None;
+        let mut wrapped = 0;
+        for i in 0..num_pages {
+            let page_ix = (start_page + i) % num_pages;
+            let dropped;
+            if inner.notify_order == NotifyOrder::Fifo {
+                // Only take what's ready now, so a task that wakes itself waits for the next
+                // pass like it would with bits.
+                let mut ready = [0; WAKER_PAGE_SIZE];
+                let mut num_ready = 0;
+                while num_ready < WAKER_PAGE_SIZE {
+                    match inner.pages[page_ix].pop_notified() {
+                        Some(subpage_ix) => ready[num_ready] = subpage_ix,
+                        None => break,
                     }
+                    num_ready += 1;
+                }
+                dropped = inner.pages[page_ix].take_dropped();
+                let subpages = ready[..num_ready].iter().copied();
+                inner = self.poll_subpages(inner, page_ix, subpages, &mut first_polled);
+            } else {
+                let mut notified = inner.pages[page_ix].take_notified();
+                dropped = inner.pages[page_ix].take_dropped();
+                if i == 0 {
+                    wrapped = notified & !high_mask;
+                    notified &= high_mask;
                 }
+                let subpages = iter_set_bits(notified);
+                inner = self.poll_subpages(inner, page_ix, subpages, &mut first_polled);
             }
             if dropped != 0 {
                 for subpage_ix in iter_set_bits(dropped) {
                     let ix = page_ix * WAKER_PAGE_SIZE + subpage_ix;
                     inner.slab.remove(ix);
+                    inner.len -= 1;
+                    inner.labels[ix] = None;
                     inner.pages[page_ix].clear(subpage_ix);
+                    // `wrapped` only holds bits from the starting page.
+                    if page_ix == start_page {
+                        wrapped &= !(1 << subpage_ix);
+                    }
                 }
             }
         }
+        let subpages = iter_set_bits(wrapped);
+        inner = self.poll_subpages(inner, start_page, subpages, &mut first_polled);
+        if let Some(ix) = first_polled {
+            inner.cursor = ix + 1;
+        }
+    }
+
+    fn poll_subpages<'a>(
+        &'a self,
+        mut inner: RefMut<'a, Inner<F>>,
+        page_ix: usize,
+        subpages: impl Iterator<Item = usize>,
+        first_polled: &mut Option<usize>,
+    ) -> RefMut<'a, Inner<F>> {
+        for subpage_ix in subpages {
+            let ix = page_ix * WAKER_PAGE_SIZE + subpage_ix;
+            // A future polled earlier in this pass may have dropped this one.
+            if inner.pages[page_ix].was_dropped(subpage_ix) || inner.slab.get(ix).is_none() {
+                continue;
+            }
+            first_polled.get_or_insert(ix);
+            let waker = unsafe { Waker::from_raw(inner.pages[page_ix].raw_waker(subpage_ix)) };
+            let mut sub_ctx = Context::from_waker(&waker);
+
+            let pinned_ref = inner.slab.get_pin_mut(ix).unwrap();
+            let pinned_ptr = unsafe { Pin::into_inner_unchecked(pinned_ref) as *mut _ };
+
+            drop(inner);
+            let pinned_ref = unsafe { Pin::new_unchecked(&mut *pinned_ptr) };
+            let poll_result = { Future::poll(pinned_ref, &mut sub_ctx) };
+            inner = self.inner.borrow_mut();
+
+            match poll_result {
+                Poll::Ready(()) => inner.pages[page_ix].mark_completed(subpage_ix),
+                Poll::Pending => (),
+            }
+        }
+        inner
     }
 }
 
 struct Inner<F: Future<Output = ()> + Unpin> {
     slab: PinSlab<F>,
     pages: Vec<WakerPageRef>,
+    page_allocator: &'static dyn PageAllocator,
+    notify_order: NotifyOrder,
     root_waker: SharedWaker,
+    // Slot at which the next `poll` pass starts scanning.
+    cursor: usize,
+    // Number of occupied slab entries, including dropped ones that haven't been reaped yet.
+    len: usize,
+    // Debugging labels, indexed by slot and only touched on insertion and removal.
+    labels: Vec<Option<&'static str>>,
 }
 
 impl<F: Future<Output = ()> + Unpin> Inner<F> {
@@ -207,13 +406,360 @@ impl<F: Future<Output = ()> + Unpin> Inner<F> {
         (&self.pages[page_ix], subpage_ix)
     }
 
-    fn insert(&mut self, future: F) -> u64 {
+    fn insert(&mut self, future: F) -> Result<u64, Fail> {
         let key = self.slab.insert(future);
         while key >= self.pages.len() * WAKER_PAGE_SIZE {
-            self.pages.push(WakerPage::new(self.root_waker.clone()));
+            let root_waker = self.root_waker.clone();
+            match WakerPage::new_ordered(root_waker, self.page_allocator, self.notify_order) {
+                Ok(page) => self.pages.push(page),
+                Err(e) => {
+                    self.slab.remove_unpin(key);
+                    return Err(e);
+                },
+            }
+            self.labels.resize(self.pages.len() * WAKER_PAGE_SIZE, None);
         }
+        self.len += 1;
         let (page, subpage_ix) = self.page(key as u64);
         page.initialize(subpage_ix);
-        key as u64
+        Ok(key as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        yield_now,
+        Scheduler,
+    };
+    use crate::{
+        collections::waker_page::{
+            GlobalPageAllocator,
+            NotifyOrder,
+            PageAllocator,
+        },
+        fail::Fail,
+    };
+    use futures::{
+        future::poll_fn,
+        task::noop_waker_ref,
+    };
+    use must_let::must_let;
+    use std::{
+        alloc::Layout,
+        cell::{
+            Cell,
+            RefCell,
+        },
+        future::Future,
+        pin::Pin,
+        ptr::NonNull,
+        rc::Rc,
+        sync::atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        task::{
+            Context,
+            Poll,
+            Waker,
+        },
+    };
+
+    /// Never completes, and asks to be polled again every time it runs.
+    struct Spinner {
+        id: usize,
+        log: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl Future for Spinner {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+            self.log.borrow_mut().push(self.id);
+            ctx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    /// Never completes, and hangs on to the waker from its latest poll.
+    struct Stash {
+        waker: Rc<RefCell<Option<Waker>>>,
+        polls: Rc<Cell<usize>>,
+    }
+
+    impl Future for Stash {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+            *self.waker.borrow_mut() = Some(ctx.waker().clone());
+            self.polls.set(self.polls.get() + 1);
+            Poll::Pending
+        }
+    }
+
+    /// Completes on its first poll if `done` is set, otherwise never.
+    struct Oneshot {
+        done: bool,
+    }
+
+    impl Future for Oneshot {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _ctx: &mut Context) -> Poll<()> {
+            if self.done {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn test_counts() {
+        let scheduler = Scheduler::new();
+        assert_eq!(scheduler.page_count(), 0);
+
+        let mut handles: Vec<_> = (0..100)
+            .map(|i| scheduler.insert(Oneshot { done: i % 10 == 0 }))
+            .collect();
+        assert_eq!(scheduler.page_count(), 2);
+        assert_eq!(scheduler.live_count(), 100);
+        assert_eq!(scheduler.completed_count(), 0);
+
+        scheduler.poll();
+        assert_eq!(scheduler.live_count(), 100);
+        assert_eq!(scheduler.completed_count(), 10);
+
+        // Dropping handles is visible immediately, before the slots are reaped.
+        handles.truncate(95);
+        assert_eq!(scheduler.live_count(), 95);
+        assert_eq!(scheduler.pending_dropped_count(), 5);
+        scheduler.poll();
+        assert_eq!(scheduler.pending_dropped_count(), 0);
+        assert_eq!(scheduler.live_count(), 95);
+        // Slot 90 completed, so taking it leaves the other completed slots behind.
+        scheduler.take(handles.remove(90));
+        assert_eq!(scheduler.live_count(), 94);
+        assert_eq!(scheduler.completed_count(), 9);
+        assert_eq!(scheduler.page_count(), 2);
+    }
+
+    /// Fails one allocation each time `fail_next` is set, and otherwise goes to `Global`.
+    struct FailingAllocator {
+        fail_next: AtomicBool,
+    }
+
+    impl PageAllocator for FailingAllocator {
+        fn alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+            if self.fail_next.swap(false, Ordering::SeqCst) {
+                return None;
+            }
+            GlobalPageAllocator.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+            GlobalPageAllocator.dealloc(ptr, layout)
+        }
+    }
+
+    /// Logs every poll and otherwise waits to be woken externally.
+    struct Sleeper {
+        id: usize,
+        log: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl Future for Sleeper {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _ctx: &mut Context) -> Poll<()> {
+            self.log.borrow_mut().push(self.id);
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_page_alloc_failure() {
+        static ALLOCATOR: FailingAllocator = FailingAllocator {
+            fail_next: AtomicBool::new(true),
+        };
+        let scheduler = Scheduler::with_page_allocator(&ALLOCATOR);
+        must_let!(let Err(Fail::OutOfMemory {}) = scheduler.try_insert(Oneshot { done: true }));
+        assert_eq!(scheduler.live_count(), 0);
+        assert_eq!(scheduler.page_count(), 0);
+
+        // The failure doesn't stick: the next insert allocates the page and reuses the slot.
+        let _handle = scheduler.try_insert(Oneshot { done: true }).unwrap();
+        assert_eq!(scheduler.live_count(), 1);
+        assert_eq!(scheduler.page_count(), 1);
+    }
+
+    #[test]
+    fn test_stale_waker() {
+        let scheduler = Scheduler::new();
+        let stolen = Rc::new(RefCell::new(None));
+        let handle = scheduler.insert(Stash {
+            waker: stolen.clone(),
+            polls: Rc::new(Cell::new(0)),
+        });
+        scheduler.poll();
+        drop(handle);
+        scheduler.poll();
+
+        // The new future lands in the slot the old one vacated...
+        let polls = Rc::new(Cell::new(0));
+        let _handle = scheduler.insert(Stash {
+            waker: Rc::new(RefCell::new(None)),
+            polls: polls.clone(),
+        });
+        assert_eq!(scheduler.tasks()[0].index, 0);
+        scheduler.poll();
+        assert_eq!(polls.get(), 1);
+
+        // ...but the old future's waker doesn't reach it.
+        stolen.borrow_mut().take().unwrap().wake();
+        scheduler.poll();
+        assert_eq!(polls.get(), 1);
+    }
+
+    #[test]
+    fn test_named_tasks() {
+        let scheduler = Scheduler::new();
+        let _a = scheduler.insert_named(Oneshot { done: true }, "a");
+        let b = scheduler.insert_named(Oneshot { done: false }, "b");
+        let _c = scheduler.insert(Oneshot { done: false });
+        scheduler.poll();
+        drop(b);
+
+        let tasks = scheduler.tasks();
+        assert_eq!(tasks.len(), 3);
+        assert_eq!(tasks[0].label, Some("a"));
+        assert!(tasks[0].completed);
+        assert_eq!(tasks[1].label, Some("b"));
+        assert!(tasks[1].dropped);
+        assert_eq!(tasks[2].label, None);
+        assert!(!tasks[2].notified && !tasks[2].completed && !tasks[2].dropped);
+
+        // Reaped slots disappear from the dump, and reusing them doesn't inherit the label.
+        scheduler.poll();
+        let _d = scheduler.insert(Oneshot { done: false });
+        let tasks = scheduler.tasks();
+        assert_eq!(tasks.len(), 3);
+        assert_eq!(tasks[1].label, None);
+        assert!(tasks[1].notified);
+    }
+
+    #[test]
+    fn test_yield_now() {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let mut f = yield_now();
+        assert!(Future::poll(Pin::new(&mut f), &mut ctx).is_pending());
+        assert!(Future::poll(Pin::new(&mut f), &mut ctx).is_ready());
+
+        // Under the scheduler, the yield re-notifies the task so the next pass resumes it.
+        let scheduler = Scheduler::new();
+        let done = Rc::new(Cell::new(false));
+        let done_ = done.clone();
+        let future: Pin<Box<dyn Future<Output = ()>>> = Box::pin(async move {
+            yield_now().await;
+            done_.set(true);
+        });
+        let handle = scheduler.insert(future);
+        scheduler.poll();
+        assert!(!done.get() && !handle.has_completed());
+        scheduler.poll();
+        assert!(done.get() && handle.has_completed());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let scheduler = Scheduler::new();
+        let log = Rc::new(RefCell::new(vec![]));
+        // Span two pages so the cursor has to wrap across a page boundary.
+        let ids = [0, 1, 2, 70];
+        let mut handles = vec![];
+        for i in 0..=70 {
+            let future = Spinner {
+                id: i,
+                log: log.clone(),
+            };
+            let handle = scheduler.insert(future);
+            if ids.contains(&i) {
+                handles.push(handle);
+            }
+        }
+        // Drop everything but the spinners we're tracking.
+        scheduler.poll();
+        scheduler.poll();
+        log.borrow_mut().clear();
+
+        let mut leaders = vec![];
+        for _ in 0..ids.len() {
+            scheduler.poll();
+            let mut polled = log.borrow_mut().split_off(0);
+            leaders.push(polled[0]);
+            polled.sort();
+            // Every ready future gets exactly one turn per pass...
+            assert_eq!(polled, ids);
+        }
+        // ...and each one gets a turn at the front of the pass.
+        leaders.sort();
+        assert_eq!(leaders, ids);
+    }
+
+    #[test]
+    fn test_drop_keeps_wrapped_wakeup() {
+        let scheduler: Scheduler<Pin<Box<dyn Future<Output = ()>>>> = Scheduler::new();
+        let log = Rc::new(RefCell::new(vec![]));
+        let _spinner = scheduler.insert(Box::pin(Spinner {
+            id: 0,
+            log: log.clone(),
+        }));
+        // Fill out the first page, and put a task at the same subpage of the second.
+        let mut handles: Vec<_> = (1..=64)
+            .map(|id| {
+                scheduler.insert(Box::pin(Sleeper {
+                    id,
+                    log: log.clone(),
+                }))
+            })
+            .collect();
+        scheduler.poll();
+        log.borrow_mut().clear();
+
+        // The cursor is now past slot 0, so the spinner's wakeup waits for the end of the pass.
+        // Reaping slot 64 on the way mustn't clear it.
+        drop(handles.pop());
+        scheduler.poll();
+        assert_eq!(*log.borrow(), vec![0]);
+    }
+
+    #[test]
+    fn test_fifo_order() {
+        let scheduler = Scheduler::with_notify_order(NotifyOrder::Fifo);
+        let log = Rc::new(RefCell::new(vec![]));
+        let wakers: Vec<Rc<RefCell<Option<Waker>>>> =
+            (0..4).map(|_| Rc::new(RefCell::new(None))).collect();
+        let _handles: Vec<_> = (0..4)
+            .map(|id| {
+                let log = log.clone();
+                let waker = wakers[id].clone();
+                scheduler.insert(poll_fn(move |ctx| {
+                    log.borrow_mut().push(id);
+                    *waker.borrow_mut() = Some(ctx.waker().clone());
+                    Poll::Pending
+                }))
+            })
+            .collect();
+        scheduler.poll();
+        assert_eq!(log.borrow_mut().split_off(0), vec![0, 1, 2, 3]);
+
+        // Tasks run in the order they were woken, and waking one that's already waiting doesn't
+        // move it back.
+        for &id in &[2, 0, 3, 2] {
+            wakers[id].borrow().as_ref().unwrap().wake_by_ref();
+        }
+        scheduler.poll();
+        assert_eq!(*log.borrow(), vec![2, 0, 3]);
     }
 }
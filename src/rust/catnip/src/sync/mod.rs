@@ -3,16 +3,20 @@ mod threadunsafe;
 
 #[cfg(feature = "threadunsafe")]
 pub use self::threadunsafe::{
+    BufferPool,
     Bytes,
     BytesMut,
     SharedWaker,
+    ThreadOwner,
     WakerU64,
 };
 
 #[cfg(not(feature = "threadunsafe"))]
 pub use self::threadsafe::{
+    BufferPool,
     Bytes,
     BytesMut,
     SharedWaker,
+    ThreadOwner,
     WakerU64,
 };
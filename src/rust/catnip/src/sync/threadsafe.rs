@@ -12,6 +12,7 @@ use std::{
             Ordering,
         },
         Arc,
+        Mutex,
     },
     task::Waker,
 };
@@ -38,6 +39,23 @@ impl SharedWaker {
     }
 }
 
+/// Everything reachable from a `Waker` is atomic in this configuration, so any thread will do.
+pub struct ThreadOwner;
+
+impl Default for ThreadOwner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ThreadOwner {
+    pub fn new() -> Self {
+        ThreadOwner
+    }
+
+    pub fn assert_current(&self) {}
+}
+
 pub struct WakerU64(AtomicU64);
 
 impl WakerU64 {
@@ -45,12 +63,12 @@ impl WakerU64 {
         WakerU64(AtomicU64::new(val))
     }
 
-    pub fn fetch_or(&self, val: u64) {
-        self.0.fetch_or(val, Ordering::SeqCst);
+    pub fn fetch_or(&self, val: u64) -> u64 {
+        self.0.fetch_or(val, Ordering::SeqCst)
     }
 
-    pub fn fetch_and(&self, val: u64) {
-        self.0.fetch_and(val, Ordering::SeqCst);
+    pub fn fetch_and(&self, val: u64) -> u64 {
+        self.0.fetch_and(val, Ordering::SeqCst)
     }
 
     pub fn fetch_add(&self, val: u64) -> u64 {
@@ -120,6 +138,80 @@ impl Bytes {
     }
 }
 
+/// Fixed-size buffers that are handed out as `Bytes` and reused once every `Bytes` pointing into
+/// one is dropped, so a runtime can receive frames without allocating for each one.
+#[derive(Clone)]
+pub struct BufferPool {
+    buffer_size: usize,
+    inner: Arc<Mutex<PoolBuffers>>,
+}
+
+struct PoolBuffers {
+    // The pool keeps a reference to every buffer, so one's free whenever that's the only
+    // reference left. `Arc::get_mut` checks that atomically, however many threads are dropping
+    // clones at once.
+    buffers: Vec<Arc<[u8]>>,
+    // Where to start looking for a free buffer, just past the last one handed out.
+    next: usize,
+}
+
+impl BufferPool {
+    /// Allocates `count` buffers of `buffer_size` bytes up front.
+    pub fn new(buffer_size: usize, count: usize) -> Self {
+        assert!(buffer_size > 0);
+        let buffers = (0..count)
+            .map(|_| unsafe { Arc::new_zeroed_slice(buffer_size).assume_init() })
+            .collect();
+        Self {
+            buffer_size,
+            inner: Arc::new(Mutex::new(PoolBuffers { buffers, next: 0 })),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Number of buffers that aren't handed out.
+    pub fn available(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .buffers
+            .iter()
+            .filter(|buf| Arc::strong_count(buf) == 1)
+            .count()
+    }
+
+    /// Lets `fill` write the first `len` bytes of a free buffer, which still hold whatever was
+    /// last written there, or returns `None` if `len` doesn't fit or none are free.
+    pub fn fill(&self, len: usize, fill: impl FnOnce(&mut [u8])) -> Option<Bytes> {
+        if len == 0 || len > self.buffer_size {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let PoolBuffers {
+            ref mut buffers,
+            ref mut next,
+        } = *inner;
+        let n = buffers.len();
+        let ix = (0..n)
+            .map(|i| (*next + i) % n)
+            .find(|&ix| Arc::get_mut(&mut buffers[ix]).is_some())?;
+        fill(&mut Arc::get_mut(&mut buffers[ix]).unwrap()[..len]);
+        *next = (ix + 1) % n;
+        Some(Bytes {
+            buf: Some(buffers[ix].clone()),
+            offset: 0,
+            len,
+        })
+    }
+
+    /// Copies `data` into a free buffer, or returns `None` if it doesn't fit or none are free.
+    pub fn copy_from(&self, data: &[u8]) -> Option<Bytes> {
+        self.fill(data.len(), |buf| buf.copy_from_slice(data))
+    }
+}
+
 impl Deref for Bytes {
     type Target = [u8];
 
@@ -1,6 +1,9 @@
 #![allow(unused)]
 use std::{
-    cell::UnsafeCell,
+    cell::{
+        RefCell,
+        UnsafeCell,
+    },
     fmt,
     mem,
     ops::{
@@ -55,6 +58,38 @@ impl SharedWaker {
     }
 }
 
+thread_local! {
+    static THREAD_MARKER: u8 = 0;
+}
+
+fn current_thread_marker() -> usize {
+    THREAD_MARKER.with(|m| m as *const u8 as usize)
+}
+
+/// Remembers the thread that created it. `Waker`s are `Send` no matter what they point to, so
+/// anything reachable from one that isn't thread-safe has to check where it's being used.
+pub struct ThreadOwner(usize);
+
+impl Default for ThreadOwner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ThreadOwner {
+    pub fn new() -> Self {
+        Self(current_thread_marker())
+    }
+
+    pub fn assert_current(&self) {
+        assert_eq!(
+            self.0,
+            current_thread_marker(),
+            "Waker used off the thread that created it"
+        );
+    }
+}
+
 pub struct WakerU64(UnsafeCell<u64>);
 
 unsafe impl Sync for WakerU64 {}
@@ -64,14 +99,18 @@ impl WakerU64 {
         WakerU64(UnsafeCell::new(val))
     }
 
-    pub fn fetch_or(&self, val: u64) {
+    pub fn fetch_or(&self, val: u64) -> u64 {
         let s = unsafe { &mut *self.0.get() };
+        let old = *s;
         *s |= val;
+        old
     }
 
-    pub fn fetch_and(&self, val: u64) {
+    pub fn fetch_and(&self, val: u64) -> u64 {
         let s = unsafe { &mut *self.0.get() };
+        let old = *s;
         *s &= val;
+        old
     }
 
     pub fn fetch_add(&self, val: u64) -> u64 {
@@ -151,6 +190,79 @@ impl Bytes {
     }
 }
 
+/// Fixed-size buffers that are handed out as `Bytes` and reused once every `Bytes` pointing into
+/// one is dropped, so a runtime can receive frames without allocating for each one.
+#[derive(Clone)]
+pub struct BufferPool {
+    buffer_size: usize,
+    inner: Rc<RefCell<PoolBuffers>>,
+}
+
+struct PoolBuffers {
+    // The pool keeps a reference to every buffer, so one's free whenever that's the only
+    // reference left.
+    buffers: Vec<Rc<[u8]>>,
+    // Where to start looking for a free buffer, just past the last one handed out.
+    next: usize,
+}
+
+impl BufferPool {
+    /// Allocates `count` buffers of `buffer_size` bytes up front.
+    pub fn new(buffer_size: usize, count: usize) -> Self {
+        assert!(buffer_size > 0);
+        let buffers = (0..count)
+            .map(|_| unsafe { Rc::new_zeroed_slice(buffer_size).assume_init() })
+            .collect();
+        Self {
+            buffer_size,
+            inner: Rc::new(RefCell::new(PoolBuffers { buffers, next: 0 })),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Number of buffers that aren't handed out.
+    pub fn available(&self) -> usize {
+        let inner = self.inner.borrow();
+        inner
+            .buffers
+            .iter()
+            .filter(|buf| Rc::strong_count(buf) == 1)
+            .count()
+    }
+
+    /// Lets `fill` write the first `len` bytes of a free buffer, which still hold whatever was
+    /// last written there, or returns `None` if `len` doesn't fit or none are free.
+    pub fn fill(&self, len: usize, fill: impl FnOnce(&mut [u8])) -> Option<Bytes> {
+        if len == 0 || len > self.buffer_size {
+            return None;
+        }
+        let mut inner = self.inner.borrow_mut();
+        let PoolBuffers {
+            ref mut buffers,
+            ref mut next,
+        } = *inner;
+        let n = buffers.len();
+        let ix = (0..n)
+            .map(|i| (*next + i) % n)
+            .find(|&ix| Rc::get_mut(&mut buffers[ix]).is_some())?;
+        fill(&mut Rc::get_mut(&mut buffers[ix]).unwrap()[..len]);
+        *next = (ix + 1) % n;
+        Some(Bytes {
+            buf: Some(buffers[ix].clone()),
+            offset: 0,
+            len,
+        })
+    }
+
+    /// Copies `data` into a free buffer, or returns `None` if it doesn't fit or none are free.
+    pub fn copy_from(&self, data: &[u8]) -> Option<Bytes> {
+        self.fill(data.len(), |buf| buf.copy_from_slice(data))
+    }
+}
+
 impl Deref for Bytes {
     type Target = [u8];
 
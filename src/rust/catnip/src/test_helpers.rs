@@ -6,6 +6,7 @@ use crate::{
     protocols::{
         arp,
         ethernet2::MacAddress,
+        ipv4,
         tcp,
     },
     runtime::{
@@ -18,6 +19,7 @@ use crate::{
         SchedulerHandle,
     },
     sync::{
+        BufferPool,
         Bytes,
         BytesMut,
     },
@@ -29,6 +31,7 @@ use crate::{
 use futures::{
     FutureExt,
 };
+use hashbrown::HashMap;
 use rand::{
     distributions::{
         Distribution,
@@ -87,10 +90,14 @@ impl TestRuntime {
             rng: SmallRng::from_seed([0; 16]),
             incoming: VecDeque::new(),
             outgoing: VecDeque::new(),
+            link_outgoing: HashMap::new(),
+            frame_pool: None,
             link_addr,
             ipv4_addr,
             tcp_options: tcp::Options::default(),
             arp_options,
+            ipv4_options: ipv4::Options::default(),
+            ipv4_identification: 0,
         };
         Self {
             inner: Rc::new(RefCell::new(inner)),
@@ -98,10 +105,51 @@ impl TestRuntime {
         }
     }
 
+    /// Overrides the ARP options. Call this before constructing an `Engine` on this runtime.
+    pub fn set_arp_options(&self, options: arp::Options) {
+        self.inner.borrow_mut().arp_options = options;
+    }
+
+    /// Serializes transmitted frames into buffers from `pool` while it has any free, like a
+    /// runtime receiving into pooled buffers would, so whoever the frames are handed to gets
+    /// `Bytes` that go back to the pool once dropped.
+    pub fn set_frame_pool(&self, pool: Option<BufferPool>) {
+        self.inner.borrow_mut().frame_pool = pool;
+    }
+
+    fn serialize_frame(&self, pkt: impl PacketBuf) -> Bytes {
+        let size = pkt.compute_size();
+        let pooled = match self.inner.borrow().frame_pool {
+            Some(ref pool) => pool.fill(size, |buf| pkt.serialize(buf)),
+            None => None,
+        };
+        pooled.unwrap_or_else(|| {
+            let mut buf = BytesMut::zeroed(size);
+            pkt.serialize(&mut buf[..]);
+            buf.freeze()
+        })
+    }
+
     pub fn pop_frame(&self) -> Bytes {
         self.inner.borrow_mut().outgoing.pop_front().unwrap()
     }
 
+    pub fn pop_frame_opt(&self) -> Option<Bytes> {
+        self.inner.borrow_mut().outgoing.pop_front()
+    }
+
+    /// Pops a frame sent with `transmit_on_link`. Link 0 shares the queue behind `pop_frame`.
+    pub fn pop_frame_on_link(&self, link_index: usize) -> Option<Bytes> {
+        if link_index == 0 {
+            return self.pop_frame_opt();
+        }
+        self.inner
+            .borrow_mut()
+            .link_outgoing
+            .get_mut(&link_index)?
+            .pop_front()
+    }
+
     pub fn push_frame(&self, buf: Bytes) {
         self.inner.borrow_mut().incoming.push_back(buf);
     }
@@ -119,21 +167,37 @@ struct Inner {
     rng: SmallRng,
     incoming: VecDeque<Bytes>,
     outgoing: VecDeque<Bytes>,
+    // Frames transmitted on links other than 0.
+    link_outgoing: HashMap<usize, VecDeque<Bytes>>,
+    frame_pool: Option<BufferPool>,
 
     link_addr: MacAddress,
     ipv4_addr: Ipv4Addr,
     tcp_options: tcp::Options,
     arp_options: arp::Options,
+    ipv4_options: ipv4::Options,
+    ipv4_identification: u16,
 }
 
 impl Runtime for TestRuntime {
     type WaitFuture = crate::timer::WaitFuture<TimerRc>;
 
     fn transmit(&self, pkt: impl PacketBuf) {
-        let size = pkt.compute_size();
-        let mut buf = BytesMut::zeroed(size);
-        pkt.serialize(&mut buf[..]);
-        self.inner.borrow_mut().outgoing.push_back(buf.freeze());
+        let buf = self.serialize_frame(pkt);
+        self.inner.borrow_mut().outgoing.push_back(buf);
+    }
+
+    fn transmit_on_link(&self, link_index: usize, pkt: impl PacketBuf) {
+        if link_index == 0 {
+            return self.transmit(pkt);
+        }
+        let buf = self.serialize_frame(pkt);
+        self.inner
+            .borrow_mut()
+            .link_outgoing
+            .entry(link_index)
+            .or_insert_with(VecDeque::new)
+            .push_back(buf);
     }
 
     fn receive(&self) -> Option<Bytes> {
@@ -160,6 +224,18 @@ impl Runtime for TestRuntime {
         self.inner.borrow().arp_options.clone()
     }
 
+    fn ipv4_options(&self) -> ipv4::Options {
+        self.inner.borrow().ipv4_options.clone()
+    }
+
+    fn set_local_ipv4_addr(&self, addr: Ipv4Addr) {
+        self.inner.borrow_mut().ipv4_addr = addr;
+    }
+
+    fn set_ipv4_options(&self, options: ipv4::Options) {
+        self.inner.borrow_mut().ipv4_options = options;
+    }
+
     fn advance_clock(&self, now: Instant) {
         self.inner.borrow_mut().timer.0.advance_clock(now);
     }
@@ -182,6 +258,10 @@ impl Runtime for TestRuntime {
         self.inner.borrow().timer.0.now()
     }
 
+    fn next_timer_expiry(&self) -> Option<Instant> {
+        self.inner.borrow().timer.0.next_expiry()
+    }
+
     fn rng_gen<T>(&self) -> T
     where
         Standard: Distribution<T>,
@@ -190,6 +270,13 @@ impl Runtime for TestRuntime {
         inner.rng.gen()
     }
 
+    fn next_ipv4_identification(&self) -> u16 {
+        let mut inner = self.inner.borrow_mut();
+        let id = inner.ipv4_identification;
+        inner.ipv4_identification = id.wrapping_add(1);
+        id
+    }
+
     fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) -> SchedulerHandle {
         self.scheduler
             .insert(Operation::Background(future.boxed_local()))
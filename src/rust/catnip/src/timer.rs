@@ -124,6 +124,15 @@ impl<P: TimerPtr> Timer<P> {
         self.inner.borrow().now
     }
 
+    /// When the earliest pending wait expires, or `None` if nothing's waiting.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        let inner = self.inner.borrow();
+        inner
+            .heap
+            .peek_min()
+            .map(|first| unsafe { first.as_ref().expiry })
+    }
+
     pub fn wait(&self, ptr: P, timeout: Duration) -> WaitFuture<P> {
         self.wait_until(ptr, self.now() + timeout)
     }
@@ -230,8 +239,10 @@ mod tests {
         Timer,
         TimerRc,
     };
+    use crate::scheduler::Scheduler;
     use futures::task::noop_waker_ref;
     use std::{
+        cell::RefCell,
         future::Future,
         pin::Pin,
         rc::Rc,
@@ -281,4 +292,62 @@ mod tests {
 
         assert!(Future::poll(Pin::new(&mut wait_future1), &mut ctx).is_ready());
     }
+
+    #[test]
+    fn test_next_expiry() {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let now = Instant::now();
+        let timer = TimerRc(Rc::new(Timer::new(now)));
+        assert_eq!(timer.next_expiry(), None);
+
+        // Waits only count once they're polled and registered.
+        let wait_future1 = timer.wait(timer.clone(), Duration::from_secs(2));
+        futures::pin_mut!(wait_future1);
+        assert_eq!(timer.next_expiry(), None);
+        assert!(Future::poll(Pin::new(&mut wait_future1), &mut ctx).is_pending());
+        assert_eq!(timer.next_expiry(), Some(now + Duration::from_secs(2)));
+
+        {
+            let wait_future2 = timer.wait(timer.clone(), Duration::from_secs(1));
+            futures::pin_mut!(wait_future2);
+            assert!(Future::poll(Pin::new(&mut wait_future2), &mut ctx).is_pending());
+            assert_eq!(timer.next_expiry(), Some(now + Duration::from_secs(1)));
+        }
+        // Dropping a wait takes it out again.
+        assert_eq!(timer.next_expiry(), Some(now + Duration::from_secs(2)));
+
+        timer.advance_clock(now + Duration::from_secs(2));
+        assert_eq!(timer.next_expiry(), None);
+        assert!(Future::poll(Pin::new(&mut wait_future1), &mut ctx).is_ready());
+    }
+
+    // Expiring a wait wakes the task awaiting it through its scheduler slot, so the scheduler
+    // runs timed-out tasks in deadline order as the clock advances.
+    #[test]
+    fn test_wakes_scheduled_tasks_in_order() {
+        let now = Instant::now();
+        let timer = TimerRc(Rc::new(Timer::new(now)));
+        let scheduler = Scheduler::new();
+        let log = Rc::new(RefCell::new(vec![]));
+        let _handles: Vec<_> = [3, 1, 2]
+            .iter()
+            .map(|&secs| {
+                let wait = timer.wait(timer.clone(), Duration::from_secs(secs));
+                let log = log.clone();
+                let future: Pin<Box<dyn Future<Output = ()>>> = Box::pin(async move {
+                    wait.await;
+                    log.borrow_mut().push(secs);
+                });
+                scheduler.insert(future)
+            })
+            .collect();
+        scheduler.poll();
+        assert!(log.borrow().is_empty());
+
+        for secs in 1..=3 {
+            timer.advance_clock(now + Duration::from_secs(secs));
+            scheduler.poll();
+            assert_eq!(*log.borrow(), (1..=secs).collect::<Vec<_>>());
+        }
+    }
 }
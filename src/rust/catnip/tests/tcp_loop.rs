@@ -76,12 +76,12 @@ fn tcp_loop() {
     let listen_port = ip::Port::try_from(80).unwrap();
     let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
 
-    let listen_fd = bob.tcp_socket();
+    let listen_fd = bob.tcp_socket().unwrap();
     bob.tcp_bind(listen_fd, listen_addr).unwrap();
     bob.tcp_listen(listen_fd, 1).unwrap();
     let mut accept_future = bob.tcp_accept(listen_fd);
 
-    let alice_fd = alice.tcp_socket();
+    let alice_fd = alice.tcp_socket().unwrap();
     let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
 
     // Send the SYN from Alice to Bob
@@ -95,6 +95,8 @@ impl TestRuntime {
             ipv4_addr,
             tcp_options: tcp::Options::default(),
             arp_options,
+            ipv4_options: ipv4::Options::default(),
+            ipv4_identification: 0,
         };
         Self {
             inner: Rc::new(RefCell::new(inner)),
@@ -113,6 +115,8 @@ struct Inner {
     ipv4_addr: Ipv4Addr,
     tcp_options: tcp::Options,
     arp_options: arp::Options,
+    ipv4_options: ipv4::Options,
+    ipv4_identification: u16,
 }
 
 impl Runtime for TestRuntime {
@@ -155,6 +159,18 @@ impl Runtime for TestRuntime {
         self.inner.borrow().arp_options.clone()
     }
 
+    fn ipv4_options(&self) -> ipv4::Options {
+        self.inner.borrow().ipv4_options.clone()
+    }
+
+    fn set_local_ipv4_addr(&self, addr: Ipv4Addr) {
+        self.inner.borrow_mut().ipv4_addr = addr;
+    }
+
+    fn set_ipv4_options(&self, options: ipv4::Options) {
+        self.inner.borrow_mut().ipv4_options = options;
+    }
+
     fn advance_clock(&self, now: Instant) {
         self.inner.borrow_mut().timer.0.advance_clock(now);
     }
@@ -177,6 +193,10 @@ impl Runtime for TestRuntime {
         self.inner.borrow().timer.0.now()
     }
 
+    fn next_timer_expiry(&self) -> Option<Instant> {
+        self.inner.borrow().timer.0.next_expiry()
+    }
+
     fn rng_gen<T>(&self) -> T
     where
         Standard: Distribution<T>,
@@ -185,6 +205,13 @@ impl Runtime for TestRuntime {
         inner.rng.gen()
     }
 
+    fn next_ipv4_identification(&self) -> u16 {
+        let mut inner = self.inner.borrow_mut();
+        let id = inner.ipv4_identification;
+        inner.ipv4_identification = id.wrapping_add(1);
+        id
+    }
+
     fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) -> SchedulerHandle {
         self.scheduler
             .insert(Operation::Background(future.boxed_local()))
@@ -0,0 +1,56 @@
+#![feature(const_fn, const_mut_refs, const_type_name)]
+
+mod common;
+
+use catnip::{
+    engine::Protocol,
+    fail::Fail,
+    protocols::{
+        ethernet2::frame::ETHERNET2_HEADER2_SIZE,
+        ip,
+        ipv4,
+    },
+    sync::BytesMut,
+    test_helpers,
+};
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    time::Instant,
+};
+
+// Datagrams whose UDP length or IPv4 TOTALLEN claim far more than they carry are dropped as
+// malformed, without anything along the way allocating for the claimed size.
+#[test]
+fn giant_length_rejected() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let port = ip::Port::try_from(80).unwrap();
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port);
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let buf = BytesMut::from(&[1u8, 2, 3][..]).freeze();
+    bob.udp().pushto(bob_fd, buf, alice_addr).unwrap();
+    let frame = bob.rt().pop_frame();
+
+    let ipv4_start = ETHERNET2_HEADER2_SIZE;
+    let udp_start = ipv4_start + 20;
+    for &offset in &[udp_start + 4, ipv4_start + 2] {
+        let mut giant = BytesMut::from(&frame[..]);
+        giant[offset] = 0xff;
+        giant[offset + 1] = 0xff;
+        let giant = giant.freeze();
+
+        let (r, largest) = common::largest_allocation(|| alice.receive(giant));
+        must_let!(let Err(Fail::Malformed { .. }) = r);
+        assert!(largest < 0xffff);
+    }
+
+    assert_eq!(alice.udp().stats().bad_length, 1);
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 0);
+    alice.receive(frame).unwrap();
+    assert_eq!(alice.udp().recv_queue_len(alice_fd).unwrap(), 1);
+}
@@ -40,11 +40,11 @@ fn udp_loop() {
     let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port);
     let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
 
-    let alice_fd = alice.socket(Protocol::Udp);
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
     let _ = alice.bind(alice_fd, alice_addr);
     let _ = alice.connect(alice_fd, bob_addr);
 
-    let bob_fd = bob.socket(Protocol::Udp);
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
     let _ = bob.bind(bob_fd, bob_addr);
     let _ = bob.connect(bob_fd, alice_addr);
 
@@ -115,3 +115,57 @@ fn udp_loop() {
     );
     println!("Max:   {:?}", Duration::from_nanos(h.maximum().unwrap()));
 }
+
+// Compares one-way sends through `push`, which looks the socket up every time, with sends
+// through a `SendHandle` taken once up front.
+#[test]
+fn udp_send_handle_loop() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let port = ip::Port::try_from(80).unwrap();
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port);
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
+
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let _ = alice.bind(alice_fd, alice_addr);
+    let _ = alice.connect(alice_fd, bob_addr);
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let _ = bob.bind(bob_fd, bob_addr);
+
+    let buf = BytesMut::from(&vec![0u8; 32][..]).freeze();
+    let num_rounds: usize = env::var("SEND_RECV_ITERS")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(1);
+
+    let mut push = histogram::Histogram::new();
+    for _ in 0..num_rounds {
+        let start = Instant::now();
+        alice.udp().push(alice_fd, buf.clone()).unwrap();
+        push.increment(start.elapsed().as_nanos() as u64).unwrap();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        bob.udp().drain_pending(bob_fd).unwrap();
+    }
+
+    let handle = alice.udp().send_handle(alice_fd).unwrap();
+    let mut handled = histogram::Histogram::new();
+    for _ in 0..num_rounds {
+        let start = Instant::now();
+        handle.push(buf.clone()).unwrap();
+        handled
+            .increment(start.elapsed().as_nanos() as u64)
+            .unwrap();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        bob.udp().drain_pending(bob_fd).unwrap();
+    }
+
+    for (name, h) in &[("push", push), ("handle", handled)] {
+        println!(
+            "{:6} p50: {:?}, p99: {:?}",
+            name,
+            Duration::from_nanos(h.percentile(0.50).unwrap()),
+            Duration::from_nanos(h.percentile(0.99).unwrap())
+        );
+    }
+}
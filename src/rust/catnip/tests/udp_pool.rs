@@ -0,0 +1,91 @@
+#![feature(const_fn, const_mut_refs, const_type_name)]
+
+mod common;
+
+use catnip::{
+    engine::Protocol,
+    protocols::{
+        ip,
+        ipv4,
+    },
+    sync::{
+        BufferPool,
+        BytesMut,
+    },
+    test_helpers,
+};
+use futures::{
+    task::noop_waker_ref,
+    Future,
+};
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    env,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    time::Instant,
+};
+
+// Counts allocations per round of sending, receiving, popping and dropping a datagram, with frames
+// allocated as usual and with the runtime putting them in pooled buffers.
+#[test]
+fn udp_pool_allocations() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let port = ip::Port::try_from(80).unwrap();
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port);
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
+
+    let alice_fd = alice.socket(Protocol::Udp).unwrap();
+    let _ = alice.bind(alice_fd, alice_addr);
+    let _ = alice.connect(alice_fd, bob_addr);
+    let bob_fd = bob.socket(Protocol::Udp).unwrap();
+    let _ = bob.bind(bob_fd, bob_addr);
+
+    let buf = BytesMut::from(&vec![0u8; 32][..]).freeze();
+    let num_rounds: usize = env::var("SEND_RECV_ITERS")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(1);
+
+    let pool = BufferPool::new(1514, 4);
+    let mut counts = vec![];
+    for frame_pool in vec![None, Some(pool.clone())] {
+        alice.rt().set_frame_pool(frame_pool);
+        let mut start = 0;
+        for i in 0..num_rounds + 1 {
+            // The first round grows queues and maps to size, which we don't count.
+            if i == 1 {
+                start = common::allocations();
+            }
+            alice.udp_push(alice_fd, buf.clone()).unwrap();
+            alice.rt().poll_scheduler();
+            bob.receive(alice.rt().pop_frame()).unwrap();
+
+            let mut pop_future = bob.udp_pop(bob_fd);
+            must_let!(let Poll::Ready(Ok((_, recv_buf))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+            assert_eq!(recv_buf.len(), buf.len());
+        }
+        counts.push(common::allocations() - start);
+    }
+    assert_eq!(pool.available(), 4);
+
+    for (name, &count) in ["default", "pooled"].iter().zip(&counts) {
+        println!(
+            "{:7} {} allocations, {:.1} per round",
+            name,
+            count,
+            count as f64 / num_rounds as f64
+        );
+    }
+    assert!(
+        counts[1] < counts[0],
+        "Pooled frames allocated as much as the default"
+    );
+}
@@ -28,6 +28,7 @@ use crate::{
         rte_mempool,
         rte_pktmbuf_pool_create,
         rte_socket_id,
+        DEV_RX_OFFLOAD_CHECKSUM,
         ETH_LINK_FULL_DUPLEX,
         ETH_LINK_UP,
         ETH_RSS_IP,
@@ -43,7 +44,10 @@ use anyhow::{
     format_err,
     Error,
 };
-use catnip::protocols::ethernet2::MacAddress;
+use catnip::{
+    protocols::ethernet2::MacAddress,
+    sync::BufferPool,
+};
 use std::{
     ffi::CString,
     mem::MaybeUninit,
@@ -68,6 +72,8 @@ pub fn initialize_dpdk(
     eal_init_args: &[CString],
     arp_table: HashMap<MacAddress, Ipv4Addr>,
     disable_arp: bool,
+    rx_queues: u16,
+    rx_pool_buffers: usize,
 ) -> Result<DPDKRuntime, Error> {
     std::env::set_var("MLX5_SHUT_UP_BF", "1");
     let eal_init_refs = eal_init_args
@@ -102,18 +108,24 @@ pub fn initialize_dpdk(
     if mbuf_pool.is_null() {
         Err(format_err!("rte_pktmbuf_pool_create failed"))?;
     }
-    let mut port_id = 0;
+    // Each port becomes a link, in the order DPDK enumerates them.
+    let mut port_ids = vec![];
     {
         let owner = RTE_ETH_DEV_NO_OWNER as u64;
         let mut p = unsafe { rte_eth_find_next_owned_by(0, owner) as u16 };
 
         while p < RTE_MAX_ETHPORTS as u16 {
-            // TODO: This is pretty hax, we clearly only support one port.
-            port_id = p;
-            initialize_dpdk_port(p, mbuf_pool)?;
+            port_ids.push(p);
+            initialize_dpdk_port(p, mbuf_pool, rx_queues)?;
             p = unsafe { rte_eth_find_next_owned_by(p + 1, owner) as u16 };
         }
     }
+    if port_ids.is_empty() {
+        bail!("No unowned ethernet ports available");
+    }
+    // TODO: We only have one link address, so frames for the other links' addresses are dropped
+    // unless they're broadcast.
+    let port_id = port_ids[0];
 
     // TODO: Where is this function?
     // if unsafe { rte_lcore_count() } > 1 {
@@ -130,18 +142,33 @@ pub fn initialize_dpdk(
         Err(format_err!("Invalid mac address"))?;
     }
 
+    // Any frame fits in a buffer the size of an mbuf.
+    let rx_pool = if rx_pool_buffers > 0 {
+        Some(BufferPool::new(
+            RTE_MBUF_DEFAULT_BUF_SIZE as usize,
+            rx_pool_buffers,
+        ))
+    } else {
+        None
+    };
+
     Ok(DPDKRuntime::new(
         local_link_addr,
         local_ipv4_addr,
-        port_id,
+        port_ids,
         mbuf_pool,
         arp_table,
         disable_arp,
+        rx_queues,
+        rx_pool,
     ))
 }
 
-fn initialize_dpdk_port(port_id: u16, mbuf_pool: *mut rte_mempool) -> Result<(), Error> {
-    let rx_rings = 1;
+fn initialize_dpdk_port(
+    port_id: u16,
+    mbuf_pool: *mut rte_mempool,
+    rx_rings: u16,
+) -> Result<(), Error> {
     let tx_rings = 1;
     let rx_ring_size = 128;
     let tx_ring_size = 512;
@@ -166,6 +193,9 @@ fn initialize_dpdk_port(port_id: u16, mbuf_pool: *mut rte_mempool) -> Result<(),
     port_conf.rxmode.max_rx_pkt_len = RTE_ETHER_MAX_LEN;
     port_conf.rxmode.mq_mode = ETH_MQ_RX_RSS;
     port_conf.rx_adv_conf.rss_conf.rss_hf = ETH_RSS_IP as u64 | dev_info.flow_type_rss_offloads;
+    // Have the NIC check IPv4 and L4 checksums where it can, which the runtime reports through
+    // `RxMetadata::checksum_validated`.
+    port_conf.rxmode.offloads = dev_info.rx_offload_capa & DEV_RX_OFFLOAD_CHECKSUM as u64;
     port_conf.txmode.mq_mode = ETH_MQ_TX_NONE;
 
     let mut rx_conf: rte_eth_rxconf = unsafe { MaybeUninit::zeroed().assume_init() };
@@ -164,7 +164,21 @@ pub extern "C" fn dmtr_init(argc: c_int, argv: *mut *mut c_char) -> c_int {
             _ => Err(format_err!("Malformed YAML config"))?,
         };
 
-        let runtime = self::dpdk::initialize_dpdk(local_ipv4_addr, &eal_init_args, arp_table, disable_arp)?;
+        // RX queues per port, which RSS spreads flows across.
+        let rx_queues = match config_obj["dpdk"]["rx_queues"].as_i64() {
+            Some(n) if n >= 1 && n <= u16::MAX as i64 => n as u16,
+            Some(..) => Err(format_err!("Invalid rx_queues in config"))?,
+            None => 1,
+        };
+
+        // Buffers to receive frames into, rather than allocating for each one. None by default.
+        let rx_pool_buffers = match config_obj["dpdk"]["rx_pool_buffers"].as_i64() {
+            Some(n) if n >= 0 => n as usize,
+            Some(..) => Err(format_err!("Invalid rx_pool_buffers in config"))?,
+            None => 0,
+        };
+
+        let runtime = self::dpdk::initialize_dpdk(local_ipv4_addr, &eal_init_args, arp_table, disable_arp, rx_queues, rx_pool_buffers)?;
         logging::initialize();
         LibOS::new(runtime)?
     };
@@ -4,16 +4,22 @@ use crate::bindings::{
     rte_eth_devices,
     rte_mbuf,
     rte_mempool,
+    PKT_RX_IP_CKSUM_GOOD,
+    PKT_RX_IP_CKSUM_MASK,
+    PKT_RX_L4_CKSUM_GOOD,
+    PKT_RX_L4_CKSUM_MASK,
 };
 use catnip::{
     protocols::{
         arp,
         ethernet2::MacAddress,
+        ipv4,
         tcp,
     },
     runtime::{
         PacketBuf,
         Runtime,
+        RxMetadata,
     },
     scheduler::{
         Operation,
@@ -21,6 +27,7 @@ use catnip::{
         SchedulerHandle,
     },
     sync::{
+        BufferPool,
         Bytes,
         BytesMut,
     },
@@ -93,21 +100,26 @@ impl DPDKRuntime {
     pub fn new(
         link_addr: MacAddress,
         ipv4_addr: Ipv4Addr,
-        dpdk_port_id: u16,
+        dpdk_port_ids: Vec<u16>,
         dpdk_mempool: *mut rte_mempool,
         arp_table: HashMap<MacAddress, Ipv4Addr>,
         disable_arp: bool,
+        rx_queues: u16,
+        rx_pool: Option<BufferPool>,
     ) -> Self {
         let mut rng = rand::thread_rng();
         let rng = SmallRng::from_rng(&mut rng).expect("Failed to initialize RNG");
         let now = Instant::now();
 
-        let mut buffered: MaybeUninit<[Bytes; MAX_QUEUE_DEPTH]> = MaybeUninit::uninit();
+        assert!(!dpdk_port_ids.is_empty());
+        assert!(rx_queues > 0);
+        let mut buffered: MaybeUninit<[(Bytes, RxMetadata); MAX_QUEUE_DEPTH]> =
+            MaybeUninit::uninit();
         for i in 0..MAX_QUEUE_DEPTH {
             unsafe {
-                (buffered.as_mut_ptr() as *mut Bytes)
+                (buffered.as_mut_ptr() as *mut (Bytes, RxMetadata))
                     .offset(i as isize)
-                    .write(Bytes::empty())
+                    .write((Bytes::empty(), RxMetadata::default()))
             };
         }
         let mut arp_options = arp::Options::default();
@@ -119,10 +131,15 @@ impl DPDKRuntime {
             ipv4_addr,
             rng,
             arp_options,
+            ipv4_options: ipv4::Options::default(),
+            ipv4_identification: 0,
             tcp_options: tcp::Options::default(),
 
-            dpdk_port_id,
+            dpdk_port_ids,
             dpdk_mempool,
+            rx_queues,
+            next_rx_queue: 0,
+            rx_pool,
 
             num_buffered: 0,
             buffered: unsafe { buffered.assume_init() },
@@ -140,21 +157,40 @@ struct Inner {
     ipv4_addr: Ipv4Addr,
     rng: SmallRng,
     arp_options: arp::Options,
+    ipv4_options: ipv4::Options,
+    ipv4_identification: u16,
     tcp_options: tcp::Options,
 
-    dpdk_port_id: u16,
+    // The DPDK port behind each link, indexed by link.
+    dpdk_port_ids: Vec<u16>,
     dpdk_mempool: *mut rte_mempool,
+    // RX queues on each port.
+    rx_queues: u16,
+    // Link and queue, numbered `link * rx_queues + queue`, to poll first on the next RX burst,
+    // so a busy one doesn't starve the others.
+    next_rx_queue: usize,
+    // Buffers received frames are copied into while any are free, instead of allocating one for
+    // each frame.
+    rx_pool: Option<BufferPool>,
 
     num_buffered: usize,
-    buffered: [Bytes; MAX_QUEUE_DEPTH],
+    buffered: [(Bytes, RxMetadata); MAX_QUEUE_DEPTH],
 }
 
 impl Runtime for DPDKRuntime {
     type WaitFuture = WaitFuture<TimerRc>;
 
     fn transmit(&self, buf: impl PacketBuf) {
+        self.transmit_on_link(0, buf)
+    }
+
+    fn transmit_on_link(&self, link_index: usize, buf: impl PacketBuf) {
         let pool = { self.inner.borrow().dpdk_mempool };
-        let dpdk_port_id = { self.inner.borrow().dpdk_port_id };
+        let dpdk_port_id = match self.inner.borrow().dpdk_port_ids.get(link_index) {
+            Some(&port_id) => port_id,
+            // Like a route to an interface that's gone, there's nowhere to send it.
+            None => return,
+        };
         let mut pkt = unsafe { catnip_libos_alloc_pkt(pool) };
         assert!(!pkt.is_null());
 
@@ -179,30 +215,55 @@ impl Runtime for DPDKRuntime {
     }
 
     fn receive(&self) -> Option<Bytes> {
+        self.receive_with_metadata().map(|(buf, _)| buf)
+    }
+
+    fn receive_with_metadata(&self) -> Option<(Bytes, RxMetadata)> {
         let mut inner = self.inner.borrow_mut();
         loop {
             if inner.num_buffered > 0 {
                 inner.num_buffered -= 1;
                 let ix = inner.num_buffered;
-                return Some(mem::replace(&mut inner.buffered[ix], Bytes::empty()));
+                let empty = (Bytes::empty(), RxMetadata::default());
+                return Some(mem::replace(&mut inner.buffered[ix], empty));
             }
 
-            let dpdk_port = inner.dpdk_port_id;
+            // Take the next burst from the first queue, starting after the one we last took from,
+            // that has anything.
+            let rx_queues = inner.rx_queues as usize;
+            let num_queues = inner.dpdk_port_ids.len() * rx_queues;
             let mut packets: [*mut rte_mbuf; MAX_QUEUE_DEPTH] = unsafe { mem::zeroed() };
-
-            // rte_eth_rx_burst is declared `inline` in the header.
-            let nb_rx = unsafe {
-                catnip_libos_eth_rx_burst(
-                    dpdk_port,
-                    0,
-                    packets.as_mut_ptr(),
-                    MAX_QUEUE_DEPTH as u16,
-                )
-            };
-            assert!(nb_rx as usize <= MAX_QUEUE_DEPTH);
+            let mut nb_rx = 0;
+            let mut ix = 0;
+            for i in 0..num_queues {
+                ix = (inner.next_rx_queue + i) % num_queues;
+                let dpdk_port = inner.dpdk_port_ids[ix / rx_queues];
+
+                // rte_eth_rx_burst is declared `inline` in the header.
+                nb_rx = unsafe {
+                    catnip_libos_eth_rx_burst(
+                        dpdk_port,
+                        (ix % rx_queues) as u16,
+                        packets.as_mut_ptr(),
+                        MAX_QUEUE_DEPTH as u16,
+                    )
+                };
+                assert!(nb_rx as usize <= MAX_QUEUE_DEPTH);
+                if nb_rx > 0 {
+                    break;
+                }
+            }
             if nb_rx == 0 {
                 return None;
             }
+            inner.next_rx_queue = (ix + 1) % num_queues;
+            let link_index = ix / rx_queues;
+            // With a single queue, RSS hasn't told us anything about the flow.
+            let rx_queue = if rx_queues > 1 {
+                Some(ix % rx_queues)
+            } else {
+                None
+            };
             // let dev = unsafe { rte_eth_devices[dpdk_port as usize] };
             // let rx_burst = dev.rx_pkt_burst.expect("Missing RX burst function");
             // // This only supports queue_id 0.
@@ -215,8 +276,18 @@ impl Runtime for DPDKRuntime {
                 };
 
                 let data = unsafe { slice::from_raw_parts(p, (*packet).data_len as usize) };
+                let metadata = RxMetadata {
+                    link_index,
+                    checksum_validated: checksums_good(unsafe { (*packet).ol_flags }),
+                    rx_queue,
+                };
+                let frame = inner
+                    .rx_pool
+                    .as_ref()
+                    .and_then(|pool| pool.copy_from(data))
+                    .unwrap_or_else(|| BytesMut::from(data).freeze());
                 let ix = inner.num_buffered;
-                inner.buffered[ix] = BytesMut::from(data).freeze();
+                inner.buffered[ix] = (frame, metadata);
                 inner.num_buffered += 1;
 
                 unsafe { catnip_libos_free_pkt(packet as *const _ as *mut _) };
@@ -240,6 +311,18 @@ impl Runtime for DPDKRuntime {
         self.inner.borrow().arp_options.clone()
     }
 
+    fn ipv4_options(&self) -> ipv4::Options {
+        self.inner.borrow().ipv4_options.clone()
+    }
+
+    fn set_local_ipv4_addr(&self, addr: Ipv4Addr) {
+        self.inner.borrow_mut().ipv4_addr = addr;
+    }
+
+    fn set_ipv4_options(&self, options: ipv4::Options) {
+        self.inner.borrow_mut().ipv4_options = options;
+    }
+
     fn advance_clock(&self, now: Instant) {
         self.inner.borrow_mut().timer.0.advance_clock(now);
     }
@@ -262,6 +345,10 @@ impl Runtime for DPDKRuntime {
         self.inner.borrow().timer.0.now()
     }
 
+    fn next_timer_expiry(&self) -> Option<Instant> {
+        self.inner.borrow().timer.0.next_expiry()
+    }
+
     fn rng_gen<T>(&self) -> T
     where
         Standard: Distribution<T>,
@@ -270,6 +357,13 @@ impl Runtime for DPDKRuntime {
         self_.rng.gen()
     }
 
+    fn next_ipv4_identification(&self) -> u16 {
+        let mut self_ = self.inner.borrow_mut();
+        let id = self_.ipv4_identification;
+        self_.ipv4_identification = id.wrapping_add(1);
+        id
+    }
+
     fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) -> SchedulerHandle {
         self.scheduler
             .insert(Operation::Background(future.boxed_local()))
@@ -279,3 +373,10 @@ impl Runtime for DPDKRuntime {
         &self.scheduler
     }
 }
+
+// Whether the NIC checked both the IPv4 and L4 checksums and found them good. Ports without
+// checksum offload report them as unknown, which we check ourselves.
+fn checksums_good(ol_flags: u64) -> bool {
+    ol_flags & PKT_RX_IP_CKSUM_MASK as u64 == PKT_RX_IP_CKSUM_GOOD as u64
+        && ol_flags & PKT_RX_L4_CKSUM_MASK as u64 == PKT_RX_L4_CKSUM_GOOD as u64
+}